@@ -0,0 +1,475 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides a textual assembler that turns hand-written AVM assembly into a `LinkedProgram`.
+//!
+//! Each non-empty line holds a label definition (`name:`), a `static:` directive followed by a
+//! value, or an instruction: an optional immediate in square brackets followed by an
+//! `AVMOpcode` mnemonic. Immediates use the same syntax as immediates in mini `asm` blocks, and
+//! additionally accept label names and the `_`, `Tuple(..)`, `CodePoint(Internal(..))` and
+//! `Buffer(..)` forms printed by the `pretty` output format, so that disassembler output can be
+//! fed back into the assembler. Everything after `//` on a line is a comment.
+
+use crate::compile::{file_id_from_name, CompileError, DebugInfo, FileInfo};
+use crate::link::{strip_labels, LinkedProgram, SerializableTypeTree};
+use crate::mavm::{AVMOpcode, CodePt, Instruction, Label, Opcode, Value};
+use crate::pos::{BytePos, Column, Line, Location};
+use crate::uint256::Uint256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+///Reads the assembly file at path, registers it in file_info_chart, and assembles it into a
+/// `LinkedProgram`.
+pub fn assemble_from_file(
+    path: &Path,
+    file_info_chart: &mut BTreeMap<u64, FileInfo>,
+) -> Result<LinkedProgram, CompileError> {
+    let mut file = File::open(path).map_err(|why| {
+        CompileError::new(
+            String::from("Assembly error"),
+            format!("Can not open {}: {:?}", path.display(), why),
+            vec![],
+        )
+    })?;
+    let mut source = String::new();
+    file.read_to_string(&mut source).map_err(|why| {
+        CompileError::new(
+            String::from("Assembly error"),
+            format!("Can not read {}: {:?}", path.display(), why),
+            vec![],
+        )
+    })?;
+
+    let file_id = file_id_from_name(&path.display().to_string());
+    file_info_chart.insert(
+        file_id,
+        FileInfo {
+            name: path.display().to_string(),
+            path: path.display().to_string(),
+            contents: source.split("\n").map(|x| x.to_string()).collect(),
+        },
+    );
+
+    let mut program = assemble(&source, file_id)?;
+    program.file_info_chart = file_info_chart.clone();
+    Ok(program)
+}
+
+///Assembles source into a `LinkedProgram`, attributing instruction locations to file_id.
+pub fn assemble(source: &str, file_id: u64) -> Result<LinkedProgram, CompileError> {
+    let mut assembler = Assembler::new(file_id);
+    let mut code = vec![];
+    let mut static_val = Value::none();
+    let mut pending_immediate: Option<(Value, Location)> = None;
+
+    let mut line_offset = 0;
+    for (line_num, full_line) in source.split("\n").enumerate() {
+        let line = match full_line.find("//") {
+            Some(idx) => &full_line[..idx],
+            None => full_line,
+        };
+        assembler.start_line(line, line_num, line_offset);
+        line_offset += full_line.len() + 1;
+
+        assembler.skip_whitespace();
+        if assembler.at_end() {
+            continue;
+        }
+        if assembler.eat_str("static:") {
+            static_val = assembler.parse_value(false)?;
+            assembler.expect_end()?;
+            continue;
+        }
+        assembler.skip_index_prefix();
+        if assembler.eat_str("[") {
+            let location = assembler.location();
+            if pending_immediate.is_some() {
+                return Err(assembler.error("Found two immediates for the same instruction"));
+            }
+            let immediate = assembler.parse_value(true)?;
+            assembler.expect("]")?;
+            pending_immediate = Some((immediate, location));
+            assembler.skip_whitespace();
+            if assembler.at_end() {
+                continue;
+            }
+        }
+
+        let location = assembler.location();
+        let name = assembler.parse_ident()?;
+        if assembler.eat_str(":") {
+            if pending_immediate.is_some() {
+                return Err(assembler.error("Expected opcode after immediate, found label"));
+            }
+            let label = assembler.define_label(name, location)?;
+            code.push(Instruction::from_opcode(
+                Opcode::Label(label),
+                DebugInfo::from(Some(location)),
+            ));
+            assembler.expect_end()?;
+            continue;
+        }
+        let opcode = AVMOpcode::from_name(&name).ok_or_else(|| {
+            CompileError::new(
+                String::from("Assembly error"),
+                format!("Unknown opcode \"{}\"", name),
+                vec![location],
+            )
+        })?;
+        let (immediate, location) = match pending_immediate.take() {
+            Some((immediate, imm_location)) => (Some(immediate), imm_location),
+            None => (None, location),
+        };
+        code.push(Instruction::new(
+            Opcode::AVMOpcode(opcode),
+            immediate,
+            DebugInfo::from(Some(location)),
+        ));
+        assembler.skip_location_annotation();
+        assembler.expect_end()?;
+    }
+
+    if let Some((_, location)) = pending_immediate {
+        return Err(CompileError::new(
+            String::from("Assembly error"),
+            String::from("Immediate value is not followed by an opcode"),
+            vec![location],
+        ));
+    }
+    assembler.check_references()?;
+
+    let (code, _) = strip_labels(code, &[], &[])?;
+    let code = code
+        .into_iter()
+        .map(|insn| {
+            if let Opcode::AVMOpcode(inner) = insn.opcode {
+                Ok(Instruction::new(inner, insn.immediate, insn.debug_info))
+            } else {
+                Err(CompileError::new(
+                    String::from("Assembly error"),
+                    format!("In final output encountered virtual opcode {}", insn.opcode),
+                    insn.debug_info.location.into_iter().collect(),
+                ))
+            }
+        })
+        .collect::<Result<Vec<_>, CompileError>>()?;
+
+    Ok(LinkedProgram {
+        arbos_version: 0,
+        code,
+        static_val,
+        globals: vec![],
+        file_info_chart: BTreeMap::new(),
+        type_tree: SerializableTypeTree::from_type_tree(HashMap::new()),
+        func_debug_info: vec![],
+    })
+}
+
+///Line-oriented parser state, along with the label bookkeeping needed across lines.
+struct Assembler {
+    file_id: u64,
+    line: Vec<u8>,
+    pos: usize,
+    line_num: usize,
+    line_offset: usize,
+    labels: HashMap<String, Label>,
+    defined_labels: HashSet<String>,
+    label_references: Vec<(String, Location)>,
+}
+
+impl Assembler {
+    fn new(file_id: u64) -> Self {
+        Assembler {
+            file_id,
+            line: vec![],
+            pos: 0,
+            line_num: 0,
+            line_offset: 0,
+            labels: HashMap::new(),
+            defined_labels: HashSet::new(),
+            label_references: vec![],
+        }
+    }
+
+    fn start_line(&mut self, line: &str, line_num: usize, line_offset: usize) {
+        self.line = line.as_bytes().to_vec();
+        self.pos = 0;
+        self.line_num = line_num;
+        self.line_offset = line_offset;
+    }
+
+    fn location(&self) -> Location {
+        Location {
+            line: Line::from(self.line_num),
+            column: Column::from(self.pos),
+            absolute: BytePos::from(self.line_offset + self.pos),
+            file_id: self.file_id,
+        }
+    }
+
+    fn error(&self, description: &str) -> CompileError {
+        CompileError::new(
+            String::from("Assembly error"),
+            description.to_string(),
+            vec![self.location()],
+        )
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.line.get(self.pos).copied()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.line.len()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    ///Skips leading whitespace, then consumes s and returns true if the line continues with s,
+    /// otherwise returns false without consuming anything other than whitespace.
+    fn eat_str(&mut self, s: &str) -> bool {
+        self.skip_whitespace();
+        if self.line[self.pos..].starts_with(s.as_bytes()) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), CompileError> {
+        if self.eat_str(s) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected \"{}\"", s)))
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), CompileError> {
+        self.skip_whitespace();
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(self.error("Unexpected text at end of line"))
+        }
+    }
+
+    ///Skips the instruction index that the `pretty` output format prints at the start of each
+    /// instruction, such as `00042:`.
+    fn skip_index_prefix(&mut self) {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        if self.pos == start || !self.eat_str(":") {
+            self.pos = start;
+        }
+    }
+
+    ///Skips the source location that the `pretty` output format prints after each opcode.
+    fn skip_location_annotation(&mut self) {
+        self.skip_whitespace();
+        let rest = &self.line[self.pos..];
+        if rest.starts_with(b"Line: ") || rest.starts_with(b"No location") {
+            self.pos = self.line.len();
+        }
+    }
+
+    fn parse_ident(&mut self) -> Result<String, CompileError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphabetic() || c == b'_' || (self.pos > start && c.is_ascii_digit()) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if self.pos == start {
+            Err(self.error("Expected identifier"))
+        } else {
+            Ok(String::from_utf8_lossy(&self.line[start..self.pos]).to_string())
+        }
+    }
+
+    fn parse_uint(&mut self) -> Result<Uint256, CompileError> {
+        self.skip_whitespace();
+        let location = self.location();
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(&self.line[start..self.pos]).to_string();
+        let parsed = if let Some(hex_digits) = text.strip_prefix("0x") {
+            Uint256::from_string_hex(hex_digits)
+        } else if text.chars().all(|c| c.is_ascii_digit()) {
+            Uint256::from_string(&text)
+        } else {
+            None
+        };
+        parsed.ok_or_else(|| {
+            CompileError::new(
+                String::from("Assembly error"),
+                format!("Invalid integer \"{}\"", text),
+                vec![location],
+            )
+        })
+    }
+
+    ///Parses the remainder of a parenthesized, comma separated list of values, with the opening
+    /// parenthesis already consumed.
+    fn parse_tuple_items(&mut self, allow_labels: bool) -> Result<Vec<Value>, CompileError> {
+        let mut items = vec![self.parse_value(allow_labels)?];
+        while self.eat_str(",") {
+            if self.eat_str(")") {
+                return Ok(items);
+            }
+            items.push(self.parse_value(allow_labels)?);
+        }
+        self.expect(")")?;
+        Ok(items)
+    }
+
+    fn parse_value(&mut self, allow_labels: bool) -> Result<Value, CompileError> {
+        self.skip_whitespace();
+        let location = self.location();
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                Ok(Value::new_tuple(self.parse_tuple_items(allow_labels)?))
+            }
+            Some(b'0'..=b'9') => Ok(Value::Int(self.parse_uint()?)),
+            Some(_) => match self.parse_ident()?.as_str() {
+                "_" => Ok(Value::none()),
+                "Tuple" => {
+                    self.expect("(")?;
+                    Ok(Value::new_tuple(self.parse_tuple_items(allow_labels)?))
+                }
+                "CodePoint" => {
+                    self.expect("(")?;
+                    self.expect("Internal")?;
+                    self.expect("(")?;
+                    let pc = self.parse_uint()?.to_usize().ok_or_else(|| {
+                        CompileError::new(
+                            String::from("Assembly error"),
+                            String::from("Code point out of range"),
+                            vec![location],
+                        )
+                    })?;
+                    self.expect(")")?;
+                    self.expect(")")?;
+                    Ok(Value::CodePoint(CodePt::new_internal(pc)))
+                }
+                "Buffer" => {
+                    self.expect("(")?;
+                    let start = self.pos;
+                    while let Some(c) = self.peek() {
+                        if c.is_ascii_hexdigit() {
+                            self.pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    let bytes = hex::decode(&self.line[start..self.pos])
+                        .map_err(|_| self.error("Invalid hex contents for buffer"))?;
+                    self.expect(")")?;
+                    Ok(Value::new_buffer(bytes))
+                }
+                name => {
+                    if !allow_labels {
+                        return Err(CompileError::new(
+                            String::from("Assembly error"),
+                            format!("Label \"{}\" can not be used here", name),
+                            vec![location],
+                        ));
+                    }
+                    let label = self.label(name);
+                    self.label_references.push((name.to_string(), location));
+                    Ok(Value::Label(label))
+                }
+            },
+            None => Err(self.error("Expected value")),
+        }
+    }
+
+    ///Returns the label associated with name, creating a new one if name hasn't been seen yet.
+    fn label(&mut self, name: &str) -> Label {
+        let next = Label::Anon(self.labels.len());
+        *self.labels.entry(name.to_string()).or_insert(next)
+    }
+
+    fn define_label(&mut self, name: String, location: Location) -> Result<Label, CompileError> {
+        if !self.defined_labels.insert(name.clone()) {
+            return Err(CompileError::new(
+                String::from("Assembly error"),
+                format!("Duplicate definition of label \"{}\"", name),
+                vec![location],
+            ));
+        }
+        Ok(self.label(&name))
+    }
+
+    fn check_references(&self) -> Result<(), CompileError> {
+        for (name, location) in &self.label_references {
+            if !self.defined_labels.contains(name) {
+                return Err(CompileError::new(
+                    String::from("Assembly error"),
+                    format!("Reference to undefined label \"{}\"", name),
+                    vec![*location],
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_asm_round_trip() {
+    let source = "
+        static: (1, 0x100000000, _)
+        // initialize the register, then loop until the counter hits zero
+            [(3, (Buffer(0102), 4),)] rset
+            [5] noop
+        loop:
+            dup0
+            iszero
+            [done] cjump
+            [1] swap1
+            sub
+            [loop] jump
+        done:
+            pop
+            halt
+    ";
+    let program = assemble(source, 0).unwrap();
+    assert_eq!(program.code.len(), 10);
+    assert_eq!(
+        program.code[4].immediate,
+        Some(Value::CodePoint(CodePt::new_internal(8)))
+    );
+
+    let mut disassembled = vec![];
+    program.to_output(&mut disassembled, Some("pretty"), true);
+    let reassembled = assemble(&String::from_utf8(disassembled).unwrap(), 0).unwrap();
+    assert_eq!(program.static_val, reassembled.static_val);
+    assert_eq!(program.code.len(), reassembled.code.len());
+    for (insn, reinsn) in program.code.iter().zip(reassembled.code.iter()) {
+        assert_eq!(insn.opcode, reinsn.opcode);
+        assert_eq!(insn.immediate, reinsn.immediate);
+    }
+}
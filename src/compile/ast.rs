@@ -14,7 +14,7 @@ use crate::pos::Location;
 use crate::stringtable::StringId;
 use crate::uint256::Uint256;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fmt::Formatter;
 
@@ -58,6 +58,61 @@ impl From<Option<Location>> for DebugInfo {
     }
 }
 
+///Debugging info describing the call frame of a compiled function, so that debuggers can show its
+/// locals by name and type.
+///
+/// The function's code spans the source from location to end, and its frame holds frame_size
+/// local slots.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FuncDebugInfo {
+    pub name: String,
+    pub location: Option<Location>,
+    pub end: Option<Location>,
+    pub frame_size: usize,
+    pub locals: Vec<LocalVarInfo>,
+}
+
+///A local variable of a function, stored in slot of its frame from location onwards.
+///
+/// Slots are reused by variables in disjoint scopes, so several locals may share a slot.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LocalVarInfo {
+    pub name: String,
+    pub slot: usize,
+    pub tipe: Type,
+    pub location: Option<Location>,
+}
+
+impl FuncDebugInfo {
+    ///Returns true if location lies within the source of this function.
+    pub fn contains(&self, location: &Location) -> bool {
+        match (self.location, self.end) {
+            (Some(start), Some(end)) => {
+                start.file_id == location.file_id
+                    && start.absolute <= location.absolute
+                    && location.absolute <= end.absolute
+            }
+            _ => false,
+        }
+    }
+
+    ///Returns the locals visible at location, at most one per slot, sorted by slot.
+    ///
+    /// Of the locals sharing a slot, the one declared last before location is chosen.
+    pub fn locals_at(&self, location: &Location) -> Vec<&LocalVarInfo> {
+        let mut by_slot: BTreeMap<usize, &LocalVarInfo> = BTreeMap::new();
+        for local in &self.locals {
+            let declared = local
+                .location
+                .map_or(true, |decl| decl.absolute <= location.absolute);
+            if declared {
+                by_slot.insert(local.slot, local);
+            }
+        }
+        by_slot.into_values().collect()
+    }
+}
+
 ///A top level language declaration.  Represents any language construct that can be directly
 /// embedded in a source file, and do not need to be contained in a function or other context.
 #[derive(Debug, Clone)]
@@ -12,7 +12,7 @@ use crate::compile::ast::{DebugInfo, MatchPatternKind};
 use crate::compile::typecheck::{
     TypeCheckedCodeBlock, TypeCheckedExprKind, TypeCheckedStatementKind,
 };
-use crate::compile::{CompileError, ErrorSystem, FileInfo};
+use crate::compile::{CompileError, ErrorSystem, FileInfo, FuncDebugInfo, LocalVarInfo};
 use crate::link::{ImportedFunc, TupleTree, TUPLE_SIZE};
 use crate::mavm::{AVMOpcode, Instruction, Label, LabelGenerator, Opcode, Value};
 use crate::pos::Location;
@@ -38,8 +38,8 @@ pub fn new_codegen_error(reason: String, location: Option<Location>) -> CodegenE
 /// builtins, imported_funcs is a list of functions imported from other modules, and global_vars
 /// lists the globals available in the module.
 ///
-/// The function returns a vector of instructions representing the generated code if it is
-/// successful, otherwise it returns a CodegenError.
+/// The function returns a vector of instructions representing the generated code and the debug
+/// info of each function's frame if it is successful, otherwise it returns a CodegenError.
 pub fn mavm_codegen(
     funcs: BTreeMap<StringId, TypeCheckedFunc>,
     string_table: &StringTable,
@@ -48,7 +48,7 @@ pub fn mavm_codegen(
     file_info_chart: &mut BTreeMap<u64, FileInfo>,
     error_system: &mut ErrorSystem,
     release_build: bool,
-) -> Result<Vec<Instruction>, CodegenError> {
+) -> Result<(Vec<Instruction>, Vec<FuncDebugInfo>), CodegenError> {
     let mut import_func_map = HashMap::new();
     for imp_func in imported_funcs {
         import_func_map.insert(imp_func.name_id, Label::External(imp_func.slot_num));
@@ -61,9 +61,10 @@ pub fn mavm_codegen(
 
     let mut label_gen = LabelGenerator::new();
     let mut funcs_code = BTreeMap::new();
+    let mut func_debug_info = vec![];
     for (_id, func) in funcs {
         let id = func.name;
-        let (lg, function_code) = mavm_codegen_func(
+        let (lg, function_code, debug_info) = mavm_codegen_func(
             func,
             label_gen,
             string_table,
@@ -75,12 +76,13 @@ pub fn mavm_codegen(
         )?;
         label_gen = lg;
         funcs_code.insert(id, function_code);
+        func_debug_info.push(debug_info);
     }
     let mut code = Vec::new();
     for (_id, mut func) in funcs_code {
         code.append(&mut func)
     }
-    Ok(code)
+    Ok((code, func_debug_info))
 }
 
 ///This generates code for individual mini functions.
@@ -91,7 +93,8 @@ pub fn mavm_codegen(
 /// module.
 ///
 /// If successful the function returns a tuple containing the state of the label generator after
-/// codegen, and a vector of the generated code, otherwise it returns a CodegenError.
+/// codegen, a vector of the generated code, and the debug info of the function's frame, otherwise
+/// it returns a CodegenError.
 fn mavm_codegen_func(
     mut func: TypeCheckedFunc,
     label_gen: LabelGenerator,
@@ -101,7 +104,7 @@ fn mavm_codegen_func(
     file_info_chart: &mut BTreeMap<u64, FileInfo>,
     error_system: &mut ErrorSystem,
     release_build: bool,
-) -> Result<(LabelGenerator, Vec<Instruction>), CodegenError> {
+) -> Result<(LabelGenerator, Vec<Instruction>, FuncDebugInfo), CodegenError> {
     if func.ret_type == Type::Void
         && func.code.last().cloned().map(|s| s.kind) != Some(TypeCheckedStatementKind::ReturnVoid())
     {
@@ -130,8 +133,15 @@ fn mavm_codegen_func(
         debug_info,
     )); // placeholder; will replace this later
 
+    let mut local_vars = vec![];
     for (index, arg) in func.args.iter().enumerate() {
         locals.insert(arg.name, index);
+        local_vars.push(LocalVarInfo {
+            name: string_table.name_from_id(arg.name).clone(),
+            slot: index,
+            tipe: arg.tipe.clone(),
+            location: debug_info.location,
+        });
     }
     let (label_gen, max_num_locals, _slot_map) = mavm_codegen_statements(
         func.code,
@@ -145,6 +155,7 @@ fn mavm_codegen_func(
         0,
         &mut vec![],
         file_info_chart,
+        &mut local_vars,
         error_system,
         release_build,
     )?;
@@ -164,7 +175,19 @@ fn mavm_codegen_func(
             debug_info.location,
         ));
     }
-    Ok((label_gen, code))
+    let end = code
+        .iter()
+        .filter_map(|insn| insn.debug_info.location)
+        .filter(|loc| Some(loc.file_id) == debug_info.location.map(|start| start.file_id))
+        .max_by_key(|loc| loc.absolute);
+    let func_debug_info = FuncDebugInfo {
+        name: string_table.name_from_id(func.name).clone(),
+        location: debug_info.location,
+        end,
+        frame_size: max_num_locals,
+        locals: local_vars,
+    };
+    Ok((label_gen, code, func_debug_info))
 }
 
 fn mavm_codegen_code_block<'a>(
@@ -179,6 +202,7 @@ fn mavm_codegen_code_block<'a>(
     prepushed_vals: usize,
     scopes: &mut Vec<(String, Label, Option<Type>)>,
     file_info_chart: &mut BTreeMap<u64, FileInfo>,
+    local_vars: &mut Vec<LocalVarInfo>,
     error_system: &mut ErrorSystem,
     debug_info: DebugInfo,
     release_build: bool,
@@ -201,6 +225,7 @@ fn mavm_codegen_code_block<'a>(
         prepushed_vals,
         scopes,
         file_info_chart,
+        local_vars,
         error_system,
         release_build,
     )?;
@@ -219,6 +244,7 @@ fn mavm_codegen_code_block<'a>(
             prepushed_vals,
             scopes,
             file_info_chart,
+            local_vars,
             error_system,
             release_build,
         )
@@ -260,6 +286,7 @@ fn mavm_codegen_statements(
     prepushed_vals: usize,
     scopes: &mut Vec<(String, Label, Option<Type>)>,
     file_info_chart: &mut BTreeMap<u64, FileInfo>,
+    local_vars: &mut Vec<LocalVarInfo>,
     error_system: &mut ErrorSystem,
     release_build: bool,
 ) -> Result<(LabelGenerator, usize, HashMap<StringId, usize>), CodegenError> {
@@ -279,6 +306,7 @@ fn mavm_codegen_statements(
             prepushed_vals,
             scopes,
             file_info_chart,
+            local_vars,
             error_system,
             release_build,
         )?;
@@ -313,6 +341,7 @@ fn mavm_codegen_statement(
     prepushed_vals: usize,
     scopes: &mut Vec<(String, Label, Option<Type>)>,
     file_info_chart: &mut BTreeMap<u64, FileInfo>,
+    local_vars: &mut Vec<LocalVarInfo>,
     error_system: &mut ErrorSystem,
     release_build: bool,
 ) -> Result<(LabelGenerator, usize, HashMap<StringId, usize>), CodegenError> {
@@ -337,6 +366,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -406,6 +436,7 @@ fn mavm_codegen_statement(
                     prepushed_vals,
                     &mut inner_scopes,
                     file_info_chart,
+                    local_vars,
                     error_system,
                     release_build,
                 )?
@@ -432,6 +463,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -466,6 +498,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -478,6 +511,7 @@ fn mavm_codegen_statement(
                 locals,
                 global_var_map,
                 string_table,
+                local_vars,
                 debug,
             )?;
             num_locals += new_locals;
@@ -506,6 +540,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -531,6 +566,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -571,6 +607,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -589,6 +626,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -621,6 +659,7 @@ fn mavm_codegen_statement(
                     prepushed_vals + i,
                     scopes,
                     file_info_chart,
+                    local_vars,
                     error_system,
                     release_build,
                 )?;
@@ -646,6 +685,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -697,6 +737,7 @@ fn mavm_codegen_statement(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -718,12 +759,19 @@ fn mavm_codegen_tuple_pattern(
     locals: &HashMap<usize, usize>,
     global_var_map: &HashMap<StringId, usize>,
     string_table: &StringTable,
+    local_vars: &mut Vec<LocalVarInfo>,
     debug_info: DebugInfo,
 ) -> Result<(usize, HashMap<usize, usize>, HashSet<usize>), CodegenError> {
     match &pattern.kind {
         MatchPatternKind::Bind(name) => {
             let mut bindings = HashMap::new();
             bindings.insert(*name, local_slot_num_base);
+            local_vars.push(LocalVarInfo {
+                name: string_table.name_from_id(*name).clone(),
+                slot: local_slot_num_base,
+                tipe: pattern.cached.clone(),
+                location: debug_info.location,
+            });
             code.push(Instruction::from_opcode_imm(
                 Opcode::SetLocal,
                 Value::Int(Uint256::from_usize(local_slot_num_base)),
@@ -777,6 +825,7 @@ fn mavm_codegen_tuple_pattern(
                     locals,
                     global_var_map,
                     string_table,
+                    local_vars,
                     debug_info,
                 )?;
                 num_bindings += num_new_bindings;
@@ -822,6 +871,7 @@ fn mavm_codegen_expr<'a>(
     prepushed_vals: usize,
     scopes: &mut Vec<(String, Label, Option<Type>)>,
     file_info_chart: &mut BTreeMap<u64, FileInfo>,
+    local_vars: &mut Vec<LocalVarInfo>,
     error_system: &mut ErrorSystem,
     release_build: bool,
 ) -> Result<(LabelGenerator, &'a mut Vec<Instruction>, usize), CodegenError> {
@@ -860,6 +910,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -882,6 +933,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -927,6 +979,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -955,6 +1008,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -970,6 +1024,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals + 1,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1038,6 +1093,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1053,6 +1109,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals + 1,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1068,6 +1125,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals + 2,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1098,6 +1156,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1127,6 +1186,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1146,6 +1206,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1179,6 +1240,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1243,6 +1305,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1266,6 +1329,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1304,6 +1368,7 @@ fn mavm_codegen_expr<'a>(
                     prepushed_vals + i,
                     scopes,
                     file_info_chart,
+                    local_vars,
                     error_system,
                     release_build,
                 )?;
@@ -1331,6 +1396,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals + n_args + 1,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1353,6 +1419,7 @@ fn mavm_codegen_expr<'a>(
             prepushed_vals,
             scopes,
             file_info_chart,
+            local_vars,
             error_system,
             debug,
             release_build,
@@ -1374,6 +1441,7 @@ fn mavm_codegen_expr<'a>(
                     prepushed_vals + i,
                     scopes,
                     file_info_chart,
+                    local_vars,
                     error_system,
                     release_build,
                 )?;
@@ -1413,6 +1481,7 @@ fn mavm_codegen_expr<'a>(
                     prepushed_vals + i,
                     scopes,
                     file_info_chart,
+                    local_vars,
                     error_system,
                     release_build,
                 )?;
@@ -1468,6 +1537,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )
@@ -1485,6 +1555,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1500,6 +1571,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals + 1,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1572,6 +1644,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )
@@ -1616,6 +1689,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )
@@ -1636,6 +1710,7 @@ fn mavm_codegen_expr<'a>(
                         prepushed_vals,
                         scopes,
                         file_info_chart,
+                        local_vars,
                         error_system,
                         release_build,
                     )?;
@@ -1725,6 +1800,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )
@@ -1762,6 +1838,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )
@@ -1782,6 +1859,7 @@ fn mavm_codegen_expr<'a>(
             prepushed_vals,
             scopes,
             file_info_chart,
+            local_vars,
             error_system,
             release_build,
         ),
@@ -1822,6 +1900,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )
@@ -1839,6 +1918,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1854,6 +1934,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals + 1,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1887,6 +1968,7 @@ fn mavm_codegen_expr<'a>(
             prepushed_vals,
             scopes,
             file_info_chart,
+            local_vars,
             error_system,
             release_build,
         ),
@@ -1906,6 +1988,7 @@ fn mavm_codegen_expr<'a>(
                     prepushed_vals + i,
                     scopes,
                     file_info_chart,
+                    local_vars,
                     error_system,
                     release_build,
                 )?;
@@ -1931,6 +2014,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -1988,6 +2072,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -2014,6 +2099,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 debug,
                 release_build,
@@ -2039,6 +2125,7 @@ fn mavm_codegen_expr<'a>(
                     prepushed_vals,
                     scopes,
                     file_info_chart,
+                    local_vars,
                     error_system,
                     debug,
                     release_build,
@@ -2055,6 +2142,15 @@ fn mavm_codegen_expr<'a>(
             let slot_num = num_locals;
             let mut new_locals = locals.clone();
             new_locals.insert(*name, slot_num);
+            local_vars.push(LocalVarInfo {
+                name: string_table.name_from_id(*name).clone(),
+                slot: slot_num,
+                tipe: match expr.get_type() {
+                    Type::Option(inner) => *inner,
+                    _ => Type::Any,
+                },
+                location: debug.location,
+            });
             let (lg, c, exp_locals) = mavm_codegen_expr(
                 expr,
                 code,
@@ -2067,6 +2163,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -2112,6 +2209,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 debug,
                 release_build,
@@ -2142,6 +2240,7 @@ fn mavm_codegen_expr<'a>(
                     prepushed_vals,
                     scopes,
                     file_info_chart,
+                    local_vars,
                     error_system,
                     debug,
                     release_build,
@@ -2186,6 +2285,7 @@ fn mavm_codegen_expr<'a>(
                 prepushed_vals,
                 scopes,
                 file_info_chart,
+                local_vars,
                 error_system,
                 release_build,
             )?;
@@ -2223,6 +2323,7 @@ fn codegen_fixed_array_mod<'a>(
     prepushed_vals: usize,
     scopes: &mut Vec<(String, Label, Option<Type>)>,
     file_info_chart: &mut BTreeMap<u64, FileInfo>,
+    local_vars: &mut Vec<LocalVarInfo>,
     error_system: &mut ErrorSystem,
     release_build: bool,
 ) -> Result<(LabelGenerator, &'a mut Vec<Instruction>, usize), CodegenError> {
@@ -2238,6 +2339,7 @@ fn codegen_fixed_array_mod<'a>(
         prepushed_vals,
         scopes,
         file_info_chart,
+        local_vars,
         error_system,
         release_build,
     )?;
@@ -2253,6 +2355,7 @@ fn codegen_fixed_array_mod<'a>(
         prepushed_vals + 1,
         scopes,
         file_info_chart,
+        local_vars,
         error_system,
         release_build,
     )?;
@@ -2268,6 +2371,7 @@ fn codegen_fixed_array_mod<'a>(
         prepushed_vals + 2,
         scopes,
         file_info_chart,
+        local_vars,
         error_system,
         release_build,
     )?;
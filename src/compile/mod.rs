@@ -10,22 +10,24 @@ use crate::pos::{BytePos, Location};
 use crate::stringtable::{StringId, StringTable};
 use ast::Func;
 use clap::Clap;
+use ethers_core::utils::keccak256;
 use lalrpop_util::lalrpop_mod;
 use lalrpop_util::ParseError;
 use mini::DeclsParser;
 use miniconstants::init_constant_table;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
-use std::hash::{Hash, Hasher};
 use std::io::{self, Read};
 use std::path::Path;
 use typecheck::TypeCheckedFunc;
 
-pub use ast::{DebugInfo, GlobalVarDecl, StructField, TopLevelDecl, Type, TypeTree};
+pub use ast::{
+    DebugInfo, FuncDebugInfo, GlobalVarDecl, LocalVarInfo, StructField, TopLevelDecl, Type,
+    TypeTree,
+};
 pub use source::Lines;
 use std::str::FromStr;
 pub use typecheck::{AbstractSyntaxTree, InliningMode, TypeCheckedNode};
@@ -61,6 +63,13 @@ pub struct CompileStruct {
     pub release_build: bool,
     #[clap(short, long)]
     pub no_builtins: bool,
+    ///Writes debug info to a sidecar file next to the output instead of into it, where loaders of
+    /// the output look for it, see `DebugSidecar::path_for`.
+    #[clap(long)]
+    pub split_debug_info: bool,
+    ///Compiles a second time on a single thread and fails if the outputs differ.
+    #[clap(long)]
+    pub verify_reproducible: bool,
 }
 
 #[derive(Clap, Debug)]
@@ -90,7 +99,7 @@ struct Module {
     ///List of functions defined locally within the source file
     funcs: BTreeMap<StringId, Func>,
     ///Map from `StringId`s in this file to the `Type`s they represent.
-    named_types: HashMap<StringId, Type>,
+    named_types: BTreeMap<StringId, Type>,
     ///List of constants used in this file.
     constants: HashSet<String>,
     ///List of global variables defined within this file.
@@ -100,7 +109,7 @@ struct Module {
     ///Map from `StringId`s to the names they derived from.
     string_table: StringTable,
     ///Map from `StringId`s to the types of the functions they represent.
-    func_table: HashMap<StringId, Type>,
+    func_table: BTreeMap<StringId, Type>,
     ///The path to the module
     path: Vec<String>,
     ///The name of the module, this may be removed later.
@@ -120,7 +129,7 @@ struct TypeCheckedModule {
     ///The list of exported functions exported through the old import/export system
     exported_funcs: Vec<ExportedFunc>,
     ///Map from `StringId`s in this file to the `Type`s they represent.
-    named_types: HashMap<StringId, Type>,
+    named_types: BTreeMap<StringId, Type>,
     ///List of constants used in this file.
     constants: HashSet<String>,
     ///List of global variables defined in this module.
@@ -221,18 +230,70 @@ impl CompileStruct {
             Ok((postlinked_prog, error_system))
         }
     }
+
+    ///Compiles the input again on a single thread and checks that the result is identical to
+    /// program, which should come from a previous call to `invoke`.
+    ///
+    /// Since every compilation uses freshly seeded `HashMap`s, this detects output that depends on
+    /// either thread scheduling or hash seeds. Returns a `CompileError` describing the first
+    /// difference found, if any.
+    pub fn verify_reproducible(&self, program: &LinkedProgram) -> Result<(), CompileError> {
+        let reproducibility_error = |description| {
+            CompileError::new(String::from("Reproducibility error"), description, vec![])
+        };
+        let rebuilt = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .stack_size(8192 * 1024)
+            .build()
+            .map_err(|e| reproducibility_error(format!("failed to create thread pool: {}", e)))?
+            .install(|| self.invoke())
+            .map_err(|_| reproducibility_error(String::from("second compilation failed")))?
+            .0;
+
+        let code_len = program.code.len().max(rebuilt.code.len());
+        if let Some(idx) =
+            (0..code_len).find(|idx| program.code.get(*idx) != rebuilt.code.get(*idx))
+        {
+            let display = |insn: Option<&Instruction<_>>| match insn {
+                Some(insn) => format!("{}", insn),
+                None => String::from("end of code"),
+            };
+            return Err(reproducibility_error(format!(
+                "instruction {} differs between builds: {} vs {}",
+                idx,
+                display(program.code.get(idx)),
+                display(rebuilt.code.get(idx)),
+            )));
+        }
+        if program.static_val != rebuilt.static_val {
+            return Err(reproducibility_error(String::from(
+                "static value differs between builds",
+            )));
+        }
+        if program.globals != rebuilt.globals {
+            return Err(reproducibility_error(String::from(
+                "globals differ between builds",
+            )));
+        }
+        if serde_json::to_string(program).ok() != serde_json::to_string(&rebuilt).ok() {
+            return Err(reproducibility_error(String::from(
+                "serialized output differs between builds",
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl Module {
     fn new(
         imported_funcs: Vec<ImportedFunc>,
         funcs: BTreeMap<StringId, Func>,
-        named_types: HashMap<usize, Type>,
+        named_types: BTreeMap<usize, Type>,
         constants: HashSet<String>,
         global_vars: Vec<GlobalVarDecl>,
         imports: Vec<Import>,
         string_table: StringTable,
-        func_table: HashMap<usize, Type>,
+        func_table: BTreeMap<usize, Type>,
         path: Vec<String>,
         name: String,
     ) -> Self {
@@ -257,7 +318,7 @@ impl TypeCheckedModule {
         string_table: StringTable,
         imported_funcs: Vec<ImportedFunc>,
         exported_funcs: Vec<ExportedFunc>,
-        named_types: HashMap<usize, Type>,
+        named_types: BTreeMap<usize, Type>,
         constants: HashSet<String>,
         global_vars: Vec<GlobalVarDecl>,
         imports: Vec<Import>,
@@ -426,6 +487,9 @@ pub struct CompiledProgram {
     pub file_info_chart: HashMap<u64, FileInfo>,
     ///Tree of the types
     pub type_tree: TypeTree,
+    ///Describes the call frame of each function, for debuggers
+    #[serde(default)]
+    pub func_debug_info: Vec<FuncDebugInfo>,
 }
 
 impl CompiledProgram {
@@ -446,6 +510,7 @@ impl CompiledProgram {
             source_file_map,
             file_info_chart,
             type_tree,
+            func_debug_info: vec![],
         }
     }
 
@@ -491,22 +556,21 @@ impl CompiledProgram {
             relocated_imported_funcs.push(imp_func.relocate(int_offset, ext_offset));
         }
 
-        (
-            CompiledProgram::new(
-                relocated_code,
-                relocated_exported_funcs,
-                relocated_imported_funcs,
-                {
-                    let mut new_vec = globals_offset.clone();
-                    new_vec.append(&mut self.globals.clone());
-                    new_vec
-                },
-                source_file_map,
-                self.file_info_chart,
-                self.type_tree,
-            ),
-            max_func_offset,
-        )
+        let mut relocated = CompiledProgram::new(
+            relocated_code,
+            relocated_exported_funcs,
+            relocated_imported_funcs,
+            {
+                let mut new_vec = globals_offset.clone();
+                new_vec.append(&mut self.globals.clone());
+                new_vec
+            },
+            source_file_map,
+            self.file_info_chart,
+            self.type_tree,
+        );
+        relocated.func_debug_info = self.func_debug_info;
+        (relocated, max_func_offset)
     }
 
     ///Writes self to output in format "format".  Supported values are: "pretty", "json", or
@@ -677,7 +741,12 @@ pub fn compile_from_folder(
         .expect("no main")];
     modules.append(&mut {
         let mut out: Vec<_> = programs.values().cloned().collect();
-        out.sort_by(|module1, module2| module2.name.cmp(&module1.name));
+        out.sort_by(|module1, module2| {
+            module2
+                .name
+                .cmp(&module1.name)
+                .then_with(|| module2.path.cmp(&module1.path))
+        });
         out
     });
     let mut typechecked_modules =
@@ -688,7 +757,7 @@ pub fn compile_from_folder(
     }
 
     // Control flow analysis stage
-    let mut program_callgraph = HashMap::new();
+    let mut program_callgraph = BTreeMap::new();
     for module in &mut typechecked_modules {
         module.flowcheck(error_system);
         program_callgraph.insert(module.path.clone(), module.build_callgraph());
@@ -717,6 +786,17 @@ pub fn compile_from_folder(
     Ok(progs)
 }
 
+///Returns the id that identifies the source file with the given name in `Location`s and file info
+/// charts.
+///
+/// Unlike `DefaultHasher`, this hash is specified to be stable across toolchains and platforms, so
+/// the file ids embedded in compiled programs are reproducible.
+pub fn file_id_from_name(name: &str) -> u64 {
+    let mut id_bytes = [0u8; 8];
+    id_bytes.copy_from_slice(&keccak256(name.as_bytes())[..8]);
+    u64::from_be_bytes(id_bytes)
+}
+
 ///Converts the `Vec<String>` used to identify a path into a single formatted string
 fn path_display(path: &Vec<String>) -> String {
     let mut s = "".to_string();
@@ -788,9 +868,7 @@ fn create_program_tree(
                 vec![],
             )
         })?;
-        let mut file_hasher = DefaultHasher::new();
-        path.hash(&mut file_hasher);
-        let file_id = file_hasher.finish();
+        let file_id = file_id_from_name(&path_display(&path));
 
         file_info_chart.insert(
             file_id,
@@ -1041,7 +1119,7 @@ fn check_global_constants(
 fn callgraph_descend(
     func: StringId,
     module: &TypeCheckedModule,
-    program_callgraph: &mut HashMap<
+    program_callgraph: &mut BTreeMap<
         Vec<String>,
         BTreeMap<StringId, (Vec<(StringId, Option<Import>)>, Location)>,
     >,
@@ -1081,7 +1159,7 @@ fn callgraph_descend(
 
 ///Walks the callgraph, pruning and/or warning on any unused functions
 fn consume_program_callgraph(
-    mut program_callgraph: HashMap<
+    mut program_callgraph: BTreeMap<
         Vec<String>,
         BTreeMap<StringId, (Vec<(StringId, Option<Import>)>, Location)>,
     >,
@@ -1164,7 +1242,7 @@ fn codegen_programs(
         name,
     } in typechecked_modules
     {
-        let code_out = codegen::mavm_codegen(
+        let (code_out, func_debug_info) = codegen::mavm_codegen(
             checked_funcs,
             &string_table,
             &imported_funcs,
@@ -1180,7 +1258,7 @@ fn codegen_programs(
                 e.location.into_iter().collect(),
             )
         })?;
-        progs.push(CompiledProgram::new(
+        let mut prog = CompiledProgram::new(
             code_out.to_vec(),
             exported_funcs,
             imported_funcs,
@@ -1191,7 +1269,9 @@ fn codegen_programs(
             )),
             HashMap::new(),
             type_tree.clone(),
-        ))
+        );
+        prog.func_debug_info = func_debug_info;
+        progs.push(prog);
     }
     Ok(progs)
 }
@@ -1531,3 +1611,22 @@ impl Display for FileInfo {
         write!(f, "{}", self.name)
     }
 }
+
+#[test]
+fn test_reproducible_output() {
+    let mut compile = CompileStruct::default();
+    compile.input = vec![String::from("test-programs/reproducible.mini")];
+    compile.test_mode = true;
+    compile.consts_file = Some(String::from("arb_os/constants.json"));
+    let build = || compile.invoke().map_err(|_| "failed to compile").unwrap().0;
+    let (first, second) = (build(), build());
+    let mut first_bytes = vec![];
+    let mut second_bytes = vec![];
+    first.to_output(&mut first_bytes, Some("mexe"), true);
+    second.to_output(&mut second_bytes, Some("mexe"), true);
+    assert!(first_bytes == second_bytes, "two builds differ");
+    compile.verify_reproducible(&first).unwrap();
+
+    // file ids are embedded in the output, so they must not depend on the toolchain
+    assert_eq!(file_id_from_name("builtin/array.mini"), 6306979741670738397);
+}
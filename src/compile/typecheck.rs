@@ -1136,9 +1136,9 @@ pub fn sort_top_level_decls(
 ) -> (
     Vec<Import>,
     BTreeMap<StringId, Func>,
-    HashMap<usize, Type>,
+    BTreeMap<usize, Type>,
     Vec<GlobalVarDecl>,
-    HashMap<usize, Type>,
+    BTreeMap<usize, Type>,
 ) {
     let mut imports = if builtins {
         builtin_func_decls()
@@ -1149,8 +1149,8 @@ pub fn sort_top_level_decls(
         vec![]
     };
     let mut funcs = BTreeMap::new();
-    let mut named_types = HashMap::new();
-    let mut func_table = HashMap::new();
+    let mut named_types = BTreeMap::new();
+    let mut func_table = BTreeMap::new();
     let mut global_vars = Vec::new();
 
     for decl in decls.iter() {
@@ -1178,11 +1178,11 @@ pub fn sort_top_level_decls(
 /// named `Type`s, and global variables.
 pub fn typecheck_top_level_decls(
     funcs: BTreeMap<StringId, Func>,
-    named_types: &HashMap<usize, Type>,
+    named_types: &BTreeMap<usize, Type>,
     mut global_vars: Vec<GlobalVarDecl>,
     imports: &Vec<Import>,
     string_table: StringTable,
-    func_map: HashMap<usize, Type>,
+    func_map: BTreeMap<usize, Type>,
     checked_funcs: &mut BTreeMap<StringId, TypeCheckedFunc>,
     type_tree: &TypeTree,
 ) -> Result<(Vec<ExportedFunc>, Vec<GlobalVarDecl>, StringTable), CompileError> {
@@ -0,0 +1,678 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides a structural comparison of two compiled mini executables.
+//!
+//! `LinkedProgram`s don't record where functions start, so function boundaries are recovered from
+//! the generated code: a function starts at an `AuxPush` (the first instruction of every function
+//! prologue) that is either a jump table entry or directly follows an unconditional control
+//! transfer. Functions are named after the `FuncDebugInfo` whose declaration is at the location of
+//! their prologue, and functions are aligned per source file by that name. Executables without
+//! function debug info fall back to aligning by the line of the prologue.
+
+use crate::compile::{CompileError, StructField};
+use crate::gen_code::get_globals_and_version;
+use crate::link::{register_init_index, LinkedProgram, TupleTree, INIT_CODE_LEN};
+use crate::mavm::{AVMOpcode, CodePt, Value};
+use crate::run::static_gas_cost;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::Path;
+
+///Summary of a single function recovered from a `LinkedProgram`.
+#[derive(Debug, Clone)]
+pub struct FuncSummary {
+    ///Source file of the function prologue, or "unknown" if it has no location.
+    pub file: String,
+    ///Line of the function prologue, as displayed to the user.
+    pub line: usize,
+    ///Name of the function, if the program has debug info for it.
+    pub func_name: Option<String>,
+    ///Index of the first instruction of the function.
+    pub start: usize,
+    pub num_insns: usize,
+    ///Sum of `static_gas_cost` over all instructions in the function.
+    pub static_gas: u64,
+    ///Whether the function entry is referenced from the jump table.
+    pub in_jump_table: bool,
+    ///Hash of the opcode sequence of the function, used to recognize unchanged functions.
+    signature: u64,
+}
+
+impl FuncSummary {
+    pub fn name(&self) -> String {
+        match &self.func_name {
+            Some(func_name) => format!("{} ({}:{})", func_name, self.file, self.line),
+            None => format!("{}:{}", self.file, self.line),
+        }
+    }
+
+    ///The key functions are aligned by within a file, the function name if known, else its line.
+    fn align_key(&self) -> Result<&str, usize> {
+        match &self.func_name {
+            Some(func_name) => Ok(func_name),
+            None => Err(self.line),
+        }
+    }
+}
+
+///The result of comparing two `LinkedProgram`s.
+#[derive(Debug, Default)]
+pub struct ProgramDiff {
+    pub arbos_versions: (u64, u64),
+    ///Names of type tree entries that were added, removed, or whose type changed.
+    pub type_tree_changes: Vec<(char, String)>,
+    pub jump_table_sizes: (usize, usize),
+    ///Pairs of aligned functions, with None standing for a function only present on one side.
+    pub funcs: Vec<(Option<FuncSummary>, Option<FuncSummary>)>,
+    ///Globals present only in the new program, these need a `set_<name>_onUpgrade` function.
+    pub added_globals: Vec<StructField>,
+    ///Globals present only in the old program.
+    pub removed_globals: Vec<StructField>,
+    ///Globals whose type changed, these need a `set_<name>_onUpgrade` function.
+    pub retyped_globals: Vec<(StructField, StructField)>,
+    ///Globals whose slot in the register tuple changed, along with the old and new slots.
+    pub moved_globals: Vec<(String, usize, usize)>,
+}
+
+///Reads a `LinkedProgram` in either json or binary mexe format from path.
+fn program_from_file(path: &Path) -> Result<LinkedProgram, CompileError> {
+    let mut bytes = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|why| {
+            CompileError::new(
+                String::from("Diff error"),
+                format!("Could not read \"{}\": {:?}", path.display(), why),
+                vec![],
+            )
+        })?;
+    LinkedProgram::from_bytes(&bytes).map_err(|_| {
+        CompileError::new(
+            String::from("Diff error"),
+            format!(
+                "Could not parse \"{}\" as a mini executable",
+                path.display()
+            ),
+            vec![],
+        )
+    })
+}
+
+///Compares the executables at old_path and new_path, see `diff_programs` for details.
+pub fn diff_files(old_path: &Path, new_path: &Path) -> Result<ProgramDiff, CompileError> {
+    Ok(diff_programs(
+        program_from_file(old_path)?,
+        program_from_file(new_path)?,
+    ))
+}
+
+///Compares old and new by ArbOS version, type tree, jump table, functions and globals.
+pub fn diff_programs(old: LinkedProgram, new: LinkedProgram) -> ProgramDiff {
+    let old_jump_table = jump_table_entries(&old);
+    let new_jump_table = jump_table_entries(&new);
+    let old_funcs = recover_funcs(&old, &old_jump_table);
+    let new_funcs = recover_funcs(&new, &new_jump_table);
+
+    let mut diff = ProgramDiff {
+        arbos_versions: (old.arbos_version, new.arbos_version),
+        jump_table_sizes: (old_jump_table.len(), new_jump_table.len()),
+        funcs: align_funcs(old_funcs, new_funcs),
+        ..ProgramDiff::default()
+    };
+
+    let old_tree = old.type_tree.clone().into_type_tree();
+    let new_tree = new.type_tree.clone().into_type_tree();
+    let mut type_changes = BTreeSet::new();
+    for (key, (tipe, name)) in &old_tree {
+        match new_tree.get(key) {
+            None => {
+                type_changes.insert(('-', name.clone()));
+            }
+            Some((new_tipe, _)) if new_tipe != tipe => {
+                type_changes.insert(('~', name.clone()));
+            }
+            _ => {}
+        }
+    }
+    for (key, (_, name)) in &new_tree {
+        if !old_tree.contains_key(key) {
+            type_changes.insert(('+', name.clone()));
+        }
+    }
+    diff.type_tree_changes = type_changes.into_iter().collect();
+
+    let (old_fields, _, _, _) = get_globals_and_version(old);
+    let (new_fields, _, _, _) = get_globals_and_version(new);
+    for (new_slot, field) in new_fields.iter().enumerate() {
+        match old_fields.iter().position(|old| old.name == field.name) {
+            None => diff.added_globals.push(field.clone()),
+            Some(old_slot) => {
+                if old_fields[old_slot].tipe != field.tipe {
+                    diff.retyped_globals
+                        .push((old_fields[old_slot].clone(), field.clone()));
+                }
+                if old_slot != new_slot {
+                    diff.moved_globals
+                        .push((field.name.clone(), old_slot, new_slot));
+                }
+            }
+        }
+    }
+    for field in &old_fields {
+        if !new_fields.iter().any(|new| new.name == field.name) {
+            diff.removed_globals.push(field.clone());
+        }
+    }
+    diff
+}
+
+///Collects the internal code points stored in the jump table, which `link` places in the
+/// `_jump_table` slot of the register initialization tuple, see `register_init_index`.
+///
+/// Programs without a `_jump_table` global, such as assembled ones, are taken to initialize the
+/// register with the jump table itself.
+fn jump_table_entries(program: &LinkedProgram) -> BTreeSet<usize> {
+    fn collect(val: &Value, entries: &mut BTreeSet<usize>) {
+        match val {
+            Value::CodePoint(CodePt::Internal(pc)) => {
+                entries.insert(*pc);
+            }
+            Value::Tuple(tup) => {
+                for sub_val in tup.iter() {
+                    collect(sub_val, entries);
+                }
+            }
+            _ => {}
+        }
+    }
+    // Only the initialization code of non-test builds starts by pushing the register.
+    let test_mode = program
+        .code
+        .first()
+        .map_or(false, |insn| insn.opcode != AVMOpcode::Rpush);
+    let init = program
+        .code
+        .get(register_init_index(test_mode))
+        .and_then(|insn| insn.immediate.as_ref());
+    let slot = program
+        .globals
+        .iter()
+        .position(|global| global.name == "_jump_table");
+    let table = match (init, slot) {
+        (Some(init), Some(slot)) => {
+            TupleTree::new(program.globals.len(), false).read_value(init, slot)
+        }
+        (Some(init), None) => Some(init.clone()),
+        (None, _) => None,
+    };
+    let mut entries = BTreeSet::new();
+    if let Some(table) = table {
+        collect(&table, &mut entries);
+    }
+    entries
+}
+
+///Splits the code of program into functions, see the module documentation for the heuristic.
+fn recover_funcs(program: &LinkedProgram, jump_table: &BTreeSet<usize>) -> Vec<FuncSummary> {
+    let code = &program.code;
+    let mut starts = vec![0];
+    for idx in INIT_CODE_LEN..code.len() {
+        let follows_transfer = matches!(
+            code[idx - 1].opcode,
+            AVMOpcode::Jump | AVMOpcode::Halt | AVMOpcode::Error
+        );
+        if code[idx].opcode == AVMOpcode::AuxPush && (follows_transfer || jump_table.contains(&idx))
+        {
+            starts.push(idx);
+        }
+    }
+
+    let mut funcs = vec![];
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).cloned().unwrap_or(code.len());
+        let (file, line) = match code[start].debug_info.location {
+            Some(loc) if start > 0 => (
+                program
+                    .file_info_chart
+                    .get(&loc.file_id)
+                    .map(|info| info.name.clone())
+                    .unwrap_or_else(|| loc.file_id.to_string()),
+                loc.line.to_usize() + 1,
+            ),
+            _ if start == 0 => (String::from("<init>"), 0),
+            _ => (String::from("unknown"), start),
+        };
+        let func_name = match code[start].debug_info.location {
+            Some(loc) if start > 0 => program
+                .func_debug_info
+                .iter()
+                .find(|info| info.location == Some(loc))
+                .map(|info| info.name.clone()),
+            _ => None,
+        };
+        let mut hasher = DefaultHasher::new();
+        for insn in &code[start..end] {
+            insn.opcode.to_number().hash(&mut hasher);
+        }
+        funcs.push(FuncSummary {
+            file,
+            line,
+            func_name,
+            start,
+            num_insns: end - start,
+            static_gas: code[start..end]
+                .iter()
+                .map(|insn| static_gas_cost(insn.opcode))
+                .sum(),
+            in_jump_table: jump_table.contains(&start),
+            signature: hasher.finish(),
+        });
+    }
+    funcs
+}
+
+///Aligns the functions of two programs file by file, see the module documentation for details.
+///
+/// Matched pairs are returned in the order of the old program, followed by functions only present
+/// in the new program.
+fn align_funcs(
+    old: Vec<FuncSummary>,
+    new: Vec<FuncSummary>,
+) -> Vec<(Option<FuncSummary>, Option<FuncSummary>)> {
+    let mut new_by_key: BTreeMap<(String, Result<String, usize>), FuncSummary> = BTreeMap::new();
+    let mut new_order = vec![];
+    for func in new {
+        let key = (
+            func.file.clone(),
+            func.align_key().map(|name| name.to_string()),
+        );
+        new_order.push(key.clone());
+        new_by_key.insert(key, func);
+    }
+
+    let mut aligned = vec![];
+    for func in old {
+        let key = (
+            func.file.clone(),
+            func.align_key().map(|name| name.to_string()),
+        );
+        let matched = new_by_key.remove(&key);
+        aligned.push((Some(func), matched));
+    }
+    for key in new_order {
+        if let Some(func) = new_by_key.remove(&key) {
+            aligned.push((None, Some(func)));
+        }
+    }
+    aligned
+}
+
+impl ProgramDiff {
+    ///Prints a human readable report of self, including unchanged functions if show_all is set.
+    pub fn print(&self, show_all: bool) {
+        if self.arbos_versions.0 == self.arbos_versions.1 {
+            println!("arbos_version: {} (unchanged)", self.arbos_versions.0);
+        } else {
+            println!(
+                "arbos_version: {} -> {}",
+                self.arbos_versions.0, self.arbos_versions.1
+            );
+        }
+        if self.type_tree_changes.is_empty() {
+            println!("type_tree: unchanged");
+        } else {
+            println!("type_tree: {} changes", self.type_tree_changes.len());
+            for (kind, name) in &self.type_tree_changes {
+                println!("    {} {}", kind, name);
+            }
+        }
+        println!(
+            "jump table: {} -> {} entries",
+            self.jump_table_sizes.0, self.jump_table_sizes.1
+        );
+
+        println!("Globals:");
+        for field in &self.added_globals {
+            println!(
+                "    + {}: {} (needs set_{}_onUpgrade)",
+                field.name,
+                field.tipe.display(),
+                field.name
+            );
+        }
+        for field in &self.removed_globals {
+            println!("    - {}: {}", field.name, field.tipe.display());
+        }
+        for (old, new) in &self.retyped_globals {
+            println!(
+                "    ~ {}: {} -> {} (needs set_{}_onUpgrade)",
+                new.name,
+                old.tipe.display(),
+                new.tipe.display(),
+                new.name
+            );
+        }
+        for (name, old_slot, new_slot) in &self.moved_globals {
+            println!("    > {}: slot {} -> {}", name, old_slot, new_slot);
+        }
+
+        println!("Functions:");
+        let (mut old_insns, mut new_insns, mut old_gas, mut new_gas) = (0, 0, 0, 0);
+        let mut changed = vec![];
+        for pair in &self.funcs {
+            let (insns, gas) = match pair {
+                (Some(old), Some(new)) => {
+                    old_insns += old.num_insns;
+                    new_insns += new.num_insns;
+                    old_gas += old.static_gas;
+                    new_gas += new.static_gas;
+                    (
+                        new.num_insns as i64 - old.num_insns as i64,
+                        new.static_gas as i64 - old.static_gas as i64,
+                    )
+                }
+                (Some(old), None) => {
+                    old_insns += old.num_insns;
+                    old_gas += old.static_gas;
+                    (-(old.num_insns as i64), -(old.static_gas as i64))
+                }
+                (None, Some(new)) => {
+                    new_insns += new.num_insns;
+                    new_gas += new.static_gas;
+                    (new.num_insns as i64, new.static_gas as i64)
+                }
+                (None, None) => (0, 0),
+            };
+            let unchanged = match pair {
+                (Some(old), Some(new)) => old.signature == new.signature,
+                _ => false,
+            };
+            if show_all || !unchanged {
+                changed.push((insns, gas, pair));
+            }
+        }
+        changed.sort_by_key(|(insns, gas, _)| (-insns.abs(), -gas.abs()));
+        for (insns, gas, pair) in changed {
+            let (kind, name) = match pair {
+                (Some(old), Some(new)) if old.name() == new.name() => (
+                    ' ',
+                    format!("{} (pc {} -> {})", old.name(), old.start, new.start),
+                ),
+                (Some(old), Some(new)) => (' ', format!("{} -> {}", old.name(), new.name())),
+                (Some(old), None) => ('-', old.name()),
+                (None, Some(new)) => ('+', new.name()),
+                (None, None) => continue,
+            };
+            let jump_table = match pair {
+                (_, Some(new)) if new.in_jump_table => " [jump table]",
+                (Some(old), None) if old.in_jump_table => " [jump table]",
+                _ => "",
+            };
+            println!(
+                "  {} {}{}: insns {:+}, static gas {:+}",
+                kind, name, jump_table, insns, gas
+            );
+        }
+        println!(
+            "Total: insns {} -> {} ({:+}), static gas {} -> {} ({:+})",
+            old_insns,
+            new_insns,
+            new_insns as i64 - old_insns as i64,
+            old_gas,
+            new_gas,
+            new_gas as i64 - old_gas as i64
+        );
+    }
+}
+
+#[test]
+fn test_diff_finds_changed_function() {
+    use crate::asm::assemble;
+
+    let old = assemble(
+        "
+        noop
+        [(main, helper)] noop
+        noop
+        main:
+            auxpush
+            [1] noop
+            auxpop
+            jump
+        helper:
+            auxpush
+            add
+            auxpop
+            jump
+        ",
+        0,
+    )
+    .unwrap();
+    let new = assemble(
+        "
+        noop
+        [(main, helper)] noop
+        noop
+        main:
+            auxpush
+            [1] noop
+            auxpop
+            jump
+        helper:
+            auxpush
+            add
+            [2] mul
+            auxpop
+            jump
+        ",
+        0,
+    )
+    .unwrap();
+    let diff = diff_programs(old, new);
+    assert_eq!(diff.jump_table_sizes, (2, 2));
+    assert_eq!(diff.funcs.len(), 3);
+    let (old_helper, new_helper) = &diff.funcs[2];
+    let (old_helper, new_helper) = (old_helper.as_ref().unwrap(), new_helper.as_ref().unwrap());
+    assert_eq!(new_helper.num_insns - old_helper.num_insns, 1);
+    assert_eq!(new_helper.static_gas - old_helper.static_gas, 3);
+    assert_ne!(old_helper.signature, new_helper.signature);
+    let (old_main, new_main) = &diff.funcs[1];
+    assert_eq!(
+        old_main.as_ref().unwrap().signature,
+        new_main.as_ref().unwrap().signature
+    );
+}
+
+#[test]
+fn test_diff_aligns_functions_by_name() {
+    use crate::asm::assemble;
+    use crate::compile::FuncDebugInfo;
+
+    fn with_names(mut program: LinkedProgram, funcs: &[(&str, usize)]) -> LinkedProgram {
+        for (name, pc) in funcs {
+            program.func_debug_info.push(FuncDebugInfo {
+                name: name.to_string(),
+                location: program.code[*pc].debug_info.location,
+                end: None,
+                frame_size: 0,
+                locals: vec![],
+            });
+        }
+        program
+    }
+
+    let old = assemble(
+        "
+        noop
+        [(main, helper)] noop
+        noop
+        main:
+            auxpush
+            [1] noop
+            auxpop
+            jump
+        helper:
+            auxpush
+            add
+            auxpop
+            jump
+        ",
+        0,
+    )
+    .unwrap();
+    let new = assemble(
+        "
+        noop
+        [(main, added, helper)] noop
+        noop
+        main:
+            auxpush
+            [1] noop
+            auxpop
+            jump
+        added:
+            auxpush
+            [3] noop
+            pop
+            auxpop
+            jump
+        helper:
+            auxpush
+            add
+            auxpop
+            jump
+        ",
+        0,
+    )
+    .unwrap();
+    let old = with_names(old, &[("main", 3), ("helper", 7)]);
+    let new = with_names(new, &[("main", 3), ("added", 7), ("helper", 12)]);
+    let diff = diff_programs(old, new);
+    assert_eq!(diff.jump_table_sizes, (2, 3));
+    let names: Vec<_> = diff
+        .funcs
+        .iter()
+        .map(|(old, new)| {
+            (
+                old.as_ref().and_then(|func| func.func_name.clone()),
+                new.as_ref().and_then(|func| func.func_name.clone()),
+            )
+        })
+        .collect();
+    let named = |name: &str| Some(name.to_string());
+    assert_eq!(
+        names,
+        vec![
+            (None, None),
+            (named("main"), named("main")),
+            (named("helper"), named("helper")),
+            (None, named("added")),
+        ]
+    );
+    let (old_helper, new_helper) = &diff.funcs[2];
+    assert_eq!(
+        old_helper.as_ref().unwrap().signature,
+        new_helper.as_ref().unwrap().signature
+    );
+}
+
+#[test]
+fn test_diff_globals_and_type_tree() {
+    use crate::asm::assemble;
+    use crate::compile::{GlobalVarDecl, Type};
+    use crate::link::SerializableTypeTree;
+    use std::collections::HashMap;
+
+    fn with_globals(
+        mut program: LinkedProgram,
+        globals: &[(&str, Type)],
+        types: &[(usize, Type, &str)],
+    ) -> LinkedProgram {
+        program.globals = globals
+            .iter()
+            .enumerate()
+            .map(|(id, (name, tipe))| GlobalVarDecl::new(id, name.to_string(), tipe.clone(), None))
+            .collect();
+        program.globals.push(GlobalVarDecl::new(
+            usize::MAX,
+            "_jump_table".to_string(),
+            Type::Any,
+            None,
+        ));
+        let mut tree = HashMap::new();
+        for (id, tipe, name) in types {
+            tree.insert(
+                (vec!["main".to_string()], *id),
+                (tipe.clone(), name.to_string()),
+            );
+        }
+        program.type_tree = SerializableTypeTree::from_type_tree(tree);
+        program
+    }
+
+    let old = with_globals(
+        assemble(
+            "
+            noop
+            [(0, 0, 0, (main))] noop
+            rset
+            main:
+                auxpush
+                auxpop
+                jump
+            ",
+            0,
+        )
+        .unwrap(),
+        &[("a", Type::Uint), ("b", Type::Bool), ("d", Type::Uint)],
+        &[(1, Type::Uint, "Foo"), (2, Type::Bool, "Bar")],
+    );
+    let new = with_globals(
+        assemble(
+            "
+            noop
+            [(0, 0, 0, (main, other))] noop
+            rset
+            main:
+                auxpush
+                auxpop
+                jump
+            other:
+                auxpush
+                auxpop
+                jump
+            ",
+            0,
+        )
+        .unwrap(),
+        &[("b", Type::Uint), ("a", Type::Uint), ("c", Type::Bool)],
+        &[(1, Type::Bool, "Foo"), (3, Type::Uint, "Baz")],
+    );
+    let diff = diff_programs(old, new);
+    assert_eq!(diff.jump_table_sizes, (1, 2));
+    assert_eq!(
+        diff.type_tree_changes,
+        vec![
+            ('+', "Baz".to_string()),
+            ('-', "Bar".to_string()),
+            ('~', "Foo".to_string()),
+        ]
+    );
+    let names = |fields: &[StructField]| -> Vec<String> {
+        fields.iter().map(|field| field.name.clone()).collect()
+    };
+    assert_eq!(names(&diff.added_globals), vec!["c"]);
+    assert_eq!(names(&diff.removed_globals), vec!["d"]);
+    assert_eq!(diff.retyped_globals.len(), 1);
+    let (old_b, new_b) = &diff.retyped_globals[0];
+    assert_eq!((&old_b.tipe, &new_b.tipe), (&Type::Bool, &Type::Uint));
+    assert_eq!(
+        diff.moved_globals,
+        vec![("b".to_string(), 1, 0), ("a".to_string(), 0, 1)]
+    );
+}
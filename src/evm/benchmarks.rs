@@ -3,9 +3,10 @@
 */
 
 use crate::evm::abi::deploy_add;
-use crate::run::load_from_file;
+use crate::run::{load_from_file, GasSchedule, RtEnvRecorder};
 use crate::uint256::Uint256;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 pub fn make_benchmarks() -> Result<(), ethabi::Error> {
     let benchmarks: Vec<(
@@ -39,6 +40,54 @@ pub fn make_benchmarks() -> Result<(), ethabi::Error> {
     Ok(())
 }
 
+///Replays each test log in the benchmarks directory iterations times under each of the named gas
+/// schedules, or the default schedule if there are none, and prints the ArbGas used and how long a
+/// replay takes on average, not counting the time to load ArbOS.
+pub fn time_replay_benchmarks(
+    iterations: u32,
+    schedules: &[(String, GasSchedule)],
+) -> std::io::Result<()> {
+    let default_schedule = [(String::from("default"), GasSchedule::default())];
+    let schedules = if schedules.is_empty() {
+        &default_schedule[..]
+    } else {
+        schedules
+    };
+    let mut paths = vec![];
+    for entry in std::fs::read_dir("benchmarks")? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "aoslog") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    for path in paths {
+        let recorder = RtEnvRecorder::from_testlog_file(&path.to_string_lossy())?;
+        for (name, schedule) in schedules {
+            let mut elapsed = Duration::default();
+            let mut gas_used = 0;
+            for _ in 0..iterations {
+                let mut machine = recorder.new_machine();
+                machine.set_gas_schedule(schedule.clone());
+                let start = Instant::now();
+                gas_used = machine.run(None);
+                elapsed += start.elapsed();
+            }
+            let seconds = elapsed.as_secs_f64() / f64::from(iterations.max(1));
+            println!(
+                "{} ({} gas schedule): {:.3}s per replay, {} ArbGas, {:.1} MArbGas/s",
+                path.display(),
+                name,
+                seconds,
+                gas_used,
+                gas_used as f64 / seconds / 1e6
+            );
+        }
+    }
+    Ok(())
+}
+
 pub fn benchmark_boot(_iterations: u64, log_to: &Path) -> Result<u64, ethabi::Error> {
     let mut machine = load_from_file(Path::new("arb_os/arbos.mexe"));
     machine.start_at_zero();
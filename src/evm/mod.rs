@@ -5,15 +5,22 @@
 use crate::evm::abi::FunctionTable;
 use crate::evm::abi::{ArbAddressTable, ArbBLS, ArbFunctionTable, ArbSys};
 use crate::evm::preinstalled_contracts::{ArbosTest, _ArbInfo};
-use crate::run::{load_from_file, load_from_file_and_env, RuntimeEnvironment};
+use crate::run::{
+    load_from_file, load_from_file_and_env, BlockOutcome, GasSchedule, Machine, RuntimeEnvironment,
+    Scenario, ScenarioBlock, ScenarioMessage, SNAPSHOT_VERSION,
+};
 use crate::uint256::Uint256;
+use ethers_core::utils::keccak256;
 use ethers_signers::Signer;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::compile::miniconstants::init_constant_table;
 pub use abi::{builtin_contract_path, contract_path, AbiForContract};
-pub use benchmarks::make_benchmarks;
+pub use benchmarks::{make_benchmarks, time_replay_benchmarks};
 pub use evmtest::run_evm_tests;
+pub use rpc::serve;
+pub use scenario::ScenarioFile;
 
 pub mod abi;
 mod benchmarks;
@@ -21,6 +28,8 @@ mod benchmarks;
 mod bls;
 mod evmtest;
 pub mod preinstalled_contracts;
+mod rpc;
+mod scenario;
 
 #[derive(Clone)]
 pub struct CallInfo<'a> {
@@ -30,6 +39,72 @@ pub struct CallInfo<'a> {
     mutating: bool,
 }
 
+///Counts snapshot files written by this process, to give each one a unique temporary name.
+static SNAPSHOTS_WRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+///Returns a machine running ArbOS in the default `RuntimeEnvironment`, that has processed its chain
+/// init message and is waiting for further messages.
+///
+/// The booted machine is saved as a snapshot in the temp directory, so that only the first caller
+/// pays the boot cost. Snapshots are keyed by the hash of the ArbOS executable, the snapshot format
+/// version, the default gas schedule, the crate version, and the size and modification time of the
+/// running executable, so that snapshots made by other builds of the emulator are never reused.
+///
+/// Booting runs ArbOS before the caller does, which changes the gas used and logs recorded from
+/// then on, so existing tests load ArbOS with `load_from_file` instead.
+pub fn booted_arbos_machine() -> Machine {
+    let arbos_path = Path::new("arb_os/arbos.mexe");
+    let mut key = std::fs::read(arbos_path).expect("couldn't read ArbOS");
+    key.extend(&SNAPSHOT_VERSION.to_le_bytes());
+    key.extend(GasSchedule::default().to_bytes());
+    key.extend(env!("CARGO_PKG_VERSION").as_bytes());
+    if let Ok(metadata) = std::env::current_exe().and_then(std::fs::metadata) {
+        key.extend(&metadata.len().to_le_bytes());
+        if let Ok(modified) = metadata.modified() {
+            key.extend(format!("{:?}", modified).as_bytes());
+        }
+    }
+    let snapshot_path = std::env::temp_dir().join(format!(
+        "arbos-{}.snapshot",
+        hex::encode(&keccak256(&key)[..8])
+    ));
+    if let Ok(machine) = Machine::load_snapshot(&snapshot_path) {
+        return machine;
+    }
+    let machine = boot_arbos_machine(arbos_path);
+    // write under a unique name first, so that concurrent tests never read a partial snapshot
+    let temp_path = snapshot_path.with_extension(format!(
+        "{}-{}.tmp",
+        std::process::id(),
+        SNAPSHOTS_WRITTEN.fetch_add(1, Ordering::SeqCst)
+    ));
+    if machine.save_snapshot(&temp_path).is_ok() {
+        let _ = std::fs::rename(&temp_path, &snapshot_path);
+    }
+    machine
+}
+
+///Loads ArbOS from arbos_path in the default `RuntimeEnvironment` and runs it until it has
+/// processed its chain init message, without using a snapshot.
+fn boot_arbos_machine(arbos_path: &Path) -> Machine {
+    let mut machine = load_from_file(arbos_path);
+    machine.start_at_zero();
+    let _ = machine.run(None);
+    machine
+}
+
+#[test]
+fn test_booted_arbos_snapshot() {
+    let booted = boot_arbos_machine(Path::new("arb_os/arbos.mexe"));
+    let path =
+        std::env::temp_dir().join(format!("arbos-round-trip-{}.snapshot", std::process::id()));
+    booted.save_snapshot(&path).unwrap();
+    let restored = Machine::load_snapshot(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(restored.hash(), booted.hash());
+    assert_eq!(booted_arbos_machine().hash(), booted.hash());
+}
+
 pub fn test_contract_path(contract_name: &str) -> String {
     format!(
         "contracts/artifacts/arbos/test/{}.sol/{}.json",
@@ -1558,6 +1633,80 @@ pub fn evm_payment_to_empty_address(log_to: Option<&Path>, debug: bool) {
     }
 }
 
+///Delivers a deposit, a transaction, heartbeats and sequencer batches to ArbOS across several L1
+/// blocks, and checks that it reads every message and then waits for the next one.
+pub fn evm_block_boundary_scenario(log_to: Option<&Path>) {
+    let mut machine = booted_arbos_machine();
+    let start_block = machine.runtime_env.current_block_num.clone();
+    let start_time = machine.runtime_env.current_timestamp.clone();
+    let my_addr = Uint256::from_u64(1025);
+    let block = |delta_blocks: u64, messages: Vec<ScenarioMessage>| ScenarioBlock {
+        block_num: start_block.add(&Uint256::from_u64(delta_blocks)),
+        timestamp: start_time.add(&Uint256::from_u64(13 * delta_blocks)),
+        messages,
+    };
+    let contract_tx = |to_addr: &Uint256, value: u64| {
+        let mut buf = vec![1u8];
+        buf.extend(Uint256::from_u64(100_000_000).to_bytes_be());
+        buf.extend(machine.runtime_env.get_gas_price().to_bytes_be());
+        buf.extend(to_addr.to_bytes_be());
+        buf.extend(Uint256::from_u64(value).to_bytes_be());
+        buf
+    };
+    let sequencer_batch = |delay_blocks: u64| ScenarioMessage::SequencerBatch {
+        sender: my_addr.clone(),
+        delay: Some((
+            Uint256::from_u64(delay_blocks),
+            Uint256::from_u64(13 * delay_blocks),
+        )),
+        messages: vec![],
+    };
+    let scenario = Scenario {
+        blocks: vec![
+            block(
+                1,
+                vec![
+                    ScenarioMessage::L1 {
+                        msg_type: 7,
+                        sender: my_addr.clone(),
+                        data: contract_tx(&my_addr, 20000),
+                    },
+                    ScenarioMessage::Heartbeat,
+                ],
+            ),
+            block(1, vec![sequencer_batch(1)]),
+            block(
+                5,
+                vec![
+                    sequencer_batch(3),
+                    ScenarioMessage::L2 {
+                        sender: my_addr.clone(),
+                        data: contract_tx(&Uint256::from_u64(4242), 10000),
+                    },
+                ],
+            ),
+            block(10, vec![ScenarioMessage::Heartbeat]),
+        ],
+    };
+
+    let reports = scenario.run(&mut machine).unwrap();
+    assert_eq!(reports.len(), scenario.blocks.len());
+    for report in &reports {
+        println!("{}", report);
+        assert!(matches!(report.outcome, BlockOutcome::Blocked { .. }));
+        assert_eq!(report.messages_pending, 0);
+    }
+    assert_eq!(machine.runtime_env.get_all_receipt_logs().len(), 2);
+
+    if let Some(path) = log_to {
+        machine
+            .runtime_env
+            .recorder
+            .to_file(path, machine.get_total_gas_usage().to_u64().unwrap())
+            .unwrap();
+    }
+}
+
 pub fn evm_eval_sha256(log_to: Option<&Path>, debug: bool) {
     let mut machine = load_from_file(Path::new("arb_os/arbos.mexe"));
     machine.start_at_zero();
@@ -1849,4 +1998,5 @@ pub fn make_logs_for_all_arbos_tests() {
         Some(Path::new("testlogs/payment_to_empty_address.aoslog")),
         false,
     );
+    evm_block_boundary_scenario(Some(Path::new("testlogs/block_boundary_scenario.aoslog")));
 }
@@ -0,0 +1,693 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides a local Ethereum JSON-RPC endpoint backed by ArbOS running in the emulator, so that
+//! scripts written against an Ethereum node, such as those in `contracts/scripts`, can be pointed
+//! at it.
+//!
+//! Only a small subset of the API is served, see `RpcServer::dispatch`. Requests are handled one
+//! at a time, each transaction is run to completion before its hash is returned, and state is
+//! always that of the latest block, so block tags in parameters are ignored.
+//!
+//! `eth_sendRawTransaction` only takes legacy transactions, since the compressed signed message
+//! that ArbOS accepts carries a legacy signature. EIP-2718 typed transactions, such as EIP-1559
+//! ones, are rejected with an error naming their type.
+
+use crate::compile::CompileError;
+use crate::run::{generic_compress_token_amount, ArbosReceipt, EvmLog, Machine};
+use crate::uint256::Uint256;
+use ethereum_types::{Bloom, BloomInput};
+use ethers_core::utils::keccak256;
+use rlp::Rlp;
+use serde_json::{json, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+///Gas limit of an `eth_call` that does not give one.
+const DEFAULT_CALL_GAS: u64 = 100_000_000;
+
+///Address of the ArbInfo precompile, which is used to answer `eth_getBalance`.
+const ARB_INFO_ADDRESS: u64 = 101;
+
+///Largest HTTP request body that is read, in bytes.
+const MAX_REQUEST_BODY: usize = 16 << 20;
+
+///An error returned to the client, with a code from EIP-1474 and the return data of a reverted
+/// call if there is any.
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Vec<u8>>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: String) -> Self {
+        RpcError {
+            code,
+            message,
+            data: None,
+        }
+    }
+
+    fn invalid_params(message: String) -> Self {
+        RpcError::new(-32602, message)
+    }
+}
+
+///A JSON-RPC server holding a machine running ArbOS, and the receipts of the transactions sent
+/// to it.
+struct RpcServer {
+    machine: Machine,
+    ///Receipts of transactions in the order ArbOS emitted them, not including those of calls.
+    receipts: Vec<ArbosReceipt>,
+    ///Number of receipts of machine that have been accounted for.
+    receipts_seen: usize,
+    ///Callee of each transaction sent with `eth_sendRawTransaction` by its hash, None for deploys.
+    callees: BTreeMap<Uint256, Option<Uint256>>,
+}
+
+impl RpcServer {
+    fn new(machine: Machine) -> Self {
+        let receipts_seen = machine.runtime_env.get_all_receipt_logs().len();
+        RpcServer {
+            machine,
+            receipts: vec![],
+            receipts_seen,
+            callees: BTreeMap::new(),
+        }
+    }
+
+    ///Handles connections on listener one at a time, each carrying a single HTTP request.
+    fn serve(&mut self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            if let Err(e) = self.handle_connection(stream?) {
+                println!("RPC connection error: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (method, body) = match read_http_request(&mut reader)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        // browsers send a preflight request before any cross-origin POST
+        let response = if method == "OPTIONS" {
+            None
+        } else {
+            Some(self.handle_body(&body).to_string())
+        };
+        write_http_response(&mut stream, response)
+    }
+
+    ///Handles a JSON-RPC request or batch of requests, returning the response.
+    fn handle_body(&mut self, body: &[u8]) -> JsonValue {
+        match serde_json::from_slice(body) {
+            Ok(JsonValue::Array(requests)) => JsonValue::Array(
+                requests
+                    .iter()
+                    .map(|request| self.handle_request(request))
+                    .collect(),
+            ),
+            Ok(request) => self.handle_request(&request),
+            Err(e) => response(
+                JsonValue::Null,
+                Err(RpcError::new(-32700, format!("parse error: {}", e))),
+            ),
+        }
+    }
+
+    fn handle_request(&mut self, request: &JsonValue) -> JsonValue {
+        let id = request.get("id").cloned().unwrap_or(JsonValue::Null);
+        let result = match request.get("method").and_then(JsonValue::as_str) {
+            Some(method) => {
+                let params = match request.get("params") {
+                    Some(JsonValue::Array(params)) => params.clone(),
+                    _ => vec![],
+                };
+                self.dispatch(method, &params)
+            }
+            None => Err(RpcError::new(-32600, String::from("missing method"))),
+        };
+        response(id, result)
+    }
+
+    ///Answers a single JSON-RPC method call.
+    ///
+    /// Besides the methods that read and change state, `eth_chainId` and `net_version` are served
+    /// so that client libraries can detect the network.
+    fn dispatch(&mut self, method: &str, params: &[JsonValue]) -> Result<JsonValue, RpcError> {
+        match method {
+            "eth_chainId" => Ok(quantity(&Uint256::from_u64(
+                self.machine.runtime_env.get_chain_id(),
+            ))),
+            "net_version" => Ok(json!(self.machine.runtime_env.get_chain_id().to_string())),
+            "eth_blockNumber" => Ok(quantity(&self.block_number())),
+            "eth_sendRawTransaction" => {
+                let raw = parse_data(param(params, 0)?)?;
+                self.send_raw_transaction(&raw).map(|hash| hash_json(&hash))
+            }
+            "eth_call" => {
+                let call = param(params, 0)?;
+                let from = match call.get("from") {
+                    Some(from) => parse_address(from)?,
+                    None => Uint256::zero(),
+                };
+                let to = parse_address(call.get("to").unwrap_or(&JsonValue::Null))?;
+                let gas = match call.get("gas") {
+                    Some(gas) => parse_quantity(gas)?,
+                    None => Uint256::from_u64(DEFAULT_CALL_GAS),
+                };
+                let data = match call.get("data").or_else(|| call.get("input")) {
+                    Some(data) => parse_data(data)?,
+                    None => vec![],
+                };
+                self.call(from, to, gas, &data).map(|data| data_json(&data))
+            }
+            "eth_getTransactionReceipt" => {
+                let hash = parse_hash(param(params, 0)?)?;
+                Ok(self
+                    .receipts
+                    .iter()
+                    .find(|receipt| receipt.get_request_id() == hash)
+                    .map_or(JsonValue::Null, |receipt| self.receipt_json(receipt)))
+            }
+            "eth_getBalance" => {
+                let address = parse_address(param(params, 0)?)?;
+                let mut calldata = keccak256(b"getBalance(address)")[..4].to_vec();
+                calldata.extend(address.to_bytes_be());
+                let balance = self.call(
+                    Uint256::zero(),
+                    Uint256::from_u64(ARB_INFO_ADDRESS),
+                    Uint256::from_u64(DEFAULT_CALL_GAS),
+                    &calldata,
+                )?;
+                Ok(quantity(&Uint256::from_bytes(&balance)))
+            }
+            "eth_getLogs" => {
+                let filter = LogFilter::new(params.first().unwrap_or(&json!({})), self)?;
+                Ok(JsonValue::Array(
+                    self.indexed_logs()
+                        .into_iter()
+                        .filter(|(receipt, log, _)| filter.matches(receipt, log))
+                        .map(|(receipt, log, index)| log_json(receipt, &log, index))
+                        .collect(),
+                ))
+            }
+            _ => Err(RpcError::new(
+                -32601,
+                format!("method {} is not supported", method),
+            )),
+        }
+    }
+
+    ///Runs the machine until it blocks on its inbox, returning the receipts it emitted.
+    fn run(&mut self) -> Vec<ArbosReceipt> {
+        let _gas_used = self.machine.run(None);
+        let receipts = self.machine.runtime_env.get_all_receipt_logs();
+        let new_receipts = receipts[self.receipts_seen..].to_vec();
+        self.receipts_seen = receipts.len();
+        new_receipts
+    }
+
+    ///The Arbitrum block number of the latest transaction, or zero if none has been sent.
+    fn block_number(&self) -> Uint256 {
+        self.receipts
+            .last()
+            .map_or(Uint256::zero(), |receipt| receipt.get_l2_block_number())
+    }
+
+    ///Sends raw, an RLP encoded signed transaction, to ArbOS and returns its hash.
+    fn send_raw_transaction(&mut self, raw: &[u8]) -> Result<Uint256, RpcError> {
+        let (msg, callee) = compress_signed_tx(raw)?;
+        // ArbOS recovers the sender from the signature, and uses the hash of raw as the request id
+        let hash = Uint256::from_bytes(&keccak256(raw));
+        self.machine
+            .runtime_env
+            .insert_l2_message(Uint256::zero(), &msg, false);
+        self.callees.insert(hash.clone(), callee);
+        let receipts = self.run();
+        self.receipts.extend(receipts);
+        Ok(hash)
+    }
+
+    ///Runs a nonmutating call of to with data and returns its return data.
+    fn call(
+        &mut self,
+        from: Uint256,
+        to: Uint256,
+        gas: Uint256,
+        data: &[u8],
+    ) -> Result<Vec<u8>, RpcError> {
+        self.machine
+            .runtime_env
+            ._insert_nonmutating_call_message(from, to, gas, data);
+        match self.run().last() {
+            Some(receipt) if receipt.succeeded() => Ok(receipt.get_return_data()),
+            Some(receipt) => Err(RpcError {
+                code: 3,
+                message: format!("execution failed: {}", receipt._get_return_code_text()),
+                data: Some(receipt.get_return_data()),
+            }),
+            None => Err(RpcError::new(
+                -32000,
+                String::from("ArbOS did not return a receipt for the call"),
+            )),
+        }
+    }
+
+    ///Every EVM log in self, with the receipt that emitted it and its index in its block.
+    fn indexed_logs(&self) -> Vec<(&ArbosReceipt, EvmLog, usize)> {
+        let mut logs = vec![];
+        let mut block_number = None;
+        let mut index = 0;
+        for receipt in &self.receipts {
+            let receipt_block_number = receipt.get_l2_block_number();
+            if block_number.as_ref() != Some(&receipt_block_number) {
+                block_number = Some(receipt_block_number);
+                index = 0;
+            }
+            for log in receipt._get_evm_logs() {
+                logs.push((receipt, log, index));
+                index += 1;
+            }
+        }
+        logs
+    }
+
+    fn receipt_json(&self, receipt: &ArbosReceipt) -> JsonValue {
+        let hash = receipt.get_request_id();
+        let block_number = receipt.get_l2_block_number();
+        let callee = self.callees.get(&hash).cloned().flatten();
+        let contract_address = match self.callees.get(&hash) {
+            Some(None) if receipt.succeeded() => Some(address_json(&Uint256::from_bytes(
+                &receipt.get_return_data(),
+            ))),
+            _ => None,
+        };
+        let logs: Vec<_> = self
+            .indexed_logs()
+            .into_iter()
+            .filter(|(log_receipt, _, _)| log_receipt.get_request_id() == hash)
+            .map(|(_, log, index)| (log, index))
+            .collect();
+        let mut bloom = Bloom::zero();
+        for (log, _) in &logs {
+            bloom.accrue(BloomInput::Raw(&log.addr.to_bytes_be()[12..]));
+            for topic in &log.vals {
+                bloom.accrue(BloomInput::Raw(&topic.to_bytes_be()));
+            }
+        }
+        json!({
+            "transactionHash": hash_json(&hash),
+            "transactionIndex": quantity(&receipt.get_index_in_block()),
+            "blockHash": hash_json(&block_hash(&block_number)),
+            "blockNumber": quantity(&block_number),
+            "from": address_json(&receipt.get_sender()),
+            "to": callee.map(|callee| address_json(&callee)),
+            "contractAddress": contract_address,
+            "cumulativeGasUsed": quantity(&receipt.get_gas_used_so_far()),
+            "gasUsed": quantity(&receipt.get_gas_used()),
+            "logs": logs
+                .iter()
+                .map(|(log, index)| log_json(receipt, log, *index))
+                .collect::<Vec<_>>(),
+            "logsBloom": data_json(bloom.as_bytes()),
+            "status": if receipt.succeeded() { "0x1" } else { "0x0" },
+        })
+    }
+}
+
+///The parameters of an `eth_getLogs` request.
+struct LogFilter {
+    from_block: Uint256,
+    to_block: Uint256,
+    ///Addresses a log may come from, or None if any address matches.
+    addresses: Option<Vec<Uint256>>,
+    ///Values each topic may have, None matching any value.
+    topics: Vec<Option<Vec<Uint256>>>,
+}
+
+impl LogFilter {
+    fn new(filter: &JsonValue, server: &RpcServer) -> Result<Self, RpcError> {
+        let block = |name: &str, default: Uint256| match filter.get(name) {
+            None | Some(JsonValue::Null) => Ok(default),
+            Some(tag) => match tag.as_str() {
+                Some("latest") | Some("pending") => Ok(server.block_number()),
+                Some("earliest") => Ok(Uint256::zero()),
+                _ => parse_quantity(tag),
+            },
+        };
+        let addresses = match filter.get("address") {
+            None | Some(JsonValue::Null) => None,
+            Some(JsonValue::Array(addresses)) => Some(
+                addresses
+                    .iter()
+                    .map(parse_address)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Some(address) => Some(vec![parse_address(address)?]),
+        };
+        let topics = match filter.get("topics") {
+            None | Some(JsonValue::Null) => vec![],
+            Some(JsonValue::Array(topics)) => topics
+                .iter()
+                .map(|topic| match topic {
+                    JsonValue::Null => Ok(None),
+                    JsonValue::Array(options) => Ok(Some(
+                        options.iter().map(parse_hash).collect::<Result<_, _>>()?,
+                    )),
+                    topic => Ok(Some(vec![parse_hash(topic)?])),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(topics) => {
+                return Err(RpcError::invalid_params(format!(
+                    "invalid topics {}",
+                    topics
+                )))
+            }
+        };
+        Ok(LogFilter {
+            from_block: block("fromBlock", server.block_number())?,
+            to_block: block("toBlock", server.block_number())?,
+            addresses,
+            topics,
+        })
+    }
+
+    fn matches(&self, receipt: &ArbosReceipt, log: &EvmLog) -> bool {
+        let block_number = receipt.get_l2_block_number();
+        block_number >= self.from_block
+            && block_number <= self.to_block
+            && self
+                .addresses
+                .as_ref()
+                .map_or(true, |addresses| addresses.contains(&log.addr))
+            && self.topics.len() <= log.vals.len()
+            && self.topics.iter().zip(&log.vals).all(|(options, topic)| {
+                options
+                    .as_ref()
+                    .map_or(true, |options| options.contains(topic))
+            })
+    }
+}
+
+///Re-encodes raw, an RLP encoded signed legacy transaction, as a compressed signed L2 message, and
+/// returns the message and the callee of the transaction, or None if it is a deploy. Typed
+/// transactions are rejected, see the module documentation.
+fn compress_signed_tx(raw: &[u8]) -> Result<(Vec<u8>, Option<Uint256>), RpcError> {
+    // an EIP-2718 typed transaction starts with its type, a legacy one with an RLP list header
+    if let Some(tx_type) = raw.first().filter(|&&first| first < 0xc0) {
+        return Err(RpcError::invalid_params(format!(
+            "unsupported transaction type {}: only legacy transactions are accepted",
+            tx_type
+        )));
+    }
+    let invalid =
+        |e: rlp::DecoderError| RpcError::invalid_params(format!("invalid transaction: {}", e));
+    let tx = Rlp::new(raw);
+    if tx.item_count().map_err(invalid)? != 9 {
+        return Err(RpcError::invalid_params(String::from(
+            "invalid transaction: expected 9 fields",
+        )));
+    }
+    let fields = (0..9)
+        .map(|i| tx.at(i).and_then(|field| field.data()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    // every field but the calldata is an integer
+    if fields
+        .iter()
+        .enumerate()
+        .any(|(i, field)| i != 5 && field.len() > 32)
+    {
+        return Err(RpcError::invalid_params(String::from(
+            "invalid transaction: integer field longer than 32 bytes",
+        )));
+    }
+    let uint = |i: usize| Uint256::from_bytes(fields[i]);
+
+    let mut msg = vec![7u8, 0xffu8];
+    for i in 0..3 {
+        // sequence number, gas price and gas limit
+        msg.extend(uint(i).rlp_encode());
+    }
+    let callee = match fields[3].len() {
+        0 => {
+            msg.push(0x80);
+            None
+        }
+        20 => {
+            msg.push(148);
+            msg.extend(fields[3]);
+            Some(uint(3))
+        }
+        _ => {
+            return Err(RpcError::invalid_params(String::from(
+                "invalid transaction: malformed callee",
+            )))
+        }
+    };
+    msg.extend(generic_compress_token_amount(uint(4)));
+    msg.extend(fields[5]);
+    msg.extend(uint(7).to_bytes_be());
+    msg.extend(uint(8).to_bytes_be());
+    // ArbOS rebuilds an EIP-155 v from its parity, but takes a pre-EIP-155 v as it is
+    msg.push(match uint(6).to_u64() {
+        Some(v) if v == 27 || v == 28 => v as u8,
+        Some(v) => (v % 2) as u8,
+        None => {
+            return Err(RpcError::invalid_params(String::from(
+                "invalid transaction: malformed signature",
+            )))
+        }
+    });
+    Ok((msg, callee))
+}
+
+///Reads an HTTP request from reader and returns its method and body, or None if the connection
+/// was closed before a request arrived.
+fn read_http_request<R: BufRead>(reader: &mut R) -> io::Result<Option<(String, Vec<u8>)>> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+    let method = request_line
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length")
+                })?;
+                if content_length > MAX_REQUEST_BODY {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "request body too large",
+                    ));
+                }
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some((method, body)))
+}
+
+///Writes a JSON response with body, or an empty response if body is None.
+fn write_http_response<W: Write>(output: &mut W, body: Option<String>) -> io::Result<()> {
+    let (status, body) = match body {
+        Some(body) => ("200 OK", body),
+        None => ("204 No Content", String::new()),
+    };
+    write!(
+        output,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Headers: Content-Type\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        status,
+        body.len(),
+        body
+    )?;
+    output.flush()
+}
+
+fn response(id: JsonValue, result: Result<JsonValue, RpcError>) -> JsonValue {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => {
+            let mut error = json!({"code": e.code, "message": e.message});
+            if let Some(data) = e.data {
+                error["data"] = data_json(&data);
+            }
+            json!({"jsonrpc": "2.0", "id": id, "error": error})
+        }
+    }
+}
+
+fn log_json(receipt: &ArbosReceipt, log: &EvmLog, index: usize) -> JsonValue {
+    let block_number = receipt.get_l2_block_number();
+    json!({
+        "address": address_json(&log.addr),
+        "topics": log.vals.iter().map(hash_json).collect::<Vec<_>>(),
+        "data": data_json(&log.data),
+        "blockNumber": quantity(&block_number),
+        "blockHash": hash_json(&block_hash(&block_number)),
+        "transactionHash": hash_json(&receipt.get_request_id()),
+        "transactionIndex": quantity(&receipt.get_index_in_block()),
+        "logIndex": quantity(&Uint256::from_usize(index)),
+        "removed": false,
+    })
+}
+
+///ArbOS does not hash its blocks, so block hashes are made up from block numbers.
+fn block_hash(block_number: &Uint256) -> Uint256 {
+    Uint256::from_bytes(&keccak256(&block_number.to_bytes_be()))
+}
+
+fn param(params: &[JsonValue], index: usize) -> Result<&JsonValue, RpcError> {
+    params
+        .get(index)
+        .ok_or_else(|| RpcError::invalid_params(format!("missing parameter {}", index)))
+}
+
+fn parse_data(value: &JsonValue) -> Result<Vec<u8>, RpcError> {
+    value
+        .as_str()
+        .and_then(|s| s.strip_prefix("0x"))
+        .and_then(|s| hex::decode(s).ok())
+        .ok_or_else(|| RpcError::invalid_params(format!("invalid data {}", value)))
+}
+
+fn parse_quantity(value: &JsonValue) -> Result<Uint256, RpcError> {
+    value
+        .as_str()
+        .and_then(|s| s.strip_prefix("0x"))
+        .and_then(Uint256::from_string_hex)
+        .ok_or_else(|| RpcError::invalid_params(format!("invalid quantity {}", value)))
+}
+
+fn parse_address(value: &JsonValue) -> Result<Uint256, RpcError> {
+    match parse_data(value) {
+        Ok(bytes) if bytes.len() == 20 => Ok(Uint256::from_bytes(&bytes)),
+        _ => Err(RpcError::invalid_params(format!(
+            "invalid address {}",
+            value
+        ))),
+    }
+}
+
+fn parse_hash(value: &JsonValue) -> Result<Uint256, RpcError> {
+    match parse_data(value) {
+        Ok(bytes) if bytes.len() == 32 => Ok(Uint256::from_bytes(&bytes)),
+        _ => Err(RpcError::invalid_params(format!("invalid hash {}", value))),
+    }
+}
+
+fn quantity(value: &Uint256) -> JsonValue {
+    json!(format!("{:#x}", value.to_u256()))
+}
+
+fn data_json(bytes: &[u8]) -> JsonValue {
+    json!(format!("0x{}", hex::encode(bytes)))
+}
+
+fn hash_json(value: &Uint256) -> JsonValue {
+    data_json(&value.to_bytes_be())
+}
+
+fn address_json(value: &Uint256) -> JsonValue {
+    data_json(&value.to_bytes_be()[12..])
+}
+
+///Serves the JSON-RPC endpoint for machine, which should be a booted ArbOS, on localhost at port
+/// until the listener fails.
+pub fn serve(machine: Machine, port: u16) -> Result<(), CompileError> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| rpc_error(format!("could not listen on port {}: {}", port, e)))?;
+    println!("Serving JSON-RPC on http://127.0.0.1:{}", port);
+    RpcServer::new(machine)
+        .serve(listener)
+        .map_err(|e| rpc_error(e.to_string()))
+}
+
+fn rpc_error(description: String) -> CompileError {
+    CompileError::new(String::from("RPC error"), description, vec![])
+}
+
+#[test]
+fn test_compress_signed_tx() {
+    use crate::run::RuntimeEnvironment;
+    use ethers_core::types::TransactionRequest;
+    use ethers_signers::Signer;
+
+    let mut env = RuntimeEnvironment::new(None);
+    let wallet = env.new_wallet();
+    let callee = Uint256::from_u64(0x1234_5678);
+    let max_gas = Uint256::from_u64(1_000_000);
+    let value = Uint256::from_u64(25_000);
+    let (expected, tx_id) = env.make_compressed_and_signed_l2_message(
+        None,
+        max_gas.clone(),
+        callee.clone(),
+        value.clone(),
+        &[1, 2, 3],
+        &wallet,
+    );
+    let tx = wallet
+        .sign_transaction(
+            TransactionRequest::new()
+                .from(wallet.address())
+                .to(callee.to_h160())
+                .gas(max_gas.to_u256())
+                .gas_price(env.get_gas_price().to_u256())
+                .value(value.to_u256())
+                .data(vec![1, 2, 3])
+                .nonce(0),
+        )
+        .unwrap();
+    let raw = tx.rlp().as_ref().to_vec();
+    assert_eq!(compress_signed_tx(&raw).unwrap(), (expected, Some(callee)));
+    assert_eq!(keccak256(&raw).to_vec(), tx_id);
+    assert!(compress_signed_tx(&raw[..raw.len() - 1]).is_err());
+    let mut typed = vec![2u8];
+    typed.extend(&raw);
+    assert!(compress_signed_tx(&typed)
+        .unwrap_err()
+        .message
+        .contains("unsupported transaction type 2"));
+}
+
+#[test]
+fn test_read_http_request() {
+    let request = b"POST / HTTP/1.1\r\nHost: localhost\r\ncontent-length: 7\r\n\r\n{\"a\":1}";
+    let (method, body) = read_http_request(&mut &request[..]).unwrap().unwrap();
+    assert_eq!(method, "POST");
+    assert_eq!(body, b"{\"a\":1}");
+    assert!(read_http_request(&mut &b""[..]).unwrap().is_none());
+    let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", 1 << 30);
+    assert!(read_http_request(&mut request.as_bytes()).is_err());
+}
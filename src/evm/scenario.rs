@@ -0,0 +1,373 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides ArbOS integration tests written as YAML or JSON scenario files instead of Rust, see
+//! `ScenarioFile`.
+
+use crate::compile::CompileError;
+use crate::evm::{test_contract_path, AbiForContract};
+use crate::run::Machine;
+use crate::uint256::Uint256;
+use ethabi::token::{LenientTokenizer, Tokenizer};
+use ethabi::{Param, ParamType, Token};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+///An ArbOS integration test: accounts funded with ETH deposits, followed by steps that deploy
+/// contracts, call them and check the receipts.
+///
+/// Contracts are given by the name of a test contract in `contracts/arbos/test`, or by the path of
+/// a compiled artifact ending in `.json`. Arguments and expected return values are given in the
+/// order of the ABI, integers in decimal and addresses and bytes in hex, and an address argument
+/// may also name an account or a deployed contract. For example:
+/// ```yaml
+/// accounts:
+///   alice: { address: "0x0000000000000000000000000000000000000401", deposit: "100000000" }
+/// steps:
+///   - deploy: { name: adder, contract: Add }
+///   - call:
+///       from: alice
+///       to: adder
+///       function: add
+///       args: [1, 2]
+///       expect: { success: true, returns: [3], logs: 0 }
+///   - advance_blocks: 1
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioFile {
+    #[serde(default)]
+    accounts: BTreeMap<String, Account>,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Account {
+    address: String,
+    deposit: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Step {
+    Deploy(Deploy),
+    Call(Call),
+    AdvanceBlocks(u64),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Deploy {
+    name: String,
+    contract: String,
+    #[serde(default)]
+    args: Vec<serde_json::Value>,
+    payment: Option<String>,
+    #[serde(default)]
+    expect: Expect,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Call {
+    from: String,
+    to: String,
+    function: String,
+    #[serde(default)]
+    args: Vec<serde_json::Value>,
+    payment: Option<String>,
+    #[serde(default)]
+    expect: Expect,
+}
+
+///The expected receipt of a step, success defaults to true and everything else is only checked if
+/// given.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Expect {
+    success: Option<bool>,
+    returns: Option<Vec<serde_json::Value>>,
+    logs: Option<usize>,
+    sends: Option<usize>,
+}
+
+impl ScenarioFile {
+    ///Reads the scenario at path, which is parsed as JSON if it has a .json extension and as YAML
+    /// otherwise.
+    pub fn from_file(path: &Path) -> Result<Self, CompileError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| scenario_error(format!("could not read \"{}\": {}", path.display(), e)))?;
+        if path.extension().map_or(false, |ext| ext == "json") {
+            serde_json::from_str(&contents).map_err(|e| e.to_string())
+        } else {
+            serde_yaml::from_str(&contents).map_err(|e| e.to_string())
+        }
+        .map_err(|e| scenario_error(format!("could not parse \"{}\": {}", path.display(), e)))
+    }
+
+    ///Runs self against machine, which should be a booted ArbOS, and returns a description of
+    /// every expectation that was not met.
+    ///
+    /// Returns an error if self is malformed, for example if it calls a contract it did not deploy.
+    /// Calls to a contract whose deployment failed are reported as unmet expectations instead.
+    pub fn run(&self, machine: &mut Machine, debug: bool) -> Result<Vec<String>, CompileError> {
+        let mut names = BTreeMap::new();
+        for (name, account) in &self.accounts {
+            let address = parse_address(&account.address)?;
+            if let Some(deposit) = &account.deposit {
+                machine.runtime_env.insert_eth_deposit_message(
+                    address.clone(),
+                    address.clone(),
+                    parse_amount(deposit)?,
+                );
+                run_machine(machine, debug);
+            }
+            names.insert(name.clone(), address);
+        }
+
+        let mut contracts = BTreeMap::new();
+        let mut failed_deploys = BTreeSet::new();
+        let mut failures = vec![];
+        for (index, step) in self.steps.iter().enumerate() {
+            let mut fail = |description: String| {
+                failures.push(format!("step {}: {}", index + 1, description));
+            };
+            match step {
+                Step::Deploy(deploy) => {
+                    let artifact = if deploy.contract.ends_with(".json") {
+                        deploy.contract.clone()
+                    } else {
+                        test_contract_path(&deploy.contract)
+                    };
+                    let mut contract = AbiForContract::new_from_file(&artifact).map_err(|e| {
+                        scenario_error(format!("could not load \"{}\": {}", artifact, e))
+                    })?;
+                    let inputs = contract
+                        .contract
+                        .constructor()
+                        .map(|constructor| constructor.inputs.clone())
+                        .unwrap_or_default();
+                    let args = tokenize(&inputs, &deploy.args, &names)?;
+                    let payment = parse_optional_amount(&deploy.payment)?;
+                    let success = match contract.deploy(&args, machine, payment, None, debug) {
+                        Ok(address) => {
+                            names.insert(deploy.name.clone(), address);
+                            true
+                        }
+                        Err(_) => false,
+                    };
+                    if deploy.expect.returns.is_some()
+                        || deploy.expect.logs.is_some()
+                        || deploy.expect.sends.is_some()
+                    {
+                        return Err(scenario_error(format!(
+                            "step {}: only success can be expected of a deploy",
+                            index + 1
+                        )));
+                    }
+                    if success != deploy.expect.success.unwrap_or(true) {
+                        fail(format!(
+                            "deploying {} {}",
+                            deploy.name,
+                            if success { "succeeded" } else { "failed" }
+                        ));
+                    }
+                    if success {
+                        failed_deploys.remove(&deploy.name);
+                        contracts.insert(deploy.name.clone(), contract);
+                    } else {
+                        failed_deploys.insert(deploy.name.clone());
+                    }
+                }
+                Step::Call(call) if failed_deploys.contains(&call.to) => {
+                    fail(format!(
+                        "cannot call {}, deploying {} failed",
+                        call.function, call.to
+                    ));
+                }
+                Step::Call(call) => {
+                    let contract = contracts.get(&call.to).ok_or_else(|| {
+                        scenario_error(format!("step {}: no contract named {}", index + 1, call.to))
+                    })?;
+                    let function = contract
+                        .get_function(&call.function)
+                        .map_err(|e| scenario_error(format!("step {}: {}", index + 1, e)))?;
+                    let args = tokenize(&function.inputs, &call.args, &names)?;
+                    let sender = match names.get(&call.from) {
+                        Some(address) => address.clone(),
+                        None => parse_address(&call.from)?,
+                    };
+                    let payment = parse_optional_amount(&call.payment)?;
+                    let (receipts, sends) = contract
+                        .call_function(sender, &call.function, &args, machine, payment, debug)
+                        .map_err(|e| scenario_error(format!("step {}: {}", index + 1, e)))?;
+                    let receipt = match receipts.as_slice() {
+                        [receipt] => receipt,
+                        _ => {
+                            fail(format!("expected 1 receipt, got {}", receipts.len()));
+                            continue;
+                        }
+                    };
+                    let expect = &call.expect;
+                    if receipt.succeeded() != expect.success.unwrap_or(true) {
+                        fail(format!(
+                            "{} returned code {}",
+                            call.function,
+                            receipt._get_return_code_text()
+                        ));
+                    }
+                    if let Some(returns) = &expect.returns {
+                        let expected = tokenize(&function.outputs, returns, &names)?;
+                        match function.decode_output(&receipt.get_return_data()) {
+                            Ok(actual) if actual == expected => {}
+                            Ok(actual) => fail(format!(
+                                "{} returned {:?}, expected {:?}",
+                                call.function, actual, expected
+                            )),
+                            Err(e) => fail(format!(
+                                "could not decode return value of {}: {}",
+                                call.function, e
+                            )),
+                        }
+                    }
+                    if let Some(logs) = expect.logs {
+                        let actual = receipt._get_evm_logs().len();
+                        if actual != logs {
+                            fail(format!("expected {} logs, got {}", logs, actual));
+                        }
+                    }
+                    if let Some(num_sends) = expect.sends {
+                        if sends.len() != num_sends {
+                            fail(format!("expected {} sends, got {}", num_sends, sends.len()));
+                        }
+                    }
+                }
+                Step::AdvanceBlocks(blocks) => {
+                    machine
+                        .runtime_env
+                        ._advance_time(Uint256::from_u64(*blocks), None, true);
+                    run_machine(machine, debug);
+                }
+            }
+        }
+        Ok(failures)
+    }
+}
+
+fn run_machine(machine: &mut Machine, debug: bool) {
+    let _gas_used = if debug {
+        machine.debug(None)
+    } else {
+        machine.run(None)
+    };
+}
+
+///Converts values to the tokens of the ABI types in params, allowing addresses to be given by a
+/// name in names.
+fn tokenize(
+    params: &[Param],
+    values: &[serde_json::Value],
+    names: &BTreeMap<String, Uint256>,
+) -> Result<Vec<Token>, CompileError> {
+    if params.len() != values.len() {
+        return Err(scenario_error(format!(
+            "expected {} values, got {}",
+            params.len(),
+            values.len()
+        )));
+    }
+    params
+        .iter()
+        .zip(values)
+        .map(|(param, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            match (&param.kind, names.get(&value)) {
+                (ParamType::Address, Some(address)) => Ok(Token::Address(address.to_h160())),
+                (kind, _) => {
+                    let value = match kind {
+                        ParamType::Address | ParamType::Bytes | ParamType::FixedBytes(_) => {
+                            value.trim_start_matches("0x")
+                        }
+                        _ => &value,
+                    };
+                    LenientTokenizer::tokenize(kind, value).map_err(|e| {
+                        scenario_error(format!(
+                            "invalid {} value \"{}\" for {}: {}",
+                            kind, value, param.name, e
+                        ))
+                    })
+                }
+            }
+        })
+        .collect()
+}
+
+fn parse_address(s: &str) -> Result<Uint256, CompileError> {
+    Uint256::from_string_hex(s.trim_start_matches("0x"))
+        .ok_or_else(|| scenario_error(format!("invalid address \"{}\"", s)))
+}
+
+fn parse_amount(s: &str) -> Result<Uint256, CompileError> {
+    Uint256::from_string(s).ok_or_else(|| scenario_error(format!("invalid amount \"{}\"", s)))
+}
+
+fn parse_optional_amount(s: &Option<String>) -> Result<Uint256, CompileError> {
+    s.as_deref().map_or(Ok(Uint256::zero()), parse_amount)
+}
+
+fn scenario_error(description: String) -> CompileError {
+    CompileError::new(String::from("Scenario error"), description, vec![])
+}
+
+#[test]
+fn test_scenario_file_parsing() {
+    let yaml = "\
+accounts:
+  alice: { address: \"0x401\", deposit: \"1000\" }
+steps:
+  - deploy: { name: adder, contract: Add }
+  - call:
+      from: alice
+      to: adder
+      function: add
+      args: [1, \"2\"]
+      expect: { returns: [3], logs: 0 }
+  - advance_blocks: 2
+";
+    let scenario: ScenarioFile = serde_yaml::from_str(yaml).unwrap();
+    assert_eq!(scenario.steps.len(), 3);
+    assert!(matches!(scenario.steps[2], Step::AdvanceBlocks(2)));
+    let json = r#"{"steps": [{"deploy": {"name": "adder", "contract": "Add", "expect": {"success": false}}}]}"#;
+    let scenario: ScenarioFile = serde_json::from_str(json).unwrap();
+    match &scenario.steps[0] {
+        Step::Deploy(deploy) => assert_eq!(deploy.expect.success, Some(false)),
+        other => panic!("unexpected step {:?}", other),
+    }
+    assert!(serde_yaml::from_str::<ScenarioFile>("steps: [{ wait: 1 }]").is_err());
+
+    let param = |kind: ParamType| Param {
+        name: String::from("x"),
+        kind,
+    };
+    let mut names = BTreeMap::new();
+    names.insert(String::from("alice"), Uint256::from_u64(0x401));
+    let tokens = tokenize(
+        &[param(ParamType::Uint(256)), param(ParamType::Address)],
+        &[serde_json::json!(7), serde_json::json!("alice")],
+        &names,
+    )
+    .unwrap();
+    assert_eq!(tokens[0], Token::Uint(7.into()));
+    assert_eq!(
+        tokens[1],
+        Token::Address(Uint256::from_u64(0x401).to_h160())
+    );
+    assert!(tokenize(&[param(ParamType::Bool)], &[], &names).is_err());
+}
@@ -270,20 +270,27 @@ fn get_globals_and_version_from_file(
             path.to_str().unwrap_or("")
         ))
     })?;
-    let mut s = String::new();
-    file.read_to_string(&mut s).map_err(|_| {
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes).map_err(|_| {
         GenCodeError::new(format!(
-            "Failed to read \"{}\" to string",
+            "Failed to read \"{}\"",
             path.to_str().unwrap_or("")
         ))
     })?;
-    let globals: LinkedProgram = serde_json::from_str(&s).map_err(|_| {
+    let globals = LinkedProgram::from_bytes(&bytes).map_err(|_| {
         GenCodeError::new(format!(
             "Failed to deserialize file \"{}\"",
             path.to_str().unwrap_or("")
         ))
     })?;
+    Ok(get_globals_and_version(globals))
+}
 
+///Returns the globals of a `LinkedProgram` as struct fields with nominal types resolved, along
+/// with the nominal subtypes they reference, the program's type tree, and its ArbOS version.
+pub(crate) fn get_globals_and_version(
+    globals: LinkedProgram,
+) -> (Vec<StructField>, HashSet<(Type, String)>, TypeTree, u64) {
     let type_tree = globals.type_tree.into_type_tree();
 
     let mut state: (Vec<_>, Rc<RefCell<HashSet<(Type, String)>>>) =
@@ -339,7 +346,7 @@ fn get_globals_and_version_from_file(
     }
     mem::drop(old_state);
     let subtypes = Rc::get_mut(&mut state.1).unwrap().clone().into_inner();
-    Ok((fields, subtypes, type_tree, globals.arbos_version))
+    (fields, subtypes, type_tree, globals.arbos_version)
 }
 
 fn type_decl_string(
@@ -0,0 +1,440 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides the binary mexe format, a compact alternative to the json serialization of
+//! `LinkedProgram`.
+//!
+//! A binary mexe file starts with `MAGIC`, a little endian `u16` format version, and the 32 byte
+//! AVM code point hash of the program's first instruction, see `code_hash_chain`. This is
+//! followed by a sequence of sections, each consisting of a one byte tag, a varint length and the
+//! section body.
+//! All integers within sections are LEB128 varints. Sections with unknown tags are skipped, and
+//! the debug info sections may be omitted entirely.
+//!
+//! Every `Value` appearing in the program is stored once in the value pool section, with tuples
+//! referring to their members by pool index, so repeated values such as the many small tuples in
+//! immediates are only stored once.
+
+use crate::compile::{CompileError, DebugInfo, FileInfo, GlobalVarDecl};
+use crate::link::{LinkedProgram, SerializableTypeTree};
+use crate::mavm::{AVMOpcode, Buffer, CodePt, Instruction, Value};
+use crate::uint256::Uint256;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+///Bytes that every binary mexe file starts with.
+pub const MAGIC: &[u8; 4] = b"MEXE";
+///The version of the binary mexe format produced by `encode`.
+pub const FORMAT_VERSION: u16 = 2;
+
+const SECTION_VALUE_POOL: u8 = 1;
+const SECTION_CODE: u8 = 2;
+const SECTION_METADATA: u8 = 3;
+const SECTION_DEBUG_INFO: u8 = 4;
+const SECTION_FILE_INFO: u8 = 5;
+const SECTION_FUNC_INFO: u8 = 6;
+
+const VALUE_INT: u8 = 0;
+const VALUE_TUPLE: u8 = 1;
+const VALUE_CODEPOINT: u8 = 2;
+const VALUE_BUFFER: u8 = 3;
+
+///Returns true if bytes starts with the binary mexe magic header.
+pub fn is_binary_mexe(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+///Computes the AVM hash of the first code point of code, see `code_point_hashes`.
+///
+/// The hash of a code point commits to the code executed after it, so the hash of the first code
+/// point commits to the entire program.
+pub fn code_hash_chain(code: &[Instruction<AVMOpcode>]) -> Uint256 {
+    code_point_hashes(code).swap_remove(0)
+}
+
+///Returns the AVM hashes of the code points of code, see `Instruction::avm_code_point_hash`,
+/// followed by the hash of the code point after the last instruction, which is zero.
+///
+/// Compiled code only refers to later code points in immediates, references to a code point that
+/// is not later in code hash to zero.
+pub fn code_point_hashes(code: &[Instruction<AVMOpcode>]) -> Vec<Uint256> {
+    let mut hashes = vec![Uint256::zero()];
+    for (idx, insn) in code.iter().enumerate().rev() {
+        let next_hash = hashes.last().unwrap().clone();
+        let hash = insn.avm_code_point_hash(&next_hash, &|codept| match codept {
+            CodePt::Internal(pc) if pc > idx && pc <= code.len() => hashes[code.len() - pc].clone(),
+            _ => Uint256::zero(),
+        });
+        hashes.push(hash);
+    }
+    hashes.reverse();
+    hashes
+}
+
+fn encode_error(description: String) -> CompileError {
+    CompileError::new(String::from("Mexe encoding error"), description, vec![])
+}
+
+fn decode_error(description: &str) -> CompileError {
+    CompileError::new(
+        String::from("Mexe decoding error"),
+        description.to_string(),
+        vec![],
+    )
+}
+
+fn push_varint(buf: &mut Vec<u8>, mut val: u64) {
+    while val >= 0x80 {
+        buf.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    buf.push(val as u8);
+}
+
+fn push_section(buf: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    buf.push(tag);
+    push_varint(buf, body.len() as u64);
+    buf.extend(body);
+}
+
+///Builds the deduplicated value pool, children are always added before their parents.
+#[derive(Default)]
+struct ValuePool {
+    encoded: Vec<u8>,
+    num_values: u64,
+    indices: HashMap<Vec<u8>, u64>,
+    tuple_indices: HashMap<*const Vec<Value>, u64>,
+}
+
+impl ValuePool {
+    ///Adds val and the values it contains to self, returning the index of val.
+    ///
+    /// Tuples are added after their members using an explicit stack rather than recursion, so that
+    /// deeply nested values cannot overflow the call stack.
+    fn add(&mut self, val: &Value) -> Result<u64, CompileError> {
+        let mut pending = vec![(val, false)];
+        let mut added = vec![];
+        while let Some((val, members_added)) = pending.pop() {
+            match val {
+                Value::Tuple(tup) if !members_added => {
+                    if let Some(idx) = self.tuple_indices.get(&Arc::as_ptr(tup)) {
+                        added.push(*idx);
+                        continue;
+                    }
+                    pending.push((val, true));
+                    pending.extend(tup.iter().rev().map(|member| (member, false)));
+                }
+                _ => {
+                    let idx = self.add_entry(val, &mut added)?;
+                    added.push(idx);
+                }
+            }
+        }
+        Ok(added.pop().unwrap())
+    }
+
+    ///Adds val to self, taking the indices of its members from the end of added if it is a tuple.
+    fn add_entry(&mut self, val: &Value, added: &mut Vec<u64>) -> Result<u64, CompileError> {
+        let mut entry = vec![];
+        match val {
+            Value::Int(ui) => {
+                let bytes = ui.to_bytes_be();
+                let first_nonzero = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+                entry.push(VALUE_INT);
+                push_varint(&mut entry, (bytes.len() - first_nonzero) as u64);
+                entry.extend(&bytes[first_nonzero..]);
+            }
+            Value::Tuple(tup) => {
+                entry.push(VALUE_TUPLE);
+                push_varint(&mut entry, tup.len() as u64);
+                for member_idx in added.split_off(added.len() - tup.len()) {
+                    push_varint(&mut entry, member_idx);
+                }
+            }
+            Value::CodePoint(CodePt::Internal(pc)) => {
+                entry.push(VALUE_CODEPOINT);
+                push_varint(&mut entry, *pc as u64);
+            }
+            Value::Buffer(buf) => {
+                let contents = buf.contents();
+                entry.push(VALUE_BUFFER);
+                push_varint(&mut entry, contents.len() as u64);
+                entry.extend(contents);
+            }
+            Value::CodePoint(cp) => {
+                return Err(encode_error(format!("unlinked code point {}", cp)));
+            }
+            Value::Label(label) => {
+                return Err(encode_error(format!("unresolved label {}", label)));
+            }
+        }
+        let idx = match self.indices.get(&entry) {
+            Some(idx) => *idx,
+            None => {
+                let idx = self.num_values;
+                self.encoded.extend(&entry);
+                self.indices.insert(entry, idx);
+                self.num_values += 1;
+                idx
+            }
+        };
+        if let Value::Tuple(tup) = val {
+            self.tuple_indices.insert(Arc::as_ptr(tup), idx);
+        }
+        Ok(idx)
+    }
+}
+
+///Encodes program in the binary mexe format, including the debug info sections if include_debug
+/// is set.
+pub fn encode(program: &LinkedProgram, include_debug: bool) -> Result<Vec<u8>, CompileError> {
+    let mut pool = ValuePool::default();
+    let mut code = vec![];
+    push_varint(&mut code, program.arbos_version);
+    push_varint(&mut code, pool.add(&program.static_val)?);
+    push_varint(&mut code, program.code.len() as u64);
+    for insn in &program.code {
+        code.push(insn.opcode.to_number());
+        match &insn.immediate {
+            Some(imm) => push_varint(&mut code, pool.add(imm)? + 1),
+            None => push_varint(&mut code, 0),
+        }
+    }
+    let mut pool_section = vec![];
+    push_varint(&mut pool_section, pool.num_values);
+    pool_section.extend(pool.encoded);
+
+    let metadata = bincode::serialize(&(&program.globals, &program.type_tree))
+        .map_err(|e| encode_error(format!("{:?}", e)))?;
+
+    let mut buf = MAGIC.to_vec();
+    buf.extend(&FORMAT_VERSION.to_le_bytes());
+    buf.extend(code_hash_chain(&program.code).to_bytes_be());
+    push_section(&mut buf, SECTION_VALUE_POOL, &pool_section);
+    push_section(&mut buf, SECTION_CODE, &code);
+    push_section(&mut buf, SECTION_METADATA, &metadata);
+    if include_debug {
+        let debug_info: Vec<DebugInfo> = program.code.iter().map(|insn| insn.debug_info).collect();
+        let debug_info =
+            bincode::serialize(&debug_info).map_err(|e| encode_error(format!("{:?}", e)))?;
+        let file_info = bincode::serialize(&program.file_info_chart)
+            .map_err(|e| encode_error(format!("{:?}", e)))?;
+        let func_info = bincode::serialize(&program.func_debug_info)
+            .map_err(|e| encode_error(format!("{:?}", e)))?;
+        push_section(&mut buf, SECTION_DEBUG_INFO, &debug_info);
+        push_section(&mut buf, SECTION_FILE_INFO, &file_info);
+        push_section(&mut buf, SECTION_FUNC_INFO, &func_info);
+    }
+    Ok(buf)
+}
+
+///Reads the binary mexe format from a byte slice.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CompileError> {
+        if len > self.bytes.len() - self.pos {
+            return Err(decode_error("unexpected end of input"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, CompileError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, CompileError> {
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            val |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(decode_error("varint too long"))
+    }
+
+    fn usize(&mut self) -> Result<usize, CompileError> {
+        Ok(self.varint()? as usize)
+    }
+}
+
+fn decode_value_pool(body: &[u8]) -> Result<Vec<Value>, CompileError> {
+    let mut reader = Reader::new(body);
+    let num_values = reader.usize()?;
+    let mut pool: Vec<Value> = Vec::with_capacity(num_values.min(body.len()));
+    for _ in 0..num_values {
+        let val = match reader.byte()? {
+            VALUE_INT => {
+                let len = reader.usize()?;
+                if len > 32 {
+                    return Err(decode_error("integer longer than 32 bytes"));
+                }
+                Value::Int(Uint256::from_bytes(reader.take(len)?))
+            }
+            VALUE_TUPLE => {
+                let len = reader.usize()?;
+                let mut members = Vec::with_capacity(len.min(body.len()));
+                for _ in 0..len {
+                    members.push(
+                        pool.get(reader.usize()?)
+                            .cloned()
+                            .ok_or_else(|| decode_error("tuple member index out of range"))?,
+                    );
+                }
+                Value::new_tuple(members)
+            }
+            VALUE_CODEPOINT => Value::CodePoint(CodePt::Internal(reader.usize()?)),
+            VALUE_BUFFER => {
+                let len = reader.usize()?;
+                Value::Buffer(Buffer::from_bytes(reader.take(len)?.to_vec()))
+            }
+            _ => return Err(decode_error("unknown value tag")),
+        };
+        pool.push(val);
+    }
+    Ok(pool)
+}
+
+///Decodes a `LinkedProgram` in the binary mexe format, verifying its code point hash chain.
+pub fn decode(bytes: &[u8]) -> Result<LinkedProgram, CompileError> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(decode_error("missing mexe header"));
+    }
+    let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+    if version != FORMAT_VERSION {
+        return Err(decode_error(&format!(
+            "unsupported mexe format version {}",
+            version
+        )));
+    }
+    let expected_hash = Uint256::from_bytes(reader.take(32)?);
+
+    let mut sections = BTreeMap::new();
+    while !reader.at_end() {
+        let tag = reader.byte()?;
+        let len = reader.usize()?;
+        sections.insert(tag, reader.take(len)?);
+    }
+    let section = |tag, name: &str| {
+        sections
+            .get(&tag)
+            .cloned()
+            .ok_or_else(|| decode_error(&format!("missing {} section", name)))
+    };
+
+    let pool = decode_value_pool(section(SECTION_VALUE_POOL, "value pool")?)?;
+    let pool_value = |idx: usize| {
+        pool.get(idx)
+            .cloned()
+            .ok_or_else(|| decode_error("value index out of range"))
+    };
+
+    let mut reader = Reader::new(section(SECTION_CODE, "code")?);
+    let arbos_version = reader.varint()?;
+    let static_val = pool_value(reader.usize()?)?;
+    let num_insns = reader.usize()?;
+    let mut code = Vec::with_capacity(num_insns.min(bytes.len()));
+    for _ in 0..num_insns {
+        let opcode = AVMOpcode::from_number(reader.byte()? as usize)
+            .ok_or_else(|| decode_error("invalid opcode"))?;
+        let immediate = match reader.usize()? {
+            0 => None,
+            idx => Some(pool_value(idx - 1)?),
+        };
+        code.push(Instruction::new(opcode, immediate, DebugInfo::default()));
+    }
+    if code_hash_chain(&code) != expected_hash {
+        return Err(decode_error("code point hash chain does not match header"));
+    }
+
+    let (globals, type_tree): (Vec<GlobalVarDecl>, SerializableTypeTree) =
+        bincode::deserialize(section(SECTION_METADATA, "metadata")?)
+            .map_err(|_| decode_error("malformed metadata section"))?;
+
+    let mut file_info_chart: BTreeMap<u64, FileInfo> = BTreeMap::new();
+    if let Some(debug_info) = sections.get(&SECTION_DEBUG_INFO) {
+        let debug_info: Vec<DebugInfo> = bincode::deserialize(debug_info)
+            .map_err(|_| decode_error("malformed debug info section"))?;
+        if debug_info.len() != code.len() {
+            return Err(decode_error("debug info does not match code length"));
+        }
+        for (insn, info) in code.iter_mut().zip(debug_info) {
+            insn.debug_info = info;
+        }
+    }
+    if let Some(file_info) = sections.get(&SECTION_FILE_INFO) {
+        file_info_chart = bincode::deserialize(file_info)
+            .map_err(|_| decode_error("malformed file info section"))?;
+    }
+    let mut func_debug_info = vec![];
+    if let Some(func_info) = sections.get(&SECTION_FUNC_INFO) {
+        func_debug_info = bincode::deserialize(func_info)
+            .map_err(|_| decode_error("malformed function info section"))?;
+    }
+
+    Ok(LinkedProgram {
+        arbos_version,
+        code,
+        static_val,
+        globals,
+        file_info_chart,
+        type_tree,
+        func_debug_info,
+    })
+}
+
+#[test]
+fn test_binary_mexe_round_trip() {
+    let json = std::fs::read("upgradetests/upgrade1_old.mexe").unwrap();
+    let program = LinkedProgram::from_bytes(&json).unwrap();
+
+    let encoded = encode(&program, true).unwrap();
+    assert!(encoded.len() < json.len());
+    let decoded = LinkedProgram::from_bytes(&encoded).unwrap();
+    assert!(decoded.code == program.code);
+    assert_eq!(decoded.static_val, program.static_val);
+    assert_eq!(decoded.globals, program.globals);
+    assert_eq!(decoded.arbos_version, program.arbos_version);
+
+    let stripped = decode(&encode(&program, false).unwrap()).unwrap();
+    assert!(stripped.file_info_chart.is_empty());
+    assert!(stripped
+        .code
+        .iter()
+        .all(|insn| insn.debug_info.location.is_none()));
+
+    let mut corrupted = encoded.clone();
+    corrupted[MAGIC.len() + 2] ^= 1;
+    assert!(decode(&corrupted).is_err());
+}
+
+#[test]
+fn test_value_pool_deep_nesting() {
+    let depth = 100_000;
+    let mut val = Value::Int(Uint256::zero());
+    for _ in 0..depth {
+        val = Value::new_tuple(vec![val]);
+    }
+    let mut pool = ValuePool::default();
+    assert_eq!(pool.add(&val).unwrap(), depth);
+    assert_eq!(pool.add(&Value::Int(Uint256::zero())).unwrap(), 0);
+    // Dropping a value nested this deeply would overflow the stack.
+    std::mem::forget(val);
+}
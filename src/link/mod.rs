@@ -5,24 +5,27 @@
 //!Provides types and utilities for linking together compiled mini programs
 
 use crate::compile::{
-    comma_list, CompileError, CompiledProgram, DebugInfo, ErrorSystem, FileInfo, GlobalVarDecl,
-    SourceFileMap, Type, TypeTree,
+    comma_list, file_id_from_name, CompileError, CompiledProgram, DebugInfo, ErrorSystem, FileInfo,
+    FuncDebugInfo, GlobalVarDecl, SourceFileMap, Type, TypeTree,
 };
 use crate::mavm::{AVMOpcode, Instruction, Label, Opcode, Value};
 use crate::pos::{try_display_location, Location};
 use crate::stringtable::{StringId, StringTable};
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::{DefaultHasher, HashMap};
+use std::collections::hash_map::HashMap;
 use std::collections::BTreeMap;
-use std::hash::Hasher;
 use std::io;
 use xformcode::make_uninitialized_tuple;
 
 use crate::compile::miniconstants::init_constant_table;
+pub use sidecar::DebugSidecar;
 use std::path::Path;
+pub use striplabels::strip_labels;
 pub use xformcode::{value_from_field_list, TupleTree, TUPLE_SIZE};
 
+pub mod mexe;
 mod optimize;
+mod sidecar;
 mod striplabels;
 mod xformcode;
 
@@ -67,13 +70,33 @@ pub struct LinkedProgram {
     #[serde(default)]
     pub file_info_chart: BTreeMap<u64, FileInfo>,
     pub type_tree: SerializableTypeTree,
+    #[serde(default)]
+    pub func_debug_info: Vec<FuncDebugInfo>,
 }
 
 impl LinkedProgram {
+    ///Reads a `LinkedProgram` from bytes, which may be either in the binary mexe format or json.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CompileError> {
+        if mexe::is_binary_mexe(bytes) {
+            mexe::decode(bytes)
+        } else {
+            serde_json::from_slice(bytes).map_err(|e| {
+                CompileError::new(
+                    String::from("Json parsing error"),
+                    format!("{:?}", e),
+                    vec![],
+                )
+            })
+        }
+    }
+
     ///Serializes self to the format specified by the format argument, with a default of json for
     /// None. The output is written to a dynamically dispatched implementor of `std::io::Write`,
     /// specified by the output argument.
-    pub fn to_output(&self, output: &mut dyn io::Write, format: Option<&str>) {
+    ///
+    /// The mexe format only includes the debug info sections if include_debug is set, which callers
+    /// clear when the debug info has been split off, see `split_debug_info`.
+    pub fn to_output(&self, output: &mut dyn io::Write, format: Option<&str>, include_debug: bool) {
         match format {
             Some("pretty") => {
                 writeln!(output, "static: {}", self.static_val).unwrap();
@@ -111,6 +134,16 @@ impl LinkedProgram {
                     writeln!(output, "bincode serialization error: {:?}", e).unwrap();
                 }
             },
+            Some("mexe") => match mexe::encode(self, include_debug) {
+                Ok(encoded) => {
+                    if let Err(e) = output.write_all(&encoded) {
+                        writeln!(output, "mexe write error: {:?}", e).unwrap();
+                    }
+                }
+                Err(e) => {
+                    writeln!(output, "mexe serialization error: {}", e.description).unwrap();
+                }
+            },
             Some(weird_value) => {
                 writeln!(output, "invalid format: {}", weird_value).unwrap();
             }
@@ -323,15 +356,29 @@ pub fn postlink_compile(
         globals: program.globals.clone(),
         file_info_chart,
         type_tree: SerializableTypeTree::from_type_tree(program.type_tree),
+        func_debug_info: program.func_debug_info,
     })
 }
 
+///Number of instructions `link` places before the linked code to initialize the register.
+pub(crate) const INIT_CODE_LEN: usize = 3;
+
+///Returns the index of the initialization instruction whose immediate holds the initial value of
+/// the register, including the jump table, see `link`.
+pub(crate) fn register_init_index(test_mode: bool) -> usize {
+    if test_mode {
+        1
+    } else {
+        2
+    }
+}
+
 fn hardcode_jump_table_into_register(
     code: &mut Vec<Instruction>,
     jump_table: &Value,
     test_mode: bool,
 ) {
-    let offset = if test_mode { 1 } else { 2 };
+    let offset = register_init_index(test_mode);
     let old_imm = code[offset].clone().immediate.unwrap();
     code[offset] = Instruction::from_opcode_imm(
         code[offset].opcode,
@@ -352,7 +399,7 @@ pub fn link(
 ) -> Result<CompiledProgram, CompileError> {
     let progs = progs_in.to_vec();
     let type_tree = progs[0].type_tree.clone();
-    let mut insns_so_far: usize = 3; // leave 2 insns of space at beginning for initialization
+    let mut insns_so_far: usize = INIT_CODE_LEN; // leave space at beginning for initialization
     let mut imports_so_far: usize = 0;
     let mut int_offsets = Vec::new();
     let mut ext_offsets = Vec::new();
@@ -428,10 +475,12 @@ pub fn link(
 
     let mut linked_exports = Vec::new();
     let mut linked_imports = Vec::new();
+    let mut linked_func_debug_info = Vec::new();
     for mut rel_prog in relocated_progs {
         linked_code.append(&mut rel_prog.code);
         linked_exports.append(&mut rel_prog.exported_funcs);
         linked_imports.append(&mut rel_prog.imported_funcs);
+        linked_func_debug_info.append(&mut rel_prog.func_debug_info);
     }
 
     let mut exports_map = HashMap::new();
@@ -456,7 +505,7 @@ pub fn link(
         linked_xlated_code.push(insn.xlate_labels(&label_xlate_map));
     }
 
-    Ok(CompiledProgram::new(
+    let mut linked_prog = CompiledProgram::new(
         linked_xlated_code,
         linked_exports,
         linked_imports,
@@ -464,20 +513,16 @@ pub fn link(
         Some(merged_source_file_map),
         {
             let mut map = HashMap::new();
-            let mut file_hasher = DefaultHasher::new();
-            file_hasher.write(b"builtin/array.mini");
             map.insert(
-                file_hasher.finish(),
+                file_id_from_name("builtin/array.mini"),
                 FileInfo {
                     name: String::from("builtin/array.mini"),
                     path: String::from("builtin/array.mini"),
                     contents: vec![], // we assume the builtin files are correct
                 },
             );
-            let mut file_hasher = DefaultHasher::new();
-            file_hasher.write(b"builtin/kvs.mini");
             map.insert(
-                file_hasher.finish(),
+                file_id_from_name("builtin/kvs.mini"),
                 FileInfo {
                     name: String::from("builtin/kvs.mini"),
                     path: String::from("builtin/kvs.mini"),
@@ -487,5 +532,7 @@ pub fn link(
             map
         },
         type_tree,
-    ))
+    );
+    linked_prog.func_debug_info = linked_func_debug_info;
+    Ok(linked_prog)
 }
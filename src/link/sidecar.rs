@@ -0,0 +1,137 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides splitting debug info out of a `LinkedProgram` into a sidecar file, and re-attaching it.
+
+use crate::compile::{CompileError, DebugInfo, FileInfo, FuncDebugInfo};
+use crate::link::mexe::code_hash_chain;
+use crate::link::LinkedProgram;
+use crate::uint256::Uint256;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+///Debug info split off from a `LinkedProgram`, keyed by instruction index.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DebugSidecar {
+    ///Code point hash of the program the debug info was split from, see `code_hash_chain`.
+    pub code_hash: Uint256,
+    pub file_info_chart: BTreeMap<u64, FileInfo>,
+    ///Debug info of every instruction whose debug info is not the default.
+    pub debug_info: BTreeMap<usize, DebugInfo>,
+    #[serde(default)]
+    pub func_debug_info: Vec<FuncDebugInfo>,
+}
+
+impl DebugSidecar {
+    ///Returns the path at which the sidecar of the executable at path is looked for.
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut sidecar_path = path.as_os_str().to_owned();
+        sidecar_path.push(".debug");
+        PathBuf::from(sidecar_path)
+    }
+
+    ///Reads a `DebugSidecar` from the json file at path.
+    pub fn from_file(path: &Path) -> Result<Self, CompileError> {
+        let mut s = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut s))
+            .map_err(|why| {
+                CompileError::new(
+                    String::from("Debug sidecar error"),
+                    format!("Could not read \"{}\": {:?}", path.display(), why),
+                    vec![],
+                )
+            })?;
+        serde_json::from_str(&s).map_err(|_| {
+            CompileError::new(
+                String::from("Debug sidecar error"),
+                format!("Could not parse \"{}\" as json", path.display()),
+                vec![],
+            )
+        })
+    }
+}
+
+impl LinkedProgram {
+    ///Removes the `DebugInfo` of every instruction, the file info chart and the function debug info
+    /// from self, returning them as a `DebugSidecar`.
+    pub fn split_debug_info(&mut self) -> DebugSidecar {
+        let mut debug_info = BTreeMap::new();
+        for (idx, insn) in self.code.iter_mut().enumerate() {
+            if insn.debug_info != DebugInfo::default() {
+                debug_info.insert(idx, insn.debug_info);
+                insn.debug_info = DebugInfo::default();
+            }
+        }
+        DebugSidecar {
+            code_hash: code_hash_chain(&self.code),
+            file_info_chart: std::mem::take(&mut self.file_info_chart),
+            debug_info,
+            func_debug_info: std::mem::take(&mut self.func_debug_info),
+        }
+    }
+
+    ///Re-attaches debug info previously split off by `split_debug_info`, returning an error and
+    /// leaving self unchanged if sidecar was split from a different program.
+    pub fn attach_debug_info(&mut self, sidecar: DebugSidecar) -> Result<(), CompileError> {
+        if code_hash_chain(&self.code) != sidecar.code_hash
+            || sidecar
+                .debug_info
+                .keys()
+                .next_back()
+                .map_or(false, |idx| *idx >= self.code.len())
+        {
+            return Err(CompileError::new(
+                String::from("Debug sidecar error"),
+                String::from("Debug info was generated for a different program"),
+                vec![],
+            ));
+        }
+        for (idx, debug_info) in sidecar.debug_info {
+            self.code[idx].debug_info = debug_info;
+        }
+        self.file_info_chart.extend(sidecar.file_info_chart);
+        self.func_debug_info.extend(sidecar.func_debug_info);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_split_and_attach_debug_info() {
+    let original =
+        LinkedProgram::from_bytes(&std::fs::read("upgradetests/upgrade1_old.mexe").unwrap())
+            .unwrap();
+    let mut program =
+        LinkedProgram::from_bytes(&std::fs::read("upgradetests/upgrade1_old.mexe").unwrap())
+            .unwrap();
+
+    let sidecar = program.split_debug_info();
+    assert!(program.file_info_chart.is_empty());
+    let (mut split_mexe, mut full_mexe) = (vec![], vec![]);
+    program.to_output(&mut split_mexe, Some("mexe"), false);
+    program.to_output(&mut full_mexe, Some("mexe"), true);
+    assert!(split_mexe.len() < full_mexe.len());
+    assert!(program
+        .code
+        .iter()
+        .all(|insn| insn.debug_info == DebugInfo::default()));
+
+    let sidecar: DebugSidecar =
+        serde_json::from_str(&serde_json::to_string(&sidecar).unwrap()).unwrap();
+    program.attach_debug_info(sidecar).unwrap();
+    assert!(program.code == original.code);
+    assert_eq!(
+        program.file_info_chart.keys().collect::<Vec<_>>(),
+        original.file_info_chart.keys().collect::<Vec<_>>()
+    );
+
+    let mut other =
+        LinkedProgram::from_bytes(&std::fs::read("upgradetests/upgrade1_new.mexe").unwrap())
+            .unwrap();
+    let sidecar = program.split_debug_info();
+    assert!(other.attach_debug_info(sidecar).is_err());
+}
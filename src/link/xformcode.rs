@@ -342,6 +342,29 @@ impl TupleTree {
         }
     }
 
+    ///Reads the index-th element of val, assuming val has the shape of self.
+    ///
+    /// This is the runtime counterpart of `read_code`, returns None if val does not have the shape
+    /// of self.
+    pub fn read_value(&self, val: &Value, index: usize) -> Option<Value> {
+        match self {
+            TupleTree::Single => Some(val.clone()),
+            TupleTree::Tree(_, v) => {
+                let mut index = index;
+                for (slot, subtree) in v.iter().enumerate() {
+                    if index < subtree.tsize() {
+                        return match val {
+                            Value::Tuple(tup) => subtree.read_value(tup.get(slot)?, index),
+                            _ => None,
+                        };
+                    }
+                    index -= subtree.tsize();
+                }
+                None
+            }
+        }
+    }
+
     ///Gets the total number of nodes in the `TupleTree`
     fn tsize(&self) -> usize {
         match self {
@@ -503,3 +526,23 @@ impl TupleTree {
 pub fn value_from_field_list(lis: Vec<Value>) -> Value {
     TupleTree::new(lis.len(), false).make_value(lis)
 }
+
+#[test]
+fn test_read_value_nested() {
+    let size = TUPLE_SIZE * TUPLE_SIZE + 3;
+    let shape = TupleTree::new(size, false);
+    let vals: Vec<Value> = (0..size)
+        .map(|i| Value::Int(Uint256::from_usize(i)))
+        .collect();
+    let tree = shape.make_value(vals.clone());
+    match &tree {
+        Value::Tuple(tup) => assert!(tup.len() <= TUPLE_SIZE),
+        _ => panic!("expected a tuple"),
+    }
+    for (index, val) in vals.iter().enumerate() {
+        assert_eq!(shape.read_value(&tree, index).as_ref(), Some(val));
+    }
+    assert_eq!(shape.read_value(&tree, size), None);
+    assert_eq!(shape.read_value(&Value::none(), 3), None);
+    assert_eq!(shape.read_value(&Value::Int(Uint256::zero()), 3), None);
+}
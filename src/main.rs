@@ -4,26 +4,34 @@
 
 #![allow(unused_parens)]
 
+use crate::asm::assemble_from_file;
 use crate::compile::miniconstants::make_parameters_list;
 use crate::compile::CompileStruct;
-use crate::link::LinkedProgram;
+use crate::link::mexe::code_hash_chain;
+use crate::link::{DebugSidecar, LinkedProgram};
 use crate::upload::CodeUploader;
 use clap::Clap;
 use compile::CompileError;
 use contracttemplates::generate_contract_template_file_or_die;
 use gen_code::gen_upgrade_code;
 use run::{
-    profile_gen_from_file, replay_from_testlog_file, run_from_file, ProfilerMode,
-    RuntimeEnvironment,
+    bisect_divergence, dap, export_profile_from_file, load_from_file, profile_gen_from_file,
+    prove_step_from_testlog_file, replay_from_testlog_file, FileSideloadProvider, FunctionFilter,
+    GasSchedule, JsonTraceWriter, ProfileFormat, ProfilerMode, ReplayOptions, RuntimeEnvironment,
+    StepRange, TraceFilter,
 };
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Instant;
 
+mod asm;
 mod compile;
 mod contracttemplates;
+mod diff;
 mod evm;
 mod gen_code;
 mod link;
@@ -42,6 +50,65 @@ struct RunStruct {
     input: String,
     #[clap(short, long)]
     debug: bool,
+    #[clap(long)]
+    dap: bool,
+    #[clap(long, default_value = "256")]
+    checkpoint_budget_mib: usize,
+    #[clap(long)]
+    print_hash_every: Option<u64>,
+    #[clap(long)]
+    coverage: Option<String>,
+    #[clap(long)]
+    sideload: Option<String>,
+    #[clap(long)]
+    record_sideloads: Option<String>,
+    #[clap(long)]
+    gas_schedule: Option<String>,
+    #[clap(long)]
+    stats: Option<String>,
+    #[clap(flatten)]
+    json_trace: JsonTraceOptions,
+}
+
+// Command line options for JSON Lines execution traces, shared by run and replay. Not a doc
+// comment, because clap would use it as the about text of those subcommands.
+#[derive(Clap, Debug)]
+struct JsonTraceOptions {
+    #[clap(long)]
+    trace_json: Option<String>,
+    #[clap(long)]
+    trace_steps: Option<StepRange>,
+    #[clap(long)]
+    trace_file: Option<String>,
+    #[clap(long)]
+    trace_function: Option<FunctionFilter>,
+    #[clap(long, default_value = "4")]
+    trace_stack_items: usize,
+}
+
+impl JsonTraceOptions {
+    ///Creates the trace writer requested by self, if any.
+    fn writer(self) -> Result<Option<JsonTraceWriter>, CompileError> {
+        let path = match self.trace_json {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let filter = TraceFilter {
+            steps: self.trace_steps,
+            file: self.trace_file,
+            function: self.trace_function,
+            stack_items: self.trace_stack_items,
+        };
+        JsonTraceWriter::new(path.as_ref(), filter)
+            .map(Some)
+            .map_err(|e| {
+                CompileError::new(
+                    String::from("Trace error"),
+                    format!("could not create \"{}\": {}", path, e),
+                    vec![],
+                )
+            })
+    }
 }
 
 ///Command line options for EvmDebug subcommand.
@@ -53,6 +120,13 @@ struct EvmDebug {
     profiler: bool,
 }
 
+///Command line options for prove-step subcommand.
+#[derive(Clap, Debug)]
+struct ProveStep {
+    input: String,
+    step: u64,
+}
+
 ///Command line options for replay subcommand.
 #[derive(Clap, Debug)]
 struct Replay {
@@ -63,6 +137,32 @@ struct Replay {
     profiler: ProfilerMode,
     #[clap(short, long)]
     trace: Option<String>,
+    #[clap(long)]
+    dap: bool,
+    #[clap(long, default_value = "256")]
+    checkpoint_budget_mib: usize,
+    #[clap(long)]
+    print_hash_every: Option<u64>,
+    #[clap(long)]
+    coverage: Option<String>,
+    #[clap(long)]
+    gas_schedule: Option<String>,
+    #[clap(long)]
+    stats: Option<String>,
+    #[clap(long)]
+    call_traces: Option<String>,
+    #[clap(flatten)]
+    json_trace: JsonTraceOptions,
+}
+
+///Command line options for bisect-divergence subcommand.
+#[derive(Clap, Debug)]
+struct BisectDivergence {
+    input: String,
+    first: PathBuf,
+    second: PathBuf,
+    #[clap(long)]
+    compare_gas: bool,
 }
 
 ///Command line options for profiler subcommand.
@@ -71,6 +171,34 @@ struct Profiler {
     input: String,
     #[clap(short, long)]
     mode: ProfilerMode,
+    #[clap(long)]
+    export: Option<ProfileFormat>,
+    #[clap(long)]
+    out: Option<String>,
+}
+
+///Command line options for replay-benchmarks subcommand.
+#[derive(Clap, Debug)]
+struct ReplayBenchmarks {
+    #[clap(short, long, default_value = "3")]
+    iterations: u32,
+    #[clap(long)]
+    gas_schedule: Vec<String>,
+}
+
+///Command line options for scenario subcommand.
+#[derive(Clap, Debug)]
+struct Scenario {
+    input: Vec<String>,
+    #[clap(short, long)]
+    debug: bool,
+}
+
+///Command line options for serve subcommand.
+#[derive(Clap, Debug)]
+struct Serve {
+    #[clap(short, long, default_value = "8547")]
+    port: u16,
 }
 
 ///Command line options for reformat subcommand.
@@ -82,6 +210,25 @@ struct Reformat {
     format: Option<String>,
 }
 
+///Command line options for asm subcommand.
+#[derive(Clap, Debug)]
+struct Asm {
+    input: String,
+    #[clap(short, long)]
+    output: Option<String>,
+    #[clap(short, long)]
+    format: Option<String>,
+}
+
+///Command line options for diff subcommand.
+#[derive(Clap, Debug)]
+struct Diff {
+    old: String,
+    new: String,
+    #[clap(short, long)]
+    all: bool,
+}
+
 ///Command line options for evm-tests subcommand.
 #[derive(Clap, Debug)]
 struct EvmTests {
@@ -114,14 +261,21 @@ struct MakeParametersList {
 #[derive(Clap, Debug)]
 enum Args {
     Compile(CompileStruct),
+    Asm(Asm),
     Run(RunStruct),
     EvmDebug(EvmDebug),
     Profiler(Profiler),
     Replay(Replay),
+    BisectDivergence(BisectDivergence),
+    ProveStep(ProveStep),
     MakeTestLogs,
     MakeBenchmarks,
+    ReplayBenchmarks(ReplayBenchmarks),
+    Scenario(Scenario),
+    Serve(Serve),
     MakeTemplates,
     Reformat(Reformat),
+    Diff(Diff),
     EvmTests(EvmTests),
     GenUpgradeCode(GenUpgrade),
     SerializeUpgrade(SerializeUpgrade),
@@ -143,8 +297,48 @@ fn main() -> Result<(), CompileError> {
             let mut output = get_output(compile.output.clone()).unwrap();
 
             let error_system = match compile.invoke() {
-                Ok((program, error_system)) => {
-                    program.to_output(&mut output, compile.format.as_deref());
+                Ok((mut program, error_system)) => {
+                    if compile.verify_reproducible {
+                        compile.verify_reproducible(&program).map_err(|e| {
+                            e.print(&error_system.file_info_chart, false);
+                            e
+                        })?;
+                        println!(
+                            "Build is reproducible, code hash: {}",
+                            code_hash_chain(&program.code)
+                        );
+                    }
+                    if compile.split_debug_info {
+                        let sidecar_path = match &compile.output {
+                            Some(output) => DebugSidecar::path_for(Path::new(output)),
+                            None => {
+                                return Err(CompileError::new(
+                                    String::from("Compilation Failure"),
+                                    String::from("--split-debug-info requires an output file"),
+                                    vec![],
+                                ))
+                            }
+                        };
+                        let sidecar = program.split_debug_info();
+                        let sidecar_json = serde_json::to_string(&sidecar).unwrap();
+                        File::create(&sidecar_path)
+                            .and_then(|mut file| writeln!(file, "{}", sidecar_json))
+                            .map_err(|_| {
+                                CompileError::new(
+                                    String::from("Compilation Failure"),
+                                    format!(
+                                        "Could not write debug info to \"{}\"",
+                                        sidecar_path.display()
+                                    ),
+                                    vec![],
+                                )
+                            })?;
+                    }
+                    program.to_output(
+                        &mut output,
+                        compile.format.as_deref(),
+                        !compile.split_debug_info,
+                    );
                     error_system
                 }
                 Err(error_system) => error_system,
@@ -164,16 +358,86 @@ fn main() -> Result<(), CompileError> {
             };
         }
 
+        Args::Asm(asm) => {
+            let mut file_info_chart = BTreeMap::new();
+            match assemble_from_file(Path::new(&asm.input), &mut file_info_chart) {
+                Ok(program) => {
+                    program.to_output(
+                        &mut get_output(asm.output).unwrap(),
+                        asm.format.as_deref(),
+                        true,
+                    );
+                }
+                Err(e) => {
+                    e.print(&file_info_chart, false);
+                    return Err(CompileError::new(
+                        String::from("Assembly Failure"),
+                        String::from("Errors were encountered during assembly"),
+                        vec![],
+                    ));
+                }
+            }
+        }
+
         Args::Run(run) => {
-            let filename = run.input;
+            let filename = run.input.clone();
             let debug = run.debug;
             let path = Path::new(&filename);
-            match run_from_file(path, Vec::new(), debug) {
-                Ok(logs) => {
-                    println!("Logs: {:?}", logs);
+            let mut machine = load_from_file(path);
+            machine.set_checkpoint_budget(run.checkpoint_budget_mib << 20);
+            if let Some(interval) = run.print_hash_every {
+                machine.set_print_hash_every(interval);
+            }
+            if run.coverage.is_some() {
+                machine.enable_coverage();
+            }
+            if let Some(schedule_file) = &run.gas_schedule {
+                machine.set_gas_schedule(GasSchedule::from_file(schedule_file.as_ref())?);
+            }
+            if run.stats.is_some() {
+                machine.enable_stats();
+            }
+            if let Some(json_trace) = run.json_trace.writer()? {
+                machine.set_json_trace(json_trace);
+            }
+            if let Some(sideload_file) = &run.sideload {
+                match FileSideloadProvider::from_file(sideload_file.as_ref()) {
+                    Ok(provider) => machine
+                        .runtime_env
+                        .set_sideload_provider(Arc::new(provider)),
+                    Err(e) => panic!("Error reading sideloads from {}: {}", sideload_file, e),
                 }
-                Err(e) => {
-                    println!("{:?}", e);
+            }
+            if run.dap {
+                dap(&mut machine, Vec::new());
+                print_time = false;
+            } else {
+                match run::run(&mut machine, Vec::new(), debug) {
+                    Ok(logs) => {
+                        println!("Logs: {:?}", logs);
+                    }
+                    Err(e) => {
+                        println!("{}", e.pretty_fmt(machine.file_info_chart()));
+                    }
+                }
+            }
+            if let Some(sideload_file) = &run.record_sideloads {
+                if let Err(e) = machine
+                    .runtime_env
+                    .recorder
+                    .sideloads_to_file(sideload_file.as_ref())
+                {
+                    panic!("Error writing sideloads to {}: {}", sideload_file, e);
+                }
+            }
+            if let (Some(stats_file), Some(stats)) = (&run.stats, machine.stats()) {
+                if let Err(e) = stats.to_file(stats_file.as_ref()) {
+                    panic!("Error writing statistics to {}: {}", stats_file, e);
+                }
+            }
+            if let (Some(coverage_file), Some(coverage)) = (run.coverage, machine.coverage()) {
+                if let Err(e) = coverage.merge_into_lcov_file(coverage_file.as_ref()) {
+                    panic!("Error writing coverage to {}: {}", coverage_file, e);
                 }
             }
         }
@@ -187,12 +451,31 @@ fn main() -> Result<(), CompileError> {
 
         Args::Profiler(path) => {
             let input = path.input;
-            profile_gen_from_file(
-                input.as_ref(),
-                Vec::new(),
-                RuntimeEnvironment::default(),
-                path.mode,
-            );
+            if let Some(format) = path.export {
+                let out = path.out.unwrap_or_else(|| {
+                    let extension = match format {
+                        ProfileFormat::Collapsed => "folded",
+                        ProfileFormat::Callgrind => "callgrind",
+                    };
+                    format!("{}.{}", input, extension)
+                });
+                if let Err(e) = export_profile_from_file(
+                    input.as_ref(),
+                    RuntimeEnvironment::default(),
+                    path.mode,
+                    format,
+                    out.as_ref(),
+                ) {
+                    panic!("Error exporting profile of {} to {}: {}", input, out, e);
+                }
+            } else {
+                profile_gen_from_file(
+                    input.as_ref(),
+                    Vec::new(),
+                    RuntimeEnvironment::default(),
+                    path.mode,
+                );
+            }
         }
 
         Args::Replay(replay) => {
@@ -200,12 +483,57 @@ fn main() -> Result<(), CompileError> {
             let debug = replay.debug;
             let profiler = replay.profiler;
             let trace_file = replay.trace.as_deref();
+            if replay.dap {
+                print_time = false;
+            }
+
+            let checkpoint_budget = replay.checkpoint_budget_mib << 20;
+            let gas_schedule = match &replay.gas_schedule {
+                Some(schedule_file) => Some(GasSchedule::from_file(schedule_file.as_ref())?),
+                None => None,
+            };
 
-            if let Err(e) = replay_from_testlog_file(path, true, debug, profiler, trace_file) {
+            let options = ReplayOptions {
+                // gas usage is expected to differ from the log under another schedule
+                require_same_gas: gas_schedule.is_none(),
+                debug,
+                profiler_mode: profiler,
+                trace_file,
+                dap: replay.dap,
+                checkpoint_budget,
+                print_hash_every: replay.print_hash_every,
+                coverage_file: replay.coverage.as_deref().map(Path::new),
+                gas_schedule,
+                stats_file: replay.stats.as_deref().map(Path::new),
+                json_trace: replay.json_trace.writer()?,
+                call_trace_file: replay.call_traces.as_deref().map(Path::new),
+            };
+            if let Err(e) = replay_from_testlog_file(path, options) {
                 panic!("Error reading from {}: {}", path, e);
             }
         }
 
+        Args::BisectDivergence(bisect) => {
+            match bisect_divergence(
+                &bisect.input,
+                &bisect.first,
+                &bisect.second,
+                bisect.compare_gas,
+            )? {
+                Some(divergence) => print!("{}", divergence),
+                None => println!("No divergence found"),
+            }
+        }
+
+        Args::ProveStep(prove) => {
+            let proof = prove_step_from_testlog_file(&prove.input, prove.step)?;
+            match serde_json::to_string_pretty(&proof) {
+                Ok(s) => println!("{}", s),
+                Err(e) => panic!("{}", e),
+            }
+            print_time = false;
+        }
+
         Args::MakeTestLogs => {
             evm::make_logs_for_all_arbos_tests();
         }
@@ -223,6 +551,48 @@ fn main() -> Result<(), CompileError> {
             })?;
         }
 
+        Args::ReplayBenchmarks(bench) => {
+            let mut schedules = vec![];
+            for schedule_file in &bench.gas_schedule {
+                schedules.push((
+                    schedule_file.clone(),
+                    GasSchedule::from_file(schedule_file.as_ref())?,
+                ));
+            }
+            evm::time_replay_benchmarks(bench.iterations, &schedules).map_err(|e| {
+                CompileError::new(String::from("Benchmark error"), e.to_string(), vec![])
+            })?;
+        }
+
+        Args::Scenario(scenario) => {
+            let mut failed = 0;
+            for input in &scenario.input {
+                let scenario_file = evm::ScenarioFile::from_file(input.as_ref())?;
+                let mut machine = evm::booted_arbos_machine();
+                let failures = scenario_file.run(&mut machine, scenario.debug)?;
+                if failures.is_empty() {
+                    println!("{}: passed", input);
+                } else {
+                    failed += 1;
+                    println!("{}: failed", input);
+                    for failure in failures {
+                        println!("    {}", failure);
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(CompileError::new(
+                    String::from("Scenario error"),
+                    format!("{} of {} scenarios failed", failed, scenario.input.len()),
+                    vec![],
+                ));
+            }
+        }
+
+        Args::Serve(serve) => {
+            evm::serve(evm::booted_arbos_machine(), serve.port)?;
+        }
+
         Args::MakeTemplates => {
             let path = Path::new("arb_os/contractTemplates.mini");
             generate_contract_template_file_or_die(path);
@@ -237,18 +607,21 @@ fn main() -> Result<(), CompileError> {
                     vec![],
                 )
             })?;
-            let mut s = String::new();
-            file.read_to_string(&mut s).map_err(|_| {
+            let mut bytes = vec![];
+            file.read_to_end(&mut bytes).map_err(|_| {
                 CompileError::new(
                     String::from("Reformat error"),
-                    format!("Failed to read input file \"{}\" to string", reformat.input),
+                    format!("Failed to read input file \"{}\"", reformat.input),
                     vec![],
                 )
             })?;
-            let result: LinkedProgram = serde_json::from_str(&s).map_err(|_| {
+            let result = LinkedProgram::from_bytes(&bytes).map_err(|_| {
                 CompileError::new(
                     String::from("Reformat error"),
-                    format!("Could not parse input file \"{}\" as json", reformat.input),
+                    format!(
+                        "Could not parse input file \"{}\" as a mini executable",
+                        reformat.input
+                    ),
                     vec![],
                 )
             })?;
@@ -256,9 +629,14 @@ fn main() -> Result<(), CompileError> {
             result.to_output(
                 &mut get_output(reformat.output).unwrap(),
                 reformat.format.as_deref(),
+                true,
             );
         }
 
+        Args::Diff(diff) => {
+            diff::diff_files(Path::new(&diff.old), Path::new(&diff.new))?.print(diff.all);
+        }
+
         Args::EvmTests(options) => {
             let mut paths = options.input;
             if paths.len() == 0 {
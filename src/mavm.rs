@@ -151,6 +151,24 @@ impl<T> Instruction<T> {
 }
 
 impl Instruction<AVMOpcode> {
+    ///Returns the AVM hash of the code point holding self, given next_hash, the hash of the code
+    /// point executed after it, and code_point_hash, which hashes code points in the immediate.
+    ///
+    /// This is the keccak256 hash of the code point type code, the opcode, the hash of the
+    /// immediate if there is one, and next_hash, as computed by the node.
+    pub fn avm_code_point_hash(
+        &self,
+        next_hash: &Uint256,
+        code_point_hash: &dyn Fn(CodePt) -> Uint256,
+    ) -> Uint256 {
+        let mut buf = vec![CODE_POINT_TYPECODE, self.opcode.to_number()];
+        if let Some(imm) = &self.immediate {
+            buf.extend(imm.avm_value_hash(code_point_hash).hash.to_bytes_be());
+        }
+        buf.extend(next_hash.to_bytes_be());
+        Uint256::from_bytes(&keccak256(&buf))
+    }
+
     pub fn _upload(&self, u: &mut CodeUploader) {
         u._push_byte(self.opcode.to_number());
         if let Some(val) = &self.immediate {
@@ -359,6 +377,11 @@ impl Buffer {
         ret
     }
 
+    ///Returns the bytes of self up to its size.
+    pub fn contents(&self) -> Vec<u8> {
+        self.as_bytes(self.size as usize)
+    }
+
     fn avm_hash(&self) -> Uint256 {
         self.root.hash()
     }
@@ -634,6 +657,50 @@ fn _levels_needed(x: u128) -> (usize, u128) {
     (height, size)
 }
 
+///Type code the AVM hashes code points with.
+const CODE_POINT_TYPECODE: u8 = 1;
+///Type code the AVM hashes tuples with.
+const TUPLE_TYPECODE: u8 = 3;
+///Number the AVM hashes together with the merkle root of a buffer.
+const BUFFER_HASH_PREFIX: usize = 123;
+
+///The AVM hash of a value together with its size, which the hashes of tuples commit to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ValueHash {
+    pub hash: Uint256,
+    pub size: Uint256,
+}
+
+impl ValueHash {
+    ///Returns the hash of a value that is not a tuple, which has size one.
+    fn single(hash: Uint256) -> Self {
+        ValueHash {
+            hash,
+            size: Uint256::one(),
+        }
+    }
+
+    ///Returns the hash of a tuple whose members hash to members.
+    ///
+    /// This is the keccak256 hash of the tuple type code, the hash of the number of members followed
+    /// by their hashes, and the size of the tuple, which is one more than the sizes of its members.
+    pub fn tuple(members: &[ValueHash]) -> Self {
+        let mut inner = vec![members.len() as u8];
+        let mut size = Uint256::one();
+        for member in members {
+            inner.extend(member.hash.to_bytes_be());
+            size = size.add(&member.size);
+        }
+        let mut buf = vec![TUPLE_TYPECODE];
+        buf.extend(&keccak256(&inner));
+        buf.extend(size.to_bytes_be());
+        ValueHash {
+            hash: Uint256::from_bytes(&keccak256(&buf)),
+            size,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Value {
     Int(Uint256),
@@ -824,6 +891,27 @@ impl Value {
         }
     }
 
+    ///Returns the AVM hash of self as computed by the node, hashing code points with
+    /// code_point_hash.
+    ///
+    /// Panics if self contains a label.
+    pub fn avm_value_hash(&self, code_point_hash: &dyn Fn(CodePt) -> Uint256) -> ValueHash {
+        match self {
+            Value::Int(ui) => ValueHash::single(ui.avm_hash()),
+            Value::Buffer(buf) => ValueHash::single(Uint256::avm_hash2(
+                &Uint256::from_usize(BUFFER_HASH_PREFIX),
+                &buf.avm_hash(),
+            )),
+            Value::CodePoint(cp) => ValueHash::single(code_point_hash(*cp)),
+            Value::Tuple(v) => ValueHash::tuple(
+                &v.iter()
+                    .map(|val| val.avm_value_hash(code_point_hash))
+                    .collect::<Vec<_>>(),
+            ),
+            Value::Label(label) => panic!("tried to hash unlinked label {}", label),
+        }
+    }
+
     pub fn avm_hash2(v1: &Self, v2: &Self) -> Value {
         if let Value::Int(ui) = v1 {
             if let Value::Int(ui2) = v2 {
@@ -1188,6 +1276,14 @@ impl AVMOpcode {
         }
     }
 
+    ///Returns the `AVMOpcode` whose mnemonic, as produced by `to_name`, is `name`, or `None` if
+    /// there is no such opcode.
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..256)
+            .filter_map(AVMOpcode::from_number)
+            .find(|op| op.to_name() == name)
+    }
+
     pub fn from_number(num: usize) -> Option<Self> {
         match num {
             0x00 => Some(AVMOpcode::Zero),
@@ -1375,6 +1471,15 @@ fn test_consistent_opcode_numbers() {
     }
 }
 
+#[test]
+fn test_consistent_opcode_names() {
+    for i in 0..256 {
+        if let Some(op) = AVMOpcode::from_number(i) {
+            assert_eq!(AVMOpcode::from_name(op.to_name()), Some(op));
+        }
+    }
+}
+
 impl fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -323,6 +323,21 @@ fn test_payment_to_empty_address() {
     crate::evm::evm_payment_to_empty_address(None, false);
 }
 
+#[test]
+fn test_block_boundary_scenario() {
+    crate::evm::evm_block_boundary_scenario(None);
+}
+
+#[test]
+fn test_add_scenario_file() {
+    let scenario = crate::evm::ScenarioFile::from_file(Path::new("scenarios/add.yaml")).unwrap();
+    let mut machine = crate::evm::booted_arbos_machine();
+    assert_eq!(
+        scenario.run(&mut machine, false).unwrap(),
+        Vec::<String>::new()
+    );
+}
+
 #[test]
 fn test_underfunded_nested_call() {
     assert!(crate::evm::_underfunded_nested_call_test(None, false).is_ok());
@@ -0,0 +1,153 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides runtime errors together with a backtrace of the source locations of their call frames.
+
+use super::emulator::{ErrorKind, ExecutionError, Machine};
+use crate::compile::{CompileError, FileInfo};
+use crate::mavm::CodePt;
+use crate::pos::{try_display_location, Location};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+
+///A call frame of a `RuntimeError`, the innermost frame is the instruction that failed and every
+/// other frame is a return address on the aux stack.
+#[derive(Clone, Debug)]
+pub struct BacktraceFrame {
+    pub codept: CodePt,
+    pub location: Option<Location>,
+}
+
+///An `ExecutionError` with the call frames that were active when it occurred, innermost first.
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub error: ExecutionError,
+    pub backtrace: Vec<BacktraceFrame>,
+}
+
+impl RuntimeError {
+    ///Captures the backtrace of machine, which has just encountered error.
+    pub(crate) fn new(error: ExecutionError, machine: &Machine) -> Self {
+        let backtrace = error
+            .codept()
+            .into_iter()
+            .chain(machine.aux_stack().all_codepts().into_iter().rev())
+            .map(|codept| BacktraceFrame {
+                codept,
+                location: machine
+                    .insn_at(codept)
+                    .and_then(|insn| insn.debug_info.location),
+            })
+            .collect();
+        RuntimeError { error, backtrace }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.error.kind()
+    }
+
+    ///Renders self in the style of `CompileError::pretty_fmt`, with a source snippet for each frame.
+    ///
+    /// Sources missing from file_info_chart, as is the case for loaded executables, are read from
+    /// the file system if they can be found.
+    pub fn pretty_fmt(&self, file_info_chart: &BTreeMap<u64, FileInfo>) -> String {
+        let file_info_chart = with_sources(file_info_chart);
+        let mut frames = self.backtrace.iter();
+        let mut pretty = CompileError::new(
+            format!("Runtime error ({})", self.kind()),
+            self.error.to_string().trim_end().to_string(),
+            frames
+                .next()
+                .and_then(|frame| frame.location)
+                .into_iter()
+                .collect(),
+        )
+        .pretty_fmt(&file_info_chart, false);
+        for frame in frames {
+            pretty += &CompileError::new(
+                String::from("Called from"),
+                frame.codept.to_string(),
+                frame.location.into_iter().collect(),
+            )
+            .pretty_fmt(&file_info_chart, false);
+        }
+        pretty
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.error)?;
+        for frame in &self.backtrace {
+            writeln!(
+                f,
+                "    at {} {}",
+                frame.codept,
+                try_display_location(frame.location, &BTreeMap::new(), false)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+///Returns a copy of file_info_chart in which files without contents are read from their path, or
+/// failing that from their name.
+fn with_sources(file_info_chart: &BTreeMap<u64, FileInfo>) -> BTreeMap<u64, FileInfo> {
+    let mut file_info_chart = file_info_chart.clone();
+    for info in file_info_chart.values_mut() {
+        if info.contents.is_empty() {
+            let source = fs::read_to_string(&info.path).or_else(|_| fs::read_to_string(&info.name));
+            if let Ok(source) = source {
+                info.contents = source.split('\n').map(|line| line.to_string()).collect();
+            }
+        }
+        if info.path.is_empty() {
+            info.path = info.name.clone();
+        }
+    }
+    file_info_chart
+}
+
+#[test]
+fn test_backtrace_pretty_fmt() {
+    let location = |line: usize| Location {
+        line: line.into(),
+        column: 4.into(),
+        absolute: 0.into(),
+        file_id: 7,
+    };
+    let error = RuntimeError {
+        error: ExecutionError::RunningErr(
+            ErrorKind::TypeMismatch,
+            "expected integer on stack",
+            CodePt::new_internal(12),
+            None,
+        ),
+        backtrace: vec![
+            BacktraceFrame {
+                codept: CodePt::new_internal(12),
+                location: Some(location(1)),
+            },
+            BacktraceFrame {
+                codept: CodePt::new_internal(3),
+                location: Some(location(0)),
+            },
+        ],
+    };
+    let mut file_info_chart = BTreeMap::new();
+    file_info_chart.insert(
+        7,
+        FileInfo {
+            name: String::from("main.mini"),
+            path: String::new(),
+            contents: vec![String::from("    callee();"), String::from("    x + ();")],
+        },
+    );
+    let pretty = error.pretty_fmt(&file_info_chart);
+    assert!(pretty.contains("Runtime error (type mismatch)"));
+    assert!(pretty.contains("Called from"));
+    assert!(pretty.find("x + ();").unwrap() < pretty.find("callee();").unwrap());
+    assert_eq!(error.to_string().lines().count(), 3);
+}
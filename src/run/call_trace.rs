@@ -0,0 +1,520 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides a decoder for the tracing debugprints ArbOS emits after each transaction, described in
+//! `doc/TracingData.md`, and export of the decoded call trees as geth `callTracer` JSON.
+
+use super::runtime_env::{tx_result_code_text, ArbosReceipt};
+use crate::compile::CompileError;
+use crate::mavm::Value;
+use crate::uint256::Uint256;
+use serde_json::{json, Value as JsonValue};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+///Tag of the debugprints that carry trace data.
+const TRACE_DEBUGPRINT_TAG: u64 = 20000;
+
+///The kind of a `CallFrame`, only the first four appear in `TraceEvent::Call`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallType {
+    Call,
+    CallCode,
+    DelegateCall,
+    StaticCall,
+    Create,
+    Create2,
+}
+
+impl fmt::Display for CallType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CallType::Call => "CALL",
+            CallType::CallCode => "CALLCODE",
+            CallType::DelegateCall => "DELEGATECALL",
+            CallType::StaticCall => "STATICCALL",
+            CallType::Create => "CREATE",
+            CallType::Create2 => "CREATE2",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+///An event in the trace data of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    ///Entry into a call frame, to is zero for the constructor of a deploy transaction.
+    Call {
+        call_type: CallType,
+        calldata: Vec<u8>,
+        value: Uint256,
+        from: Uint256,
+        to: Uint256,
+        gas: Uint256,
+        gas_price: Uint256,
+    },
+    ///Exit from the innermost call frame, result_code is a transaction result code.
+    Return {
+        result_code: Uint256,
+        return_data: Vec<u8>,
+        gas_used: Uint256,
+    },
+    ///A create instruction, followed by the `Call` that runs its constructor if it gets that far.
+    Create { code: Vec<u8>, address: Uint256 },
+    ///A create2 instruction, followed by the `Call` that runs its constructor if it gets that far.
+    Create2 {
+        code: Vec<u8>,
+        creator: Uint256,
+        salt: Uint256,
+        address: Uint256,
+    },
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceEvent::Call {
+                call_type,
+                calldata,
+                value,
+                from,
+                to,
+                gas,
+                gas_price,
+            } => write!(
+                f,
+                "{} from {} to {} with value {}, gas {} at price {}, calldata 0x{}",
+                call_type,
+                from,
+                to,
+                value,
+                gas,
+                gas_price,
+                hex::encode(calldata)
+            ),
+            TraceEvent::Return {
+                result_code,
+                return_data,
+                gas_used,
+            } => write!(
+                f,
+                "return with code {} after {} gas, returndata 0x{}",
+                result_code,
+                gas_used,
+                hex::encode(return_data)
+            ),
+            TraceEvent::Create { code, address } => {
+                write!(f, "create of {} with code 0x{}", address, hex::encode(code))
+            }
+            TraceEvent::Create2 {
+                code,
+                creator,
+                salt,
+                address,
+            } => write!(
+                f,
+                "create2 of {} by {} with salt {}, code 0x{}",
+                address,
+                creator,
+                salt,
+                hex::encode(code)
+            ),
+        }
+    }
+}
+
+impl TraceEvent {
+    ///Decodes the events in the trace data of val if it is a tracing debugprint, returns None if
+    /// it is some other debugprint.
+    pub fn decode_debugprint(val: &Value) -> Option<Result<Vec<Self>, CompileError>> {
+        match val {
+            Value::Tuple(tup)
+                if tup.len() == 2
+                    && tup[0] == Value::Int(Uint256::from_u64(TRACE_DEBUGPRINT_TAG)) =>
+            {
+                Some(TraceEvent::decode_list(&tup[1]))
+            }
+            _ => None,
+        }
+    }
+
+    ///Decodes trace data, a linked list of events.
+    pub fn decode_list(val: &Value) -> Result<Vec<Self>, CompileError> {
+        let mut events = vec![];
+        let mut val = val;
+        while !val.is_none() {
+            match val {
+                Value::Tuple(tup) if tup.len() == 2 => {
+                    events.push(TraceEvent::decode(&tup[0])?);
+                    val = &tup[1];
+                }
+                _ => return Err(trace_error(format!("malformed event list {}", val))),
+            }
+        }
+        Ok(events)
+    }
+
+    fn decode(val: &Value) -> Result<Self, CompileError> {
+        let malformed = || trace_error(format!("malformed event {}", val));
+        let (typecode, data) = match val {
+            Value::Tuple(tup) if tup.len() == 2 => match &tup[1] {
+                Value::Tuple(data) => (uint(&tup[0])?, data),
+                _ => return Err(malformed()),
+            },
+            _ => return Err(malformed()),
+        };
+        // ArbOS appends a ~0 to the fields listed in TracingData.md, which is ignored here
+        let field = |index: usize| data.get(index).ok_or_else(malformed);
+        Ok(match typecode.to_u64() {
+            Some(0) => TraceEvent::Call {
+                call_type: match uint(field(0)?)?.to_u64() {
+                    Some(0) => CallType::Call,
+                    Some(1) => CallType::CallCode,
+                    Some(2) => CallType::DelegateCall,
+                    Some(3) => CallType::StaticCall,
+                    _ => return Err(malformed()),
+                },
+                calldata: bytes(field(1)?)?,
+                value: uint(field(2)?)?,
+                from: uint(field(3)?)?,
+                to: uint(field(4)?)?,
+                gas: uint(field(5)?)?,
+                gas_price: uint(field(6)?)?,
+            },
+            Some(1) => TraceEvent::Return {
+                result_code: uint(field(0)?)?,
+                return_data: bytes(field(1)?)?,
+                gas_used: uint(field(2)?)?,
+            },
+            Some(2) => TraceEvent::Create {
+                code: bytes(field(0)?)?,
+                address: uint(field(1)?)?,
+            },
+            Some(3) => TraceEvent::Create2 {
+                code: bytes(field(0)?)?,
+                creator: uint(field(1)?)?,
+                salt: uint(field(2)?)?,
+                address: uint(field(3)?)?,
+            },
+            _ => return Err(malformed()),
+        })
+    }
+}
+
+///A call frame in the call tree of a transaction, with the frames it called in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallFrame {
+    pub call_type: CallType,
+    pub from: Uint256,
+    ///Callee, or address of the created contract, zero for the constructor of a deploy transaction
+    /// whose address is not known.
+    pub to: Uint256,
+    pub value: Uint256,
+    pub gas: Uint256,
+    pub gas_used: Uint256,
+    pub input: Vec<u8>,
+    pub output: Vec<u8>,
+    ///Result code of the frame, or None if a create failed before its constructor ran.
+    pub result_code: Option<Uint256>,
+    pub calls: Vec<CallFrame>,
+}
+
+impl CallFrame {
+    ///Builds the call tree of a transaction from its trace events.
+    pub fn from_events(events: &[TraceEvent]) -> Result<Self, CompileError> {
+        let mut stack: Vec<CallFrame> = vec![];
+        let mut pending_create = None;
+        let mut root = None;
+        for event in events {
+            if root.is_some() {
+                return Err(trace_error(format!("{} after the outermost return", event)));
+            }
+            match event {
+                TraceEvent::Call {
+                    call_type,
+                    calldata,
+                    value,
+                    from,
+                    to,
+                    gas,
+                    gas_price: _,
+                } => {
+                    let mut frame = CallFrame {
+                        call_type: *call_type,
+                        from: from.clone(),
+                        to: to.clone(),
+                        value: value.clone(),
+                        gas: gas.clone(),
+                        gas_used: Uint256::zero(),
+                        input: calldata.clone(),
+                        output: vec![],
+                        result_code: None,
+                        calls: vec![],
+                    };
+                    match pending_create.take() {
+                        Some((create_type, code, address)) if address == *to => {
+                            frame.call_type = create_type;
+                            frame.input = code;
+                        }
+                        Some(create) => push_failed_create(&mut stack, create, event)?,
+                        None if stack.is_empty() && to.is_zero() => {
+                            frame.call_type = CallType::Create
+                        }
+                        None => {}
+                    }
+                    stack.push(frame);
+                }
+                TraceEvent::Return {
+                    result_code,
+                    return_data,
+                    gas_used,
+                } => {
+                    if let Some(create) = pending_create.take() {
+                        push_failed_create(&mut stack, create, event)?;
+                    }
+                    let mut frame = stack
+                        .pop()
+                        .ok_or_else(|| trace_error(format!("{} without a matching call", event)))?;
+                    frame.result_code = Some(result_code.clone());
+                    frame.output = return_data.clone();
+                    frame.gas_used = gas_used.clone();
+                    match stack.last_mut() {
+                        Some(caller) => caller.calls.push(frame),
+                        None => root = Some(frame),
+                    }
+                }
+                TraceEvent::Create { code, address } => {
+                    if let Some(create) = pending_create.take() {
+                        push_failed_create(&mut stack, create, event)?;
+                    }
+                    pending_create = Some((CallType::Create, code.clone(), address.clone()));
+                }
+                TraceEvent::Create2 { code, address, .. } => {
+                    if let Some(create) = pending_create.take() {
+                        push_failed_create(&mut stack, create, event)?;
+                    }
+                    pending_create = Some((CallType::Create2, code.clone(), address.clone()));
+                }
+            }
+        }
+        root.ok_or_else(|| trace_error(format!("{} calls without a return", stack.len())))
+    }
+
+    ///Renders self in the format of the geth `callTracer`.
+    pub fn to_call_tracer_json(&self) -> JsonValue {
+        let mut json = json!({
+            "type": self.call_type.to_string(),
+            "from": address_json(&self.from),
+            "to": address_json(&self.to),
+            "gas": quantity(&self.gas),
+            "gasUsed": quantity(&self.gas_used),
+            "input": data_json(&self.input),
+            "output": data_json(&self.output),
+        });
+        if !matches!(
+            self.call_type,
+            CallType::DelegateCall | CallType::StaticCall
+        ) {
+            json["value"] = quantity(&self.value);
+        }
+        match &self.result_code {
+            Some(code) if code.is_zero() => {}
+            Some(code) if *code == Uint256::one() => json["error"] = json!("execution reverted"),
+            Some(code) => json["error"] = json!(tx_result_code_text(code)),
+            None => json["error"] = json!("contract creation failed"),
+        }
+        if !self.calls.is_empty() {
+            json["calls"] = self
+                .calls
+                .iter()
+                .map(CallFrame::to_call_tracer_json)
+                .collect();
+        }
+        json
+    }
+}
+
+///Records a create that never reached its constructor as a failed frame of the innermost call,
+/// before event.
+fn push_failed_create(
+    stack: &mut [CallFrame],
+    (call_type, input, to): (CallType, Vec<u8>, Uint256),
+    event: &TraceEvent,
+) -> Result<(), CompileError> {
+    let caller = stack
+        .last_mut()
+        .ok_or_else(|| trace_error(format!("create outside of a call before {}", event)))?;
+    let from = caller.to.clone();
+    caller.calls.push(CallFrame {
+        call_type,
+        from,
+        to,
+        value: Uint256::zero(),
+        gas: Uint256::zero(),
+        gas_used: Uint256::zero(),
+        input,
+        output: vec![],
+        result_code: None,
+        calls: vec![],
+    });
+    Ok(())
+}
+
+///The call tree of a transaction, with the id of its request.
+#[derive(Debug, Clone)]
+pub struct TxTrace {
+    pub request_id: Uint256,
+    pub root: CallFrame,
+}
+
+impl TxTrace {
+    ///Builds the trace of the transaction of receipt, the last receipt before the tracing
+    /// debugprint whose events are given.
+    ///
+    /// The address of a deployed contract is not in the trace data, so it is taken from the
+    /// return data of receipt.
+    pub fn new(events: &[TraceEvent], receipt: &ArbosReceipt) -> Result<Self, CompileError> {
+        let mut root = CallFrame::from_events(events)?;
+        if root.call_type == CallType::Create && root.to.is_zero() && receipt.succeeded() {
+            root.to = Uint256::from_bytes(&receipt.get_return_data());
+        }
+        Ok(TxTrace {
+            request_id: receipt.get_request_id(),
+            root,
+        })
+    }
+
+    ///Renders self like an entry of the result of geth's `debug_traceBlock` with the `callTracer`.
+    pub fn to_json(&self) -> JsonValue {
+        json!({
+            "txHash": data_json(&self.request_id.to_bytes_be()),
+            "result": self.root.to_call_tracer_json(),
+        })
+    }
+}
+
+///Writes traces to the file at path as JSON Lines, one `TxTrace::to_json` per line.
+pub fn write_call_traces(traces: &[TxTrace], path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for trace in traces {
+        writeln!(writer, "{}", trace.to_json())?;
+    }
+    writer.flush()
+}
+
+fn uint(val: &Value) -> Result<Uint256, CompileError> {
+    match val {
+        Value::Int(ui) => Ok(ui.clone()),
+        _ => Err(trace_error(format!("expected integer, got {}", val))),
+    }
+}
+
+///Decodes a ByteArray, a tuple of size, offset and buffer.
+fn bytes(val: &Value) -> Result<Vec<u8>, CompileError> {
+    if let Value::Tuple(tup) = val {
+        if let [Value::Int(size), Value::Int(offset), Value::Buffer(buf)] = &tup[..] {
+            if let (Some(size), Some(offset)) = (size.to_usize(), offset.to_usize()) {
+                let end = offset.checked_add(size).ok_or_else(|| {
+                    trace_error(format!(
+                        "byte array of size {} at offset {} is out of range",
+                        size, offset
+                    ))
+                })?;
+                return Ok(buf.as_bytes(end)[offset..].to_vec());
+            }
+        }
+    }
+    Err(trace_error(format!("expected byte array, got {}", val)))
+}
+
+fn quantity(value: &Uint256) -> JsonValue {
+    json!(format!("{:#x}", value.to_u256()))
+}
+
+fn data_json(bytes: &[u8]) -> JsonValue {
+    json!(format!("0x{}", hex::encode(bytes)))
+}
+
+fn address_json(value: &Uint256) -> JsonValue {
+    data_json(&value.to_bytes_be()[12..])
+}
+
+fn trace_error(description: String) -> CompileError {
+    CompileError::new(String::from("Trace error"), description, vec![])
+}
+
+#[test]
+fn test_call_trace_decoding() {
+    let int = |n: u64| Value::Int(Uint256::from_u64(n));
+    let byte_array = |contents: &[u8]| {
+        Value::new_tuple(vec![
+            int(contents.len() as u64),
+            int(0),
+            Value::new_buffer(contents.to_vec()),
+        ])
+    };
+    let event = |typecode: u64, mut data: Vec<Value>| {
+        data.push(Value::Int(Uint256::max_uint()));
+        Value::new_tuple(vec![int(typecode), Value::new_tuple(data)])
+    };
+    let call = |call_type: u64, from: u64, to: u64| {
+        event(
+            0,
+            vec![
+                int(call_type),
+                byte_array(&[0xab]),
+                int(5),
+                int(from),
+                int(to),
+                int(1000),
+                int(1),
+            ],
+        )
+    };
+    let ret = |code: u64| event(1, vec![int(code), byte_array(&[1, 2]), int(300)]);
+    let events = vec![
+        call(0, 0x10, 0x20),
+        event(2, vec![byte_array(&[0x60, 0x80]), int(0x30)]),
+        call(0, 0x20, 0x30),
+        ret(0),
+        call(3, 0x20, 0x40),
+        ret(1),
+        ret(0),
+    ];
+    let list = events.into_iter().rev().fold(Value::none(), |rest, event| {
+        Value::new_tuple(vec![event, rest])
+    });
+    let debugprint = Value::new_tuple(vec![int(TRACE_DEBUGPRINT_TAG), list]);
+
+    let events = TraceEvent::decode_debugprint(&debugprint).unwrap().unwrap();
+    assert_eq!(events.len(), 7);
+    let root = CallFrame::from_events(&events).unwrap();
+    assert_eq!(root.calls.len(), 2);
+    assert_eq!(root.calls[0].call_type, CallType::Create);
+    assert_eq!(root.calls[0].input, vec![0x60, 0x80]);
+
+    let json = root.to_call_tracer_json();
+    assert_eq!(json["type"], "CALL");
+    assert_eq!(json["to"], "0x0000000000000000000000000000000000000020");
+    assert_eq!(json["gasUsed"], "0x12c");
+    assert_eq!(json["input"], "0xab");
+    assert_eq!(json["calls"][0]["type"], "CREATE");
+    assert_eq!(json["calls"][1]["error"], "execution reverted");
+    assert!(json["calls"][1].get("value").is_none());
+    assert!(json["calls"][0].get("calls").is_none());
+
+    let overflowing = Value::new_tuple(vec![
+        int(2),
+        Value::Int(Uint256::from_usize(usize::MAX)),
+        Value::new_buffer(vec![1, 2]),
+    ]);
+    assert!(bytes(&overflowing).is_err());
+    assert_eq!(bytes(&byte_array(&[1, 2])).unwrap(), vec![1, 2]);
+
+    assert!(TraceEvent::decode_debugprint(&int(3)).is_none());
+    assert!(CallFrame::from_events(&events[..6]).is_err());
+    assert!(CallFrame::from_events(&events[1..]).is_err());
+}
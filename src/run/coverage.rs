@@ -0,0 +1,167 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides source line coverage of mini programs, collected by a `Machine` and written in the lcov
+//! tracefile format.
+
+use crate::compile::FileInfo;
+use crate::mavm::{AVMOpcode, Instruction};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+///Execution counts of source lines, by file name and line number, with lines counted from 1 as in
+/// lcov.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageData {
+    files: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+impl CoverageData {
+    ///Builds a `CoverageData` from the execution counts of counts, by file id and zero based line,
+    /// also listing every other line of code with a count of zero so that unexecuted lines show up.
+    pub(crate) fn new(
+        code: &[Instruction<AVMOpcode>],
+        counts: &HashMap<(u64, u32), u64>,
+        file_info_chart: &BTreeMap<u64, FileInfo>,
+    ) -> Self {
+        let mut coverage = CoverageData::default();
+        for location in code.iter().filter_map(|insn| insn.debug_info.location) {
+            coverage.add(location.file_id, location.line(), 0, file_info_chart);
+        }
+        for ((file_id, line), count) in counts {
+            coverage.add(*file_id, *line, *count, file_info_chart);
+        }
+        coverage
+    }
+
+    fn add(
+        &mut self,
+        file_id: u64,
+        line: u32,
+        count: u64,
+        file_info_chart: &BTreeMap<u64, FileInfo>,
+    ) {
+        let file_name = match file_info_chart.get(&file_id) {
+            Some(info) => info.name.clone(),
+            None => format!("unknown_file_{}", file_id),
+        };
+        *self
+            .files
+            .entry(file_name)
+            .or_default()
+            .entry(line + 1)
+            .or_insert(0) += count;
+    }
+
+    ///Adds the counts of other to self.
+    pub fn merge(&mut self, other: &CoverageData) {
+        for (file_name, lines) in &other.files {
+            let file = self.files.entry(file_name.clone()).or_default();
+            for (line, count) in lines {
+                *file.entry(*line).or_insert(0) += count;
+            }
+        }
+    }
+
+    ///Parses the `SF`, `DA` and `end_of_record` entries of an lcov tracefile, ignoring the rest.
+    pub fn from_lcov<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut coverage = CoverageData::default();
+        let mut current_file = None;
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if let Some(file_name) = line.strip_prefix("SF:") {
+                current_file = Some(file_name.to_string());
+            } else if line == "end_of_record" {
+                current_file = None;
+            } else if let Some(entry) = line.strip_prefix("DA:") {
+                let file_name = current_file.clone().ok_or_else(|| {
+                    invalid_data(format!("line data outside of a source file: {}", line))
+                })?;
+                let mut fields = entry.split(',');
+                let line_num = fields.next().and_then(|f| f.parse::<u32>().ok());
+                let count = fields.next().and_then(|f| f.parse::<u64>().ok());
+                match (line_num, count) {
+                    (Some(line_num), Some(count)) => {
+                        *coverage
+                            .files
+                            .entry(file_name)
+                            .or_default()
+                            .entry(line_num)
+                            .or_insert(0) += count;
+                    }
+                    _ => return Err(invalid_data(format!("invalid line data: {}", line))),
+                }
+            }
+        }
+        Ok(coverage)
+    }
+
+    ///Writes self as an lcov tracefile.
+    pub fn write_lcov(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+        for (file_name, lines) in &self.files {
+            writeln!(out, "SF:{}", file_name)?;
+            for (line, count) in lines {
+                writeln!(out, "DA:{},{}", line, count)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(
+                out,
+                "LH:{}",
+                lines.values().filter(|count| **count > 0).count()
+            )?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    ///Adds self to the lcov tracefile at path, creating it if it does not exist, so that coverage
+    /// accumulates over several runs.
+    pub fn merge_into_lcov_file(&self, path: &Path) -> io::Result<()> {
+        let mut total = if path.exists() {
+            CoverageData::from_lcov(BufReader::new(File::open(path)?))?
+        } else {
+            CoverageData::default()
+        };
+        total.merge(self);
+        let mut file = BufWriter::new(File::create(path)?);
+        total.write_lcov(&mut file)?;
+        file.flush()
+    }
+}
+
+fn invalid_data(description: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description)
+}
+
+#[test]
+fn test_lcov_round_trip() {
+    let mut file_info_chart = BTreeMap::new();
+    file_info_chart.insert(
+        3,
+        FileInfo {
+            name: String::from("main.mini"),
+            path: String::new(),
+            contents: vec![],
+        },
+    );
+    let mut counts = HashMap::new();
+    counts.insert((3, 0), 2);
+    counts.insert((3, 4), 1);
+    let coverage = CoverageData::new(&[], &counts, &file_info_chart);
+    let mut out = vec![];
+    coverage.write_lcov(&mut out).unwrap();
+    let text = String::from_utf8(out.clone()).unwrap();
+    assert_eq!(
+        text,
+        "TN:\nSF:main.mini\nDA:1,2\nDA:5,1\nLF:2\nLH:2\nend_of_record\n"
+    );
+    let mut doubled = CoverageData::from_lcov(&out[..]).unwrap();
+    assert_eq!(doubled, coverage);
+    doubled.merge(&coverage);
+    assert_eq!(doubled.files["main.mini"][&1], 4);
+}
@@ -0,0 +1,829 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides a Debug Adapter Protocol server for the emulator, so that it can be driven from an IDE.
+//!
+//! The server handles a single session over a pair of streams, normally stdin and stdout, and
+//! exposes the machine as a single thread. Stack frames are recovered from the return addresses on
+//! the aux stack, the values between two return addresses being the locals of that frame. Locals
+//! are named and typed using the `FuncDebugInfo` of the function a frame is in.
+//!
+//! Since the session may own stdout, the emulator writes its diagnostics to stderr, and debugprint
+//! output is captured and forwarded to the client as output events.
+
+use super::emulator::{MachineState, SourceStep};
+use crate::compile::Type;
+use crate::link::TupleTree;
+use crate::mavm::{CodePt, Value};
+use crate::run::Machine;
+use serde_json::{json, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+///Maximum length of the displayed form of a variable's value.
+const MAX_DISPLAY_LEN: usize = 200;
+
+///A variable shown to the client, tipe is used to decode value if present.
+#[derive(Clone)]
+struct Variable {
+    name: String,
+    value: Value,
+    tipe: Option<Type>,
+}
+
+///How a resumed machine came to a halt.
+enum StopReason {
+    Breakpoint,
+    Step,
+    Terminated,
+}
+
+///A Debug Adapter Protocol session reading requests from input and writing to output.
+pub struct DapServer<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    seq: u64,
+    ///Resolved breakpoints, by the source path the client set them for.
+    breakpoints: BTreeMap<String, Vec<(u64, u32)>>,
+    ///Variables behind each variables reference handed to the client, reference n is at n - 1.
+    handles: Vec<Vec<Variable>>,
+    gas_used: u64,
+    ///Source line of the last executed instruction that had one, see `Machine::at_breakpoint`.
+    previous_line: Option<(u64, u32)>,
+}
+
+impl<R: BufRead, W: Write> DapServer<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        DapServer {
+            input,
+            output,
+            seq: 0,
+            breakpoints: BTreeMap::new(),
+            handles: vec![],
+            gas_used: 0,
+            previous_line: None,
+        }
+    }
+
+    ///Reads the next message from input, returning None at the end of input.
+    fn read_message(&mut self) -> io::Result<Option<JsonValue>> {
+        let mut content_length = None;
+        loop {
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                if content_length.is_some() {
+                    break;
+                }
+                continue;
+            }
+            if let Some(len) = line.strip_prefix("Content-Length:") {
+                content_length = len.trim().parse::<usize>().ok();
+            }
+        }
+        let mut body = vec![0u8; content_length.unwrap_or(0)];
+        self.input.read_exact(&mut body)?;
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn send(&mut self, mut message: JsonValue) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn respond(&mut self, request: &JsonValue, body: JsonValue) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &JsonValue, message: String) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: JsonValue) -> io::Result<()> {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }))
+    }
+
+    ///Forwards debugprint output collected by machine to the client.
+    fn flush_output(&mut self, machine: &mut Machine) -> io::Result<()> {
+        for output in machine.take_captured_output() {
+            self.event(
+                "output",
+                json!({"category": "stdout", "output": output + "\n"}),
+            )?;
+        }
+        Ok(())
+    }
+
+    ///Serves requests until the client disconnects or input ends, running machine until its
+    /// program counter reaches stop_pc. Returns the gas used by machine.
+    pub fn serve(&mut self, machine: &mut Machine, stop_pc: Option<CodePt>) -> io::Result<u64> {
+        machine.capture_output();
+        let mut stop_on_entry = false;
+        while let Some(request) = self.read_message()? {
+            let args = request["arguments"].clone();
+            match request["command"].as_str().unwrap_or("") {
+                "initialize" => {
+                    self.respond(
+                        &request,
+                        json!({
+                            "supportsConfigurationDoneRequest": true,
+                        }),
+                    )?;
+                    self.event("initialized", json!({}))?;
+                }
+                "launch" | "attach" => {
+                    stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                    self.respond(&request, json!({}))?;
+                }
+                "setBreakpoints" => {
+                    let path = args["source"]["path"]
+                        .as_str()
+                        .or_else(|| args["source"]["name"].as_str())
+                        .unwrap_or("")
+                        .to_string();
+                    let file_ids = machine.matching_file_ids(&path);
+                    let mut resolved = vec![];
+                    let mut reply = vec![];
+                    for bp in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+                        let line = bp["line"].as_u64().unwrap_or(0) as u32;
+                        let verified = line > 0 && !file_ids.is_empty();
+                        if verified {
+                            resolved.extend(file_ids.iter().map(|id| (*id, line - 1)));
+                        }
+                        reply.push(json!({"verified": verified, "line": line}));
+                    }
+                    self.breakpoints.insert(path, resolved);
+                    self.respond(&request, json!({ "breakpoints": reply }))?;
+                }
+                "configurationDone" => {
+                    self.respond(&request, json!({}))?;
+                    if stop_on_entry {
+                        self.event("stopped", json!({"reason": "entry", "threadId": 1}))?;
+                    } else {
+                        self.resume(machine, SourceStep::None, stop_pc)?;
+                    }
+                }
+                "threads" => {
+                    self.respond(&request, json!({"threads": [{"id": 1, "name": "AVM"}]}))?;
+                }
+                "stackTrace" => {
+                    let frames: Vec<_> = frames(machine)
+                        .iter()
+                        .enumerate()
+                        .map(|(id, (codept, _))| frame_json(machine, id, *codept))
+                        .collect();
+                    self.respond(
+                        &request,
+                        json!({"stackFrames": frames, "totalFrames": frames.len()}),
+                    )?;
+                }
+                "scopes" => {
+                    let frame_id = args["frameId"].as_u64().unwrap_or(0) as usize;
+                    let locals = frames(machine)
+                        .get(frame_id)
+                        .map(|(_, locals)| locals.clone())
+                        .unwrap_or_default();
+                    let globals = machine
+                        .globals()
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, global)| Variable {
+                            name: global.name.clone(),
+                            value: machine.read_global(idx).unwrap_or_else(Value::none),
+                            tipe: Some(global.tipe.clone()),
+                        })
+                        .collect();
+                    let stack = stack_variables(machine.stack().iter().cloned().collect());
+                    let aux_stack = stack_variables(machine.aux_stack().iter().cloned().collect());
+                    let scopes: Vec<_> = vec![
+                        ("Locals", locals),
+                        ("Globals", globals),
+                        ("Stack", stack),
+                        ("Aux Stack", aux_stack),
+                    ]
+                    .into_iter()
+                    .map(|(name, vars)| {
+                        json!({
+                            "name": name,
+                            "variablesReference": self.add_handle(vars),
+                            "expensive": false,
+                        })
+                    })
+                    .collect();
+                    self.respond(&request, json!({ "scopes": scopes }))?;
+                }
+                "variables" => {
+                    let reference = args["variablesReference"].as_u64().unwrap_or(0) as usize;
+                    let vars = match reference {
+                        0 => vec![],
+                        _ => self.handles.get(reference - 1).cloned().unwrap_or_default(),
+                    };
+                    let mut reply = vec![];
+                    for var in vars {
+                        let (display, children) = describe(machine, &var.value, &var.tipe);
+                        let reference = if children.is_empty() {
+                            0
+                        } else {
+                            self.add_handle(children)
+                        };
+                        reply.push(json!({
+                            "name": var.name,
+                            "value": display,
+                            "variablesReference": reference,
+                        }));
+                    }
+                    self.respond(&request, json!({ "variables": reply }))?;
+                }
+                "continue" => {
+                    self.respond(&request, json!({"allThreadsContinued": true}))?;
+                    self.resume(machine, SourceStep::None, stop_pc)?;
+                }
+                "next" => {
+                    self.respond(&request, json!({}))?;
+                    let step = SourceStep::Over(machine.next_source_line(), machine.frame_depth());
+                    self.resume(machine, step, stop_pc)?;
+                }
+                "stepIn" => {
+                    self.respond(&request, json!({}))?;
+                    let step = SourceStep::Into(machine.next_source_line());
+                    self.resume(machine, step, stop_pc)?;
+                }
+                "stepOut" => {
+                    self.respond(&request, json!({}))?;
+                    let step = SourceStep::Out(machine.frame_depth());
+                    self.resume(machine, step, stop_pc)?;
+                }
+                "disconnect" | "terminate" => {
+                    self.respond(&request, json!({}))?;
+                    break;
+                }
+                other => {
+                    let message = format!("unsupported request \"{}\"", other);
+                    self.respond_error(&request, message)?;
+                }
+            }
+        }
+        Ok(self.gas_used)
+    }
+
+    fn add_handle(&mut self, vars: Vec<Variable>) -> usize {
+        self.handles.push(vars);
+        self.handles.len()
+    }
+
+    ///Runs machine until it reaches a breakpoint, the target of step, or stops, and notifies the
+    /// client accordingly.
+    fn resume(
+        &mut self,
+        machine: &mut Machine,
+        step: SourceStep,
+        stop_pc: Option<CodePt>,
+    ) -> io::Result<()> {
+        self.handles.clear();
+        let reason = self.run_until_stop(machine, &step, stop_pc);
+        self.flush_output(machine)?;
+        match reason {
+            StopReason::Breakpoint | StopReason::Step => self.event(
+                "stopped",
+                json!({
+                    "reason": if let StopReason::Step = reason { "step" } else { "breakpoint" },
+                    "threadId": 1,
+                    "allThreadsStopped": true,
+                }),
+            ),
+            StopReason::Terminated => {
+                let exit_code = match &machine.state {
+                    MachineState::Error(e) => {
+                        self.event(
+                            "output",
+                            json!({"category": "stderr", "output": format!("{}", e)}),
+                        )?;
+                        1
+                    }
+                    _ => 0,
+                };
+                self.event("terminated", json!({}))?;
+                self.event("exited", json!({ "exitCode": exit_code }))
+            }
+        }
+    }
+
+    fn run_until_stop(
+        &mut self,
+        machine: &mut Machine,
+        step: &SourceStep,
+        stop_pc: Option<CodePt>,
+    ) -> StopReason {
+        loop {
+            match &machine.state {
+                MachineState::Running(pc) if Some(*pc) != stop_pc => {}
+                _ => return StopReason::Terminated,
+            }
+            self.gas_used += machine.next_op_gas().unwrap_or(0);
+            self.previous_line = machine.next_source_line().or(self.previous_line);
+            match machine.run_one(false) {
+                Ok(true) => {}
+                Ok(false) => {
+                    if !machine.state.is_running() {
+                        return StopReason::Terminated;
+                    }
+                }
+                Err(e) => {
+                    machine.state = MachineState::Error(e);
+                    return StopReason::Terminated;
+                }
+            }
+            let breakpoints = &self.breakpoints;
+            if machine.at_breakpoint(self.previous_line, |file_id, line| {
+                breakpoints
+                    .values()
+                    .any(|bps| bps.contains(&(file_id, line)))
+            }) {
+                return StopReason::Breakpoint;
+            }
+            if machine.reached_step_target(step) {
+                return StopReason::Step;
+            }
+        }
+    }
+}
+
+///Returns the code point and locals of each frame of machine, innermost first.
+///
+/// While a call or return is jumping, the return address of the innermost frame is still on the data
+/// stack, and the frame has no locals.
+fn frames(machine: &Machine) -> Vec<(Option<CodePt>, Vec<Variable>)> {
+    let mut frames = vec![];
+    let mut codept = machine.get_pc().ok();
+    if machine.frame_depth() > machine.aux_stack().all_codepts().len() {
+        if let Some(Value::CodePoint(return_codept)) = machine.stack().top() {
+            frames.push((codept, vec![]));
+            codept = Some(return_codept);
+        }
+    }
+    let mut values = vec![];
+    for val in machine.aux_stack().iter().rev() {
+        if let Value::CodePoint(return_codept) = val {
+            frames.push((codept, frame_variables(machine, codept, values)));
+            codept = Some(*return_codept);
+            values = vec![];
+        } else {
+            values.push(val.clone());
+        }
+    }
+    frames.push((codept, frame_variables(machine, codept, values)));
+    frames
+}
+
+///Returns the variables of the frame at codept, whose aux stack values are values, top first.
+///
+/// The bottom value of a frame is its locals tuple, which is decoded into the locals visible at
+/// codept if the function containing it has debug info. Other values are listed by position.
+fn frame_variables(
+    machine: &Machine,
+    codept: Option<CodePt>,
+    mut values: Vec<Value>,
+) -> Vec<Variable> {
+    let location = codept
+        .and_then(|codept| machine.insn_at(codept))
+        .and_then(|insn| insn.debug_info.location);
+    let func = location.and_then(|loc| machine.func_debug_info_at(&loc));
+    let mut variables = vec![];
+    if let (Some(func), Some(location), Some(Value::Tuple(_))) = (func, location, values.last()) {
+        let frame = values.pop().unwrap();
+        let shape = TupleTree::new(func.frame_size, true);
+        for local in func.locals_at(&location) {
+            variables.push(Variable {
+                name: local.name.clone(),
+                value: shape
+                    .read_value(&frame, local.slot)
+                    .unwrap_or_else(Value::none),
+                tipe: Some(local.tipe.clone()),
+            });
+        }
+    }
+    for (idx, value) in values.into_iter().enumerate() {
+        variables.push(Variable {
+            name: format!("aux[{}]", idx),
+            value,
+            tipe: None,
+        });
+    }
+    variables
+}
+
+fn frame_json(machine: &Machine, id: usize, codept: Option<CodePt>) -> JsonValue {
+    let location = codept
+        .and_then(|codept| machine.insn_at(codept))
+        .and_then(|insn| insn.debug_info.location);
+    let name = match codept {
+        Some(codept) => format!("{}", codept),
+        None => String::from("<stopped>"),
+    };
+    match location {
+        Some(loc) => {
+            let file = machine.file_info_chart().get(&loc.file_id);
+            json!({
+                "id": id,
+                "name": name,
+                "line": loc.line() + 1,
+                "column": loc.column.to_usize() + 1,
+                "source": {
+                    "name": file.map(|info| info.name.clone()),
+                    "path": file.map(|info| info.path.clone()),
+                },
+            })
+        }
+        None => json!({"id": id, "name": name, "line": 0, "column": 0}),
+    }
+}
+
+///Lists the items of a stack, top first.
+fn stack_variables(items: Vec<Value>) -> Vec<Variable> {
+    items
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, value)| Variable {
+            name: idx.to_string(),
+            value,
+            tipe: None,
+        })
+        .collect()
+}
+
+///Returns the displayed form of val and its child variables, decoding val according to tipe.
+fn describe(machine: &Machine, val: &Value, tipe: &Option<Type>) -> (String, Vec<Variable>) {
+    let tipe = tipe
+        .as_ref()
+        .and_then(|tipe| tipe.get_representation(machine.type_tree()).ok());
+    let mut display = format!("{}", val);
+    if display.len() > MAX_DISPLAY_LEN {
+        display.truncate(MAX_DISPLAY_LEN);
+        display.push_str("...");
+    }
+    match (tipe, val) {
+        (Some(Type::Bool), Value::Int(ui)) => (format!("{}", !ui.is_zero()), vec![]),
+        (Some(Type::Struct(fields)), Value::Tuple(_)) => {
+            let shape = TupleTree::new(fields.len(), false);
+            let children = fields
+                .iter()
+                .enumerate()
+                .map(|(idx, field)| Variable {
+                    name: field.name.clone(),
+                    value: shape.read_value(val, idx).unwrap_or_else(Value::none),
+                    tipe: Some(field.tipe.clone()),
+                })
+                .collect();
+            (format!("struct {}", display), children)
+        }
+        (Some(Type::Tuple(types)), Value::Tuple(tup)) if types.len() == tup.len() => {
+            let children = tup
+                .iter()
+                .zip(types)
+                .enumerate()
+                .map(|(idx, (value, tipe))| Variable {
+                    name: idx.to_string(),
+                    value: value.clone(),
+                    tipe: Some(tipe),
+                })
+                .collect();
+            (display, children)
+        }
+        (_, Value::Tuple(tup)) => {
+            let children = tup
+                .iter()
+                .enumerate()
+                .map(|(idx, value)| Variable {
+                    name: idx.to_string(),
+                    value: value.clone(),
+                    tipe: None,
+                })
+                .collect();
+            (display, children)
+        }
+        _ => (display, vec![]),
+    }
+}
+
+impl Machine {
+    ///Starts a Debug Adapter Protocol session over stdin and stdout, execution will end when the
+    /// program counter of self reaches stop_pc, an error state is reached, or the client
+    /// disconnects.
+    ///
+    /// Returns the total gas used by the machine.
+    pub fn dap(&mut self, stop_pc: Option<CodePt>) -> u64 {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        DapServer::new(stdin.lock(), stdout.lock())
+            .serve(self, stop_pc)
+            .expect("failed to communicate with debug adapter client")
+    }
+}
+
+///Serves a session of requests, given as command and arguments, to machine and returns the
+/// messages sent to the client.
+#[cfg(test)]
+fn dap_transcript(machine: &mut Machine, requests: &[(&str, JsonValue)]) -> Vec<JsonValue> {
+    let mut input = vec![];
+    for (seq, (command, arguments)) in requests.iter().enumerate() {
+        let body = json!({
+            "seq": seq + 1,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        input.extend(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes());
+    }
+    let mut output = vec![];
+    DapServer::new(&input[..], &mut output)
+        .serve(machine, None)
+        .unwrap();
+
+    let mut messages = vec![];
+    let mut reader = &output[..];
+    while !reader.is_empty() {
+        let header_end = reader
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let header = std::str::from_utf8(&reader[..header_end]).unwrap();
+        let len: usize = header["Content-Length: ".len()..].parse().unwrap();
+        let body = &reader[header_end + 4..header_end + 4 + len];
+        messages.push(serde_json::from_slice(body).unwrap());
+        reader = &reader[header_end + 4 + len..];
+    }
+    messages
+}
+
+#[cfg(test)]
+fn stop_reasons(messages: &[JsonValue]) -> Vec<String> {
+    messages
+        .iter()
+        .filter(|msg| msg["event"] == "stopped" || msg["event"] == "terminated")
+        .map(|msg| match msg["body"]["reason"].as_str() {
+            Some(reason) => reason.to_string(),
+            None => String::from("terminated"),
+        })
+        .collect()
+}
+
+#[test]
+fn test_dap_session_runs_to_completion() {
+    use crate::asm::assemble;
+    use crate::run::RuntimeEnvironment;
+
+    let program = assemble("[1] noop\n[2] add\nhalt\n", 0).unwrap();
+    let mut machine = Machine::new(program, RuntimeEnvironment::default());
+    machine.start_at_zero();
+
+    let messages = dap_transcript(
+        &mut machine,
+        &[
+            ("initialize", json!({})),
+            ("launch", json!({})),
+            ("configurationDone", json!({})),
+            ("threads", json!({})),
+            ("disconnect", json!({})),
+        ],
+    );
+    assert!(messages.iter().any(|msg| msg["event"] == "initialized"));
+    assert_eq!(stop_reasons(&messages), vec!["terminated"]);
+    assert!(messages
+        .iter()
+        .any(|msg| msg["body"]["threads"][0]["name"] == "AVM"));
+    assert_eq!(
+        messages
+            .iter()
+            .filter(|msg| msg["type"] == "response")
+            .count(),
+        5
+    );
+}
+
+#[test]
+fn test_dap_line_breakpoint_stops_once_per_entry() {
+    use crate::asm::assemble;
+    use crate::compile::FileInfo;
+    use crate::run::RuntimeEnvironment;
+
+    // the loop body on line 6 runs three times, and its instructions share a source line
+    let source = "[3] noop\nloop:\ndup0\niszero\n[done] cjump\n[1] swap1\nsub\n[loop] jump\n\
+                  done:\npop\nhalt\n";
+    let mut program = assemble(source, 7).unwrap();
+    let body_location = program.code[4].debug_info.location;
+    for insn in &mut program.code[4..7] {
+        insn.debug_info.location = body_location;
+    }
+    program.file_info_chart.insert(
+        7,
+        FileInfo {
+            name: String::from("loop.asm"),
+            path: String::from("loop.asm"),
+            contents: vec![],
+        },
+    );
+    let mut machine = Machine::new(program, RuntimeEnvironment::default());
+    machine.start_at_zero();
+
+    let messages = dap_transcript(
+        &mut machine,
+        &[
+            ("initialize", json!({})),
+            ("launch", json!({})),
+            (
+                "setBreakpoints",
+                json!({"source": {"path": "loop.asm"}, "breakpoints": [{"line": 6}]}),
+            ),
+            ("configurationDone", json!({})),
+            ("continue", json!({})),
+            ("continue", json!({})),
+            ("continue", json!({})),
+            ("disconnect", json!({})),
+        ],
+    );
+    assert_eq!(
+        stop_reasons(&messages),
+        vec!["breakpoint", "breakpoint", "breakpoint", "terminated"]
+    );
+}
+
+#[test]
+fn test_dap_stepping_and_typed_locals() {
+    use crate::asm::assemble;
+    use crate::compile::{FileInfo, FuncDebugInfo, LocalVarInfo};
+    use crate::run::RuntimeEnvironment;
+
+    // main calls f on line 2, f builds a frame with locals a and b, and returns from line 9
+    let source = "[ret] noop\n[f] jump\nret:\nhalt\nf:\nauxpush\n[(7, 1)] auxpush\nnoop\n\
+                  auxpop\npop\nauxpop\njump\n";
+    let mut program = assemble(source, 7).unwrap();
+    let start = program.code[3].debug_info.location;
+    let local = |name: &str, slot, tipe| LocalVarInfo {
+        name: String::from(name),
+        slot,
+        tipe,
+        location: start,
+    };
+    program.func_debug_info = vec![FuncDebugInfo {
+        name: String::from("f"),
+        location: start,
+        end: program.code.last().unwrap().debug_info.location,
+        frame_size: 2,
+        locals: vec![local("a", 0, Type::Uint), local("b", 1, Type::Bool)],
+    }];
+    program.file_info_chart.insert(
+        7,
+        FileInfo {
+            name: String::from("call.asm"),
+            path: String::from("call.asm"),
+            contents: vec![],
+        },
+    );
+    let mut machine = Machine::new(program, RuntimeEnvironment::default());
+    machine.start_at_zero();
+
+    let messages = dap_transcript(
+        &mut machine,
+        &[
+            ("initialize", json!({})),
+            ("launch", json!({})),
+            (
+                "setBreakpoints",
+                json!({"source": {"path": "call.asm"}, "breakpoints": [{"line": 8}]}),
+            ),
+            ("configurationDone", json!({})),
+            ("stackTrace", json!({})),
+            ("scopes", json!({"frameId": 0})),
+            ("variables", json!({"variablesReference": 1})),
+            ("next", json!({})),
+            ("stackTrace", json!({})),
+            ("stepOut", json!({})),
+            ("stackTrace", json!({})),
+            ("continue", json!({})),
+            ("disconnect", json!({})),
+        ],
+    );
+    assert_eq!(
+        stop_reasons(&messages),
+        vec!["breakpoint", "step", "step", "terminated"]
+    );
+    let lines: Vec<Vec<u64>> = messages
+        .iter()
+        .filter(|msg| msg["command"] == "stackTrace")
+        .map(|msg| {
+            msg["body"]["stackFrames"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|frame| frame["line"].as_u64().unwrap())
+                .collect()
+        })
+        .collect();
+    assert_eq!(lines, vec![vec![8, 4], vec![9, 4], vec![4]]);
+
+    let locals = messages
+        .iter()
+        .find(|msg| msg["command"] == "variables")
+        .unwrap()["body"]["variables"]
+        .clone();
+    assert_eq!(locals[0]["name"], "a");
+    assert_eq!(locals[0]["value"], "7");
+    assert_eq!(locals[1]["name"], "b");
+    assert_eq!(locals[1]["value"], "true");
+    assert_eq!(locals.as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn test_dap_compiled_locals() {
+    use crate::compile::CompileStruct;
+    use crate::run::RuntimeEnvironment;
+
+    let mut compile = CompileStruct::default();
+    compile.input = vec![String::from("test-programs/stepping.mini")];
+    compile.test_mode = true;
+    compile.consts_file = Some(String::from("arb_os/constants.json"));
+    let program = compile.invoke().map_err(|_| "failed to compile").unwrap().0;
+    let mut machine = Machine::new(program, RuntimeEnvironment::default());
+    machine.call_state(CodePt::new_internal(1), vec![]);
+
+    let messages = dap_transcript(
+        &mut machine,
+        &[
+            ("initialize", json!({})),
+            ("launch", json!({})),
+            (
+                "setBreakpoints",
+                json!({
+                    "source": {"path": "test-programs/stepping.mini"},
+                    "breakpoints": [{"line": 8}],
+                }),
+            ),
+            ("configurationDone", json!({})),
+            ("scopes", json!({"frameId": 0})),
+            ("variables", json!({"variablesReference": 1})),
+            ("scopes", json!({"frameId": 1})),
+            ("variables", json!({"variablesReference": 5})),
+            ("disconnect", json!({})),
+        ],
+    );
+    assert_eq!(stop_reasons(&messages), vec!["breakpoint"]);
+    let variables: Vec<Vec<(String, String)>> = messages
+        .iter()
+        .filter(|msg| msg["command"] == "variables")
+        .map(|msg| {
+            msg["body"]["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|var| {
+                    (
+                        var["name"].as_str().unwrap().to_string(),
+                        var["value"].as_str().unwrap().to_string(),
+                    )
+                })
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        variables[0],
+        vec![
+            (String::from("a"), String::from("1")),
+            (String::from("b"), String::from("2")),
+        ]
+    );
+    // main is still calling f, so y is not declared yet
+    let caller_locals: Vec<_> = variables[1].iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(caller_locals, vec!["x"]);
+}
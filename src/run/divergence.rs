@@ -0,0 +1,314 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides differential execution of two programs on the same inbox, reporting the first output in
+//! which they differ.
+
+use super::emulator::Machine;
+use super::runtime_env::{strip_var_from_log, RtEnvRecorder};
+use crate::compile::CompileError;
+use crate::mavm::{AVMOpcode, Value};
+use crate::pos::try_display_location;
+use std::fmt;
+use std::path::Path;
+
+///An output of a program, in the order it was produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputEvent {
+    Log(Value),
+    Send(Vec<u8>),
+    DebugPrint(Value),
+}
+
+impl fmt::Display for OutputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputEvent::Log(val) => write!(f, "log {}", val),
+            OutputEvent::Send(bytes) => write!(f, "send 0x{}", hex::encode(bytes)),
+            OutputEvent::DebugPrint(val) => write!(f, "debugprint {}", val),
+        }
+    }
+}
+
+///An `OutputEvent` with where in the run it was produced.
+#[derive(Debug, Clone)]
+pub struct ObservedEvent {
+    pub event: OutputEvent,
+    ///Index of the inbox message that was last read when the event was produced, or None if the
+    /// program had not read any.
+    pub inbox_index: Option<usize>,
+    ///Source location of the instruction that produced the event.
+    pub location: String,
+    ///Number of instructions executed before the one that produced the event.
+    pub step: u64,
+}
+
+impl fmt::Display for ObservedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n    ", self.event)?;
+        match self.inbox_index {
+            Some(index) => write!(f, "while processing inbox message {}", index)?,
+            None => write!(f, "before reading the inbox")?,
+        }
+        write!(f, ", at step {}, {}", self.step, self.location)
+    }
+}
+
+///The first event on which two programs disagree, see `bisect_divergence`.
+#[derive(Debug)]
+pub struct Divergence {
+    ///Number of events the programs agreed on before this one.
+    pub event_index: usize,
+    ///The event of each program, or None if it stopped without producing one.
+    pub events: [Option<ObservedEvent>; 2],
+    ///Why each program stopped, if it did.
+    pub stop_reasons: [Option<String>; 2],
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Programs diverge after {} matching events",
+            self.event_index
+        )?;
+        for (i, (event, stop_reason)) in self.events.iter().zip(&self.stop_reasons).enumerate() {
+            write!(f, "  program {}: ", i + 1)?;
+            match (event, stop_reason) {
+                (Some(event), _) => writeln!(f, "{}", event)?,
+                (None, Some(reason)) => writeln!(f, "stopped ({})", reason)?,
+                (None, None) => writeln!(f, "stopped")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+///Runs a `Machine` until it produces an `OutputEvent`, keeping track of its progress through the
+/// inbox.
+struct EventSource {
+    machine: Machine,
+    inbox_size: usize,
+    steps: u64,
+    compare_gas: bool,
+    stop_reason: Option<String>,
+}
+
+impl EventSource {
+    fn new(mut machine: Machine, compare_gas: bool) -> Self {
+        // debugprints are compared rather than printed
+        machine.capture_output();
+        let inbox_size = machine.runtime_env.l1_inbox.len();
+        EventSource {
+            machine,
+            inbox_size,
+            steps: 0,
+            compare_gas,
+            stop_reason: None,
+        }
+    }
+
+    ///Returns the next event of the program, or None if it stopped before producing one.
+    fn next_event(&mut self) -> Option<ObservedEvent> {
+        while self.stop_reason.is_none() {
+            let pc = match self.machine.get_pc() {
+                Ok(pc) => pc,
+                Err(e) => {
+                    self.stop_reason = Some(e.to_string());
+                    break;
+                }
+            };
+            let (opcode, immediate, location) = match self.machine.insn_at(pc) {
+                Some(insn) => (
+                    insn.opcode,
+                    insn.immediate.clone(),
+                    insn.debug_info.location,
+                ),
+                None => {
+                    self.stop_reason = Some(format!("invalid program counter {}", pc));
+                    break;
+                }
+            };
+            let printed = match opcode {
+                // an immediate is pushed before the opcode runs, so it is what gets printed
+                AVMOpcode::DebugPrint => immediate.or_else(|| self.machine.stack().top()),
+                _ => None,
+            };
+            let step = self.steps;
+            match self.machine.run_one(false) {
+                Ok(true) => self.steps += 1,
+                Ok(false) => {
+                    self.stop_reason = Some(String::from("halted or waiting for an empty inbox"));
+                    break;
+                }
+                Err(e) => {
+                    self.stop_reason = Some(e.to_string());
+                    break;
+                }
+            }
+            self.machine.take_captured_output();
+            let event = match opcode {
+                AVMOpcode::Log => self
+                    .machine
+                    .runtime_env
+                    .logs
+                    .last()
+                    .cloned()
+                    .and_then(|log| {
+                        if self.compare_gas {
+                            Some(log)
+                        } else {
+                            strip_var_from_log(log)
+                        }
+                    })
+                    .map(OutputEvent::Log),
+                AVMOpcode::Send => self
+                    .machine
+                    .runtime_env
+                    .sends
+                    .last()
+                    .cloned()
+                    .map(OutputEvent::Send),
+                AVMOpcode::DebugPrint => printed.map(OutputEvent::DebugPrint),
+                _ => None,
+            };
+            if let Some(event) = event {
+                let consumed = self.inbox_size - self.machine.runtime_env.l1_inbox.len();
+                return Some(ObservedEvent {
+                    event,
+                    inbox_index: consumed.checked_sub(1),
+                    location: try_display_location(location, self.machine.file_info_chart(), false),
+                    step,
+                });
+            }
+        }
+        None
+    }
+}
+
+///Runs the programs at first and second on the inbox of the test log at testlog, in lockstep by
+/// output, and returns the first log, send or debugprint on which they differ, or None if they
+/// produce the same outputs.
+///
+/// Gas usage in logs is ignored unless compare_gas is set, as it will usually differ between two
+/// builds.
+pub fn bisect_divergence(
+    testlog: &str,
+    first: &Path,
+    second: &Path,
+    compare_gas: bool,
+) -> Result<Option<Divergence>, CompileError> {
+    let recorder = RtEnvRecorder::from_testlog_file(testlog).map_err(|e| {
+        CompileError::new(
+            String::from("Divergence error"),
+            format!("could not read \"{}\": {}", testlog, e),
+            vec![],
+        )
+    })?;
+    Ok(first_divergence(
+        [
+            recorder.new_machine_for(first),
+            recorder.new_machine_for(second),
+        ],
+        compare_gas,
+    ))
+}
+
+///Runs machines in lockstep by output and returns the first event on which they differ, see
+/// `bisect_divergence`.
+fn first_divergence(machines: [Machine; 2], compare_gas: bool) -> Option<Divergence> {
+    let [first, second] = machines;
+    let mut sources = [
+        EventSource::new(first, compare_gas),
+        EventSource::new(second, compare_gas),
+    ];
+    let mut event_index = 0;
+    loop {
+        let events = [sources[0].next_event(), sources[1].next_event()];
+        let same = match &events {
+            [None, None] => return None,
+            [Some(a), Some(b)] => a.event == b.event,
+            _ => false,
+        };
+        if !same {
+            return Some(Divergence {
+                event_index,
+                events,
+                stop_reasons: [sources[0].stop_reason.take(), sources[1].stop_reason.take()],
+            });
+        }
+        event_index += 1;
+    }
+}
+
+#[test]
+fn test_first_divergence_on_debugprint() {
+    use crate::asm::assemble;
+    use crate::run::RuntimeEnvironment;
+    use crate::uint256::Uint256;
+
+    let first = assemble(
+        "
+        inbox
+        pop
+        [(2, 5)] log
+        inbox
+        pop
+        [7] debugprint
+        halt
+        ",
+        0,
+    )
+    .unwrap();
+    let second = assemble(
+        "
+        inbox
+        pop
+        [(2, 5)] log
+        inbox
+        pop
+        noop
+        [8] debugprint
+        halt
+        ",
+        0,
+    )
+    .unwrap();
+    let locations = [
+        try_display_location(
+            first.code[5].debug_info.location,
+            &first.file_info_chart,
+            false,
+        ),
+        try_display_location(
+            second.code[6].debug_info.location,
+            &second.file_info_chart,
+            false,
+        ),
+    ];
+    let new_machine = |program| {
+        let mut rt_env = RuntimeEnvironment::default();
+        rt_env.insert_full_inbox_contents(vec![
+            Value::Int(Uint256::zero()),
+            Value::Int(Uint256::one()),
+        ]);
+        let mut machine = Machine::new(program, rt_env);
+        machine.start_at_zero();
+        machine
+    };
+
+    let divergence = first_divergence([new_machine(first), new_machine(second)], false).unwrap();
+    assert_eq!(divergence.event_index, 1);
+    let values = [
+        Value::Int(Uint256::from_u64(7)),
+        Value::Int(Uint256::from_u64(8)),
+    ];
+    for ((event, value), location) in divergence.events.iter().zip(&values).zip(&locations) {
+        let event = event.as_ref().unwrap();
+        assert_eq!(event.event, OutputEvent::DebugPrint(value.clone()));
+        assert_eq!(event.inbox_index, Some(1));
+        assert_eq!(&event.location, location);
+    }
+    assert_ne!(locations[0], locations[1]);
+}
@@ -4,26 +4,43 @@
 
 //!Provides utilities for emulation of AVM bytecode.
 
+use super::backtrace::RuntimeError;
+use super::coverage::CoverageData;
+use super::gas_schedule::GasSchedule;
+use super::stats::ExecutionStats;
+use super::trace::JsonTraceWriter;
 use super::RuntimeEnvironment;
-use crate::compile::{CompileError, DebugInfo, FileInfo};
-use crate::link::LinkedProgram;
-use crate::mavm::{AVMOpcode, Buffer, CodePt, Instruction, Value};
+use crate::compile::{CompileError, DebugInfo, FileInfo, FuncDebugInfo, GlobalVarDecl, TypeTree};
+use crate::link::mexe::code_point_hashes;
+use crate::link::{LinkedProgram, SerializableTypeTree, TupleTree};
+use crate::mavm::{AVMOpcode, Buffer, CodePt, Instruction, Value, ValueHash};
 use crate::pos::{try_display_location, Location};
 use crate::run::blake2b::blake2bf_instruction;
 use crate::run::ripemd160port;
 use crate::uint256::Uint256;
 use clap::Clap;
 use ethers_core::types::{Signature, H256};
+use ethers_core::utils::keccak256;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::cmp::{max, Ordering};
 use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
 use std::fmt;
 use std::fs::File;
-use std::io::{stdin, BufWriter, Write};
+use std::io::{self, stdin, BufWriter, Write};
 use std::path::Path;
 use std::str::FromStr;
 
 const MAX_PAIRING_SIZE: u64 = 30;
+///Default number of instructions between two debugger checkpoints, see `CheckpointHistory`.
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 10_000;
+///Default memory budget in bytes for debugger checkpoints, see `CheckpointHistory`. This matches
+/// the default of the `--checkpoint-budget-mib` option.
+pub const DEFAULT_CHECKPOINT_BUDGET: usize = 256 << 20;
+///Version of the format written by `Machine::save_snapshot`, snapshots of other versions are
+/// rejected by `Machine::load_snapshot`.
+pub const SNAPSHOT_VERSION: u32 = 2;
 
 ///Represents a stack of `Value`s
 #[derive(Debug, Default, Clone)]
@@ -46,6 +63,11 @@ impl ValueStack {
         self.contents.len()
     }
 
+    ///Iterates over the items of self from bottom to top.
+    pub fn iter(&self) -> im::vector::Iter<'_, Value> {
+        self.contents.iter()
+    }
+
     ///Pushes val to the top of self.
     pub fn push(&mut self, val: Value) {
         self.contents.push_back(val);
@@ -97,7 +119,12 @@ impl ValueStack {
     pub fn pop(&mut self, state: &MachineState) -> Result<Value, ExecutionError> {
         match self.contents.pop_back() {
             Some(v) => Ok(v),
-            None => Err(ExecutionError::new("stack underflow", state, None)),
+            None => Err(ExecutionError::new(
+                ErrorKind::StackUnderflow,
+                "stack underflow",
+                state,
+                None,
+            )),
         }
     }
 
@@ -109,6 +136,7 @@ impl ValueStack {
             Ok(cp)
         } else {
             Err(ExecutionError::new(
+                ErrorKind::TypeMismatch,
                 "expected CodePoint on stack",
                 state,
                 Some(val),
@@ -124,6 +152,7 @@ impl ValueStack {
             Ok(i)
         } else {
             Err(ExecutionError::new(
+                ErrorKind::TypeMismatch,
                 "expected integer on stack",
                 state,
                 Some(val),
@@ -138,6 +167,7 @@ impl ValueStack {
         match val.to_usize() {
             Some(u) => Ok(u),
             None => Err(ExecutionError::new(
+                ErrorKind::TypeMismatch,
                 "expected small integer on stack",
                 state,
                 Some(Value::Int(val)),
@@ -154,6 +184,7 @@ impl ValueStack {
             Ok(vs.to_vec())
         } else {
             Err(ExecutionError::new(
+                ErrorKind::TypeMismatch,
                 "expected tuple on stack",
                 state,
                 Some(val),
@@ -169,6 +200,7 @@ impl ValueStack {
             Ok(v.clone())
         } else {
             Err(ExecutionError::new(
+                ErrorKind::TypeMismatch,
                 "expected buffer on stack",
                 state,
                 Some(val),
@@ -202,23 +234,72 @@ impl fmt::Display for ValueStack {
     }
 }
 
+///The category of an `ExecutionError`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    ///An instruction needed more values than were on the stack or aux stack.
+    StackUnderflow,
+    ///An operand had the wrong type or shape.
+    TypeMismatch,
+    ///The instruction cost more ArbGas than remained.
+    OutOfGas,
+    ///The program executed an `Error` or `Zero` instruction.
+    Panic,
+    ///The program counter did not point to an instruction.
+    BadCodePoint,
+    ///An operand had the right type but an unusable value, such as a zero divisor.
+    InvalidOperand,
+    ///The machine was not running.
+    NotRunning,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::StackUnderflow => "stack underflow",
+            ErrorKind::TypeMismatch => "type mismatch",
+            ErrorKind::OutOfGas => "out of gas",
+            ErrorKind::Panic => "panic",
+            ErrorKind::BadCodePoint => "bad code point",
+            ErrorKind::InvalidOperand => "invalid operand",
+            ErrorKind::NotRunning => "machine not running",
+        })
+    }
+}
+
 ///Represents an error encountered during runtime.
 ///
 /// StoppedErr is for errors encountered when the `Machine` is not running, RunningErr is for when
 /// the machine is running, and Wrapped adds additional context to its contained error.
 #[derive(Clone, Debug)]
 pub enum ExecutionError {
-    StoppedErr(&'static str),
-    Wrapped(&'static str, Box<ExecutionError>),
-    RunningErr(&'static str, CodePt, Option<Value>),
+    StoppedErr(ErrorKind, &'static str),
+    Wrapped(ErrorKind, &'static str, Box<ExecutionError>),
+    RunningErr(ErrorKind, &'static str, CodePt, Option<Value>),
 }
 
 impl ExecutionError {
-    fn new(why: &'static str, state: &MachineState, val: Option<Value>) -> Self {
+    fn new(kind: ErrorKind, why: &'static str, state: &MachineState, val: Option<Value>) -> Self {
         match state {
-            MachineState::Stopped => ExecutionError::StoppedErr(why),
-            MachineState::Error(e) => ExecutionError::Wrapped(why, Box::new(e.clone())),
-            MachineState::Running(cp) => ExecutionError::RunningErr(why, *cp, val),
+            MachineState::Stopped => ExecutionError::StoppedErr(kind, why),
+            MachineState::Error(e) => ExecutionError::Wrapped(kind, why, Box::new(e.clone())),
+            MachineState::Running(cp) => ExecutionError::RunningErr(kind, why, *cp, val),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ExecutionError::StoppedErr(kind, _)
+            | ExecutionError::Wrapped(kind, _, _)
+            | ExecutionError::RunningErr(kind, _, _, _) => *kind,
+        }
+    }
+
+    ///Returns the code point of the instruction that caused self, if the machine was running.
+    pub fn codept(&self) -> Option<CodePt> {
+        match self {
+            ExecutionError::RunningErr(_, _, cp, _) => Some(*cp),
+            _ => None,
         }
     }
 }
@@ -226,9 +307,9 @@ impl ExecutionError {
 impl fmt::Display for ExecutionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ExecutionError::StoppedErr(s) => writeln!(f, "error with machine stopped: {}", s),
-            ExecutionError::Wrapped(s, bee) => writeln!(f, "{} ({})", s, *bee),
-            ExecutionError::RunningErr(s, cp, ov) => match ov {
+            ExecutionError::StoppedErr(_, s) => writeln!(f, "error with machine stopped: {}", s),
+            ExecutionError::Wrapped(_, s, bee) => writeln!(f, "{} ({})", s, *bee),
+            ExecutionError::RunningErr(_, s, cp, ov) => match ov {
                 Some(val) => writeln!(f, "{} ({}) with value {}", s, cp, val),
                 None => writeln!(f, "{} ({})", s, cp),
             },
@@ -314,35 +395,33 @@ impl CodeStore {
         CodePt::new_in_segment(self.segments.len() - 1, 0)
     }
 
-    ///Appends an instruction with opcode derived from op, and immediate from imm, to the end of the
-    /// code segment pointed to by codept.
+    ///Appends an instruction with opcode derived from op, and immediate from imm, to the code
+    /// segment pointed to by codept, so that it continues at codept when executed.
+    ///
+    /// If codept is not the end of its segment, the segment is forked: a new segment is created
+    /// holding a copy of the code up to codept, and the instruction is appended to that, so that code
+    /// already built on top of codept is unaffected.
     ///
-    /// The codept argument must point to a code segment, and must point to the end of that segment,
-    /// also op must be the numeric representation of some AVM opcode, if these conditions aren't
-    /// met the function will panic.
+    /// The codept argument must point to a code segment, and op must be the numeric representation
+    /// of some AVM opcode, if these conditions aren't met the function will panic.
     fn push_insn(&mut self, op: usize, imm: Option<Value>, codept: CodePt) -> Option<CodePt> {
         if let CodePt::InSegment(seg_num, old_offset) = codept {
-            if seg_num >= self.segments.len() {
-                panic!("bad segment number in push_insn");
-            //None
-            } else {
-                let segment = &mut self.segments[seg_num];
-                if old_offset == segment.len() - 1 {
-                    if let Some(opcode) = AVMOpcode::from_number(op) {
-                        segment.push(Instruction::new(opcode, imm, DebugInfo::default()));
-                        Some(CodePt::new_in_segment(seg_num, old_offset + 1))
-                    } else {
-                        panic!(
-                            "bad opcode number {} in push_insn at length {}",
-                            op,
-                            segment.len()
-                        );
-                        //None
-                    }
-                } else {
-                    unimplemented!("branching segments");
-                }
+            if seg_num >= self.segments.len() || old_offset >= self.segments[seg_num].len() {
+                panic!("bad code point {} in push_insn", codept);
             }
+            let opcode = match AVMOpcode::from_number(op) {
+                Some(opcode) => opcode,
+                None => panic!("bad opcode number {} in push_insn at {}", op, codept),
+            };
+            let seg_num = if old_offset == self.segments[seg_num].len() - 1 {
+                seg_num
+            } else {
+                let prefix = self.segments[seg_num][..=old_offset].to_vec();
+                self.segments.push(prefix);
+                self.segments.len() - 1
+            };
+            self.segments[seg_num].push(Instruction::new(opcode, imm, DebugInfo::default()));
+            Some(CodePt::new_in_segment(seg_num, old_offset + 1))
         } else {
             panic!("invalid codepoint in push_insn: {}", codept);
         }
@@ -364,6 +443,37 @@ pub struct ProfilerData {
     stack_tree: HashMap<CodePt, (Vec<ProfilerEvent>, Option<Location>)>,
     unknown_gas: u64,
     file_info_chart: BTreeMap<u64, FileInfo>,
+    ///Gas used in the innermost function of each call stack, outermost function first.
+    stack_gas: HashMap<Vec<CodePt>, u64>,
+    ///Gas used by each function, by the file id and line of the instructions using it.
+    line_gas: HashMap<CodePt, BTreeMap<Option<(u64, u32)>, u64>>,
+    ///Number of calls from one function to another.
+    call_counts: HashMap<(CodePt, CodePt), u64>,
+}
+
+///Formats a `ProfilerData` can be exported in, see `ProfilerData::export`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ProfileFormat {
+    ///One line per call stack with the gas used in its innermost function, for flamegraph tools.
+    Collapsed,
+    ///The callgrind format read by KCachegrind.
+    Callgrind,
+}
+
+impl FromStr for ProfileFormat {
+    type Err = CompileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &(s.to_lowercase())[..] {
+            "collapsed" => Ok(ProfileFormat::Collapsed),
+            "callgrind" => Ok(ProfileFormat::Callgrind),
+            _ => Err(CompileError::new(
+                String::from("Profile error"),
+                String::from("Invalid profile export format"),
+                vec![],
+            )),
+        }
+    }
 }
 
 impl ProfilerData {
@@ -633,6 +743,119 @@ impl ProfilerData {
         }
     }
 
+    ///Writes the call stacks and gas usage of self to out in format.
+    pub fn export(&self, format: ProfileFormat, out: &mut dyn Write) -> io::Result<()> {
+        match format {
+            ProfileFormat::Collapsed => self.write_collapsed(out),
+            ProfileFormat::Callgrind => self.write_callgrind(out),
+        }
+    }
+
+    ///Returns the name of the function at func, made of the file name and line of its location.
+    fn func_name(&self, func: &CodePt) -> String {
+        match self.stack_tree.get(func).and_then(|(_, loc)| loc.as_ref()) {
+            Some(loc) => format!("{}:{}", self.file_name(loc.file_id), loc.line() + 1),
+            None => format!("{}", func).replace(' ', "_"),
+        }
+    }
+
+    fn file_name(&self, file_id: u64) -> String {
+        match self.file_info_chart.get(&file_id) {
+            Some(info) => info.name.clone(),
+            None => String::from("unknown_file"),
+        }
+    }
+
+    fn write_collapsed(&self, out: &mut dyn Write) -> io::Result<()> {
+        let stacks: BTreeMap<String, u64> = self
+            .stack_gas
+            .iter()
+            .map(|(stack, gas)| {
+                let names: Vec<_> = stack.iter().map(|func| self.func_name(func)).collect();
+                (names.join(";"), *gas)
+            })
+            .collect();
+        for (stack, gas) in stacks {
+            writeln!(out, "{} {}", stack, gas)?;
+        }
+        Ok(())
+    }
+
+    fn write_callgrind(&self, out: &mut dyn Write) -> io::Result<()> {
+        // gas used by callee and everything it calls, for calls from caller, counting recursive
+        // calls once per call stack
+        let mut inclusive_gas: BTreeMap<(String, String), u64> = BTreeMap::new();
+        for (stack, gas) in &self.stack_gas {
+            let mut seen = vec![];
+            for pair in stack.windows(2) {
+                if !seen.contains(&pair) {
+                    seen.push(pair);
+                    *inclusive_gas
+                        .entry((self.func_name(&pair[0]), self.func_name(&pair[1])))
+                        .or_insert(0) += *gas;
+                }
+            }
+        }
+        let mut call_counts: BTreeMap<(String, String), u64> = BTreeMap::new();
+        for ((caller, callee), count) in &self.call_counts {
+            *call_counts
+                .entry((self.func_name(caller), self.func_name(callee)))
+                .or_insert(0) += *count;
+        }
+        let func_file = |func: &CodePt| match self.stack_tree.get(func) {
+            Some((_, Some(loc))) => (self.file_name(loc.file_id), loc.line() + 1),
+            _ => (String::from("unknown_file"), 0),
+        };
+        let funcs: BTreeMap<String, &CodePt> = self
+            .stack_tree
+            .keys()
+            .map(|func| (self.func_name(func), func))
+            .collect();
+
+        writeln!(out, "version: 1")?;
+        writeln!(out, "creator: mini profiler")?;
+        writeln!(out, "positions: line")?;
+        writeln!(out, "events: ArbGas")?;
+        for (name, func) in &funcs {
+            let (file, line) = func_file(func);
+            writeln!(out, "\nfl={}\nfn={}", file, name)?;
+            if let Some(lines) = self.line_gas.get(func) {
+                let mut current_file = file.clone();
+                for (location, gas) in lines {
+                    let (line_file, line) = match location {
+                        Some((file_id, line)) => (self.file_name(*file_id), line + 1),
+                        None => (file.clone(), 0),
+                    };
+                    if line_file != current_file {
+                        writeln!(out, "fi={}", line_file)?;
+                        current_file = line_file;
+                    }
+                    writeln!(out, "{} {}", line, gas)?;
+                }
+                if current_file != file {
+                    writeln!(out, "fe={}", file)?;
+                }
+            }
+            let calls = call_counts
+                .range((name.clone(), String::new())..)
+                .take_while(|((caller, _), _)| caller == name);
+            for ((_, callee), count) in calls {
+                let (callee_file, callee_line) = match funcs.get(callee) {
+                    Some(callee_func) => func_file(callee_func),
+                    None => (String::from("unknown_file"), 0),
+                };
+                let gas = inclusive_gas
+                    .get(&(name.clone(), callee.clone()))
+                    .cloned()
+                    .unwrap_or(0);
+                writeln!(out, "cfl={}\ncfn={}", callee_file, callee)?;
+                writeln!(out, "calls={} {}", count, callee_line)?;
+                writeln!(out, "{} {}", line, gas)?;
+            }
+        }
+        Ok(())
+    }
+
     fn menu(&self, mut command: String) -> Result<ProfilerAction, String> {
         let trimmed_command = command.trim_end();
         if "exit" == trimmed_command {
@@ -729,6 +952,160 @@ pub struct Machine {
     file_info_chart: BTreeMap<u64, FileInfo>,
     total_gas_usage: Uint256,
     trace_writer: Option<BufWriter<File>>,
+    globals: Vec<GlobalVarDecl>,
+    type_tree: TypeTree,
+    func_debug_info: Vec<FuncDebugInfo>,
+    ///If set, debugprint output is collected here rather than written to stdout.
+    captured_output: Option<Vec<String>>,
+    ///Approximate number of bytes the debugger may spend on checkpoints for reverse execution.
+    checkpoint_budget: usize,
+    ///AVM hashes of the code points of the runtime segment, computed on first use by
+    /// `code_point_hash`.
+    runtime_code_hashes: RefCell<Vec<Uint256>>,
+    ///If set, `run` prints the machine hash every this many instructions.
+    hash_interval: Option<u64>,
+    ///Number of instructions executed by `run`.
+    steps_run: u64,
+    ///If set, `run` counts the instructions it executes here, by file id and line.
+    line_counts: Option<HashMap<(u64, u32), u64>>,
+    gas_schedule: GasSchedule,
+    ///If set, counts of the opcodes executed, see `enable_stats`.
+    stats: Option<ExecutionStats>,
+    json_trace: Option<JsonTraceWriter>,
+}
+
+///Source level stepping requested in a debugger, see `Machine::debug`.
+///
+/// Function frames are identified by their depth, see `Machine::frame_depth`.
+pub(crate) enum SourceStep {
+    None,
+    ///Stop at the next source line, including lines in called functions.
+    Into(Option<(u64, u32)>),
+    ///Stop at the next source line in the current function or one of its callers.
+    Over(Option<(u64, u32)>, usize),
+    ///Stop at the first source line after the current function returns.
+    Out(usize),
+}
+
+///Returns the AVM hash of a stack whose items hash to items, given from bottom to top.
+///
+/// The node represents a stack as a pair of its top item and the rest of the stack, the empty
+/// stack being the empty tuple, and hashes it like that value.
+fn stack_hash(items: impl Iterator<Item = ValueHash>) -> ValueHash {
+    items.fold(ValueHash::tuple(&[]), |rest, item| {
+        ValueHash::tuple(&[item, rest])
+    })
+}
+
+///The complete state of a `Machine` in serializable form, see `Machine::save_snapshot`.
+#[derive(Serialize, Deserialize)]
+struct MachineSnapshot {
+    version: u32,
+    stack: Vec<Value>,
+    aux_stack: Vec<Value>,
+    ///Program counter of the machine, or None if it is stopped.
+    pc: Option<CodePt>,
+    segments: Vec<Vec<Instruction<AVMOpcode>>>,
+    static_val: Value,
+    register: Value,
+    err_codepoint: CodePt,
+    arb_gas_remaining: Uint256,
+    total_gas_usage: Uint256,
+    runtime_env: RuntimeEnvironment,
+    file_info_chart: BTreeMap<u64, FileInfo>,
+    globals: Vec<GlobalVarDecl>,
+    type_tree: SerializableTypeTree,
+    func_debug_info: Vec<FuncDebugInfo>,
+}
+
+///A copy of the mutable state of a `Machine`, taken by the debugger after step instructions.
+///
+/// Values are reference counted, so this only copies the outer stacks and the runtime environment.
+/// Code segments are only ever appended to, so only their lengths are recorded.
+struct MachineCheckpoint {
+    step: u64,
+    gas_cost: u64,
+    stack: ValueStack,
+    aux_stack: ValueStack,
+    state: MachineState,
+    register: Value,
+    err_codepoint: CodePt,
+    arb_gas_remaining: Uint256,
+    runtime_env: RuntimeEnvironment,
+    total_gas_usage: Uint256,
+    segment_sizes: Vec<usize>,
+    ///Source line of the last instruction with a location executed before the checkpoint.
+    previous_line: Option<(u64, u32)>,
+}
+
+impl MachineCheckpoint {
+    ///Returns an estimate of the number of bytes kept alive only by self.
+    fn approx_size(&self) -> usize {
+        let values = self.stack.num_items()
+            + self.aux_stack.num_items()
+            + self.runtime_env.l1_inbox.len()
+            + self.runtime_env.logs.len();
+        let sends: usize = self.runtime_env.sends.iter().map(|send| send.len()).sum();
+        std::mem::size_of::<Self>()
+            + values * std::mem::size_of::<Value>()
+            + sends
+            + self.segment_sizes.len() * std::mem::size_of::<usize>()
+    }
+}
+
+///Checkpoints taken by the debugger at regular instruction counts, used for reverse execution.
+///
+/// When the checkpoints exceed the memory budget, every other checkpoint is dropped and the
+/// interval is doubled, so the history always covers the whole execution at a coarser grain.
+struct CheckpointHistory {
+    checkpoints: Vec<MachineCheckpoint>,
+    interval: u64,
+    budget: usize,
+    size: usize,
+}
+
+impl CheckpointHistory {
+    fn new(budget: usize) -> Self {
+        CheckpointHistory {
+            checkpoints: vec![],
+            interval: DEFAULT_CHECKPOINT_INTERVAL,
+            budget,
+            size: 0,
+        }
+    }
+
+    ///Takes a checkpoint of machine if step is due for one and has not been recorded yet.
+    fn record(
+        &mut self,
+        machine: &Machine,
+        step: u64,
+        gas_cost: u64,
+        previous_line: Option<(u64, u32)>,
+    ) {
+        if step % self.interval != 0
+            || self
+                .checkpoints
+                .last()
+                .map_or(false, |last| last.step >= step)
+        {
+            return;
+        }
+        let checkpoint = machine.checkpoint(step, gas_cost, previous_line);
+        self.size += checkpoint.approx_size();
+        self.checkpoints.push(checkpoint);
+        while self.size > self.budget && self.checkpoints.len() > 1 {
+            self.interval *= 2;
+            let interval = self.interval;
+            self.checkpoints
+                .retain(|checkpoint| checkpoint.step % interval == 0);
+            self.size = self.checkpoints.iter().map(|cp| cp.approx_size()).sum();
+        }
+    }
+
+    ///Returns the latest checkpoint taken before step.
+    fn latest_before(&self, step: u64) -> Option<&MachineCheckpoint> {
+        self.checkpoints.iter().rev().find(|cp| cp.step < step)
+    }
 }
 
 impl Machine {
@@ -746,7 +1123,115 @@ impl Machine {
             file_info_chart: program.file_info_chart,
             total_gas_usage: Uint256::zero(),
             trace_writer: None,
+            globals: program.globals,
+            type_tree: program.type_tree.into_type_tree(),
+            func_debug_info: program.func_debug_info,
+            captured_output: None,
+            checkpoint_budget: DEFAULT_CHECKPOINT_BUDGET,
+            runtime_code_hashes: RefCell::new(vec![]),
+            hash_interval: None,
+            steps_run: 0,
+            line_counts: None,
+            gas_schedule: GasSchedule::default(),
+            stats: None,
+            json_trace: None,
+        }
+    }
+
+    ///Writes the complete state of self, including its code and runtime environment, to the file at
+    /// path, so that it can be resumed later with `load_snapshot`.
+    ///
+    /// Returns an error if the file cannot be written, if self is in an error state, or if its
+    /// runtime environment has a `SideloadProvider`, which cannot be serialized and would otherwise
+    /// be silently missing from the restored machine.
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        if self.runtime_env.has_sideload_provider() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot snapshot a machine with a sideload provider",
+            ));
+        }
+        let pc = match &self.state {
+            MachineState::Running(pc) => Some(*pc),
+            MachineState::Stopped => None,
+            MachineState::Error(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot snapshot a machine in an error state: {}", e),
+                ))
+            }
+        };
+        let snapshot = MachineSnapshot {
+            version: SNAPSHOT_VERSION,
+            stack: self.stack.contents.iter().cloned().collect(),
+            aux_stack: self.aux_stack.contents.iter().cloned().collect(),
+            pc,
+            segments: self.code.segments.clone(),
+            static_val: self.static_val.clone(),
+            register: self.register.clone(),
+            err_codepoint: self.err_codepoint,
+            arb_gas_remaining: self.arb_gas_remaining.clone(),
+            total_gas_usage: self.total_gas_usage.clone(),
+            runtime_env: self.runtime_env.clone(),
+            file_info_chart: self.file_info_chart.clone(),
+            globals: self.globals.clone(),
+            type_tree: SerializableTypeTree::from_type_tree(self.type_tree.clone()),
+            func_debug_info: self.func_debug_info.clone(),
+        };
+        let file = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(file, &snapshot)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    ///Reads a `Machine` previously written by `save_snapshot` from the file at path.
+    pub fn load_snapshot(path: &Path) -> io::Result<Self> {
+        let file = io::BufReader::new(File::open(path)?);
+        let snapshot: MachineSnapshot = bincode::deserialize_from(file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "snapshot has version {}, expected {}",
+                    snapshot.version, SNAPSHOT_VERSION
+                ),
+            ));
         }
+        Ok(Machine {
+            stack: ValueStack {
+                contents: snapshot.stack.into_iter().collect(),
+            },
+            aux_stack: ValueStack {
+                contents: snapshot.aux_stack.into_iter().collect(),
+            },
+            state: match snapshot.pc {
+                Some(pc) => MachineState::Running(pc),
+                None => MachineState::Stopped,
+            },
+            code: CodeStore {
+                segments: snapshot.segments,
+            },
+            static_val: snapshot.static_val,
+            register: snapshot.register,
+            err_codepoint: snapshot.err_codepoint,
+            arb_gas_remaining: snapshot.arb_gas_remaining,
+            runtime_env: snapshot.runtime_env,
+            file_info_chart: snapshot.file_info_chart,
+            total_gas_usage: snapshot.total_gas_usage,
+            trace_writer: None,
+            globals: snapshot.globals,
+            type_tree: snapshot.type_tree.into_type_tree(),
+            func_debug_info: snapshot.func_debug_info,
+            captured_output: None,
+            checkpoint_budget: DEFAULT_CHECKPOINT_BUDGET,
+            runtime_code_hashes: RefCell::new(vec![]),
+            hash_interval: None,
+            steps_run: 0,
+            line_counts: None,
+            gas_schedule: GasSchedule::default(),
+            stats: None,
+            json_trace: None,
+        })
     }
 
     #[cfg(test)]
@@ -767,6 +1252,44 @@ impl Machine {
         }
     }
 
+    ///Makes `run` print the hash of self after every interval instructions, see `hash`.
+    pub fn set_print_hash_every(&mut self, interval: u64) {
+        self.hash_interval = Some(interval).filter(|interval| *interval > 0);
+    }
+
+    ///Makes self charge ArbGas according to schedule instead of the default costs.
+    pub fn set_gas_schedule(&mut self, schedule: GasSchedule) {
+        self.gas_schedule = schedule;
+    }
+
+    ///Makes self count the opcodes it executes and the gas they use, see `stats`.
+    pub fn enable_stats(&mut self) {
+        self.stats = Some(ExecutionStats::default());
+    }
+
+    ///Returns the statistics collected since `enable_stats` was called, if it was.
+    pub fn stats(&self) -> Option<&ExecutionStats> {
+        self.stats.as_ref()
+    }
+
+    ///Makes `run` record which source lines it executes, see `coverage`.
+    pub fn enable_coverage(&mut self) {
+        self.line_counts = Some(HashMap::new());
+    }
+
+    ///Returns the source lines of the runtime and how many instructions `run` executed at each, or
+    /// None if `enable_coverage` has not been called.
+    pub fn coverage(&self) -> Option<CoverageData> {
+        self.line_counts
+            .as_ref()
+            .map(|counts| CoverageData::new(&self.code.segments[0], counts, &self.file_info_chart))
+    }
+
+    ///Makes `run` write each step to json_trace, see `JsonTraceWriter`.
+    pub fn set_json_trace(&mut self, json_trace: JsonTraceWriter) {
+        self.json_trace = Some(json_trace);
+    }
+
     ///Adds a trace writer to the machine
     pub fn add_trace_writer(&mut self, filename: &str) {
         self.trace_writer = Some(BufWriter::new(File::create(Path::new(filename)).unwrap()));
@@ -793,14 +1316,14 @@ impl Machine {
     ///Calls the function at address func_addr and runs until the program counter advances by the
     /// result of `runtime_segment_size`, or an error is encountered.
     ///
-    /// If the machine stops normally, then returns the stack contents, otherwise returns an
-    /// `ExecutionError`
+    /// If the machine stops normally, then returns the stack contents, otherwise returns a
+    /// `RuntimeError` with the backtrace at the point of failure.
     pub fn test_call(
         &mut self,
         func_addr: CodePt,
         args: Vec<Value>,
         debug: bool,
-    ) -> Result<ValueStack, ExecutionError> {
+    ) -> Result<ValueStack, RuntimeError> {
         let stop_pc = self.call_state(func_addr, args);
         let cost = if debug {
             self.debug(Some(stop_pc))
@@ -811,13 +1334,17 @@ impl Machine {
         if let Some(ret_val) = self.stack.top() {
             println!("Stack top: {}", ret_val);
         }
-        match &self.state {
-            MachineState::Stopped => {
-                Err(ExecutionError::new("execution stopped", &self.state, None))
-            }
-            MachineState::Error(e) => Err(e.clone()),
-            MachineState::Running(_) => Ok(self.stack.clone()),
-        }
+        let error = match &self.state {
+            MachineState::Stopped => ExecutionError::new(
+                ErrorKind::NotRunning,
+                "execution stopped",
+                &self.state,
+                None,
+            ),
+            MachineState::Error(e) => e.clone(),
+            MachineState::Running(_) => return Ok(self.stack.clone()),
+        };
+        Err(RuntimeError::new(error, self))
     }
 
     ///If the machine is running returns the `CodePt` that represents the current program counter,
@@ -827,6 +1354,7 @@ impl Machine {
             Ok(*pc)
         } else {
             Err(ExecutionError::new(
+                ErrorKind::NotRunning,
                 "tried to get PC of non-running machine",
                 &self.state,
                 None,
@@ -863,6 +1391,349 @@ impl Machine {
         }
     }
 
+    ///Returns the globals of the program self was loaded from.
+    pub(crate) fn globals(&self) -> &[GlobalVarDecl] {
+        &self.globals
+    }
+
+    pub(crate) fn type_tree(&self) -> &TypeTree {
+        &self.type_tree
+    }
+
+    ///Returns the debug info of the innermost function whose source contains location.
+    pub(crate) fn func_debug_info_at(&self, location: &Location) -> Option<&FuncDebugInfo> {
+        self.func_debug_info
+            .iter()
+            .filter(|func| func.contains(location))
+            .max_by_key(|func| func.location.map(|start| start.absolute))
+    }
+
+    pub(crate) fn file_info_chart(&self) -> &BTreeMap<u64, FileInfo> {
+        &self.file_info_chart
+    }
+
+    pub(crate) fn stack(&self) -> &ValueStack {
+        &self.stack
+    }
+
+    pub(crate) fn aux_stack(&self) -> &ValueStack {
+        &self.aux_stack
+    }
+
+    ///Returns the instruction at codept, if it exists.
+    pub(crate) fn insn_at(&self, codept: CodePt) -> Option<&Instruction<AVMOpcode>> {
+        self.code.get_insn(codept)
+    }
+
+    pub(crate) fn static_val(&self) -> &Value {
+        &self.static_val
+    }
+
+    pub(crate) fn err_codepoint(&self) -> CodePt {
+        self.err_codepoint
+    }
+
+    pub(crate) fn arb_gas_remaining(&self) -> &Uint256 {
+        &self.arb_gas_remaining
+    }
+
+    pub(crate) fn runtime_segment_size(&self) -> usize {
+        self.code.runtime_segment_size()
+    }
+
+    ///Makes self collect debugprint output instead of writing it to stdout, see
+    /// `take_captured_output`.
+    pub(crate) fn capture_output(&mut self) {
+        self.captured_output = Some(vec![]);
+    }
+
+    ///Returns the output collected since the last call, if output is being captured.
+    pub(crate) fn take_captured_output(&mut self) -> Vec<String> {
+        self.captured_output
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    ///Returns the current value of the global variable at index in the globals of the program, or
+    /// None if the register has not been initialized yet.
+    pub(crate) fn read_global(&self, index: usize) -> Option<Value> {
+        TupleTree::new(self.globals.len(), false).read_value(&self.register, index)
+    }
+
+    ///Computes the AVM machine hash of self, as computed by the node and the on-chain verifier.
+    ///
+    /// A stopped machine hashes to zero and a machine in an error state to one. Otherwise this is
+    /// the keccak256 hash of the hashes of the program counter, the data stack, the aux stack, the
+    /// register and the static value, the remaining arb gas, and the hash of the error code point.
+    pub fn hash(&self) -> Uint256 {
+        let pc = match self.state {
+            MachineState::Stopped => return Uint256::zero(),
+            MachineState::Error(_) => return Uint256::one(),
+            MachineState::Running(pc) => pc,
+        };
+        let hashes = [
+            self.code_point_hash(pc),
+            self.stack_hash(&self.stack.contents).hash,
+            self.stack_hash(&self.aux_stack.contents).hash,
+            self.value_hash(&self.register).hash,
+            self.value_hash(&self.static_val).hash,
+            self.arb_gas_remaining.clone(),
+            self.code_point_hash(self.err_codepoint),
+        ];
+        let mut buf = vec![];
+        for hash in hashes.iter() {
+            buf.extend(hash.to_bytes_be());
+        }
+        Uint256::from_bytes(&keccak256(&buf))
+    }
+
+    ///Returns the AVM hash of val, see `Value::avm_value_hash`.
+    pub(crate) fn value_hash(&self, val: &Value) -> ValueHash {
+        val.avm_value_hash(&|codept| self.code_point_hash(codept))
+    }
+
+    ///Returns the AVM hash of a stack holding items, given from bottom to top, see `stack_hash`.
+    pub(crate) fn stack_hash<'a>(&self, items: impl IntoIterator<Item = &'a Value>) -> ValueHash {
+        stack_hash(items.into_iter().map(|val| self.value_hash(val)))
+    }
+
+    ///Returns the AVM hash of the code point codept, see `Instruction::avm_code_point_hash`.
+    ///
+    /// Code in the runtime segment runs in increasing order and code in other segments in
+    /// decreasing order, the hashes of the runtime segment are computed once and cached. Code
+    /// points referred to by immediates are hashed only if they run after the referring code point
+    /// in the same segment or are in the runtime segment, other references hash to zero, as do the
+    /// error code point and the code point after the end of a segment.
+    pub(crate) fn code_point_hash(&self, codept: CodePt) -> Uint256 {
+        match codept {
+            CodePt::Internal(pc) => {
+                if self.runtime_code_hashes.borrow().is_empty() {
+                    let hashes = code_point_hashes(&self.code.segments[0]);
+                    *self.runtime_code_hashes.borrow_mut() = hashes;
+                }
+                self.runtime_code_hashes
+                    .borrow()
+                    .get(pc)
+                    .cloned()
+                    .unwrap_or_else(Uint256::zero)
+            }
+            CodePt::InSegment(seg_num, offset) if seg_num > 0 => {
+                let segment = match self.code.segments.get(seg_num) {
+                    Some(segment) => segment,
+                    None => return Uint256::zero(),
+                };
+                let mut hashes: Vec<Uint256> = vec![];
+                for insn in segment.iter().take(offset + 1) {
+                    let next_hash = hashes.last().cloned().unwrap_or_else(Uint256::zero);
+                    let hash = insn.avm_code_point_hash(&next_hash, &|codept| match codept {
+                        CodePt::InSegment(seg, off) if seg == seg_num && off < hashes.len() => {
+                            hashes[off].clone()
+                        }
+                        CodePt::Internal(_) => self.code_point_hash(codept),
+                        _ => Uint256::zero(),
+                    });
+                    hashes.push(hash);
+                }
+                hashes.pop().unwrap_or_else(Uint256::zero)
+            }
+            _ => Uint256::zero(),
+        }
+    }
+
+    ///Sets the approximate number of bytes the debugger may spend on checkpoints for reverse
+    /// execution.
+    pub fn set_checkpoint_budget(&mut self, budget: usize) {
+        self.checkpoint_budget = budget;
+    }
+
+    fn checkpoint(
+        &self,
+        step: u64,
+        gas_cost: u64,
+        previous_line: Option<(u64, u32)>,
+    ) -> MachineCheckpoint {
+        MachineCheckpoint {
+            step,
+            gas_cost,
+            stack: self.stack.clone(),
+            aux_stack: self.aux_stack.clone(),
+            state: self.state.clone(),
+            register: self.register.clone(),
+            err_codepoint: self.err_codepoint,
+            arb_gas_remaining: self.arb_gas_remaining.clone(),
+            runtime_env: self.runtime_env.clone(),
+            total_gas_usage: self.total_gas_usage.clone(),
+            segment_sizes: self.code.segments.iter().map(|seg| seg.len()).collect(),
+            previous_line,
+        }
+    }
+
+    fn restore(&mut self, checkpoint: &MachineCheckpoint) {
+        self.stack = checkpoint.stack.clone();
+        self.aux_stack = checkpoint.aux_stack.clone();
+        self.state = checkpoint.state.clone();
+        self.register = checkpoint.register.clone();
+        self.err_codepoint = checkpoint.err_codepoint;
+        self.arb_gas_remaining = checkpoint.arb_gas_remaining.clone();
+        self.runtime_env = checkpoint.runtime_env.clone();
+        self.total_gas_usage = checkpoint.total_gas_usage.clone();
+        self.code.segments.truncate(checkpoint.segment_sizes.len());
+        for (segment, size) in self.code.segments.iter_mut().zip(&checkpoint.segment_sizes) {
+            segment.truncate(*size);
+        }
+    }
+
+    ///Restores checkpoint and re-executes up to instruction number target, without debugprint
+    /// output or trace writing, calling at_step with the machine and the previous source line, see
+    /// `at_breakpoint`, before each instruction.
+    ///
+    /// Returns the debugger gas count and the previous source line at target.
+    fn replay_from_checkpoint(
+        &mut self,
+        checkpoint: &MachineCheckpoint,
+        target: u64,
+        mut at_step: impl FnMut(&Machine, u64, Option<(u64, u32)>),
+    ) -> (u64, Option<(u64, u32)>) {
+        self.restore(checkpoint);
+        let trace_writer = self.trace_writer.take();
+        let captured_output = self.captured_output.replace(vec![]);
+        let mut gas_cost = checkpoint.gas_cost;
+        let mut previous_line = checkpoint.previous_line;
+        for step in checkpoint.step..target {
+            at_step(self, step, previous_line);
+            gas_cost += self.next_op_gas().unwrap_or(0);
+            previous_line = self.next_source_line().or(previous_line);
+            match self.run_one(false) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    self.state = MachineState::Error(e);
+                    break;
+                }
+            }
+        }
+        self.trace_writer = trace_writer;
+        self.captured_output = captured_output;
+        (gas_cost, previous_line)
+    }
+
+    ///Returns true if the next instruction has the breakpoint attribute, or if it enters a source
+    /// line for which is_breakpoint returns true.
+    ///
+    /// previous_line is the source line of the last executed instruction that had one, so that a
+    /// line breakpoint stops once when its line is entered rather than on every instruction of it.
+    pub(crate) fn at_breakpoint(
+        &self,
+        previous_line: Option<(u64, u32)>,
+        is_breakpoint: impl Fn(u64, u32) -> bool,
+    ) -> bool {
+        match self.next_opcode() {
+            Some(insn) => {
+                insn.debug_info.attributes.breakpoint
+                    || insn.debug_info.location.map_or(false, |location| {
+                        let line = (location.file_id, location.line());
+                        Some(line) != previous_line && is_breakpoint(line.0, line.1)
+                    })
+            }
+            None => false,
+        }
+    }
+
+    ///Returns true if the next instruction has the breakpoint attribute, or if it enters break_line
+    /// or one of source_breakpoints, see `at_breakpoint`.
+    fn at_debugger_breakpoint(
+        &self,
+        break_line: u32,
+        source_breakpoints: &[(u64, u32)],
+        previous_line: Option<(u64, u32)>,
+    ) -> bool {
+        self.at_breakpoint(previous_line, |file_id, line| {
+            line == break_line || source_breakpoints.contains(&(file_id, line))
+        })
+    }
+
+    ///Returns the file id and line of the next instruction, if it has a location.
+    pub(crate) fn next_source_line(&self) -> Option<(u64, u32)> {
+        self.next_opcode()?
+            .debug_info
+            .location
+            .map(|loc| (loc.file_id, loc.line()))
+    }
+
+    ///Returns the number of function frames below the next instruction.
+    ///
+    /// Every call moves a return address from the data stack onto the aux stack, and every return
+    /// moves it back before jumping to it. Frames are counted by the return addresses on the aux
+    /// stack, plus one while a return address is in transit on the data stack, so that the first
+    /// and last instructions of a function count as part of it.
+    pub(crate) fn frame_depth(&self) -> usize {
+        let in_transit = match (self.next_opcode(), self.stack.top()) {
+            (Some(insn), Some(Value::CodePoint(_))) => {
+                insn.immediate.is_none()
+                    && (insn.opcode == AVMOpcode::AuxPush || insn.opcode == AVMOpcode::Jump)
+            }
+            _ => false,
+        };
+        self.aux_stack.all_codepts().len() + in_transit as usize
+    }
+
+    ///Returns true if the machine has reached the place where step should stop.
+    pub(crate) fn reached_step_target(&self, step: &SourceStep) -> bool {
+        let line = self.next_source_line();
+        let depth = self.frame_depth();
+        match step {
+            SourceStep::None => false,
+            SourceStep::Into(start) => line.is_some() && line != *start,
+            SourceStep::Over(start, start_depth) => {
+                depth <= *start_depth && line.is_some() && line != *start
+            }
+            SourceStep::Out(start_depth) => depth < *start_depth && line.is_some(),
+        }
+    }
+
+    ///Returns the ids of the files in self's file info chart whose name or path matches file.
+    pub(crate) fn matching_file_ids(&self, file: &str) -> Vec<u64> {
+        let canonical = Path::new(file).canonicalize().ok();
+        self.file_info_chart
+            .iter()
+            .filter(|(_, info)| {
+                info.name == file
+                    || info.path == file
+                    || info.path.ends_with(&format!("/{}", file))
+                    || info
+                        .name
+                        .ends_with(&format!("::{}", file.trim_end_matches(".mini")))
+                    || (canonical.is_some()
+                        && Path::new(&info.path).canonicalize().ok() == canonical)
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    ///Resolves a debugger breakpoint of the form `file:line` to a list of (file id, line) pairs,
+    /// matching file against the names and paths in self's file info chart.
+    fn resolve_source_breakpoint(&self, spec: &str) -> Result<Vec<(u64, u32)>, String> {
+        let (file, line) = match spec.rfind(':') {
+            Some(idx) => (&spec[..idx], &spec[idx + 1..]),
+            None => return Err(format!("expected file:line, got \"{}\"", spec)),
+        };
+        let line = match line.parse::<u32>() {
+            Ok(line) if line > 0 => line - 1,
+            _ => return Err(format!("invalid line number \"{}\"", line)),
+        };
+        let file_ids: Vec<_> = self
+            .matching_file_ids(file)
+            .into_iter()
+            .map(|id| (id, line))
+            .collect();
+        if file_ids.is_empty() {
+            Err(format!("no source file matching \"{}\"", file))
+        } else {
+            Ok(file_ids)
+        }
+    }
+
     ///Starts the debugger, execution will end when the program counter of self reaches stop_pc, or
     /// an error state is reached.
     ///
@@ -870,28 +1741,55 @@ impl Machine {
     pub fn debug(&mut self, stop_pc: Option<CodePt>) -> u64 {
         println!("Blank line or \"step\" to run one opcode, \"set break\" followed by a \
          line number to resume program until that line, \"show static\" to show the static contents.");
+        println!(
+            "\"break <file>:<line>\" adds a source breakpoint, \"watch <global>\" stops when a \
+         global changes, \"next\", \"step into\" and \"step out\" step by source line, \
+         \"show globals\" shows all globals by name, \"reverse-step\" runs backwards to the \
+         previous opcode and \"reverse-continue\" to the previous breakpoint, watched global \
+         change or gas breakpoint."
+        );
         let mut breakpoint = true;
         let mut break_line = 0;
+        let mut source_breakpoints: Vec<(u64, u32)> = vec![];
+        let mut watchpoints: Vec<(usize, Option<Value>)> = vec![];
+        let mut step = SourceStep::None;
         let mut break_gas_amount = 0u64;
         let mut gas_cost = 0;
         let mut show_aux = true;
         let mut show_reg = true;
+        let mut history = CheckpointHistory::new(self.checkpoint_budget);
+        let mut steps = 0u64;
+        let mut previous_line = None;
         while self.state.is_running() {
+            history.record(self, steps, gas_cost, previous_line);
             if let Some(gas) = self.next_op_gas() {
                 gas_cost += gas;
             } else {
                 println!("Warning: next opcode does not have a gas cost")
             }
+            for (index, last_val) in watchpoints.iter_mut() {
+                let val = self.read_global(*index);
+                if val != *last_val {
+                    let display = |val: &Option<Value>| match val {
+                        Some(val) => format!("{}", val),
+                        None => String::from("uninitialized"),
+                    };
+                    println!(
+                        "Watchpoint: {} changed from {} to {}",
+                        self.globals[*index].name,
+                        display(last_val),
+                        display(&val)
+                    );
+                    *last_val = val;
+                    breakpoint = true;
+                }
+            }
             if !breakpoint {
-                if let Some(insn) = self.next_opcode() {
-                    if insn.debug_info.attributes.breakpoint {
-                        breakpoint = true;
-                    }
-                    if let Some(location) = insn.debug_info.location {
-                        if location.line() == break_line {
-                            breakpoint = true;
-                        }
-                    }
+                if self.at_debugger_breakpoint(break_line, &source_breakpoints, previous_line) {
+                    breakpoint = true;
+                }
+                if self.reached_step_target(&step) {
+                    breakpoint = true;
                 }
                 if self.total_gas_usage > Uint256::from_u64(break_gas_amount)
                     && break_gas_amount > 0
@@ -900,6 +1798,7 @@ impl Machine {
                 }
             }
             if breakpoint {
+                step = SourceStep::None;
                 if let Ok(pc) = self.get_pc() {
                     println!("PC: {}", pc);
                 }
@@ -939,6 +1838,7 @@ impl Machine {
                 }
                 println!();
                 let mut exit = false;
+                let mut reversed = false;
                 loop {
                     let mut debugger_state = String::new();
                     std::io::stdin().read_line(&mut debugger_state).unwrap();
@@ -983,12 +1883,151 @@ impl Machine {
                         "toggle reg\n" => {
                             show_reg = !show_reg;
                         }
+                        "next\n" | "step over\n" => {
+                            step = SourceStep::Over(self.next_source_line(), self.frame_depth());
+                            breakpoint = false;
+                            exit = true;
+                        }
+                        "step into\n" => {
+                            step = SourceStep::Into(self.next_source_line());
+                            breakpoint = false;
+                            exit = true;
+                        }
+                        "step out\n" | "finish\n" => {
+                            step = SourceStep::Out(self.frame_depth());
+                            breakpoint = false;
+                            exit = true;
+                        }
+                        "show globals\n" => {
+                            for (index, global) in self.globals.iter().enumerate() {
+                                match self.read_global(index) {
+                                    Some(val) => println!("{}: {}", global.name, val),
+                                    None => println!("{}: uninitialized", global.name),
+                                }
+                            }
+                        }
+                        "reverse-step\n" | "rs\n" => match history.latest_before(steps) {
+                            Some(checkpoint) => {
+                                let (gas, line) = self.replay_from_checkpoint(
+                                    checkpoint,
+                                    steps - 1,
+                                    |_, _, _| {},
+                                );
+                                gas_cost = gas;
+                                previous_line = line;
+                                steps -= 1;
+                                reversed = true;
+                                exit = true;
+                            }
+                            None => println!("Already at the start of execution"),
+                        },
+                        "reverse-continue\n" | "rc\n" => {
+                            // Watchpoints and the gas breakpoint stop where their state changes,
+                            // which is found by comparing the state at each step with the last.
+                            let watch_state = |machine: &Machine| {
+                                let globals: Vec<_> = watchpoints
+                                    .iter()
+                                    .map(|(index, _)| machine.read_global(*index))
+                                    .collect();
+                                let over_gas = break_gas_amount > 0
+                                    && machine.total_gas_usage
+                                        > Uint256::from_u64(break_gas_amount);
+                                (globals, over_gas)
+                            };
+                            let watch_stops = |last: &(Vec<Option<Value>>, bool),
+                                               state: &(Vec<Option<Value>>, bool)| {
+                                last.0 != state.0 || (!last.1 && state.1)
+                            };
+                            let mut end = steps;
+                            let mut found = None;
+                            while let Some(checkpoint) = history.latest_before(end) {
+                                let mut last_stop = None;
+                                let mut last_state = None;
+                                self.replay_from_checkpoint(
+                                    checkpoint,
+                                    end,
+                                    |machine, step, previous_line| {
+                                        let state = watch_state(machine);
+                                        let changed = last_state
+                                            .as_ref()
+                                            .map_or(false, |last| watch_stops(last, &state));
+                                        if changed
+                                            || machine.at_debugger_breakpoint(
+                                                break_line,
+                                                &source_breakpoints,
+                                                previous_line,
+                                            )
+                                        {
+                                            last_stop = Some(step);
+                                        }
+                                        last_state = Some(state);
+                                    },
+                                );
+                                // The step at end was searched first, but without the state
+                                // before it to compare watchpoints with.
+                                if end < steps {
+                                    if let Some(last) = &last_state {
+                                        if watch_stops(last, &watch_state(self)) {
+                                            last_stop = Some(end);
+                                        }
+                                    }
+                                }
+                                if let Some(stop) = last_stop {
+                                    found = Some((checkpoint, stop));
+                                    break;
+                                }
+                                end = checkpoint.step;
+                            }
+                            let (checkpoint, target) = match found {
+                                Some(found) => found,
+                                None => {
+                                    println!("No earlier breakpoint, stopping at the start");
+                                    match history.checkpoints.first() {
+                                        Some(first) => (first, first.step),
+                                        None => continue,
+                                    }
+                                }
+                            };
+                            let (gas, line) =
+                                self.replay_from_checkpoint(checkpoint, target, |_, _, _| {});
+                            gas_cost = gas;
+                            previous_line = line;
+                            steps = target;
+                            reversed = true;
+                            exit = true;
+                        }
+                        command if command.starts_with("break ") => {
+                            match self.resolve_source_breakpoint(command[6..].trim()) {
+                                Ok(resolved) => {
+                                    println!("Breakpoint set at {}", command[6..].trim());
+                                    source_breakpoints.extend(resolved);
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        command if command.starts_with("watch ") => {
+                            let name = command[6..].trim();
+                            match self.globals.iter().position(|global| global.name == name) {
+                                Some(index) => {
+                                    println!("Watching {}", name);
+                                    watchpoints.push((index, self.read_global(index)));
+                                }
+                                None => println!("no global named \"{}\"", name),
+                            }
+                        }
                         _ => println!("invalid input"),
                     }
                     if exit {
                         break;
                     }
                 }
+                if reversed {
+                    for (index, last_val) in watchpoints.iter_mut() {
+                        *last_val = self.read_global(*index);
+                    }
+                    breakpoint = true;
+                    continue;
+                }
             }
             if let Some(spc) = stop_pc {
                 if let MachineState::Running(pc) = self.state {
@@ -997,6 +2036,7 @@ impl Machine {
                     }
                 }
             }
+            previous_line = self.next_source_line().or(previous_line);
             match self.run_one(false) {
                 Ok(false) => {
                     return gas_cost;
@@ -1007,6 +2047,7 @@ impl Machine {
                 }
                 _ => {}
             }
+            steps += 1;
         }
         gas_cost
     }
@@ -1027,13 +2068,29 @@ impl Machine {
                 gas_used += gas;
                 gas
             } else {
-                println!("Warning: next opcode does not have a gas cost");
+                eprintln!("Warning: next opcode does not have a gas cost");
                 1
             };
 
+            if let Some(mut json_trace) = self.json_trace.take() {
+                json_trace
+                    .write_step(self, self.steps_run)
+                    .expect("failed to write JSON trace file");
+                self.json_trace = Some(json_trace);
+            }
+
             let cp = self.get_pc();
 
             if let Ok(codept) = cp {
+                if let Some(line_counts) = &mut self.line_counts {
+                    let location = self
+                        .code
+                        .get_insn(codept)
+                        .and_then(|insn| insn.debug_info.location);
+                    if let Some(loc) = location {
+                        *line_counts.entry((loc.file_id, loc.line())).or_insert(0) += 1;
+                    }
+                }
                 if let Some(trace_writer) = &mut self.trace_writer {
                     let res = match codept {
                         CodePt::Internal(pc) => Some((
@@ -1083,6 +2140,12 @@ impl Machine {
                     return gas_used;
                 }
             }
+            self.steps_run += 1;
+            if let Some(interval) = self.hash_interval {
+                if self.steps_run % interval == 0 {
+                    eprintln!("Step {}: machine hash {}", self.steps_run, self.hash());
+                }
+            }
         }
         gas_used
     }
@@ -1219,6 +2282,10 @@ impl Machine {
                 } else {
                     panic!("Internal error: calling from an untracked function");
                 }
+                *loc_map
+                    .call_counts
+                    .entry((*current_codepoint, *next_codepoint))
+                    .or_insert(0) += 1;
                 *current_codepoint = *next_codepoint;
                 if let Some((func_info, _)) = loc_map.stack_tree.get_mut(&current_codepoint) {
                     func_info.push(ProfilerEvent::EnterFunc(*total_gas));
@@ -1228,95 +2295,24 @@ impl Machine {
             }
         }
         *stack_len = alt_stack.len();
+        let mut call_stack = vec![CodePt::new_internal(0)];
+        call_stack.extend(stack.iter().cloned());
+        *loc_map.stack_gas.entry(call_stack).or_insert(0) += next_op_gas;
+        *loc_map
+            .line_gas
+            .entry(*current_codepoint)
+            .or_default()
+            .entry(loc.map(|loc| (loc.file_id, loc.line())))
+            .or_insert(0) += next_op_gas;
     }
 
     ///If the opcode has a specified gas cost returns the gas cost, otherwise returns None.
     pub(crate) fn next_op_gas(&self) -> Option<u64> {
         if let MachineState::Running(pc) = self.state {
             Some(match self.code.get_insn(pc)?.opcode {
-                AVMOpcode::Zero => 5,
-                AVMOpcode::Add => 3,
-                AVMOpcode::Mul => 3,
-                AVMOpcode::Sub => 3,
-                AVMOpcode::Div => 4,
-                AVMOpcode::Sdiv => 7,
-                AVMOpcode::Mod => 4,
-                AVMOpcode::Smod => 7,
-                AVMOpcode::AddMod => 4,
-                AVMOpcode::MulMod => 4,
-                AVMOpcode::Exp => 25,
-                AVMOpcode::SignExtend => 7,
-                AVMOpcode::LessThan => 2,
-                AVMOpcode::GreaterThan => 2,
-                AVMOpcode::SLessThan => 2,
-                AVMOpcode::SGreaterThan => 2,
-                AVMOpcode::Equal => 2,
-                AVMOpcode::IsZero => 1,
-                AVMOpcode::BitwiseAnd => 2,
-                AVMOpcode::BitwiseOr => 2,
-                AVMOpcode::BitwiseXor => 2,
-                AVMOpcode::BitwiseNeg => 1,
-                AVMOpcode::Byte => 4,
-                AVMOpcode::ShiftLeft => 4,
-                AVMOpcode::ShiftRight => 4,
-                AVMOpcode::ShiftArith => 4,
-                AVMOpcode::Hash => 7,
-                AVMOpcode::Type => 3,
-                AVMOpcode::EthHash2 => 8,
-                AVMOpcode::Keccakf => 600,
-                AVMOpcode::Sha256f => 250,
-                AVMOpcode::Ripemd160f => 250, //TODO: measure and update this
                 AVMOpcode::Blake2f => self.gas_for_blake2f(),
-                AVMOpcode::Pop => 1,
-                AVMOpcode::Spush => 1,
-                AVMOpcode::Rpush => 1,
-                AVMOpcode::Rset => 2,
-                AVMOpcode::Jump => 4,
-                AVMOpcode::Cjump => 4,
-                AVMOpcode::StackEmpty => 2,
-                AVMOpcode::PCpush => 1,
-                AVMOpcode::AuxPush => 1,
-                AVMOpcode::AuxPop => 1,
-                AVMOpcode::AuxStackEmpty => 2,
-                AVMOpcode::Noop => 1,
-                AVMOpcode::ErrPush => 1,
-                AVMOpcode::ErrSet => 1,
-                AVMOpcode::Dup0 => 1,
-                AVMOpcode::Dup1 => 1,
-                AVMOpcode::Dup2 => 1,
-                AVMOpcode::Swap1 => 1,
-                AVMOpcode::Swap2 => 1,
-                AVMOpcode::Tget => 2,
-                AVMOpcode::Tset => 40,
-                AVMOpcode::Tlen => 2,
-                AVMOpcode::Xget => 3,
-                AVMOpcode::Xset => 41,
-                AVMOpcode::Breakpoint => 100,
-                AVMOpcode::Log => 100,
-                AVMOpcode::Send => 100,
-                AVMOpcode::InboxPeek => 40,
-                AVMOpcode::Inbox => 40,
-                AVMOpcode::Error => 5,
-                AVMOpcode::Halt => 10,
-                AVMOpcode::ErrCodePoint => 25,
-                AVMOpcode::PushInsn => 25,
-                AVMOpcode::PushInsnImm => 25,
-                AVMOpcode::OpenInsn => 25,
-                AVMOpcode::DebugPrint => 1,
-                AVMOpcode::PushGas => 1,
-                AVMOpcode::SetGas => 1,
-                AVMOpcode::EcRecover => 20_000,
-                AVMOpcode::EcAdd => 3500,
-                AVMOpcode::EcMul => 82_000,
                 AVMOpcode::EcPairing => self.gas_for_pairing(),
-                AVMOpcode::Sideload => 10,
-                AVMOpcode::NewBuffer => 1,
-                AVMOpcode::GetBuffer8 => 10,
-                AVMOpcode::GetBuffer64 => 10,
-                AVMOpcode::GetBuffer256 => 10,
-                AVMOpcode::SetBuffer8 => 100,
-                AVMOpcode::SetBuffer64 => 100,
-                AVMOpcode::SetBuffer256 => 100,
+                opcode => self.gas_schedule.cost(opcode),
             })
         } else {
             None
@@ -1324,22 +2320,24 @@ impl Machine {
     }
 
     fn gas_for_pairing(&self) -> u64 {
+        let base = self.gas_schedule.cost(AVMOpcode::EcPairing);
+        let per_point = self.gas_schedule.pairing_per_point;
         if let Some(val) = self.stack.contents.get(0) {
             let mut v = val;
             for i in 0..MAX_PAIRING_SIZE {
                 if let Value::Tuple(tup) = v {
                     if tup.len() != 2 {
-                        return 1000 + i * 500_000;
+                        return base + i * per_point;
                     } else {
                         v = &tup[1];
                     }
                 } else {
-                    return 1000 + i * 500_000;
+                    return base + i * per_point;
                 }
             }
-            1000 + MAX_PAIRING_SIZE * 500_000
+            base + MAX_PAIRING_SIZE * per_point
         } else {
-            1000
+            base
         }
     }
 
@@ -1350,12 +2348,12 @@ impl Machine {
                 if num_rounds > 0xffff {
                     num_rounds = 0xffff;
                 }
-                10 * (num_rounds as u64)
+                self.gas_schedule.blake2f_per_round * (num_rounds as u64)
             } else {
-                10
+                self.gas_schedule.cost(AVMOpcode::Blake2f)
             }
         } else {
-            10
+            self.gas_schedule.cost(AVMOpcode::Blake2f)
         }
     }
 
@@ -1388,9 +2386,17 @@ impl Machine {
                     if let Some(remaining) = self.arb_gas_remaining.sub(&gas256) {
                         self.arb_gas_remaining = remaining;
                         self.total_gas_usage = self.total_gas_usage.add(&gas256);
+                        if let Some(stats) = &mut self.stats {
+                            stats.record(insn.opcode, gas);
+                        }
                     } else {
                         self.arb_gas_remaining = Uint256::max_uint();
-                        return Err(ExecutionError::new("Out of ArbGas", &self.state, None));
+                        return Err(ExecutionError::new(
+                            ErrorKind::OutOfGas,
+                            "Out of ArbGas",
+                            &self.state,
+                            None,
+                        ));
                     }
                     gas_remaining_before
                 } else {
@@ -1401,9 +2407,12 @@ impl Machine {
                         self.incr_pc();
                         Ok(true)
                     }
-                    AVMOpcode::Zero | AVMOpcode::Error => {
-                        Err(ExecutionError::new("panicked", &self.state, None))
-                    }
+                    AVMOpcode::Zero | AVMOpcode::Error => Err(ExecutionError::new(
+                        ErrorKind::Panic,
+                        "panicked",
+                        &self.state,
+                        None,
+                    )),
                     AVMOpcode::Jump => {
                         self.state = MachineState::Running(self.stack.pop_codepoint(&self.state)?);
                         Ok(true)
@@ -1454,6 +2463,7 @@ impl Machine {
                             Ok(true)
                         } else {
                             Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
                                 "index out of bounds in Tset",
                                 &self.state,
                                 None,
@@ -1469,6 +2479,7 @@ impl Machine {
                             Ok(true)
                         } else {
                             Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
                                 "index out of bounds in Tget",
                                 &self.state,
                                 None,
@@ -1512,6 +2523,7 @@ impl Machine {
                             Some(top) => top,
                             None => {
                                 return Err(ExecutionError::new(
+                                    ErrorKind::StackUnderflow,
                                     "aux stack underflow",
                                     &self.state,
                                     None,
@@ -1526,6 +2538,7 @@ impl Machine {
                                     Ok(true)
                                 }
                                 None => Err(ExecutionError::new(
+                                    ErrorKind::InvalidOperand,
                                     "tuple access out of bounds",
                                     &self.state,
                                     None,
@@ -1533,6 +2546,7 @@ impl Machine {
                             }
                         } else {
                             Err(ExecutionError::new(
+                                ErrorKind::TypeMismatch,
                                 "expected tuple on aux stack",
                                 &self.state,
                                 Some(aux_top),
@@ -1550,6 +2564,7 @@ impl Machine {
                             Ok(true)
                         } else {
                             Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
                                 "tuple access out of bounds",
                                 &self.state,
                                 None,
@@ -1654,7 +2669,12 @@ impl Machine {
                                 self.incr_pc();
                                 Ok(true)
                             }
-                            None => Err(ExecutionError::new("divide by zero", &self.state, None)),
+                            None => Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
+                                "divide by zero",
+                                &self.state,
+                                None,
+                            )),
                         }
                     }
                     AVMOpcode::Mod => {
@@ -1667,7 +2687,12 @@ impl Machine {
                                 self.incr_pc();
                                 Ok(true)
                             }
-                            None => Err(ExecutionError::new("modulo by zero", &self.state, None)),
+                            None => Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
+                                "modulo by zero",
+                                &self.state,
+                                None,
+                            )),
                         }
                     }
                     AVMOpcode::Sdiv => {
@@ -1680,7 +2705,12 @@ impl Machine {
                                 self.incr_pc();
                                 Ok(true)
                             }
-                            None => Err(ExecutionError::new("divide by zero", &self.state, None)),
+                            None => Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
+                                "divide by zero",
+                                &self.state,
+                                None,
+                            )),
                         }
                     }
                     AVMOpcode::Smod => {
@@ -1693,7 +2723,12 @@ impl Machine {
                                 self.incr_pc();
                                 Ok(true)
                             }
-                            None => Err(ExecutionError::new("modulo by zero", &self.state, None)),
+                            None => Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
+                                "modulo by zero",
+                                &self.state,
+                                None,
+                            )),
                         }
                     }
                     AVMOpcode::AddMod => {
@@ -1707,7 +2742,12 @@ impl Machine {
                                 self.incr_pc();
                                 Ok(true)
                             }
-                            None => Err(ExecutionError::new("modulo by zero", &self.state, None)),
+                            None => Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
+                                "modulo by zero",
+                                &self.state,
+                                None,
+                            )),
                         }
                     }
                     AVMOpcode::MulMod => {
@@ -1721,7 +2761,12 @@ impl Machine {
                                 self.incr_pc();
                                 Ok(true)
                             }
-                            None => Err(ExecutionError::new("modulo by zero", &self.state, None)),
+                            None => Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
+                                "modulo by zero",
+                                &self.state,
+                                None,
+                            )),
                         }
                     }
                     AVMOpcode::Exp => {
@@ -1747,6 +2792,7 @@ impl Machine {
                                         .sub(&Uint256::one())
                                         .ok_or_else(|| {
                                             ExecutionError::new(
+                                                ErrorKind::InvalidOperand,
                                                 "underflow in signextend",
                                                 &self.state,
                                                 None,
@@ -1943,6 +2989,7 @@ impl Machine {
                                         Ok(true)
                                     } else {
                                         Err(ExecutionError::new(
+                                            ErrorKind::TypeMismatch,
                                             "inbox contents not a tuple",
                                             &self.state,
                                             None,
@@ -1950,6 +2997,7 @@ impl Machine {
                                     }
                                 } else {
                                     Err(ExecutionError::new(
+                                        ErrorKind::TypeMismatch,
                                         "blocknum not an integer",
                                         &self.state,
                                         None,
@@ -2004,6 +3052,7 @@ impl Machine {
                             Ok(true)
                         } else {
                             Err(ExecutionError::new(
+                                ErrorKind::TypeMismatch,
                                 "invalid args to PushInsn",
                                 &self.state,
                                 None,
@@ -2021,6 +3070,7 @@ impl Machine {
                             Ok(true)
                         } else {
                             Err(ExecutionError::new(
+                                ErrorKind::TypeMismatch,
                                 "invalid args to PushInsnImm",
                                 &self.state,
                                 None,
@@ -2051,9 +3101,9 @@ impl Machine {
                     }
                     AVMOpcode::DebugPrint => {
                         let r1 = self.stack.pop(&self.state)?;
-                        println!("debugprint: {}", r1);
-                        println!(
-                            "{}\n{}",
+                        let output = format!(
+                            "debugprint: {}\n{}\n{}",
+                            r1,
                             try_display_location(
                                 insn.debug_info.location,
                                 &self.file_info_chart,
@@ -2061,7 +3111,12 @@ impl Machine {
                             ),
                             self.arb_gas_remaining
                         );
+                        match &mut self.captured_output {
+                            Some(captured) => captured.push(output),
+                            None => println!("{}", output),
+                        }
                         check_debugprint_for_malformed_trace_info(&r1);
+                        self.runtime_env.push_debugprint(&r1);
                         self.incr_pc();
                         Ok(true)
                     }
@@ -2077,8 +3132,9 @@ impl Machine {
                         Ok(true)
                     }
                     AVMOpcode::Sideload => {
-                        let _block_num = self.stack.pop_uint(&self.state)?;
-                        self.stack.push(Value::none());
+                        let block_num = self.stack.pop_uint(&self.state)?;
+                        let value = self.runtime_env.sideload(&block_num);
+                        self.stack.push(value);
                         self.incr_pc();
                         Ok(true)
                     }
@@ -2097,8 +3153,9 @@ impl Machine {
                         let x1 = self.stack.pop_uint(&self.state)?;
                         let y0 = self.stack.pop_uint(&self.state)?;
                         let y1 = self.stack.pop_uint(&self.state)?;
-                        let (z0, z1) = do_ecadd(x0, x1, y0, y1)
-                            .map_err(|msg| ExecutionError::new(&msg, &self.state, None))?;
+                        let (z0, z1) = do_ecadd(x0, x1, y0, y1).map_err(|msg| {
+                            ExecutionError::new(ErrorKind::InvalidOperand, &msg, &self.state, None)
+                        })?;
                         self.stack.push_uint(z1);
                         self.stack.push_uint(z0);
                         self.incr_pc();
@@ -2108,8 +3165,9 @@ impl Machine {
                         let x0 = self.stack.pop_uint(&self.state)?;
                         let x1 = self.stack.pop_uint(&self.state)?;
                         let n = self.stack.pop_uint(&self.state)?;
-                        let (z0, z1) = do_ecmul(x0, x1, n)
-                            .map_err(|msg| ExecutionError::new(&msg, &self.state, None))?;
+                        let (z0, z1) = do_ecmul(x0, x1, n).map_err(|msg| {
+                            ExecutionError::new(ErrorKind::InvalidOperand, &msg, &self.state, None)
+                        })?;
                         self.stack.push_uint(z1);
                         self.stack.push_uint(z0);
                         self.incr_pc();
@@ -2123,6 +3181,7 @@ impl Machine {
                             Ok(true)
                         } else {
                             Err(ExecutionError::new(
+                                ErrorKind::TypeMismatch,
                                 "invalid operand to EcPairing instruction",
                                 &self.state,
                                 None,
@@ -2147,6 +3206,7 @@ impl Machine {
                         let buf = self.stack.pop_buffer(&self.state)?;
                         if offset + 7 < offset {
                             return Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
                                 "buffer overflow",
                                 &self.state,
                                 Some(Value::Int(Uint256::from_usize(offset))),
@@ -2165,6 +3225,7 @@ impl Machine {
                         let buf = self.stack.pop_buffer(&self.state)?;
                         if offset + 31 < offset {
                             return Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
                                 "buffer overflow",
                                 &self.state,
                                 Some(Value::Int(Uint256::from_usize(offset))),
@@ -2192,6 +3253,7 @@ impl Machine {
                         let offset = self.stack.pop_usize(&self.state)?;
                         if offset + 7 < offset {
                             return Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
                                 "buffer overflow",
                                 &self.state,
                                 Some(Value::Int(Uint256::from_usize(offset))),
@@ -2212,6 +3274,7 @@ impl Machine {
                         let offset = self.stack.pop_usize(&self.state)?;
                         if offset + 31 < offset {
                             return Err(ExecutionError::new(
+                                ErrorKind::InvalidOperand,
                                 "buffer overflow",
                                 &self.state,
                                 Some(Value::Int(Uint256::from_usize(offset))),
@@ -2231,6 +3294,7 @@ impl Machine {
                 }
             } else {
                 Err(ExecutionError::new(
+                    ErrorKind::BadCodePoint,
                     "invalid program counter",
                     &self.state,
                     None,
@@ -2238,6 +3302,7 @@ impl Machine {
             }
         } else {
             Err(ExecutionError::new(
+                ErrorKind::NotRunning,
                 "tried to run machine that is not runnable",
                 &self.state,
                 None,
@@ -2246,6 +3311,99 @@ impl Machine {
     }
 }
 
+///Returns the default ArbGas cost of opcode that can be determined without looking at the machine
+/// state, see `GasSchedule`.
+///
+/// For `Blake2f` and `EcPairing`, whose cost depends on their operands, this is the cost charged
+/// when the operand is missing or malformed.
+pub fn static_gas_cost(opcode: AVMOpcode) -> u64 {
+    match opcode {
+        AVMOpcode::Zero => 5,
+        AVMOpcode::Add => 3,
+        AVMOpcode::Mul => 3,
+        AVMOpcode::Sub => 3,
+        AVMOpcode::Div => 4,
+        AVMOpcode::Sdiv => 7,
+        AVMOpcode::Mod => 4,
+        AVMOpcode::Smod => 7,
+        AVMOpcode::AddMod => 4,
+        AVMOpcode::MulMod => 4,
+        AVMOpcode::Exp => 25,
+        AVMOpcode::SignExtend => 7,
+        AVMOpcode::LessThan => 2,
+        AVMOpcode::GreaterThan => 2,
+        AVMOpcode::SLessThan => 2,
+        AVMOpcode::SGreaterThan => 2,
+        AVMOpcode::Equal => 2,
+        AVMOpcode::IsZero => 1,
+        AVMOpcode::BitwiseAnd => 2,
+        AVMOpcode::BitwiseOr => 2,
+        AVMOpcode::BitwiseXor => 2,
+        AVMOpcode::BitwiseNeg => 1,
+        AVMOpcode::Byte => 4,
+        AVMOpcode::ShiftLeft => 4,
+        AVMOpcode::ShiftRight => 4,
+        AVMOpcode::ShiftArith => 4,
+        AVMOpcode::Hash => 7,
+        AVMOpcode::Type => 3,
+        AVMOpcode::EthHash2 => 8,
+        AVMOpcode::Keccakf => 600,
+        AVMOpcode::Sha256f => 250,
+        AVMOpcode::Ripemd160f => 250, //TODO: measure and update this
+        AVMOpcode::Blake2f => 10,
+        AVMOpcode::Pop => 1,
+        AVMOpcode::Spush => 1,
+        AVMOpcode::Rpush => 1,
+        AVMOpcode::Rset => 2,
+        AVMOpcode::Jump => 4,
+        AVMOpcode::Cjump => 4,
+        AVMOpcode::StackEmpty => 2,
+        AVMOpcode::PCpush => 1,
+        AVMOpcode::AuxPush => 1,
+        AVMOpcode::AuxPop => 1,
+        AVMOpcode::AuxStackEmpty => 2,
+        AVMOpcode::Noop => 1,
+        AVMOpcode::ErrPush => 1,
+        AVMOpcode::ErrSet => 1,
+        AVMOpcode::Dup0 => 1,
+        AVMOpcode::Dup1 => 1,
+        AVMOpcode::Dup2 => 1,
+        AVMOpcode::Swap1 => 1,
+        AVMOpcode::Swap2 => 1,
+        AVMOpcode::Tget => 2,
+        AVMOpcode::Tset => 40,
+        AVMOpcode::Tlen => 2,
+        AVMOpcode::Xget => 3,
+        AVMOpcode::Xset => 41,
+        AVMOpcode::Breakpoint => 100,
+        AVMOpcode::Log => 100,
+        AVMOpcode::Send => 100,
+        AVMOpcode::InboxPeek => 40,
+        AVMOpcode::Inbox => 40,
+        AVMOpcode::Error => 5,
+        AVMOpcode::Halt => 10,
+        AVMOpcode::ErrCodePoint => 25,
+        AVMOpcode::PushInsn => 25,
+        AVMOpcode::PushInsnImm => 25,
+        AVMOpcode::OpenInsn => 25,
+        AVMOpcode::DebugPrint => 1,
+        AVMOpcode::PushGas => 1,
+        AVMOpcode::SetGas => 1,
+        AVMOpcode::EcRecover => 20_000,
+        AVMOpcode::EcAdd => 3500,
+        AVMOpcode::EcMul => 82_000,
+        AVMOpcode::EcPairing => 1000,
+        AVMOpcode::Sideload => 10,
+        AVMOpcode::NewBuffer => 1,
+        AVMOpcode::GetBuffer8 => 10,
+        AVMOpcode::GetBuffer64 => 10,
+        AVMOpcode::GetBuffer256 => 10,
+        AVMOpcode::SetBuffer8 => 100,
+        AVMOpcode::SetBuffer64 => 100,
+        AVMOpcode::SetBuffer256 => 100,
+    }
+}
+
 fn check_debugprint_for_malformed_trace_info(r1: &Value) {
     if let Value::Tuple(tup) = r1 {
         if (tup.len() == 2) && (tup[0] == Value::Int(Uint256::from_u64(20000))) {
@@ -2289,8 +3447,8 @@ fn tuple_keccak(intup: Vec<Value>, state: &MachineState) -> Result<Vec<Value>, E
     let mask64 = Uint256::from_u64(((1 << 32) + 1) * ((1 << 32) - 1));
     let two_to_64 = mask64.add(&Uint256::one());
     if intup.len() != 7 {
-        println!("keccakf operand length: {}", intup.len());
         return Err(ExecutionError::new(
+            ErrorKind::TypeMismatch,
             "invalid tuple length for keccakf: ",
             state,
             None,
@@ -2302,6 +3460,7 @@ fn tuple_keccak(intup: Vec<Value>, state: &MachineState) -> Result<Vec<Value>, E
             ui
         } else {
             return Err(ExecutionError::new(
+                ErrorKind::TypeMismatch,
                 "invalid operand for keccakf",
                 state,
                 None,
@@ -2519,13 +3678,7 @@ fn ripemd160_compression(acc: Uint256, buf0: Uint256, buf1: Uint256) -> Uint256
     let mut acc_buf = [words[3], words[4], words[5], words[6], words[7]];
     let mut buf = buf0.to_bytes_be();
     buf.extend(buf1.to_bytes_be());
-
-    println!("before {:?}", acc_buf);
-    println!("buf    {:?}", buf);
     ripemd160port::process_msg_block(&mut acc_buf, &buf);
-    println!("after  {:?}", acc_buf);
-
-    println!("reversed {:?}", reverse32(acc_buf[0]));
 
     Uint256::from_u32_digits(&[
         reverse32(acc_buf[4]),
@@ -2555,3 +3708,366 @@ impl fmt::Display for StackTrace {
         self.trace.iter().map(|v| writeln!(f, "{:?}", v)).collect()
     }
 }
+
+#[test]
+fn test_branching_segments() {
+    let mut code = CodeStore::new(vec![]);
+    let start = code.create_segment();
+    let mut prefix = start;
+    for _ in 0..3 {
+        prefix = code
+            .push_insn(AVMOpcode::Noop.to_number() as usize, None, prefix)
+            .unwrap();
+    }
+    let opcodes = [AVMOpcode::Pop, AVMOpcode::Dup0, AVMOpcode::Swap1];
+    let mut branches = vec![];
+    for opcode in opcodes.iter() {
+        let mut tip = prefix;
+        for i in 0..2 {
+            tip = code
+                .push_insn(
+                    opcode.to_number() as usize,
+                    Some(Value::Int(Uint256::from_usize(i))),
+                    tip,
+                )
+                .unwrap();
+        }
+        branches.push(tip);
+    }
+    // a branch off the middle of the first branch
+    let inner = code
+        .push_insn(
+            AVMOpcode::Halt.to_number() as usize,
+            None,
+            branches[0].incr().unwrap(),
+        )
+        .unwrap();
+
+    for (opcode, tip) in opcodes.iter().zip(branches.iter()) {
+        let mut codept = *tip;
+        for i in (0..2).rev() {
+            let insn = code.get_insn(codept).unwrap();
+            assert_eq!(insn.opcode, *opcode);
+            assert_eq!(insn.immediate, Some(Value::Int(Uint256::from_usize(i))));
+            codept = codept.incr().unwrap();
+        }
+        while let Some(next) = codept.incr() {
+            assert_eq!(code.get_insn(codept).unwrap().opcode, AVMOpcode::Noop);
+            codept = next;
+        }
+        assert_eq!(code.get_insn(codept).unwrap().opcode, AVMOpcode::Zero);
+    }
+    assert_eq!(code.segments.len(), 5);
+    assert_eq!(code.get_insn(inner).unwrap().opcode, AVMOpcode::Halt);
+    let below = inner.incr().unwrap();
+    assert_eq!(code.get_insn(below).unwrap().opcode, AVMOpcode::Pop);
+    assert_eq!(code.get_insn(branches[0]).unwrap().opcode, AVMOpcode::Pop);
+}
+
+#[test]
+fn test_collapsed_profile_export() {
+    let mut profile = ProfilerData::default();
+    let root = CodePt::new_internal(0);
+    let callee = CodePt::new_internal(7);
+    profile.stack_gas.insert(vec![root], 10);
+    profile.stack_gas.insert(vec![root, callee], 25);
+    let mut out = vec![];
+    profile.export(ProfileFormat::Collapsed, &mut out).unwrap();
+    let expected = format!(
+        "{} 10\n{};{} 25\n",
+        profile.func_name(&root),
+        profile.func_name(&root),
+        profile.func_name(&callee)
+    );
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+#[test]
+fn test_machine_hash() {
+    use crate::asm::assemble;
+
+    let source = "[1] noop\n[2] add\nhalt\n";
+    let new_machine = || {
+        let mut machine = Machine::new(assemble(source, 0).unwrap(), RuntimeEnvironment::default());
+        machine.start_at_zero();
+        machine
+    };
+    let mut machine = new_machine();
+    let mut twin = new_machine();
+
+    // Recompute the hash of the machine from the encoding of the on-chain verifier.
+    let hash = |bytes: &[&[u8]]| keccak256(&bytes.concat()).to_vec();
+    let int_hash = |val: usize| hash(&[&Uint256::from_usize(val).to_bytes_be()]);
+    let size = |val: usize| Uint256::from_usize(val).to_bytes_be();
+    let tuple_typecode = [3u8];
+    let code_point = |opcode: AVMOpcode, imm: Option<usize>, next: &[u8]| match imm {
+        Some(imm) => hash(&[&[1u8, opcode.to_number()], &int_hash(imm), next]),
+        None => hash(&[&[1u8, opcode.to_number()], next]),
+    };
+    let empty_inner = hash(&[&[0u8]]);
+    assert_eq!(
+        Uint256::from_bytes(&empty_inner),
+        Uint256::from_string_hex(
+            "bc36789e7a1e281436464229828f817d6612f7b477d66591ff96a9e064bcc98a"
+        )
+        .unwrap()
+    );
+    let empty_tuple = hash(&[&tuple_typecode, &empty_inner, &size(1)]);
+    let halt = code_point(AVMOpcode::Halt, None, &[0u8; 32]);
+    let add = code_point(AVMOpcode::Add, Some(2), &halt);
+    let noop = code_point(AVMOpcode::Noop, Some(1), &add);
+    let stack_of_one = hash(&[
+        &tuple_typecode,
+        &hash(&[&[2u8], &int_hash(1), &empty_tuple]),
+        &size(3),
+    ]);
+    let machine_hash = |pc: &[u8], stack: &[u8], gas: &Uint256| {
+        Uint256::from_bytes(&hash(&[
+            pc,
+            stack,
+            &empty_tuple,
+            &empty_tuple,
+            &empty_tuple,
+            &gas.to_bytes_be(),
+            &[0u8; 32],
+        ]))
+    };
+
+    assert_eq!(
+        machine.code_point_hash(CodePt::new_internal(0)),
+        Uint256::from_bytes(&noop)
+    );
+    let start = machine.hash();
+    assert_eq!(
+        start,
+        machine_hash(&noop, &empty_tuple, &machine.arb_gas_remaining)
+    );
+    assert!(machine.run_one(false).unwrap());
+    let after_one = machine.hash();
+    assert_eq!(
+        after_one,
+        machine_hash(&add, &stack_of_one, &machine.arb_gas_remaining)
+    );
+    assert!(twin.run_one(false).unwrap());
+    assert_eq!(twin.hash(), after_one);
+
+    machine.arb_gas_remaining = Uint256::zero();
+    assert_ne!(machine.hash(), after_one);
+
+    while let MachineState::Running(_) = twin.state {
+        twin.run_one(false).unwrap();
+    }
+    assert_eq!(twin.hash(), Uint256::zero());
+    twin.state = MachineState::Error(ExecutionError::new(
+        ErrorKind::TypeMismatch,
+        "",
+        &twin.state,
+        None,
+    ));
+    assert_eq!(twin.hash(), Uint256::one());
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    use crate::asm::assemble;
+
+    let program = assemble("[(1, 2)] auxpush\n[3] noop\n[4] add\ndup0\nrset\nhalt\n", 0).unwrap();
+    let mut machine = Machine::new(program, RuntimeEnvironment::default());
+    machine.start_at_zero();
+    for _ in 0..3 {
+        assert!(machine.run_one(false).unwrap());
+    }
+    let path = std::env::temp_dir().join(format!("round-trip-{}.snapshot", std::process::id()));
+    machine.save_snapshot(&path).unwrap();
+    let mut restored = Machine::load_snapshot(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(restored.hash(), machine.hash());
+    assert_eq!(restored.get_pc().unwrap(), machine.get_pc().unwrap());
+
+    machine.run(None);
+    restored.run(None);
+    assert_eq!(restored.hash(), machine.hash());
+    assert_eq!(
+        restored.stack.contents.iter().collect::<Vec<_>>(),
+        vec![&Value::Int(Uint256::from_usize(7))]
+    );
+    assert_eq!(restored.register, Value::Int(Uint256::from_usize(7)));
+
+    restored
+        .runtime_env
+        .set_sideload_provider(std::sync::Arc::new(
+            crate::run::FileSideloadProvider::default(),
+        ));
+    assert!(restored.save_snapshot(&path).is_err());
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_reverse_step_matches_fresh_run() {
+    use crate::asm::assemble;
+
+    // counts down from 20, pushing every count onto the aux stack
+    let source = "[20] noop\nloop:\ndup0\nauxpush\ndup0\niszero\n[done] cjump\n[1] swap1\nsub\n\
+                  [loop] jump\ndone:\nhalt\n";
+    let new_machine = || {
+        let mut machine = Machine::new(assemble(source, 0).unwrap(), RuntimeEnvironment::default());
+        machine.start_at_zero();
+        machine
+    };
+    // runs machine to step target the way the debugger does, returning its gas count
+    let run_to = |machine: &mut Machine, history: &mut CheckpointHistory, target: u64| {
+        let mut gas_cost = 0;
+        let mut previous_line = None;
+        for step in 0..target {
+            history.record(machine, step, gas_cost, previous_line);
+            gas_cost += machine.next_op_gas().unwrap();
+            previous_line = machine.next_source_line().or(previous_line);
+            assert!(machine.run_one(false).unwrap());
+        }
+        gas_cost
+    };
+
+    let mut machine = new_machine();
+    let mut history = CheckpointHistory::new(DEFAULT_CHECKPOINT_BUDGET);
+    history.interval = 8;
+    run_to(&mut machine, &mut history, 50);
+    for target in (38..50).rev() {
+        let checkpoint = history.latest_before(target + 1).unwrap();
+        let (gas_cost, _) = machine.replay_from_checkpoint(checkpoint, target, |_, _, _| {});
+
+        let mut fresh = new_machine();
+        let fresh_gas_cost = run_to(&mut fresh, &mut CheckpointHistory::new(0), target);
+        assert_eq!(machine.get_pc().unwrap(), fresh.get_pc().unwrap());
+        assert!(machine.stack.contents == fresh.stack.contents);
+        assert!(machine.aux_stack.contents == fresh.aux_stack.contents);
+        assert_eq!(gas_cost, fresh_gas_cost);
+        assert_eq!(machine.total_gas_usage, fresh.total_gas_usage);
+    }
+}
+
+#[test]
+fn test_checkpoint_budget_eviction() {
+    use crate::asm::assemble;
+
+    let program = assemble("loop:\n[loop] jump\n", 0).unwrap();
+    let mut machine = Machine::new(program, RuntimeEnvironment::default());
+    machine.start_at_zero();
+    let checkpoint_size = machine.checkpoint(0, 0, None).approx_size();
+    let mut history = CheckpointHistory::new(4 * checkpoint_size);
+    history.interval = 1;
+    for step in 0..32 {
+        history.record(&machine, step, 0, None);
+        assert!(machine.run_one(false).unwrap());
+        assert!(history.size <= history.budget);
+    }
+    // every overflow drops every other checkpoint and doubles the interval
+    let steps: Vec<_> = history.checkpoints.iter().map(|cp| cp.step).collect();
+    assert_eq!(steps, vec![0, 8, 16, 24]);
+    assert_eq!(history.interval, 8);
+    assert_eq!(history.latest_before(24).unwrap().step, 16);
+    assert!(history.latest_before(0).is_none());
+}
+
+#[test]
+fn test_resolve_source_breakpoint() {
+    use crate::asm::assemble;
+
+    let mut program = assemble("noop\nhalt\n", 7).unwrap();
+    program.file_info_chart.insert(
+        7,
+        FileInfo {
+            name: String::from("stepping"),
+            path: String::from("test-programs/stepping.mini"),
+            contents: vec![],
+        },
+    );
+    let machine = Machine::new(program, RuntimeEnvironment::default());
+    assert_eq!(
+        machine.resolve_source_breakpoint("stepping.mini:3"),
+        Ok(vec![(7, 2)])
+    );
+    assert_eq!(
+        machine.resolve_source_breakpoint("test-programs/stepping.mini:1"),
+        Ok(vec![(7, 0)])
+    );
+    assert_eq!(
+        machine.resolve_source_breakpoint("stepping:12"),
+        Ok(vec![(7, 11)])
+    );
+    assert!(machine.resolve_source_breakpoint("other.mini:3").is_err());
+    assert!(machine
+        .resolve_source_breakpoint("stepping.mini:0")
+        .is_err());
+    assert!(machine
+        .resolve_source_breakpoint("stepping.mini:x")
+        .is_err());
+    assert!(machine.resolve_source_breakpoint("stepping.mini").is_err());
+}
+
+#[test]
+fn test_line_breakpoint_fires_on_entry() {
+    use crate::asm::assemble;
+
+    let mut program = assemble("noop\nnoop\nnoop\nnoop\n[1] noop\nnoop\nhalt\n", 0).unwrap();
+    // instructions 1 to 3 all belong to the line of instruction 1
+    program.code[2].debug_info.location = program.code[1].debug_info.location;
+    program.code[3].debug_info.location = program.code[1].debug_info.location;
+    let location = program.code[1].debug_info.location.unwrap();
+    let breakpoint = (location.file_id, location.line());
+    let mut machine = Machine::new(program, RuntimeEnvironment::default());
+    machine.start_at_zero();
+
+    let mut previous_line = None;
+    let mut stops = vec![];
+    while machine.state.is_running() {
+        if machine.at_breakpoint(previous_line, |file_id, line| (file_id, line) == breakpoint) {
+            stops.push(machine.get_pc().unwrap());
+        }
+        previous_line = machine.next_source_line().or(previous_line);
+        machine.run_one(false).unwrap();
+    }
+    assert_eq!(stops, vec![CodePt::new_internal(1)]);
+}
+
+#[test]
+fn test_source_stepping_by_frame_depth() {
+    use crate::compile::CompileStruct;
+
+    let mut compile = CompileStruct::default();
+    compile.input = vec![String::from("test-programs/stepping.mini")];
+    compile.test_mode = true;
+    compile.consts_file = Some(String::from("arb_os/constants.json"));
+    let new_machine = || {
+        let program = compile.invoke().map_err(|_| "failed to compile").unwrap().0;
+        let mut machine = Machine::new(program, RuntimeEnvironment::default());
+        let file_id = machine.matching_file_ids("test-programs/stepping.mini")[0];
+        machine.call_state(CodePt::new_internal(1), vec![]);
+        // run to the first instruction of the line calling f
+        while machine.next_source_line() != Some((file_id, 1)) {
+            assert!(machine.run_one(false).unwrap());
+        }
+        machine
+    };
+    let run_to = |machine: &mut Machine, step: &SourceStep| {
+        while !machine.reached_step_target(step) {
+            assert!(machine.run_one(false).unwrap());
+        }
+        (machine.next_source_line().unwrap().1, machine.frame_depth())
+    };
+
+    let mut machine = new_machine();
+    let depth = machine.frame_depth();
+    let step = SourceStep::Over(machine.next_source_line(), depth);
+    assert_eq!(run_to(&mut machine, &step), (2, depth));
+
+    let mut machine = new_machine();
+    let step = SourceStep::Into(machine.next_source_line());
+    let (line, inner_depth) = run_to(&mut machine, &step);
+    assert_eq!((line, inner_depth), (5, depth + 1));
+    let step = SourceStep::Over(machine.next_source_line(), inner_depth);
+    assert_eq!(run_to(&mut machine, &step), (6, inner_depth));
+    let step = SourceStep::Out(inner_depth);
+    let (line, outer_depth) = run_to(&mut machine, &step);
+    assert!((1..=2).contains(&line));
+    assert_eq!(outer_depth, depth);
+}
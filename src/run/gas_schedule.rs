@@ -0,0 +1,129 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides the ArbGas costs charged by the emulator, which can be loaded from a file to evaluate
+//! repricings without recompiling.
+
+use super::emulator::static_gas_cost;
+use crate::compile::CompileError;
+use crate::mavm::AVMOpcode;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+///The ArbGas cost of each opcode.
+///
+/// The cost of `Blake2f` is blake2f_per_round per round, and the cost of `EcPairing` is the static
+/// cost of `EcPairing` plus pairing_per_point per pair of points.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasSchedule {
+    costs: Vec<u64>,
+    pub blake2f_per_round: u64,
+    pub pairing_per_point: u64,
+}
+
+///The contents of a gas schedule file, every entry overrides the corresponding default cost.
+///
+/// Opcodes are given by their mnemonic, for example:
+/// ```toml
+/// blake2f_per_round = 12
+///
+/// [costs]
+/// ripemd160f = 300
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GasScheduleFile {
+    #[serde(default)]
+    costs: BTreeMap<String, u64>,
+    blake2f_per_round: Option<u64>,
+    pairing_per_point: Option<u64>,
+}
+
+impl Default for GasSchedule {
+    fn default() -> Self {
+        GasSchedule {
+            costs: (0..256)
+                .map(|num| AVMOpcode::from_number(num).map_or(0, static_gas_cost))
+                .collect(),
+            blake2f_per_round: 10,
+            pairing_per_point: 500_000,
+        }
+    }
+}
+
+impl GasSchedule {
+    ///Returns the cost of opcode that does not depend on its operands, see `static_gas_cost`.
+    pub fn cost(&self, opcode: AVMOpcode) -> u64 {
+        self.costs[opcode.to_number() as usize]
+    }
+
+    ///Returns the costs of self as bytes, two schedules have the same bytes iff they are equal.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.costs
+            .iter()
+            .chain(&[self.blake2f_per_round, self.pairing_per_point])
+            .flat_map(|cost| cost.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    ///Loads the default schedule with the overrides in the file at path, which is parsed as JSON if
+    /// it has a .json extension and as TOML otherwise.
+    pub fn from_file(path: &Path) -> Result<Self, CompileError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            gas_schedule_error(format!("could not read \"{}\": {}", path.display(), e))
+        })?;
+        let file: GasScheduleFile = if path.extension().map_or(false, |ext| ext == "json") {
+            serde_json::from_str(&contents).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&contents).map_err(|e| e.to_string())
+        }
+        .map_err(|e| {
+            gas_schedule_error(format!("could not parse \"{}\": {}", path.display(), e))
+        })?;
+        GasSchedule::from_overrides(file)
+    }
+
+    fn from_overrides(file: GasScheduleFile) -> Result<Self, CompileError> {
+        let mut schedule = GasSchedule::default();
+        for (name, cost) in file.costs {
+            let opcode = AVMOpcode::from_name(&name)
+                .ok_or_else(|| gas_schedule_error(format!("unknown opcode \"{}\"", name)))?;
+            schedule.costs[opcode.to_number() as usize] = cost;
+        }
+        if let Some(cost) = file.blake2f_per_round {
+            schedule.blake2f_per_round = cost;
+        }
+        if let Some(cost) = file.pairing_per_point {
+            schedule.pairing_per_point = cost;
+        }
+        Ok(schedule)
+    }
+}
+
+fn gas_schedule_error(description: String) -> CompileError {
+    CompileError::new(String::from("Gas schedule error"), description, vec![])
+}
+
+#[test]
+fn test_gas_schedule_overrides() {
+    let file: GasScheduleFile =
+        toml::from_str("pairing_per_point = 7\n\n[costs]\nripemd160f = 300\nadd = 4\n").unwrap();
+    let schedule = GasSchedule::from_overrides(file).unwrap();
+    assert_eq!(schedule.cost(AVMOpcode::Ripemd160f), 300);
+    assert_eq!(schedule.cost(AVMOpcode::Add), 4);
+    assert_eq!(
+        schedule.cost(AVMOpcode::Mul),
+        static_gas_cost(AVMOpcode::Mul)
+    );
+    assert_eq!(schedule.pairing_per_point, 7);
+    assert_eq!(
+        schedule.blake2f_per_round,
+        GasSchedule::default().blake2f_per_round
+    );
+
+    let file: GasScheduleFile = serde_json::from_str(r#"{"costs": {"nosuchop": 1}}"#).unwrap();
+    assert!(GasSchedule::from_overrides(file).is_err());
+}
@@ -4,27 +4,46 @@
 
 //!Provides functionality for running mavm executables.
 
-use crate::link::LinkedProgram;
+use crate::link::{DebugSidecar, LinkedProgram};
 use crate::mavm::{CodePt, Value};
-use emulator::{ExecutionError, StackTrace};
 use std::{fs::File, io::Read, path::Path};
 
-pub use emulator::{Machine, ProfilerMode};
+pub use backtrace::RuntimeError;
+pub use divergence::bisect_divergence;
+pub use emulator::{static_gas_cost, Machine, ProfileFormat, ProfilerMode, SNAPSHOT_VERSION};
+pub use gas_schedule::GasSchedule;
+pub use proof::prove_step_from_testlog_file;
 pub use runtime_env::{
     _bytes_from_bytestack, _bytestack_from_bytes, generic_compress_token_amount,
-    replay_from_testlog_file, ArbosReceipt, RuntimeEnvironment,
+    replay_from_testlog_file, ArbosReceipt, EvmLog, ReplayOptions, RtEnvRecorder,
+    RuntimeEnvironment,
 };
+pub use scenario::{BlockOutcome, Scenario, ScenarioBlock, ScenarioMessage};
+pub use sideload::FileSideloadProvider;
+pub use trace::{FunctionFilter, JsonTraceWriter, StepRange, TraceFilter};
 
+mod backtrace;
 mod blake2b;
+mod call_trace;
+mod coverage;
+mod dap;
+mod divergence;
 mod emulator;
+mod gas_schedule;
+mod proof;
 mod ripemd160port;
 mod runtime_env;
+mod scenario;
+mod sideload;
+mod stats;
+mod trace;
 
+#[cfg(test)]
 pub fn run_from_file(
     path: &Path,
     args: Vec<Value>,
     debug: bool,
-) -> Result<Vec<Value>, (ExecutionError, StackTrace)> {
+) -> Result<Vec<Value>, RuntimeError> {
     run_from_file_and_env(path, args, RuntimeEnvironment::default(), debug)
 }
 ///Executes the file located at path, or starts the debugger if debug is set to true.
@@ -38,12 +57,13 @@ pub fn run_from_file(
 ///
 /// This function will panic if the specified path cannot be opened or does not contain a valid
 /// mini executable.
+#[cfg(test)]
 pub fn run_from_file_and_env(
     path: &Path,
     args: Vec<Value>,
     env: RuntimeEnvironment,
     debug: bool,
-) -> Result<Vec<Value>, (ExecutionError, StackTrace)> {
+) -> Result<Vec<Value>, RuntimeError> {
     let mut machine = load_from_file_and_env(path, env);
     run(&mut machine, args, debug)
 }
@@ -55,6 +75,8 @@ pub fn load_from_file(path: &Path) -> Machine {
 ///Generates a `Machine` from the given path and `RuntimeEnvironment`. See `RuntimeEnvironment` for
 /// more details.
 ///
+/// If a `DebugSidecar` is present next to path, its debug info is attached to the loaded program.
+///
 /// Will panic if the path cannot be opened or doesn't represent a valid mini executable.
 pub fn load_from_file_and_env(path: &Path, env: RuntimeEnvironment) -> Machine {
     let display = path.display();
@@ -64,28 +86,34 @@ pub fn load_from_file_and_env(path: &Path, env: RuntimeEnvironment) -> Machine {
         Ok(file) => file,
     };
 
-    let mut s = String::new();
-    s = match file.read_to_string(&mut s) {
-        Err(why) => panic!("couldn't read {}: {:?}", display, why),
-        Ok(_) => s,
-    };
+    let mut bytes = vec![];
+    if let Err(why) = file.read_to_end(&mut bytes) {
+        panic!("couldn't read {}: {:?}", display, why);
+    }
 
-    load_from_string(s, env)
+    load_from_bytes(&bytes, &DebugSidecar::path_for(path), env)
 }
 
-///Interprets s as a mini executable and generates a `Machine` with the specified
-/// `RuntimeEnvironment`.
+///Interprets bytes as a mini executable, in either the json or binary mexe format, and generates a
+/// `Machine` with the specified `RuntimeEnvironment`, attaching the debug info at sidecar_path if
+/// that file exists.
 ///
-/// Will panic if s cannot be interpreted as a mini executable.
-fn load_from_string(s: String, env: RuntimeEnvironment) -> Machine {
-    let parse_result: Result<LinkedProgram, serde_json::Error> = serde_json::from_str(&s);
-    let program = match parse_result {
+/// Will panic if bytes cannot be interpreted as a mini executable.
+fn load_from_bytes(bytes: &[u8], sidecar_path: &Path, env: RuntimeEnvironment) -> Machine {
+    let mut program = match LinkedProgram::from_bytes(bytes) {
         Ok(prog) => prog,
         Err(e) => {
-            println!("json parsing error: {:?}", e);
+            println!("{}: {}", e.title, e.description);
             panic!();
         }
     };
+    if sidecar_path.exists() {
+        if let Err(e) = DebugSidecar::from_file(sidecar_path)
+            .and_then(|sidecar| program.attach_debug_info(sidecar))
+        {
+            eprintln!("Ignoring debug info: {}", e.description);
+        }
+    }
     Machine::new(program, env)
 }
 
@@ -94,14 +122,41 @@ pub fn run(
     machine: &mut Machine,
     args: Vec<Value>,
     debug: bool,
-) -> Result<Vec<Value>, (ExecutionError, StackTrace)> {
+) -> Result<Vec<Value>, RuntimeError> {
     // We use PC 1 here because PC pushes an unwanted value--designed for a different entry ABI
     match machine.test_call(CodePt::new_internal(1), args, debug) {
         Ok(_stack) => Ok(machine.runtime_env.get_all_raw_logs()),
-        Err(e) => Err((e, machine.get_stack_trace())),
+        Err(e) => Err(e),
     }
 }
 
+///Runs the specified `Machine` from its first codepoint under a Debug Adapter Protocol session over
+/// stdin and stdout, see `Machine::dap` for more details.
+pub fn dap(machine: &mut Machine, args: Vec<Value>) -> u64 {
+    let stop_pc = machine.call_state(CodePt::new_internal(1), args);
+    machine.dap(Some(stop_pc))
+}
+
+///Profiles the mini executable at path with `RuntimeEnvironment` env, or replays path if it is a
+/// `.aoslog` test log, and writes the profile in format to out.
+pub fn export_profile_from_file(
+    path: &Path,
+    env: RuntimeEnvironment,
+    mode: ProfilerMode,
+    format: ProfileFormat,
+    out: &Path,
+) -> std::io::Result<()> {
+    let mut machine = if path.extension().map_or(false, |ext| ext == "aoslog") {
+        let filename = path.to_str().unwrap_or_default();
+        runtime_env::RtEnvRecorder::from_testlog_file(filename)?.new_machine()
+    } else {
+        load_from_file_and_env(path, env)
+    };
+    let profile = machine.profile_gen(vec![], mode);
+    let mut file = std::io::BufWriter::new(File::create(out)?);
+    profile.export(format, &mut file)
+}
+
 ///Interprets path as a mini executable and starts a profiler session with executable arguments args
 /// and `RuntimeEnvironment` env.  See `profiler_session` for more details.
 pub fn profile_gen_from_file(
@@ -0,0 +1,624 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides generation of one-step proofs, which let a verifier check the execution of a single
+//! instruction against the machine hashes before and after it.
+//!
+//! Proofs commit to the AVM machine hash of `Machine::hash`, as computed by the node and the
+//! on-chain verifier. The instruction and the operands it reads are serialized in the format of
+//! `Instruction::_upload`, values that format cannot hold are given by their hash and size.
+
+use super::emulator::{ErrorKind, ExecutionError, MachineState};
+use super::runtime_env::RtEnvRecorder;
+use crate::compile::CompileError;
+use crate::mavm::{AVMOpcode, Buffer, CodePt, Value, ValueHash};
+use crate::run::Machine;
+use crate::uint256::Uint256;
+use crate::upload::CodeUploader;
+use serde::Serialize;
+
+///A value in a proof, either serialized like `Value::_upload`, or as its hash and size if it
+/// cannot be.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProofValue {
+    Serialized(String),
+    Hash(ValueHash),
+}
+
+///The instruction proven by a `OneStepProof`.
+#[derive(Debug, Serialize)]
+pub struct ProofInstruction {
+    ///The instruction serialized like `Instruction::_upload`, in hex.
+    pub serialized: String,
+    ///Hash of the code point executed after this instruction, see `Machine::code_point_hash`.
+    pub next_code_point_hash: Uint256,
+}
+
+///Everything needed to check the execution of one instruction.
+///
+/// The stacks are split into the items the instruction reads, top first, and the hash of the rest
+/// of the stack below them. Together with the remaining hashes this is enough to recompute
+/// machine_hash_before, see `Machine::hash`.
+///
+/// Instructions that read from outside the machine also carry what they read: inbox_message is
+/// the message at the head of the inbox for `Inbox` and `InboxPeek`, and sideload_value is the
+/// value pushed by `Sideload`.
+#[derive(Debug, Serialize)]
+pub struct OneStepProof {
+    pub step: u64,
+    pub pc: String,
+    pub machine_hash_before: Uint256,
+    pub machine_hash_after: Uint256,
+    pub instruction: ProofInstruction,
+    pub stack: Vec<ProofValue>,
+    pub stack_rest_hash: ValueHash,
+    pub aux_stack: Vec<ProofValue>,
+    pub aux_stack_rest_hash: ValueHash,
+    pub register_hash: Uint256,
+    pub static_hash: Uint256,
+    pub arb_gas_remaining: Uint256,
+    pub err_codepoint_hash: Uint256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inbox_message: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sideload_value: Option<Value>,
+}
+
+///Returns the number of data stack and aux stack items read by an instruction with opcode, counting
+/// an immediate value as the top of the data stack.
+///
+/// Values read from the inbox or a sideload are not stack items, they are carried separately by
+/// `OneStepProof`.
+fn operand_counts(opcode: AVMOpcode) -> (usize, usize) {
+    match opcode {
+        AVMOpcode::Zero
+        | AVMOpcode::Spush
+        | AVMOpcode::Rpush
+        | AVMOpcode::StackEmpty
+        | AVMOpcode::PCpush
+        | AVMOpcode::AuxStackEmpty
+        | AVMOpcode::Noop
+        | AVMOpcode::ErrPush
+        | AVMOpcode::Breakpoint
+        | AVMOpcode::Inbox
+        | AVMOpcode::Error
+        | AVMOpcode::Halt
+        | AVMOpcode::PushGas
+        | AVMOpcode::ErrCodePoint
+        | AVMOpcode::NewBuffer => (0, 0),
+        AVMOpcode::IsZero
+        | AVMOpcode::BitwiseNeg
+        | AVMOpcode::Hash
+        | AVMOpcode::Type
+        | AVMOpcode::Keccakf
+        | AVMOpcode::Blake2f
+        | AVMOpcode::Pop
+        | AVMOpcode::Rset
+        | AVMOpcode::Jump
+        | AVMOpcode::ErrSet
+        | AVMOpcode::Dup0
+        | AVMOpcode::Tlen
+        | AVMOpcode::Log
+        | AVMOpcode::InboxPeek
+        | AVMOpcode::SetGas
+        | AVMOpcode::OpenInsn
+        | AVMOpcode::Sideload
+        | AVMOpcode::EcPairing
+        | AVMOpcode::DebugPrint
+        | AVMOpcode::AuxPush => (1, 0),
+        AVMOpcode::Add
+        | AVMOpcode::Mul
+        | AVMOpcode::Sub
+        | AVMOpcode::Div
+        | AVMOpcode::Sdiv
+        | AVMOpcode::Mod
+        | AVMOpcode::Smod
+        | AVMOpcode::Exp
+        | AVMOpcode::SignExtend
+        | AVMOpcode::LessThan
+        | AVMOpcode::GreaterThan
+        | AVMOpcode::SLessThan
+        | AVMOpcode::SGreaterThan
+        | AVMOpcode::Equal
+        | AVMOpcode::BitwiseAnd
+        | AVMOpcode::BitwiseOr
+        | AVMOpcode::BitwiseXor
+        | AVMOpcode::Byte
+        | AVMOpcode::ShiftLeft
+        | AVMOpcode::ShiftRight
+        | AVMOpcode::ShiftArith
+        | AVMOpcode::EthHash2
+        | AVMOpcode::Cjump
+        | AVMOpcode::Dup1
+        | AVMOpcode::Swap1
+        | AVMOpcode::Tget
+        | AVMOpcode::Send
+        | AVMOpcode::PushInsn
+        | AVMOpcode::GetBuffer8
+        | AVMOpcode::GetBuffer64
+        | AVMOpcode::GetBuffer256 => (2, 0),
+        AVMOpcode::AddMod
+        | AVMOpcode::MulMod
+        | AVMOpcode::Sha256f
+        | AVMOpcode::Ripemd160f
+        | AVMOpcode::Dup2
+        | AVMOpcode::Swap2
+        | AVMOpcode::Tset
+        | AVMOpcode::PushInsnImm
+        | AVMOpcode::EcMul
+        | AVMOpcode::SetBuffer8
+        | AVMOpcode::SetBuffer64
+        | AVMOpcode::SetBuffer256 => (3, 0),
+        AVMOpcode::EcRecover | AVMOpcode::EcAdd => (4, 0),
+        AVMOpcode::AuxPop => (0, 1),
+        AVMOpcode::Xget => (1, 1),
+        AVMOpcode::Xset => (2, 1),
+    }
+}
+
+///Returns true if val can be serialized in the upload format.
+fn is_uploadable(val: &Value) -> bool {
+    match val {
+        Value::Int(_) => true,
+        Value::Tuple(tup) => tup.iter().all(is_uploadable),
+        Value::CodePoint(CodePt::Internal(_)) => true,
+        Value::Buffer(buf) => *buf == Buffer::new_empty(),
+        _ => false,
+    }
+}
+
+impl Machine {
+    fn proof_value(&self, val: &Value, uploader: &mut CodeUploader) -> ProofValue {
+        if is_uploadable(val) {
+            ProofValue::Serialized(hex::encode(uploader._serialize_value(val)))
+        } else {
+            ProofValue::Hash(self.value_hash(val))
+        }
+    }
+
+    ///Splits items, given from bottom to top, into the top count items, top first, and the hash of
+    /// the rest.
+    fn split_stack(
+        &self,
+        items: Vec<Value>,
+        count: usize,
+        uploader: &mut CodeUploader,
+    ) -> (Vec<ProofValue>, ValueHash) {
+        let (rest, top) = items.split_at(items.len() - count.min(items.len()));
+        (
+            top.iter()
+                .rev()
+                .map(|val| self.proof_value(val, uploader))
+                .collect(),
+            self.stack_hash(rest),
+        )
+    }
+
+    ///Executes the next instruction of self, returning a proof of that execution, step is the
+    /// number of instructions executed before it and is only recorded in the proof.
+    ///
+    /// If the instruction fails, self is left in the error state and the proof shows the
+    /// corresponding machine hash. Instructions with an immediate that cannot be serialized, such
+    /// as a code point in a code segment built at runtime, cannot be proven.
+    pub fn prove_step(&mut self, step: u64) -> Result<OneStepProof, ExecutionError> {
+        let pc = self.get_pc()?;
+        let insn = match self.insn_at(pc) {
+            Some(insn) => insn.clone(),
+            None => {
+                return Err(ExecutionError::RunningErr(
+                    ErrorKind::BadCodePoint,
+                    "invalid program counter",
+                    pc,
+                    None,
+                ))
+            }
+        };
+        let mut uploader = CodeUploader::_new(self.runtime_segment_size());
+        if !insn.immediate.iter().all(is_uploadable) {
+            return Err(ExecutionError::RunningErr(
+                ErrorKind::InvalidOperand,
+                "immediate cannot be serialized",
+                pc,
+                insn.immediate,
+            ));
+        }
+        let serialized = hex::encode(uploader._serialize_insn(&insn));
+        let (stack_reads, aux_reads) = operand_counts(insn.opcode);
+        let stack_reads = stack_reads.saturating_sub(insn.immediate.iter().count());
+        let (stack, stack_rest_hash) = self.split_stack(
+            self.stack().iter().cloned().collect(),
+            stack_reads,
+            &mut uploader,
+        );
+        let (aux_stack, aux_stack_rest_hash) = self.split_stack(
+            self.aux_stack().iter().cloned().collect(),
+            aux_reads,
+            &mut uploader,
+        );
+        let next_code_point_hash = match pc.incr() {
+            Some(next) => self.code_point_hash(next),
+            None => Uint256::zero(),
+        };
+        let err_codepoint = self.err_codepoint();
+        let err_codepoint_hash = self.code_point_hash(err_codepoint);
+        let register_hash = self.value_hash(&self.register).hash;
+        let static_hash = self.value_hash(self.static_val()).hash;
+        let arb_gas_remaining = self.arb_gas_remaining().clone();
+        let machine_hash_before = self.hash();
+        let inbox_message = match insn.opcode {
+            AVMOpcode::Inbox | AVMOpcode::InboxPeek => self.runtime_env.peek_at_inbox_head(),
+            _ => None,
+        };
+
+        let executed = match self.run_one(false) {
+            Ok(executed) => executed,
+            Err(e) => {
+                self.state = MachineState::Error(e);
+                false
+            }
+        };
+        let sideload_value = if executed && insn.opcode == AVMOpcode::Sideload {
+            self.stack().top()
+        } else {
+            None
+        };
+
+        Ok(OneStepProof {
+            step,
+            pc: format!("{}", pc),
+            machine_hash_before,
+            machine_hash_after: self.hash(),
+            instruction: ProofInstruction {
+                serialized,
+                next_code_point_hash,
+            },
+            stack,
+            stack_rest_hash,
+            aux_stack,
+            aux_stack_rest_hash,
+            register_hash,
+            static_hash,
+            arb_gas_remaining,
+            err_codepoint_hash,
+            inbox_message,
+            sideload_value,
+        })
+    }
+}
+
+fn proof_error(description: String) -> CompileError {
+    CompileError::new(String::from("Proof error"), description, vec![])
+}
+
+///Replays the test log at filename for step instructions, and returns the one-step proof of the
+/// instruction after them.
+pub fn prove_step_from_testlog_file(
+    filename: &str,
+    step: u64,
+) -> Result<OneStepProof, CompileError> {
+    let recorder = RtEnvRecorder::from_testlog_file(filename)
+        .map_err(|e| proof_error(format!("could not read \"{}\": {}", filename, e)))?;
+    let mut machine = recorder.new_machine();
+    for executed in 0..step {
+        match machine.run_one(false) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(proof_error(format!(
+                    "machine stopped after {} of {} steps",
+                    executed, step
+                )))
+            }
+            Err(e) => {
+                return Err(proof_error(format!(
+                    "execution failed after {} of {} steps: {}",
+                    executed, step, e
+                )))
+            }
+        }
+    }
+    machine
+        .prove_step(step)
+        .map_err(|e| proof_error(format!("could not prove step {}: {}", step, e)))
+}
+
+#[test]
+fn test_split_stack() {
+    let machine = Machine::new(
+        crate::asm::assemble("halt\n", 0).unwrap(),
+        crate::run::RuntimeEnvironment::default(),
+    );
+    let items: Vec<Value> = (0..5).map(|i| Value::Int(Uint256::from_usize(i))).collect();
+    let mut uploader = CodeUploader::_new(0);
+    let (top, rest_hash) = machine.split_stack(items.clone(), 2, &mut uploader);
+    assert_eq!(rest_hash, machine.stack_hash(&items[..3]));
+    let mut expected = CodeUploader::_new(0);
+    match &top[..] {
+        [ProofValue::Serialized(first), ProofValue::Serialized(second)] => {
+            assert_eq!(*first, hex::encode(expected._serialize_value(&items[4])));
+            assert_eq!(*second, hex::encode(expected._serialize_value(&items[3])));
+        }
+        _ => panic!("expected two serialized values"),
+    }
+    let (top, rest_hash) = machine.split_stack(items.clone(), 10, &mut uploader);
+    assert_eq!(top.len(), 5);
+    assert_eq!(rest_hash, ValueHash::tuple(&[]));
+}
+
+///Proves the instruction of source after steps instructions, on a machine prepared by setup, and
+/// checks the proof against a second machine executing the same instruction. Returns the proof.
+#[cfg(test)]
+fn check_proof(
+    source: &str,
+    setup: fn(&mut Machine),
+    steps: u64,
+    reads: (usize, usize),
+) -> OneStepProof {
+    use crate::asm::assemble;
+    use crate::run::RuntimeEnvironment;
+
+    let new_machine = || {
+        let mut machine = Machine::new(assemble(source, 0).unwrap(), RuntimeEnvironment::default());
+        setup(&mut machine);
+        machine.start_at_zero();
+        for _ in 0..steps {
+            assert!(machine.run_one(false).unwrap());
+        }
+        machine
+    };
+    let mut machine = new_machine();
+    let mut twin = new_machine();
+    let stack: Vec<Value> = twin.stack().iter().cloned().collect();
+    let aux_stack: Vec<Value> = twin.aux_stack().iter().cloned().collect();
+    let insn = twin.insn_at(twin.get_pc().unwrap()).unwrap().clone();
+    let hash_before = twin.hash();
+    if let Err(e) = twin.run_one(false) {
+        twin.state = MachineState::Error(e);
+    }
+
+    let proof = machine.prove_step(steps).unwrap();
+    assert_eq!(proof.step, steps);
+    assert_eq!(
+        proof.instruction.serialized,
+        hex::encode(CodeUploader::_new(twin.runtime_segment_size())._serialize_insn(&insn))
+    );
+    assert_eq!((proof.stack.len(), proof.aux_stack.len()), reads);
+    assert_eq!(proof.machine_hash_before, hash_before);
+    assert_eq!(proof.machine_hash_after, twin.hash());
+
+    // Recompute the hash before the step from the proof and the items it read.
+    let push_items = |rest: &ValueHash, items: &[Value], proved: &[ProofValue]| {
+        let top = &items[items.len() - proved.len()..];
+        let mut uploader = CodeUploader::_new(0);
+        for (val, proved) in top.iter().rev().zip(proved) {
+            assert_eq!(
+                serde_json::to_string(&twin.proof_value(val, &mut uploader)).unwrap(),
+                serde_json::to_string(proved).unwrap()
+            );
+        }
+        let top_hashes = top.iter().map(|val| twin.value_hash(val));
+        top_hashes
+            .fold(rest.clone(), |rest, item| ValueHash::tuple(&[item, rest]))
+            .hash
+    };
+    let hashes = [
+        insn.avm_code_point_hash(&proof.instruction.next_code_point_hash, &|codept| {
+            twin.code_point_hash(codept)
+        }),
+        push_items(&proof.stack_rest_hash, &stack, &proof.stack),
+        push_items(&proof.aux_stack_rest_hash, &aux_stack, &proof.aux_stack),
+        proof.register_hash.clone(),
+        proof.static_hash.clone(),
+        proof.arb_gas_remaining.clone(),
+        proof.err_codepoint_hash.clone(),
+    ];
+    let mut buf = vec![];
+    for hash in hashes.iter() {
+        buf.extend(hash.to_bytes_be());
+    }
+    assert_eq!(
+        Uint256::from_bytes(&ethers_core::utils::keccak256(&buf)),
+        proof.machine_hash_before
+    );
+    proof
+}
+
+#[test]
+fn test_prove_step_operand_shapes() {
+    let pushes = "[3] noop\n[4] noop\n[5] noop\n[6] noop\n[7] noop\n";
+    let no_setup: fn(&mut Machine) = |_| {};
+    for (opcode, reads) in [
+        ("noop", (0, 0)),
+        ("iszero", (1, 0)),
+        ("add", (2, 0)),
+        ("addmod", (3, 0)),
+        ("ecrecover", (4, 0)),
+    ]
+    .iter()
+    {
+        check_proof(
+            &format!("{}{}\nhalt\n", pushes, opcode),
+            no_setup,
+            5,
+            *reads,
+        );
+    }
+    check_proof(&format!("{}[1] add\nhalt\n", pushes), no_setup, 5, (1, 0));
+    check_proof("[(1, 2)] auxpush\nauxpop\nhalt\n", no_setup, 1, (0, 1));
+    check_proof("[(1, 2)] auxpush\n[1] xget\nhalt\n", no_setup, 1, (0, 1));
+    check_proof(
+        "[(1, 2)] auxpush\n[5] noop\n[1] xset\nhalt\n",
+        no_setup,
+        2,
+        (1, 1),
+    );
+    check_proof(
+        "[(1, 2)] auxpush\n[8] noop\nxset\nhalt\n",
+        no_setup,
+        2,
+        (1, 1),
+    );
+    check_proof(
+        "[(1, 2)] auxpush\n[8] noop\n[0] noop\nxset\nhalt\n",
+        no_setup,
+        3,
+        (2, 1),
+    );
+    // a failing instruction proves the transition into the error state
+    let proof = check_proof("pop\nhalt\n", no_setup, 0, (0, 0));
+    assert_eq!(proof.machine_hash_after, Uint256::one());
+}
+
+#[test]
+fn test_prove_step_external_reads() {
+    fn with_message(machine: &mut Machine) {
+        machine
+            .runtime_env
+            .insert_l1_message(3, Uint256::from_usize(9), &[1, 2], None, None);
+    }
+    let proof = check_proof("inbox\nhalt\n", with_message, 0, (0, 0));
+    let mut expected = Machine::new(
+        crate::asm::assemble("halt\n", 0).unwrap(),
+        crate::run::RuntimeEnvironment::default(),
+    );
+    with_message(&mut expected);
+    let head = expected.runtime_env.peek_at_inbox_head();
+    assert_eq!(proof.inbox_message, head);
+    assert!(proof.inbox_message.is_some());
+    assert_eq!(proof.sideload_value, None);
+
+    let proof = check_proof("[0] inboxpeek\nhalt\n", with_message, 0, (0, 0));
+    assert_eq!(proof.inbox_message, head);
+    let proof = check_proof("[0] noop\ninboxpeek\nhalt\n", with_message, 1, (1, 0));
+    assert!(proof.inbox_message.is_some());
+
+    fn with_sideload(machine: &mut Machine) {
+        use crate::run::FileSideloadProvider;
+        use std::{collections::BTreeMap, sync::Arc};
+
+        let mut values = BTreeMap::new();
+        values.insert(
+            Uint256::from_usize(4),
+            Value::new_tuple(vec![Value::none()]),
+        );
+        machine
+            .runtime_env
+            .set_sideload_provider(Arc::new(FileSideloadProvider::new(values)));
+    }
+    let proof = check_proof("[4] sideload\nhalt\n", with_sideload, 0, (0, 0));
+    assert_eq!(
+        proof.sideload_value,
+        Some(Value::new_tuple(vec![Value::none()]))
+    );
+    assert_eq!(proof.inbox_message, None);
+    let proof = check_proof("[5] sideload\nhalt\n", with_sideload, 0, (0, 0));
+    assert_eq!(proof.sideload_value, Some(Value::none()));
+}
+
+///Decodes the value at the start of bytes, serialized like `Value::_upload`, returning its hash and
+/// the remaining bytes. Only handles integers and tuples.
+#[cfg(test)]
+fn decode_proof_value(bytes: &[u8]) -> (ValueHash, &[u8]) {
+    match bytes[0] {
+        0 => {
+            let rlp = rlp::Rlp::new(&bytes[1..]);
+            let info = rlp.payload_info().unwrap();
+            let val = Uint256::from_bytes(rlp.data().unwrap());
+            let hash = Value::Int(val).avm_value_hash(&|_| panic!("unexpected code point"));
+            (hash, &bytes[1 + info.header_len + info.value_len..])
+        }
+        typecode if typecode >= 10 => {
+            let mut rest = &bytes[1..];
+            let mut members = vec![];
+            for _ in 0..typecode - 10 {
+                let (member, after) = decode_proof_value(rest);
+                members.push(member);
+                rest = after;
+            }
+            (ValueHash::tuple(&members), rest)
+        }
+        typecode => panic!("unexpected type code {}", typecode),
+    }
+}
+
+#[test]
+fn test_verify_add_proof() {
+    use crate::run::{GasSchedule, RuntimeEnvironment};
+    use ethers_core::utils::keccak256;
+
+    let mut machine = Machine::new(
+        crate::asm::assemble(
+            "[(3, 5)] noop\n[4] noop\n[(1, 2)] auxpush\n[6] add\nhalt\n",
+            0,
+        )
+        .unwrap(),
+        RuntimeEnvironment::default(),
+    );
+    machine.start_at_zero();
+    for _ in 0..3 {
+        assert!(machine.run_one(false).unwrap());
+    }
+    let proof = machine.prove_step(3).unwrap();
+
+    // Check the proof like the verifier, using nothing but its contents.
+    let machine_hash = |pc: &Uint256, stack: &Uint256, aux_stack: &Uint256, gas: &Uint256| {
+        let hashes = [
+            pc,
+            stack,
+            aux_stack,
+            &proof.register_hash,
+            &proof.static_hash,
+            gas,
+            &proof.err_codepoint_hash,
+        ];
+        let mut buf = vec![];
+        for hash in hashes.iter() {
+            buf.extend(hash.to_bytes_be());
+        }
+        Uint256::from_bytes(&keccak256(&buf))
+    };
+    let insn = hex::decode(&proof.instruction.serialized).unwrap();
+    assert_eq!(insn[..2], [AVMOpcode::Add.to_number(), 1]);
+    let (imm, rest) = decode_proof_value(&insn[2..]);
+    assert!(rest.is_empty());
+    let mut buf = vec![1u8, insn[0]];
+    buf.extend(imm.hash.to_bytes_be());
+    buf.extend(proof.instruction.next_code_point_hash.to_bytes_be());
+    let pc_hash = Uint256::from_bytes(&keccak256(&buf));
+    let operand = match &proof.stack[..] {
+        [ProofValue::Serialized(operand)] => hex::decode(operand).unwrap(),
+        _ => panic!("expected one serialized operand"),
+    };
+    let (operand, rest) = decode_proof_value(&operand);
+    assert!(rest.is_empty());
+    let aux_stack = match &proof.aux_stack[..] {
+        [] => proof.aux_stack_rest_hash.clone(),
+        _ => panic!("expected no aux stack operands"),
+    };
+    let stack = ValueHash::tuple(&[operand, proof.stack_rest_hash.clone()]);
+    assert_eq!(
+        machine_hash(
+            &pc_hash,
+            &stack.hash,
+            &aux_stack.hash,
+            &proof.arb_gas_remaining
+        ),
+        proof.machine_hash_before
+    );
+
+    let sum = Value::Int(Uint256::from_usize(10)).avm_value_hash(&|_| Uint256::zero());
+    let stack = ValueHash::tuple(&[sum, proof.stack_rest_hash.clone()]);
+    let cost = Uint256::from_u64(GasSchedule::default().cost(AVMOpcode::Add));
+    let gas = proof.arb_gas_remaining.sub(&cost).unwrap();
+    assert_eq!(
+        machine_hash(
+            &proof.instruction.next_code_point_hash,
+            &stack.hash,
+            &aux_stack.hash,
+            &gas
+        ),
+        proof.machine_hash_after
+    );
+    assert_eq!(proof.machine_hash_after, machine.hash());
+}
@@ -3,7 +3,10 @@
  */
 
 use crate::mavm::{Buffer, Value};
-use crate::run::{load_from_file_and_env, ProfilerMode};
+use crate::run::call_trace::{write_call_traces, TraceEvent, TxTrace};
+use crate::run::emulator::DEFAULT_CHECKPOINT_BUDGET;
+use crate::run::sideload::{FileSideloadProvider, SideloadProvider};
+use crate::run::{load_from_file_and_env, GasSchedule, JsonTraceWriter, Machine, ProfilerMode};
 use crate::uint256::Uint256;
 use ethers_core::rand::rngs::StdRng;
 use ethers_core::rand::SeedableRng;
@@ -12,11 +15,16 @@ use ethers_core::utils::keccak256;
 use ethers_signers::{Signer, Wallet};
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
-use std::io::Read;
+use std::io::{BufWriter, Read, Write};
 use std::sync::Arc;
-use std::{collections::HashMap, fs::File, io, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io,
+    path::Path,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RuntimeEnvironment {
     pub chain_id: u64,
     pub l1_inbox: Vec<Value>,
@@ -32,6 +40,10 @@ pub struct RuntimeEnvironment {
     charging_policy: Option<(Uint256, Uint256, Uint256)>,
     num_wallets: u64,
     chain_init_message: Vec<u8>,
+    #[serde(skip)]
+    sideload_provider: Option<Arc<dyn SideloadProvider>>,
+    #[serde(skip)]
+    call_traces: Option<Vec<TxTrace>>,
 }
 
 impl RuntimeEnvironment {
@@ -84,6 +96,8 @@ impl RuntimeEnvironment {
             charging_policy: charging_policy.clone(),
             num_wallets: 0,
             chain_init_message: RuntimeEnvironment::get_params_bytes(owner, chain_id),
+            sideload_provider: None,
+            call_traces: None,
         };
 
         ret.send_chain_init_message();
@@ -507,6 +521,29 @@ impl RuntimeEnvironment {
         cur_seq_num
     }
 
+    ///Makes the sideload instruction get its values from provider.
+    pub fn set_sideload_provider(&mut self, provider: Arc<dyn SideloadProvider>) {
+        self.sideload_provider = Some(provider);
+    }
+
+    ///Returns true if a `SideloadProvider` was set with `set_sideload_provider`.
+    pub fn has_sideload_provider(&self) -> bool {
+        self.sideload_provider.is_some()
+    }
+
+    ///Returns the value the sideload instruction pushes for block_num, an empty tuple if the
+    /// `SideloadProvider` has none. Values found are recorded so that replays see them too.
+    pub fn sideload(&mut self, block_num: &Uint256) -> Value {
+        let provider = self.sideload_provider.as_ref();
+        match provider.and_then(|provider| provider.sideload(block_num)) {
+            Some(value) => {
+                self.recorder.add_sideload(block_num.clone(), value.clone());
+                value
+            }
+            None => Value::none(),
+        }
+    }
+
     pub fn get_from_inbox(&mut self) -> Option<Value> {
         if self.l1_inbox.is_empty() {
             None
@@ -528,6 +565,38 @@ impl RuntimeEnvironment {
         self.recorder.add_log(log_item);
     }
 
+    ///Makes tracing debugprints get decoded into call trees, see `get_call_traces`.
+    pub fn enable_call_traces(&mut self) {
+        self.call_traces = Some(vec![]);
+    }
+
+    ///Decodes value into a `TxTrace` for the latest receipt if call traces are enabled and value
+    /// is a tracing debugprint. Traces that cannot be decoded are reported on stderr and skipped.
+    pub fn push_debugprint(&mut self, value: &Value) {
+        if let Some(traces) = &mut self.call_traces {
+            if let Some(events) = TraceEvent::decode_debugprint(value) {
+                let receipt = self
+                    .logs
+                    .iter()
+                    .rev()
+                    .find_map(|log| ArbosReceipt::new(log.clone()));
+                match (events, receipt) {
+                    (Ok(events), Some(receipt)) => match TxTrace::new(&events, &receipt) {
+                        Ok(trace) => traces.push(trace),
+                        Err(e) => eprintln!("Could not build call trace: {}", e),
+                    },
+                    (Err(e), _) => eprintln!("Could not decode call trace: {}", e),
+                    (_, None) => eprintln!("Call trace without a receipt"),
+                }
+            }
+        }
+    }
+
+    ///Returns the call traces decoded so far, which is none unless `enable_call_traces` was called.
+    pub fn get_call_traces(&self) -> &[TxTrace] {
+        self.call_traces.as_deref().unwrap_or_default()
+    }
+
     pub fn get_all_raw_logs(&self) -> Vec<Value> {
         self.logs.clone()
     }
@@ -613,7 +682,7 @@ fn get_send_contents(log: Value) -> Option<Vec<u8>> {
 // TxCompressor assumes that all client traffic uses it.
 // For example, it assumes nobody else affects ArbOS's address compression table.
 // This is fine for testing but wouldn't work in a less controlled setting.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxCompressor {
     address_map: HashMap<Vec<u8>, Vec<u8>>,
     next_index: u64,
@@ -882,31 +951,32 @@ impl ArbosReceipt {
         panic!("Malformed request info in tx receipt");
     }
 
+    ///Returns the Arbitrum block number the request was included in, unlike `_get_block_number`
+    /// which returns the L1 block number.
+    pub fn get_l2_block_number(&self) -> Uint256 {
+        if let Value::Tuple(tup) = self.get_request() {
+            if let Value::Int(bn) = &tup[2] {
+                return bn.clone();
+            }
+        }
+        panic!("Malformed request info in tx receipt");
+    }
+
+    pub fn get_sender(&self) -> Uint256 {
+        if let Value::Tuple(tup) = self.get_request() {
+            if let Value::Int(sender) = &tup[4] {
+                return sender.clone();
+            }
+        }
+        panic!("Malformed request info in tx receipt");
+    }
+
     pub fn get_return_code(&self) -> Uint256 {
         self.return_code.clone()
     }
 
     pub fn _get_return_code_text(&self) -> String {
-        match self.get_return_code().to_u64().unwrap() {
-            0 => "success",
-            1 => "transaction reverted",
-            2 => "dropped due to L2 congestion",
-            3 => "insufficient funds for ArbGas",
-            4 => "insufficient balance for callvalue",
-            5 => "bad sequence number",
-            6 => "message format error",
-            7 => "cannot deploy at address",
-            8 => "exceeded tx gas limit",
-            9 => "insufficient gas for base fee",
-            10 => "below minimum ArbGas for contract tx",
-            11 => "gas price too low",
-            12 => "no gas for auto-redeem",
-            13 => "sender not permitted",
-            14 => "sequence number too low",
-            15 => "sequence number too high",
-            _ => "unknown error",
-        }
-        .to_string()
+        tx_result_code_text(&self.get_return_code())
     }
 
     pub fn succeeded(&self) -> bool {
@@ -929,11 +999,39 @@ impl ArbosReceipt {
         self.gas_so_far.clone()
     }
 
+    pub fn get_index_in_block(&self) -> Uint256 {
+        self.index_in_block.clone()
+    }
+
     pub fn _get_fee_stats(&self) -> Vec<Vec<Uint256>> {
         self.fee_stats.clone()
     }
 }
 
+///Describes a transaction result code of an ArbOS receipt or trace.
+pub fn tx_result_code_text(code: &Uint256) -> String {
+    match code.to_u64() {
+        Some(0) => "success",
+        Some(1) => "transaction reverted",
+        Some(2) => "dropped due to L2 congestion",
+        Some(3) => "insufficient funds for ArbGas",
+        Some(4) => "insufficient balance for callvalue",
+        Some(5) => "bad sequence number",
+        Some(6) => "message format error",
+        Some(7) => "cannot deploy at address",
+        Some(8) => "exceeded tx gas limit",
+        Some(9) => "insufficient gas for base fee",
+        Some(10) => "below minimum ArbGas for contract tx",
+        Some(11) => "gas price too low",
+        Some(12) => "no gas for auto-redeem",
+        Some(13) => "sender not permitted",
+        Some(14) => "sequence number too low",
+        Some(15) => "sequence number too high",
+        _ => "unknown error",
+    }
+    .to_string()
+}
+
 fn size_buffer_tuple_to_bytes(val: &Value) -> Option<Vec<u8>> {
     if let Value::Tuple(tup) = val {
         if let (Value::Int(usz), Value::Buffer(buf)) = (&tup[0], &tup[1]) {
@@ -1243,6 +1341,8 @@ pub struct RtEnvRecorder {
     logs: Vec<Value>,
     sends: Vec<Vec<u8>>,
     total_gas: u64,
+    #[serde(default)]
+    sideloads: BTreeMap<Uint256, Value>,
 }
 
 impl RtEnvRecorder {
@@ -1253,6 +1353,7 @@ impl RtEnvRecorder {
             logs: Vec::new(),
             sends: Vec::new(),
             total_gas: 0,
+            sideloads: BTreeMap::new(),
         }
     }
 
@@ -1268,6 +1369,17 @@ impl RtEnvRecorder {
         self.sends.push(send_item);
     }
 
+    fn add_sideload(&mut self, block_num: Uint256, value: Value) {
+        self.sideloads.insert(block_num, value);
+    }
+
+    ///Writes the recorded sideloads to path, in the format read by `FileSideloadProvider`.
+    pub fn sideloads_to_file(&self, path: &Path) -> Result<(), io::Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut file, &self.sideloads)?;
+        file.flush()
+    }
+
     pub fn to_json_string(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
     }
@@ -1278,29 +1390,80 @@ impl RtEnvRecorder {
         writeln!(file, "{}", self.to_json_string()?)
     }
 
-    pub fn replay_and_compare(
-        &self,
-        require_same_gas: bool,
-        debug: bool,
-        profiler_mode: ProfilerMode,
-        trace_file: Option<&str>,
-    ) -> bool {
-        // returns true iff result matches
+    ///Reads a recorder from the test log at filename.
+    pub fn from_testlog_file(filename: &str) -> std::io::Result<Self> {
+        let mut file = File::open(filename)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        // need to be tricky about how we deserialize, to work around serde_json's recursion limit
+        let mut deserializer = serde_json::Deserializer::from_str(&contents);
+        deserializer.disable_recursion_limit();
+        let deserializer = serde_stacker::Deserializer::new(&mut deserializer);
+        let json_value = serde_json::Value::deserialize(deserializer)?;
+        Ok(serde_json::from_value(json_value)?)
+    }
+
+    ///Returns an ArbOS machine with the recorded inbox contents, ready to run from its start.
+    pub fn new_machine(&self) -> Machine {
+        self.new_machine_for(Path::new("arb_os/arbos.mexe"))
+    }
+
+    ///Loads the program at path into a new `Machine` with the inbox and sideloads of self.
+    pub fn new_machine_for(&self, path: &Path) -> Machine {
         let mut rt_env = RuntimeEnvironment::default();
         rt_env.insert_full_inbox_contents(self.inbox.clone());
-        let mut machine = load_from_file_and_env(Path::new("arb_os/arbos.mexe"), rt_env);
-        if let Some(trace_file_name) = trace_file {
+        rt_env.set_sideload_provider(Arc::new(FileSideloadProvider::new(self.sideloads.clone())));
+        let mut machine = load_from_file_and_env(path, rt_env);
+        machine.start_at_zero();
+        machine
+    }
+
+    pub fn replay_and_compare(&self, options: ReplayOptions) -> std::io::Result<bool> {
+        // returns true iff result matches
+        let mut machine = self.new_machine();
+        if options.call_trace_file.is_some() {
+            machine.runtime_env.enable_call_traces();
+        }
+        if let Some(json_trace) = options.json_trace {
+            machine.set_json_trace(json_trace);
+        }
+        if options.stats_file.is_some() {
+            machine.enable_stats();
+        }
+        if let Some(schedule) = options.gas_schedule {
+            machine.set_gas_schedule(schedule);
+        }
+        if options.coverage_file.is_some() {
+            machine.enable_coverage();
+        }
+        if let Some(trace_file_name) = options.trace_file {
             machine.add_trace_writer(trace_file_name);
         }
-        machine.start_at_zero();
-        if debug {
+        machine.set_checkpoint_budget(options.checkpoint_budget);
+        if let Some(interval) = options.print_hash_every {
+            machine.set_print_hash_every(interval);
+        }
+        if options.dap {
+            let _ = machine.dap(None);
+        } else if options.debug {
             let _ = machine.debug(None);
-        } else if (profiler_mode != ProfilerMode::Never) {
-            let profile_data = machine.profile_gen(vec![], profiler_mode);
+        } else if (options.profiler_mode != ProfilerMode::Never) {
+            let profile_data = machine.profile_gen(vec![], options.profiler_mode);
             profile_data.profiler_session();
         } else {
             let _ = machine.run(None);
         }
+        if let (Some(path), Some(coverage)) = (options.coverage_file, machine.coverage()) {
+            coverage.merge_into_lcov_file(path)?;
+        }
+        if let (Some(path), Some(stats)) = (options.stats_file, machine.stats()) {
+            stats.to_file(path)?;
+        }
+        if let Some(path) = options.call_trace_file {
+            write_call_traces(machine.runtime_env.get_call_traces(), path)?;
+        }
+        let require_same_gas = options.require_same_gas;
         let logs_expected = if require_same_gas {
             self.logs.clone()
         } else {
@@ -1324,7 +1487,7 @@ impl RtEnvRecorder {
         };
         if !(logs_expected == logs_seen) {
             print_output_differences("log", machine.runtime_env.recorder.logs, self.logs.clone());
-            return false;
+            return Ok(false);
         }
         if !(self.sends == machine.runtime_env.recorder.sends) {
             print_output_differences_bytevec(
@@ -1332,14 +1495,14 @@ impl RtEnvRecorder {
                 machine.runtime_env.recorder.sends,
                 self.sends.clone(),
             );
-            return false;
+            return Ok(false);
         }
 
-        return true;
+        Ok(true)
     }
 }
 
-fn strip_var_from_log(log: Value) -> Option<Value> {
+pub(crate) fn strip_var_from_log(log: Value) -> Option<Value> {
     // strip from a log item all info that might legitimately vary as ArbOS evolves (e.g. gas usage)
     if let Value::Tuple(tup) = log.clone() {
         if let Value::Int(item_type) = tup[0].clone() {
@@ -1439,35 +1602,54 @@ fn print_output_differences_bytevec(kind: &str, seen: Vec<Vec<u8>>, expected: Ve
     }
 }
 
-pub fn replay_from_testlog_file(
-    filename: &str,
-    require_same_gas: bool,
-    debug: bool,
-    profiler_mode: ProfilerMode,
-    trace_file: Option<&str>,
-) -> std::io::Result<bool> {
-    let mut file = File::open(filename)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-
-    // need to be tricky about how we deserialize, to work around serde_json's recursion limit
-    let mut deserializer = serde_json::Deserializer::from_str(&contents);
-    deserializer.disable_recursion_limit();
-    let deserializer = serde_stacker::Deserializer::new(&mut deserializer);
-    let json_value = serde_json::Value::deserialize(deserializer).unwrap();
-    let res: Result<RtEnvRecorder, serde_json::error::Error> = serde_json::from_value(json_value);
-
-    match res {
-        Ok(recorder) => {
-            let success =
-                recorder.replay_and_compare(require_same_gas, debug, profiler_mode, trace_file);
-            println!("{}", if success { "success" } else { "mismatch " });
-            Ok(success)
+///How `RtEnvRecorder::replay_and_compare` runs a replay, and what it writes besides the result.
+pub struct ReplayOptions<'a> {
+    ///Whether logs must match including gas usage, which is expected to differ under another gas
+    /// schedule.
+    pub require_same_gas: bool,
+    pub debug: bool,
+    pub profiler_mode: ProfilerMode,
+    pub trace_file: Option<&'a str>,
+    pub dap: bool,
+    ///Memory budget in bytes for debugger checkpoints.
+    pub checkpoint_budget: usize,
+    pub print_hash_every: Option<u64>,
+    pub coverage_file: Option<&'a Path>,
+    pub gas_schedule: Option<GasSchedule>,
+    pub stats_file: Option<&'a Path>,
+    pub json_trace: Option<JsonTraceWriter>,
+    pub call_trace_file: Option<&'a Path>,
+}
+
+impl Default for ReplayOptions<'_> {
+    fn default() -> Self {
+        ReplayOptions {
+            require_same_gas: true,
+            debug: false,
+            profiler_mode: ProfilerMode::Never,
+            trace_file: None,
+            dap: false,
+            checkpoint_budget: DEFAULT_CHECKPOINT_BUDGET,
+            print_hash_every: None,
+            coverage_file: None,
+            gas_schedule: None,
+            stats_file: None,
+            json_trace: None,
+            call_trace_file: None,
         }
-        Err(e) => panic!("json parsing failed: {}", e),
     }
 }
 
+pub fn replay_from_testlog_file(filename: &str, options: ReplayOptions) -> std::io::Result<bool> {
+    let recorder = RtEnvRecorder::from_testlog_file(filename)?;
+    let dap = options.dap;
+    let success = recorder.replay_and_compare(options)?;
+    if !dap {
+        println!("{}", if success { "success" } else { "mismatch " });
+    }
+    Ok(success)
+}
+
 #[test]
 fn test_rust_bytestacks() {
     let before =
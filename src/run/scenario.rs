@@ -0,0 +1,251 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides a driver that feeds a `Machine` a timeline of inbox messages grouped by L1 block, and
+//! reports where the machine blocks on its inbox after each block.
+
+use super::emulator::{Machine, MachineState};
+use super::runtime_env::RuntimeEnvironment;
+use crate::compile::CompileError;
+use crate::mavm::{AVMOpcode, Value};
+use crate::pos::try_display_location;
+use crate::uint256::Uint256;
+use std::fmt;
+
+///A timeline of L1 blocks, in order of increasing block number and timestamp.
+#[derive(Debug, Clone, Default)]
+pub struct Scenario {
+    pub blocks: Vec<ScenarioBlock>,
+}
+
+///The messages that arrive in the inbox in one L1 block.
+#[derive(Debug, Clone)]
+pub struct ScenarioBlock {
+    pub block_num: Uint256,
+    pub timestamp: Uint256,
+    pub messages: Vec<ScenarioMessage>,
+}
+
+///A message in a `ScenarioBlock`, tagged with the block number and timestamp of its block.
+#[derive(Debug, Clone)]
+pub enum ScenarioMessage {
+    ///An L1 message of type msg_type, see `RuntimeEnvironment::insert_l1_message`.
+    L1 {
+        msg_type: u8,
+        sender: Uint256,
+        data: Vec<u8>,
+    },
+    ///An L2 message, see `RuntimeEnvironment::insert_l2_message`.
+    L2 { sender: Uint256, data: Vec<u8> },
+    ///A sequencer batch of the L2 messages in messages, released delay blocks and seconds before
+    /// the block it arrives in, see `RuntimeEnvironment::_new_sequencer_batch`.
+    SequencerBatch {
+        sender: Uint256,
+        delay: Option<(Uint256, Uint256)>,
+        messages: Vec<Vec<u8>>,
+    },
+    ///An empty L2 message, which lets ArbOS notice that time has advanced.
+    Heartbeat,
+}
+
+///What happened after the messages of a `ScenarioBlock` were delivered.
+#[derive(Debug, Clone)]
+pub struct BlockReport {
+    pub block_num: Uint256,
+    pub timestamp: Uint256,
+    ///Number of inbox messages the machine read, including any left over from earlier blocks.
+    pub messages_read: usize,
+    ///Number of delivered messages still in the inbox.
+    pub messages_pending: usize,
+    pub gas_used: u64,
+    pub logs: usize,
+    pub sends: usize,
+    pub outcome: BlockOutcome,
+}
+
+///Where a machine stopped after the messages of a `ScenarioBlock` were delivered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockOutcome {
+    ///Blocked on an `Inbox` instruction, or on an `InboxPeek` asking whether the next message
+    /// is in block peek_block_num.
+    Blocked {
+        opcode: AVMOpcode,
+        peek_block_num: Option<Uint256>,
+        location: String,
+    },
+    Halted,
+    Failed(String),
+}
+
+impl fmt::Display for BlockReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "block {} at time {}: read {} messages ({} pending), used {} gas, {} logs, {} sends, ",
+            self.block_num,
+            self.timestamp,
+            self.messages_read,
+            self.messages_pending,
+            self.gas_used,
+            self.logs,
+            self.sends
+        )?;
+        match &self.outcome {
+            BlockOutcome::Blocked {
+                opcode,
+                peek_block_num,
+                location,
+            } => {
+                write!(f, "blocked on {}", opcode)?;
+                if let Some(block_num) = peek_block_num {
+                    write!(f, " for block {}", block_num)?;
+                }
+                write!(f, " at {}", location)
+            }
+            BlockOutcome::Halted => write!(f, "halted"),
+            BlockOutcome::Failed(reason) => write!(f, "failed: {}", reason.trim_end()),
+        }
+    }
+}
+
+impl ScenarioMessage {
+    fn deliver(&self, env: &mut RuntimeEnvironment) {
+        match self {
+            ScenarioMessage::L1 {
+                msg_type,
+                sender,
+                data,
+            } => {
+                env.insert_l1_message(*msg_type, sender.clone(), data, None, None);
+            }
+            ScenarioMessage::L2 { sender, data } => {
+                env.insert_l2_message(sender.clone(), data, false);
+            }
+            ScenarioMessage::SequencerBatch {
+                sender,
+                delay,
+                messages,
+            } => {
+                let mut batch = env._new_sequencer_batch(delay.clone());
+                for msg in messages {
+                    batch.extend(Uint256::from_usize(msg.len()).rlp_encode());
+                    batch.extend(msg);
+                }
+                env.insert_batch_message(sender.clone(), &batch);
+            }
+            ScenarioMessage::Heartbeat => {
+                env.insert_l2_message(Uint256::zero(), &[6u8], false);
+            }
+        }
+    }
+}
+
+impl Scenario {
+    ///Returns an error if the block numbers or timestamps of self ever decrease.
+    pub fn check(&self) -> Result<(), CompileError> {
+        for pair in self.blocks.windows(2) {
+            check_order(&pair[0].block_num, &pair[0].timestamp, &pair[1])?;
+        }
+        Ok(())
+    }
+
+    ///Delivers the blocks of self to machine in order, running it until it blocks after each one,
+    /// and returns a report for every block delivered.
+    ///
+    /// The block number and timestamp of machine's `RuntimeEnvironment` are set to those of each
+    /// block before its messages are delivered, so the first block must not be earlier than the
+    /// environment. Delivery stops early if the machine halts or fails.
+    pub fn run(&self, machine: &mut Machine) -> Result<Vec<BlockReport>, CompileError> {
+        self.check()?;
+        let mut reports = vec![];
+        for block in &self.blocks {
+            let env = &mut machine.runtime_env;
+            check_order(&env.current_block_num, &env.current_timestamp, block)?;
+            env.current_block_num = block.block_num.clone();
+            env.current_timestamp = block.timestamp.clone();
+            for message in &block.messages {
+                message.deliver(env);
+            }
+            let inbox_before = env.l1_inbox.len();
+            let logs_before = env.logs.len();
+            let sends_before = env.sends.len();
+
+            let gas_used = machine.run(None);
+
+            let outcome = match &machine.state {
+                MachineState::Running(pc) => {
+                    let insn = machine.insn_at(*pc);
+                    let opcode = insn.map_or(AVMOpcode::Noop, |insn| insn.opcode);
+                    BlockOutcome::Blocked {
+                        opcode,
+                        peek_block_num: match (opcode, machine.stack().top()) {
+                            (AVMOpcode::InboxPeek, Some(Value::Int(block_num))) => Some(block_num),
+                            _ => None,
+                        },
+                        location: try_display_location(
+                            insn.and_then(|insn| insn.debug_info.location),
+                            machine.file_info_chart(),
+                            false,
+                        ),
+                    }
+                }
+                MachineState::Stopped => BlockOutcome::Halted,
+                MachineState::Error(e) => BlockOutcome::Failed(e.to_string()),
+            };
+            let env = &machine.runtime_env;
+            let blocked = matches!(outcome, BlockOutcome::Blocked { .. });
+            reports.push(BlockReport {
+                block_num: block.block_num.clone(),
+                timestamp: block.timestamp.clone(),
+                messages_read: inbox_before - env.l1_inbox.len(),
+                messages_pending: env.l1_inbox.len(),
+                gas_used,
+                logs: env.logs.len() - logs_before,
+                sends: env.sends.len() - sends_before,
+                outcome,
+            });
+            if !blocked {
+                break;
+            }
+        }
+        Ok(reports)
+    }
+}
+
+fn check_order(
+    block_num: &Uint256,
+    timestamp: &Uint256,
+    next: &ScenarioBlock,
+) -> Result<(), CompileError> {
+    if next.block_num < *block_num || next.timestamp < *timestamp {
+        Err(scenario_error(format!(
+            "block {} at time {} comes after block {} at time {}",
+            next.block_num, next.timestamp, block_num, timestamp
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn scenario_error(description: String) -> CompileError {
+    CompileError::new(String::from("Scenario error"), description, vec![])
+}
+
+#[test]
+fn test_scenario_order() {
+    let block = |block_num: u64, timestamp: u64| ScenarioBlock {
+        block_num: Uint256::from_u64(block_num),
+        timestamp: Uint256::from_u64(timestamp),
+        messages: vec![ScenarioMessage::Heartbeat],
+    };
+    let mut scenario = Scenario {
+        blocks: vec![block(10, 100), block(10, 100), block(12, 130)],
+    };
+    assert!(scenario.check().is_ok());
+    scenario.blocks.push(block(11, 140));
+    assert!(scenario.check().is_err());
+    scenario.blocks.pop();
+    scenario.blocks.push(block(13, 120));
+    assert!(scenario.check().is_err());
+}
@@ -0,0 +1,69 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides sources for the values returned by the sideload instruction, which lets a node inject
+//! the state of an earlier block into ArbOS.
+
+use crate::mavm::Value;
+use crate::uint256::Uint256;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+///A source of sideloaded values, see `RuntimeEnvironment::set_sideload_provider`.
+pub trait SideloadProvider: Debug + Send + Sync {
+    ///Returns the value to sideload for block_num, or None if there is none, in which case the
+    /// sideload instruction pushes an empty tuple.
+    fn sideload(&self, block_num: &Uint256) -> Option<Value>;
+}
+
+///Serves sideloaded values from a JSON file mapping hex block numbers to values.
+#[derive(Debug, Clone, Default)]
+pub struct FileSideloadProvider {
+    values: BTreeMap<Uint256, Value>,
+}
+
+impl FileSideloadProvider {
+    pub fn new(values: BTreeMap<Uint256, Value>) -> Self {
+        FileSideloadProvider { values }
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(FileSideloadProvider::new(serde_json::from_reader(reader)?))
+    }
+}
+
+impl SideloadProvider for FileSideloadProvider {
+    fn sideload(&self, block_num: &Uint256) -> Option<Value> {
+        self.values.get(block_num).cloned()
+    }
+}
+
+#[test]
+fn test_recorded_sideloads_round_trip() {
+    use crate::run::runtime_env::RuntimeEnvironment;
+    use std::sync::Arc;
+
+    let mut values = BTreeMap::new();
+    values.insert(
+        Uint256::from_u64(7),
+        Value::new_tuple(vec![Value::Int(Uint256::one())]),
+    );
+    let mut rt_env = RuntimeEnvironment::new(None);
+    rt_env.set_sideload_provider(Arc::new(FileSideloadProvider::new(values.clone())));
+    assert_eq!(rt_env.sideload(&Uint256::from_u64(8)), Value::none());
+    assert_eq!(
+        rt_env.sideload(&Uint256::from_u64(7)),
+        values[&Uint256::from_u64(7)]
+    );
+
+    let path = std::env::temp_dir().join("test_recorded_sideloads_round_trip.json");
+    rt_env.recorder.sideloads_to_file(&path).unwrap();
+    let replayed = FileSideloadProvider::from_file(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(replayed.values, values);
+}
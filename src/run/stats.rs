@@ -0,0 +1,237 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides counts of executed opcodes and of adjacent opcode sequences, to guide peephole
+//! optimizations and gas repricing.
+
+use crate::mavm::AVMOpcode;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+///Number of pairs and triples listed by `ExecutionStats::to_file`.
+const REPORTED_SEQUENCES: usize = 50;
+
+///Execution statistics collected by a `Machine`, see `Machine::enable_stats`.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionStats {
+    opcodes: HashMap<AVMOpcode, (u64, u64)>,
+    pairs: HashMap<[AVMOpcode; 2], u64>,
+    triples: HashMap<[AVMOpcode; 3], u64>,
+    previous: Option<AVMOpcode>,
+    before_previous: Option<AVMOpcode>,
+}
+
+///An opcode in a `StatsReport`.
+#[derive(Debug, Serialize)]
+pub struct OpcodeStats {
+    pub opcode: String,
+    pub count: u64,
+    pub gas: u64,
+}
+
+///A sequence of adjacent opcodes in a `StatsReport`.
+#[derive(Debug, Serialize)]
+pub struct SequenceStats {
+    pub opcodes: Vec<String>,
+    pub count: u64,
+}
+
+///The JSON report written by `ExecutionStats::to_file`. Lists are sorted by decreasing count.
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    pub instructions: u64,
+    pub gas: u64,
+    pub opcodes: Vec<OpcodeStats>,
+    pub pairs: Vec<SequenceStats>,
+    pub triples: Vec<SequenceStats>,
+    pub gas_by_class: BTreeMap<&'static str, u64>,
+}
+
+impl ExecutionStats {
+    ///Records the execution of opcode at a cost of gas.
+    pub(crate) fn record(&mut self, opcode: AVMOpcode, gas: u64) {
+        let entry = self.opcodes.entry(opcode).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += gas;
+        if let Some(previous) = self.previous {
+            *self.pairs.entry([previous, opcode]).or_insert(0) += 1;
+            if let Some(before_previous) = self.before_previous {
+                *self
+                    .triples
+                    .entry([before_previous, previous, opcode])
+                    .or_insert(0) += 1;
+            }
+        }
+        self.before_previous = self.previous;
+        self.previous = Some(opcode);
+    }
+
+    ///Summarizes self, keeping only the max_sequences most frequent pairs and triples.
+    pub fn report(&self, max_sequences: usize) -> StatsReport {
+        let mut opcodes: Vec<_> = self
+            .opcodes
+            .iter()
+            .map(|(opcode, (count, gas))| OpcodeStats {
+                opcode: opcode.to_string(),
+                count: *count,
+                gas: *gas,
+            })
+            .collect();
+        opcodes.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.opcode.cmp(&b.opcode)));
+        let mut gas_by_class = BTreeMap::new();
+        for (opcode, (_, gas)) in &self.opcodes {
+            *gas_by_class.entry(opcode_class(*opcode)).or_insert(0) += gas;
+        }
+        StatsReport {
+            instructions: self.opcodes.values().map(|(count, _)| count).sum(),
+            gas: self.opcodes.values().map(|(_, gas)| gas).sum(),
+            opcodes,
+            pairs: most_frequent(&self.pairs, max_sequences),
+            triples: most_frequent(&self.triples, max_sequences),
+            gas_by_class,
+        }
+    }
+
+    ///Writes the report of self to path as JSON, see `report`.
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut file, &self.report(REPORTED_SEQUENCES))?;
+        file.flush()
+    }
+}
+
+fn most_frequent<S: AsRef<[AVMOpcode]>>(
+    sequences: &HashMap<S, u64>,
+    max_sequences: usize,
+) -> Vec<SequenceStats> {
+    let mut sequences: Vec<_> = sequences
+        .iter()
+        .map(|(sequence, count)| SequenceStats {
+            opcodes: sequence.as_ref().iter().map(|op| op.to_string()).collect(),
+            count: *count,
+        })
+        .collect();
+    sequences.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.opcodes.cmp(&b.opcodes))
+    });
+    sequences.truncate(max_sequences);
+    sequences
+}
+
+///Returns the name of the group of related opcodes that opcode belongs to.
+fn opcode_class(opcode: AVMOpcode) -> &'static str {
+    match opcode {
+        AVMOpcode::Add
+        | AVMOpcode::Mul
+        | AVMOpcode::Sub
+        | AVMOpcode::Div
+        | AVMOpcode::Sdiv
+        | AVMOpcode::Mod
+        | AVMOpcode::Smod
+        | AVMOpcode::AddMod
+        | AVMOpcode::MulMod
+        | AVMOpcode::Exp
+        | AVMOpcode::SignExtend => "arithmetic",
+        AVMOpcode::LessThan
+        | AVMOpcode::GreaterThan
+        | AVMOpcode::SLessThan
+        | AVMOpcode::SGreaterThan
+        | AVMOpcode::Equal
+        | AVMOpcode::IsZero => "comparison",
+        AVMOpcode::BitwiseAnd
+        | AVMOpcode::BitwiseOr
+        | AVMOpcode::BitwiseXor
+        | AVMOpcode::BitwiseNeg
+        | AVMOpcode::Byte
+        | AVMOpcode::ShiftLeft
+        | AVMOpcode::ShiftRight
+        | AVMOpcode::ShiftArith => "bitwise",
+        AVMOpcode::Hash
+        | AVMOpcode::EthHash2
+        | AVMOpcode::Keccakf
+        | AVMOpcode::Sha256f
+        | AVMOpcode::Ripemd160f
+        | AVMOpcode::Blake2f
+        | AVMOpcode::EcRecover
+        | AVMOpcode::EcAdd
+        | AVMOpcode::EcMul
+        | AVMOpcode::EcPairing => "crypto",
+        AVMOpcode::Pop
+        | AVMOpcode::Spush
+        | AVMOpcode::Rpush
+        | AVMOpcode::Rset
+        | AVMOpcode::StackEmpty
+        | AVMOpcode::PCpush
+        | AVMOpcode::AuxPush
+        | AVMOpcode::AuxPop
+        | AVMOpcode::AuxStackEmpty
+        | AVMOpcode::Dup0
+        | AVMOpcode::Dup1
+        | AVMOpcode::Dup2
+        | AVMOpcode::Swap1
+        | AVMOpcode::Swap2 => "stack",
+        AVMOpcode::Type
+        | AVMOpcode::Tget
+        | AVMOpcode::Tset
+        | AVMOpcode::Tlen
+        | AVMOpcode::Xget
+        | AVMOpcode::Xset => "tuple",
+        AVMOpcode::NewBuffer
+        | AVMOpcode::GetBuffer8
+        | AVMOpcode::GetBuffer64
+        | AVMOpcode::GetBuffer256
+        | AVMOpcode::SetBuffer8
+        | AVMOpcode::SetBuffer64
+        | AVMOpcode::SetBuffer256 => "buffer",
+        AVMOpcode::Jump
+        | AVMOpcode::Cjump
+        | AVMOpcode::Noop
+        | AVMOpcode::ErrPush
+        | AVMOpcode::ErrSet
+        | AVMOpcode::ErrCodePoint
+        | AVMOpcode::PushInsn
+        | AVMOpcode::PushInsnImm
+        | AVMOpcode::OpenInsn
+        | AVMOpcode::Zero
+        | AVMOpcode::Error
+        | AVMOpcode::Halt
+        | AVMOpcode::Breakpoint => "control",
+        AVMOpcode::Inbox
+        | AVMOpcode::InboxPeek
+        | AVMOpcode::Log
+        | AVMOpcode::Send
+        | AVMOpcode::Sideload
+        | AVMOpcode::DebugPrint => "io",
+        AVMOpcode::PushGas | AVMOpcode::SetGas => "gas",
+    }
+}
+
+#[test]
+fn test_execution_stats() {
+    let mut stats = ExecutionStats::default();
+    for opcode in &[
+        AVMOpcode::Dup0,
+        AVMOpcode::Add,
+        AVMOpcode::Dup0,
+        AVMOpcode::Add,
+        AVMOpcode::Pop,
+    ] {
+        stats.record(*opcode, 3);
+    }
+    let report = stats.report(1);
+    assert_eq!(report.instructions, 5);
+    assert_eq!(report.gas, 15);
+    assert_eq!(report.opcodes[0].count, 2);
+    assert_eq!(report.pairs.len(), 1);
+    assert_eq!(report.pairs[0].opcodes, vec!["dup0", "add"]);
+    assert_eq!(report.pairs[0].count, 2);
+    assert_eq!(report.triples[0].count, 1);
+    assert_eq!(report.gas_by_class["stack"], 9);
+    assert_eq!(report.gas_by_class["arithmetic"], 6);
+}
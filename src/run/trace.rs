@@ -0,0 +1,290 @@
+/*
+ * Copyright 2021, Offchain Labs, Inc. All rights reserved.
+ */
+
+//!Provides an execution trace in the JSON Lines format, with one header object followed by one
+//! object per executed instruction.
+
+use super::emulator::Machine;
+use crate::compile::CompileError;
+use crate::mavm::{CodePt, Value};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+///Version of the trace format, recorded in the header line. Increase it whenever the meaning of an
+/// existing field changes.
+pub const TRACE_FORMAT_VERSION: u64 = 1;
+
+///Selects which steps are written to a `JsonTraceWriter`. A step is written only if it passes
+/// every filter that is set.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    ///Only write steps numbered in this range, see `StepRange`.
+    pub steps: Option<StepRange>,
+    ///Only write steps at a source location in a file with this name or path suffix.
+    pub file: Option<String>,
+    ///Only write steps while the function starting at this source location is running, see
+    /// `FunctionFilter`.
+    pub function: Option<FunctionFilter>,
+    ///Number of data stack items, from the top, written with each step.
+    pub stack_items: usize,
+}
+
+///A range of step numbers given as `first..end`, where either bound may be left out, and end is
+/// excluded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepRange {
+    pub first: u64,
+    pub end: Option<u64>,
+}
+
+impl StepRange {
+    fn contains(&self, step: u64) -> bool {
+        step >= self.first && self.end.map_or(true, |end| step < end)
+    }
+}
+
+impl FromStr for StepRange {
+    type Err = CompileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bound = |bound: &str| -> Result<Option<u64>, CompileError> {
+            if bound.is_empty() {
+                Ok(None)
+            } else {
+                bound.parse().map(Some).map_err(|_| {
+                    trace_error(format!("invalid step \"{}\" in range \"{}\"", bound, s))
+                })
+            }
+        };
+        match s.find("..") {
+            Some(index) => Ok(StepRange {
+                first: bound(&s[..index])?.unwrap_or(0),
+                end: bound(&s[index + 2..])?,
+            }),
+            None => Err(trace_error(format!(
+                "expected a step range like 100..200, got \"{}\"",
+                s
+            ))),
+        }
+    }
+}
+
+///A function given as `file:line`, the location of its first instruction, as named in profiler
+/// exports.
+///
+/// Tracing starts when execution reaches that location and stops when the function returns, which
+/// is detected by the number of return addresses on the aux stack dropping below the number while
+/// the function ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionFilter {
+    pub file: String,
+    pub line: u32,
+}
+
+impl FromStr for FunctionFilter {
+    type Err = CompileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let index = s.rfind(':').ok_or_else(|| {
+            trace_error(format!(
+                "expected a function like file.mini:12, got \"{}\"",
+                s
+            ))
+        })?;
+        let line = s[index + 1..]
+            .parse()
+            .map_err(|_| trace_error(format!("invalid line number in \"{}\"", s)))?;
+        Ok(FunctionFilter {
+            file: s[..index].to_string(),
+            line,
+        })
+    }
+}
+
+///Progress through the function selected by a `FunctionFilter`.
+#[derive(Debug, Clone, Copy)]
+enum FunctionState {
+    Outside,
+    ///The function was reached with this many return addresses on the aux stack, and has not yet
+    /// pushed its own.
+    Entering(usize),
+    ///The function is running with this many return addresses on the aux stack, including its own.
+    Inside(usize),
+}
+
+#[derive(Serialize)]
+struct TraceHeader {
+    format: &'static str,
+    version: u64,
+}
+
+#[derive(Serialize)]
+struct TraceLocation {
+    file: String,
+    line: u32,
+    column: u32,
+}
+
+#[derive(Serialize)]
+struct TraceStep {
+    step: u64,
+    codept: String,
+    opcode: String,
+    immediate: Option<String>,
+    stack: Vec<String>,
+    gas_remaining: String,
+    location: Option<TraceLocation>,
+}
+
+///Writes the steps of a `Machine` that pass a `TraceFilter` as JSON Lines, see
+/// `Machine::set_json_trace`.
+#[derive(Debug)]
+pub struct JsonTraceWriter {
+    out: BufWriter<File>,
+    filter: TraceFilter,
+    function_state: FunctionState,
+}
+
+impl JsonTraceWriter {
+    ///Creates a trace at path, and writes the header line to it.
+    pub fn new(path: &Path, filter: TraceFilter) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(
+            &mut out,
+            &TraceHeader {
+                format: "mini-trace",
+                version: TRACE_FORMAT_VERSION,
+            },
+        )?;
+        writeln!(out)?;
+        Ok(JsonTraceWriter {
+            out,
+            filter,
+            function_state: FunctionState::Outside,
+        })
+    }
+
+    ///Writes the instruction that machine is about to execute as step number step, if it passes
+    /// the filter.
+    pub(crate) fn write_step(&mut self, machine: &Machine, step: u64) -> io::Result<()> {
+        let pc = match machine.get_pc() {
+            Ok(pc) => pc,
+            Err(_) => return Ok(()),
+        };
+        let insn = match machine.insn_at(pc) {
+            Some(insn) => insn,
+            None => return Ok(()),
+        };
+        let file_name = insn.debug_info.location.map(|loc| {
+            machine
+                .file_info_chart()
+                .get(&loc.file_id)
+                .map(|info| info.name.clone())
+                .unwrap_or_else(|| format!("unknown_file_{}", loc.file_id))
+        });
+        let location = insn
+            .debug_info
+            .location
+            .zip(file_name)
+            .map(|(loc, file)| TraceLocation {
+                file,
+                line: loc.line() + 1,
+                column: loc.column.to_usize() as u32 + 1,
+            });
+        if !self.in_function(machine, location.as_ref())
+            || !self.filter.steps.map_or(true, |steps| steps.contains(step))
+        {
+            return Ok(());
+        }
+        if let Some(file) = &self.filter.file {
+            match &location {
+                Some(location) if matches_file(&location.file, file) => {}
+                _ => return Ok(()),
+            }
+        }
+        let stack = (0..self.filter.stack_items)
+            .filter_map(|n| machine.stack().nth(n))
+            .map(|val| val.to_string())
+            .collect();
+        let trace_step = TraceStep {
+            step,
+            codept: pc.to_string(),
+            opcode: insn.opcode.to_string(),
+            immediate: insn.immediate.as_ref().map(Value::to_string),
+            stack,
+            gas_remaining: machine.arb_gas_remaining().to_string(),
+            location,
+        };
+        serde_json::to_writer(&mut self.out, &trace_step)?;
+        writeln!(self.out)
+    }
+
+    ///Updates the progress through the filtered function, and returns whether the current step is
+    /// inside it, which is always the case if there is no function filter.
+    fn in_function(&mut self, machine: &Machine, location: Option<&TraceLocation>) -> bool {
+        let function = match &self.filter.function {
+            Some(function) => function,
+            None => return true,
+        };
+        let depth = machine
+            .aux_stack()
+            .iter()
+            .filter(|val| matches!(val, Value::CodePoint(CodePt::Internal(_))))
+            .count();
+        self.function_state = match self.function_state {
+            FunctionState::Outside => match location {
+                Some(location)
+                    if location.line == function.line
+                        && matches_file(&location.file, &function.file) =>
+                {
+                    FunctionState::Entering(depth)
+                }
+                _ => FunctionState::Outside,
+            },
+            FunctionState::Entering(entry_depth) if depth > entry_depth => {
+                FunctionState::Inside(depth)
+            }
+            FunctionState::Entering(entry_depth) if depth < entry_depth => FunctionState::Outside,
+            FunctionState::Inside(inside_depth) if depth < inside_depth => FunctionState::Outside,
+            state => state,
+        };
+        !matches!(self.function_state, FunctionState::Outside)
+    }
+}
+
+///Returns true if file_name is file, or ends with file as its last path components.
+fn matches_file(file_name: &str, file: &str) -> bool {
+    file_name == file || file_name.ends_with(&format!("/{}", file))
+}
+
+fn trace_error(description: String) -> CompileError {
+    CompileError::new(String::from("Trace error"), description, vec![])
+}
+
+#[test]
+fn test_trace_filter_parsing() {
+    assert_eq!(
+        "100..200".parse::<StepRange>().unwrap(),
+        StepRange {
+            first: 100,
+            end: Some(200)
+        }
+    );
+    let open_range = "..5".parse::<StepRange>().unwrap();
+    assert!(open_range.contains(0) && open_range.contains(4) && !open_range.contains(5));
+    assert!("100".parse::<StepRange>().is_err());
+    assert_eq!(
+        "arb_os/inbox.mini:42".parse::<FunctionFilter>().unwrap(),
+        FunctionFilter {
+            file: String::from("arb_os/inbox.mini"),
+            line: 42
+        }
+    );
+    assert!("inbox.mini".parse::<FunctionFilter>().is_err());
+    assert!(matches_file("arb_os/inbox.mini", "inbox.mini"));
+    assert!(!matches_file("arb_os/xinbox.mini", "inbox.mini"));
+}
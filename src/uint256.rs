@@ -2,34 +2,27 @@
  * Copyright 2020, Offchain Labs, Inc. All rights reserved.
  */
 
-use ethereum_types::{H160, U256};
+use ethereum_types::{H160, U256, U512};
 use ethers_core::utils::keccak256;
-use num_bigint::{BigInt, BigUint, Sign, ToBigInt};
-use num_traits::cast::ToPrimitive;
-use num_traits::identities::{One, Zero};
-use num_traits::sign::Signed;
-use num_traits::CheckedSub;
+use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::fmt;
-use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Rem, Shl, Sub};
 
-#[derive(Debug, Clone, PartialEq, Eq, Ord, Hash)]
+///A 256 bit unsigned integer, stored inline as four 64 bit limbs so that arithmetic never
+/// allocates.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Uint256 {
-    val: BigUint,
+    val: U256,
 }
 
 impl Uint256 {
     pub fn from_u64(x: u64) -> Self {
-        Uint256 {
-            val: BigUint::from(x),
-        }
+        Uint256 { val: U256::from(x) }
     }
 
     pub fn from_usize(x: usize) -> Self {
-        Uint256 {
-            val: BigUint::from(x),
-        }
+        Uint256 { val: U256::from(x) }
     }
 
     pub fn from_bool(b: bool) -> Self {
@@ -41,52 +34,68 @@ impl Uint256 {
     }
 
     pub fn from_string(s: &str) -> Option<Self> {
-        match BigUint::parse_bytes(s.as_bytes(), 10) {
-            Some(bui) => match Uint256::trim(&bui) {
-                (val, true) => Some(Uint256 { val }),
-                (_, false) => None,
-            },
-            None => None,
-        }
+        Uint256::parse_radix(s, 10)
     }
 
     pub fn from_string_hex(s: &str) -> Option<Self> {
-        match BigUint::parse_bytes(s.as_bytes(), 16) {
-            Some(bui) => match Uint256::trim(&bui) {
-                (val, true) => Some(Uint256 { val }),
-                (_, false) => None,
-            },
-            None => None,
-        }
+        Uint256::parse_radix(s, 16)
     }
 
     pub fn from_signed_string(s: &str) -> Option<Self> {
         let s = s.replace("s", "");
-        match BigInt::parse_bytes(s.as_bytes(), 10) {
-            Some(bi) => match Uint256::trim(&Uint256::bigint_to_biguint(bi)) {
-                (val, true) => Some(Uint256 { val }),
-                (_, false) => None,
-            },
-            None => None,
+        match s.strip_prefix('-') {
+            Some(magnitude) => Uint256::parse_radix(magnitude, 10)?.unary_minus(),
+            None => Uint256::parse_radix(&s, 10),
         }
     }
 
+    ///Parses s as a number in radix, allowing a leading `+` and `_` separators after the first
+    /// digit, returns None if s is not a valid number or does not fit in 256 bits.
+    fn parse_radix(s: &str, radix: u32) -> Option<Self> {
+        let s = s.strip_prefix('+').unwrap_or(s);
+        if s.is_empty() || s.starts_with('_') {
+            return None;
+        }
+        let mut val = U256::zero();
+        for c in s.chars().filter(|c| *c != '_') {
+            let digit = c.to_digit(radix)?;
+            let (shifted, overflow) = val.overflowing_mul(U256::from(radix));
+            if overflow {
+                return None;
+            }
+            val = shifted.checked_add(U256::from(digit))?;
+        }
+        Some(Uint256 { val })
+    }
+
+    /// Reads a big-endian integer of at most 32 bytes; panics on longer
+    /// input. Use `try_from_bytes` for untrusted data.
     pub fn from_bytes(b: &[u8]) -> Self {
-        Uint256 {
-            val: BigUint::from_bytes_be(b),
+        Uint256::try_from_bytes(b)
+            .unwrap_or_else(|| panic!("Uint256::from_bytes: {} bytes is longer than 32", b.len()))
+    }
+
+    /// Reads a big-endian integer, or returns None if `b` is longer than
+    /// 32 bytes.
+    pub fn try_from_bytes(b: &[u8]) -> Option<Self> {
+        if b.len() > 32 {
+            return None;
         }
+        Some(Uint256 {
+            val: U256::from_big_endian(b),
+        })
     }
 
     pub fn from_u256(x: &U256) -> Self {
-        let mut b: Vec<u8> = vec![0u8; 32];
-        x.to_big_endian(&mut b);
-        Uint256::from_bytes(&b)
+        Uint256 { val: *x }
     }
 
     pub fn from_u32_digits(b: &[u32]) -> Self {
-        let mut val = BigUint::from(0u64);
-        val.assign_from_slice(b);
-        Uint256 { val }
+        let mut limbs = [0u64; 4];
+        for (i, digit) in b.iter().take(8).enumerate() {
+            limbs[i / 2] |= (*digit as u64) << (32 * (i % 2));
+        }
+        Uint256 { val: U256(limbs) }
     }
 
     pub fn _from_gwei(num_gwei: u64) -> Self {
@@ -98,77 +107,54 @@ impl Uint256 {
     }
 
     pub fn to_usize(&self) -> Option<usize> {
-        self.val.to_usize()
+        self.to_u64().and_then(|x| usize::try_from(x).ok())
     }
 
     pub fn to_u64(&self) -> Option<u64> {
-        self.val.to_u64()
+        if self.val.bits() <= 64 {
+            Some(self.val.low_u64())
+        } else {
+            None
+        }
     }
 
     pub fn to_bytes_be(&self) -> Vec<u8> {
         // always returns 32 bytes
-        let raw = self.val.to_bytes_be();
-        if raw.len() < 32 {
-            let mut ret = vec![0u8; 32 - raw.len()];
-            ret.extend(raw);
-            ret
-        } else {
-            raw
-        }
+        let mut ret = vec![0u8; 32];
+        self.val.to_big_endian(&mut ret);
+        ret
     }
 
     pub fn to_h160(&self) -> H160 {
-        H160::from_slice(&{
-            let raw = self.val.to_bytes_be();
-            if raw.len() < 20 {
-                let mut ret = vec![0u8; 20 - raw.len()];
-                ret.extend(raw);
-                ret
-            } else {
-                raw
-            }
-        })
+        H160::from_slice(&self.to_bytes_be()[12..])
     }
 
     pub fn to_u256(&self) -> U256 {
-        U256::from_big_endian(&self.to_bytes_minimal())
+        self.val
     }
 
     pub fn trim_to_u64(&self) -> u64 {
-        self.val
-            .clone()
-            .bitand(BigUint::from(0xffffffffffffffffu64))
-            .to_u64()
-            .unwrap()
+        self.val.low_u64()
     }
 
     pub fn to_bytes_minimal(&self) -> Vec<u8> {
-        if self.is_zero() {
-            vec![]
-        } else {
-            self.val.to_bytes_be()
-        }
+        let bytes = self.to_bytes_be();
+        bytes[(self.val.leading_zeros() / 8) as usize..].to_vec()
     }
 
     pub fn to_u32_digits_be(&self) -> [u32; 8] {
         let mut ret = [0u32; 8];
-        let v = self.val.to_u32_digits();
-        for (i, vv) in v.iter().enumerate() {
-            ret[7 - i] = *vv;
+        for (i, limb) in self.val.0.iter().enumerate() {
+            ret[7 - 2 * i] = *limb as u32;
+            ret[6 - 2 * i] = (*limb >> 32) as u32;
         }
         ret
     }
 
     pub fn to_u32_digits_be_2(&self, ui2: &Self) -> [u32; 16] {
         let mut ret = [0u32; 16];
-        let v = self.val.to_u32_digits();
-        for (i, vv) in v.iter().enumerate() {
-            ret[7 - i] = *vv;
-        }
-        let v = ui2.val.to_u32_digits();
-        for (i, vv) in v.iter().enumerate() {
-            ret[15 - i] = *vv;
-        }
+        ret[..8].copy_from_slice(&self.to_u32_digits_be());
+        ret[8..].copy_from_slice(&ui2.to_u32_digits_be());
         ret
     }
 
@@ -182,20 +168,16 @@ impl Uint256 {
     }
 
     pub fn zero() -> Self {
-        Uint256 {
-            val: BigUint::zero(),
-        }
+        Uint256 { val: U256::zero() }
     }
 
     pub fn one() -> Self {
-        Uint256 {
-            val: BigUint::one(),
-        }
+        Uint256 { val: U256::one() }
     }
 
     pub fn max_uint() -> Self {
         Uint256 {
-            val: BigUint::new(vec![0xffff_ffff; 8]),
+            val: U256::max_value(),
         }
     }
 
@@ -204,135 +186,118 @@ impl Uint256 {
     }
 
     pub fn is_zero(&self) -> bool {
-        self.val == BigUint::zero()
+        self.val.is_zero()
     }
 
     pub fn unary_minus(&self) -> Option<Self> {
-        if self.val == BigUint::new(vec![0, 0, 0, 0, 0, 0, 0, 0x8000_0000]) {
-            Some(self.clone())
-        } else {
-            Some(self.bitwise_neg().add(&Uint256::one()))
-        }
+        // negating the most negative value gives itself
+        Some(Uint256 {
+            val: self.val.overflowing_neg().0,
+        })
     }
 
     pub fn bitwise_neg(&self) -> Self {
-        let all_ones = BigUint::new(vec![0xffff_ffff; 8]);
-        let val = self.val.clone().bitxor(all_ones);
-        Uint256 { val }
+        Uint256 { val: !self.val }
     }
 
     pub fn bitwise_and(&self, other: &Self) -> Self {
-        let val = self.val.clone().bitand(other.val.clone());
-        Uint256 { val }
+        Uint256 {
+            val: self.val & other.val,
+        }
     }
 
     pub fn bitwise_or(&self, other: &Self) -> Self {
-        let val = self.val.clone().bitor(other.val.clone());
-        Uint256 { val }
+        Uint256 {
+            val: self.val | other.val,
+        }
     }
 
     pub fn bitwise_xor(&self, other: &Self) -> Self {
-        let val = self.val.clone().bitxor(other.val.clone());
-        Uint256 { val }
+        Uint256 {
+            val: self.val ^ other.val,
+        }
     }
 
     pub fn add(&self, other: &Self) -> Self {
-        let res = self.val.clone().add(&other.val);
-        let (val, _) = Uint256::trim(&res);
-        Uint256 { val }
+        Uint256 {
+            val: self.val.overflowing_add(other.val).0,
+        }
     }
 
     pub fn sub(&self, other: &Self) -> Option<Self> {
-        let res = self.val.clone().checked_sub(&other.val)?;
-        let (val, _) = Uint256::trim(&res);
-        Some(Uint256 { val })
+        Some(Uint256 {
+            val: self.val.checked_sub(other.val)?,
+        })
     }
 
     pub fn unchecked_sub(&self, other: &Self) -> Self {
         // subtraction mod 2**256
-        if self < other {
-            other.sub(self).unwrap().unary_minus().unwrap()
-        } else {
-            self.sub(other).unwrap()
+        Uint256 {
+            val: self.val.overflowing_sub(other.val).0,
         }
     }
 
     pub fn mul(&self, other: &Self) -> Self {
-        let res = self.val.clone().mul(&other.val);
-        let (val, _) = Uint256::trim(&res);
-        Uint256 { val }
+        Uint256 {
+            val: self.val.overflowing_mul(other.val).0,
+        }
     }
 
     pub fn div(&self, other: &Self) -> Option<Self> {
-        if other.val.is_zero() {
-            None
-        } else {
-            let val = self.val.clone().div(&other.val);
-            Some(Uint256 { val })
-        }
+        Some(Uint256 {
+            val: self.val.checked_div(other.val)?,
+        })
     }
 
     pub fn modulo(&self, other: &Self) -> Option<Self> {
-        if other.val.is_zero() {
-            None
-        } else {
-            let val = self.val.clone().rem(&other.val);
-            Some(Uint256 { val })
-        }
+        Some(Uint256 {
+            val: self.val.checked_rem(other.val)?,
+        })
     }
 
     pub fn sdiv(&self, other: &Self) -> Option<Self> {
-        if other.val.is_zero() {
-            None
+        // rounds toward zero
+        let quotient = self.abs().div(&other.abs())?;
+        if self.is_negative() != other.is_negative() {
+            quotient.unary_minus()
         } else {
-            let val = self.to_signed().div(&other.to_signed());
-            Some(Uint256 {
-                val: Uint256::bigint_to_biguint(val),
-            })
+            Some(quotient)
         }
     }
 
     pub fn smodulo(&self, other: &Self) -> Option<Self> {
         //TODO: verify that semantics match AVM
-        if other.val.is_zero() {
-            None
+        // the result takes the sign of self
+        let remainder = self.abs().modulo(&other.abs())?;
+        if self.is_negative() {
+            remainder.unary_minus()
         } else {
-            let val = self.to_signed().rem(&other.to_signed());
-            Some(Uint256 {
-                val: Uint256::bigint_to_biguint(val),
-            })
+            Some(remainder)
         }
     }
 
     pub fn add_mod(&self, denom: &Self, modulus: &Self) -> Option<Self> {
-        if modulus.val.is_zero() {
+        if modulus.is_zero() {
             None
         } else {
-            let val = self
-                .val
-                .clone()
-                .add(denom.val.clone())
-                .rem(modulus.val.clone());
-            Some(Uint256 { val })
+            let sum = U512::from(self.val) + U512::from(denom.val);
+            Some(Uint256::from_u512(sum % U512::from(modulus.val)))
         }
     }
 
     pub fn mul_mod(&self, denom: &Self, modulus: &Self) -> Option<Self> {
-        if modulus.val.is_zero() {
+        if modulus.is_zero() {
             None
         } else {
-            let val = self
-                .val
-                .clone()
-                .mul(denom.val.clone())
-                .rem(modulus.val.clone());
-            Some(Uint256 { val })
+            let product = self.val.full_mul(denom.val);
+            Some(Uint256::from_u512(product % U512::from(modulus.val)))
         }
     }
 
     pub fn exp(&self, other: &Self) -> Self {
+        // exponentiation mod 2**256
         Uint256 {
-            val: self.val.modpow(&other.val, &BigUint::one().shl(256)),
+            val: self.val.overflowing_pow(other.val).0,
         }
     }
 
@@ -341,59 +306,56 @@ impl Uint256 {
             Uint256::zero()
         } else {
             Uint256 {
-                val: Uint256::trim(&(self.val.clone() << num)).0,
+                val: self.val << num,
             }
         }
     }
 
     pub fn shift_right(&self, num: usize) -> Self {
-        Uint256 {
-            val: (self.val.clone() >> num),
+        if num >= 256 {
+            Uint256::zero()
+        } else {
+            Uint256 {
+                val: self.val >> num,
+            }
         }
     }
 
     pub fn shift_arith(&self, raw_num: usize) -> Self {
-        let need_fill = self.val.bits() == 256;
         let num = if raw_num > 256 { 256 } else { raw_num };
-        let mut val = (self.val.clone() >> num);
-        if need_fill {
-            val = val + (Uint256::max_uint().val << (256 - num))
-        }
-        Uint256 {
-            val: Uint256::trim(&val).0,
+        let shifted = self.shift_right(num);
+        if self.is_negative() && num > 0 {
+            // fill the vacated high bits with the sign bit
+            shifted.bitwise_or(&Uint256::max_uint().shift_right(num).bitwise_neg())
+        } else {
+            shifted
         }
     }
 
     pub fn s_less_than(&self, other: &Self) -> bool {
-        self.to_signed() < other.to_signed()
-    }
-
-    fn trim(bui: &BigUint) -> (BigUint, bool) {
-        if bui.bits() <= 256 {
-            (bui.clone(), true)
+        if self.is_negative() == other.is_negative() {
+            self < other
         } else {
-            let mask = BigUint::new(vec![0xffff_ffff; 8]);
-            (bui.bitand(mask), false)
+            self.is_negative()
         }
     }
 
-    fn to_signed(&self) -> BigInt {
-        if self.val < BigUint::new(vec![0, 0, 0, 0, 0, 0, 0, 0x8000_0000]) {
-            self.val.to_bigint().unwrap()
+    fn is_negative(&self) -> bool {
+        self.val.bit(255)
+    }
+
+    ///Returns the magnitude of self interpreted as a two's complement number, as an unsigned value.
+    fn abs(&self) -> Self {
+        if self.is_negative() {
+            self.unary_minus().unwrap()
         } else {
-            let unshifted = self.val.to_bigint().unwrap();
-            let shift = BigInt::new(Sign::Plus, vec![0, 0, 0, 0, 0, 0, 0, 0, 1]);
-            unshifted.sub(shift)
+            self.clone()
         }
     }
 
-    fn bigint_to_biguint(bi: BigInt) -> BigUint {
-        if bi.is_negative() {
-            bi.add(BigInt::new(Sign::Plus, vec![0, 0, 0, 0, 0, 0, 0, 0, 1]))
-                .to_biguint()
-                .unwrap()
-        } else {
-            bi.to_biguint().unwrap()
+    fn from_u512(val: U512) -> Self {
+        Uint256 {
+            val: U256::try_from(val).expect("value reduced mod a 256 bit number must fit"),
         }
     }
 
@@ -412,16 +374,9 @@ impl Uint256 {
     }
 }
 
-impl PartialOrd for Uint256 {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.val.cmp(&other.val))
-    }
-}
-
 impl fmt::Display for Uint256 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let cutoff: usize = 1 << 32;
-        if self.val < BigUint::from(cutoff) {
+        if self.val.bits() <= 32 {
             write!(f, "{}", self.val)
         } else {
             write!(f, "{:#x}", self.val)
@@ -434,9 +389,7 @@ impl Serialize for Uint256 {
     where
         S: Serializer,
     {
-        let mut s = String::new();
-        s.push_str(&self.val.to_str_radix(16));
-        s.serialize(serializer)
+        format!("{:x}", self.val).serialize(serializer)
     }
 }
 
@@ -446,9 +399,8 @@ impl<'de> Deserialize<'de> for Uint256 {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(Uint256 {
-            val: BigUint::parse_bytes(s.as_bytes(), 16).unwrap(),
-        })
+        Uint256::from_string_hex(&s)
+            .ok_or_else(|| D::Error::custom(format!("invalid 256 bit hex number: {}", s)))
     }
 }
 
@@ -464,3 +416,80 @@ fn test_uint256_u32_digit_order() {
         0x6a09e667u32
     );
 }
+
+#[test]
+fn test_uint256_from_bytes_length() {
+    assert_eq!(Uint256::from_bytes(&[1, 0]), Uint256::from_u64(256));
+    assert_eq!(
+        Uint256::try_from_bytes(&[0xff; 32]),
+        Some(Uint256::max_uint())
+    );
+    assert_eq!(Uint256::try_from_bytes(&[0; 33]), None);
+}
+
+#[test]
+fn test_uint256_matches_biguint() {
+    use num_bigint::BigUint;
+    let modulus: BigUint = BigUint::from(1u64) << 256usize;
+    let to_big = |x: &Uint256| BigUint::from_bytes_be(&x.to_bytes_be());
+    let from_big = |x: BigUint| Uint256::from_bytes(&(x % &modulus).to_bytes_be());
+    let samples: Vec<Uint256> = vec![
+        Uint256::zero(),
+        Uint256::one(),
+        Uint256::from_u64(3),
+        Uint256::from_u64(0xffff_ffff_ffff_ffff),
+        Uint256::_max_neg_int(),
+        Uint256::max_uint(),
+        Uint256::from_string_hex(
+            "6a09e667bb67ae853c6ef372a54ff53a510e527f9b05688c1f83d9ab5be0cd19",
+        )
+        .unwrap(),
+        Uint256::from_signed_string("-12345").unwrap(),
+    ];
+    for a in &samples {
+        assert_eq!(
+            Uint256::from_string(&to_big(a).to_str_radix(10)),
+            Some(a.clone())
+        );
+        assert_eq!(
+            serde_json::from_str::<Uint256>(&serde_json::to_string(a).unwrap()).unwrap(),
+            *a
+        );
+        for b in &samples {
+            assert_eq!(a.add(b), from_big(to_big(a) + to_big(b)));
+            assert_eq!(a.mul(b), from_big(to_big(a) * to_big(b)));
+            assert_eq!(a.exp(b), from_big(to_big(a).modpow(&to_big(b), &modulus)));
+            assert_eq!(
+                a.unchecked_sub(b),
+                from_big(to_big(a) + &modulus - to_big(b))
+            );
+            for m in &samples {
+                assert_eq!(
+                    a.mul_mod(b, m),
+                    to_big(m)
+                        .ne(&BigUint::from(0u64))
+                        .then(|| from_big(to_big(a) * to_big(b) % to_big(m)))
+                );
+            }
+        }
+    }
+    let minus_one = Uint256::max_uint();
+    let minus_four = Uint256::from_signed_string("-4").unwrap();
+    assert_eq!(
+        minus_four.shift_arith(1),
+        Uint256::from_signed_string("-2").unwrap()
+    );
+    assert_eq!(minus_four.shift_arith(300), minus_one);
+    assert_eq!(Uint256::from_u64(4).shift_arith(300), Uint256::zero());
+    assert_eq!(
+        minus_four.sdiv(&Uint256::from_u64(3)),
+        Uint256::from_signed_string("-1")
+    );
+    assert_eq!(
+        minus_four.smodulo(&Uint256::from_u64(3)),
+        Uint256::from_signed_string("-1")
+    );
+    assert!(minus_four.s_less_than(&Uint256::one()));
+    assert_eq!(Uint256::from_string("1_000"), Some(Uint256::from_u64(1000)));
+    assert_eq!(Uint256::from_string_hex(&"f".repeat(65)), None);
+}
@@ -3,7 +3,7 @@
  */
 
 use crate::link::LinkedProgram;
-use crate::mavm::{AVMOpcode, Instruction};
+use crate::mavm::{AVMOpcode, Instruction, Value};
 use rustc_hex::ToHex;
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -35,14 +35,12 @@ impl CodeUploader {
             Ok(file) => file,
         };
 
-        let mut s = String::new();
-        s = match file.read_to_string(&mut s) {
-            Err(why) => panic!("couldn't read {}: {:?}", path.display(), why),
-            Ok(_) => s,
-        };
+        let mut bytes = vec![];
+        if let Err(why) = file.read_to_end(&mut bytes) {
+            panic!("couldn't read {}: {:?}", path.display(), why);
+        }
 
-        let parse_result: Result<LinkedProgram, serde_json::Error> = serde_json::from_str(&s);
-        match parse_result {
+        match LinkedProgram::from_bytes(&bytes) {
             Ok(prog) => {
                 let code_len = prog.code.len();
                 let mut ret = CodeUploader::_new(code_len);
@@ -71,6 +69,18 @@ impl CodeUploader {
         self._finish_batch();
     }
 
+    ///Serializes insn on its own, returning its bytes.
+    pub fn _serialize_insn(&mut self, insn: &Instruction<AVMOpcode>) -> Vec<u8> {
+        insn._upload(self);
+        std::mem::take(&mut self.build_buffer)
+    }
+
+    ///Serializes val on its own, returning its bytes.
+    pub fn _serialize_value(&mut self, val: &Value) -> Vec<u8> {
+        val._upload(self);
+        std::mem::take(&mut self.build_buffer)
+    }
+
     pub fn _translate_pc(&self, pc: usize) -> usize {
         self.num_total - pc
     }